@@ -1,11 +1,440 @@
-use android_bootimg::{parser::BootHeader, parser::BootImage, patcher::BootImagePatchOption};
+use android_bootimg::avb::{AvbAlgorithm, AvbDescriptor, AvbHashStatus, AvbPublicKey, VbMetaImage};
+use android_bootimg::builder::BootImageBuilder;
+use android_bootimg::compress::{
+    CompressFormat, LimitedDecoder, detect_format_from_reader, estimate_uncompressed_size,
+    get_decoder, transcode,
+};
+use android_bootimg::container::{ContainerKind, unwrap_image, wrap_lz4};
+#[cfg(unix)]
+use android_bootimg::cpio::{AddDirOptions, ExtractOptions};
+use android_bootimg::cpio::{Cpio, CpioEntry, CpioLoadOptions, MagiskPatchOptions, PatchKind};
+use android_bootimg::digest::{DigestAlgorithm, digest_reader};
+use android_bootimg::error::BootImgError;
+use android_bootimg::header_text::{HeaderOverrides, parse_header_text};
+use android_bootimg::id::IdStatus;
+use android_bootimg::layouts::VendorRamdiskTableEntryType;
+use android_bootimg::mkbootimg::MkbootimgArgs;
+use android_bootimg::pattern::{HexPattern, hexpatch};
+#[cfg(feature = "payload")]
+use android_bootimg::payload::Payload;
+#[cfg(feature = "sign")]
+use android_bootimg::sign::AvbSigningKey;
+use android_bootimg::{
+    kernel::{detect_kernel_arch, find_linux_banner},
+    parser::BootHeader,
+    parser::BootImage,
+    parser::BootImageVersion,
+    parser::DEFAULT_MAX_DECOMPRESSED_SIZE,
+    parser::RamdiskContentKind,
+    patcher::{
+        AppendedDtbAction, AvbFooterOptions, BootImagePatchOption, CompressLevel, CompressOptions,
+    },
+};
 use anyhow::{Result, bail};
+use clap::{Args, Parser, Subcommand};
+use log::{Level, LevelFilter, error, info, warn};
 use memmap2::Mmap;
 use paste::paste;
-use std::env;
+use serde::{Deserialize, Serialize};
+use std::fs;
 use std::fs::{File, OpenOptions};
+#[cfg(feature = "tar")]
+use std::io::BufReader;
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
 use std::str::from_utf8;
 
+#[derive(Parser)]
+#[command(version, about = "Inspect, unpack and repack Android boot images")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Only print errors on stderr
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print debug messages, twice for trace messages
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the header, blocks, ramdisk listing and AVB state of an image
+    Info {
+        image: PathBuf,
+        /// Print a JSON document instead, stable across releases unlike the text
+        #[arg(long)]
+        json: bool,
+        /// Print every raw header field
+        #[arg(long)]
+        all: bool,
+        /// List the merged vendor ramdisks with the entry each file comes from
+        #[arg(long)]
+        merge_vendor_ramdisks: bool,
+    },
+    /// Write every block of an image and a `header` text file to a directory
+    Unpack {
+        image: PathBuf,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// Keep the kernel and ramdisks compressed
+        #[arg(long)]
+        no_decompress: bool,
+        /// Also write the merged vendor ramdisks to vendor_ramdisk.cpio
+        #[arg(long)]
+        merge_vendor_ramdisks: bool,
+    },
+    /// Rebuild an image, replacing the given blocks
+    ///
+    /// The source may be a directory written by unpack, the image is then
+    /// built again from its manifest.json and files, edited or not. The AVB
+    /// footer of the unpacked image is not kept, see --avb-footer. Other
+    /// options apply on top of the directory.
+    Repack(Box<RepackArgs>),
+    /// Create an image from its blocks without a source image, like mkbootimg
+    ///
+    /// The files are written as they are, load addresses default to those of
+    /// mkbootimg.
+    Create(Box<CreateArgs>),
+    /// Create images from the arguments of AOSP mkbootimg
+    ///
+    /// Takes the same vocabulary, e.g. --kernel, --ramdisk, --header_version,
+    /// --base and the *_offset addresses, --os_version and --vendor_boot. GKI
+    /// signing is not supported.
+    #[command(disable_help_flag = true)]
+    Mkbootimg {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Print a vbmeta image, optionally writing a copy with new flags
+    Vbmeta {
+        image: PathBuf,
+        #[arg(long)]
+        flags: Option<u32>,
+        #[arg(short, long, default_value = "new-vbmeta.img")]
+        output: PathBuf,
+    },
+    /// Inspect and patch the AVB footer of an image, like avbtool
+    Avb {
+        #[command(subcommand)]
+        command: AvbCommand,
+    },
+    /// Compress a file, the input is taken as raw data as magiskboot does
+    ///
+    /// `-` reads stdin or writes stdout. The output defaults to the input with
+    /// the extension of the format added.
+    Compress {
+        #[arg(short, long, default_value = "gzip")]
+        format: String,
+        input: PathBuf,
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        options: CompressArgs,
+    },
+    /// Decompress a file, the format is detected
+    ///
+    /// `-` reads stdin or writes stdout. The output defaults to the input with
+    /// its compression extension removed.
+    Decompress {
+        input: PathBuf,
+        output: Option<PathBuf>,
+    },
+    /// Edit a cpio archive like `magiskboot cpio`
+    ///
+    /// Each command is one argument and they run in order; the archive is
+    /// saved once at the end. Commands: `ls [-r] [path]`, `extract [entry out]`,
+    /// `add <mode> <entry> <file>`, `mkdir <mode> <entry>`, `ln <target> <entry>`,
+    /// `rm [-r] <entry>`, `mv <from> <to>`, `exists <entry>`, `test`, `patch`,
+    /// `backup <original cpio>` and `restore`. `exists` exits with 0 when the entry
+    /// exists and 1 otherwise; `test` exits with 0 for a stock archive, 1 for
+    /// Magisk and 2 for other patches. Both end the run without saving.
+    Cpio {
+        archive: PathBuf,
+        #[arg(required = true)]
+        commands: Vec<String>,
+    },
+    /// Patch hex bytes in the decompressed content of a block, recompressing it
+    ///
+    /// Spaces in the pattern are ignored and `??` matches any byte.
+    Hexpatch {
+        image: PathBuf,
+        #[arg(value_parser = BLOCK_NAMES)]
+        block: String,
+        pattern: String,
+        replacement: String,
+        #[arg(short, long, default_value = "new-boot.img")]
+        output: PathBuf,
+    },
+    /// Build an image from another, taking the kernel or ramdisk of a second one
+    ///
+    /// The blocks are copied as they are, keeping their compression format and
+    /// the appended dtb of the kernel.
+    Merge {
+        image: PathBuf,
+        #[arg(long, required_unless_present = "ramdisk_from")]
+        kernel_from: Option<PathBuf>,
+        #[arg(long)]
+        ramdisk_from: Option<PathBuf>,
+        #[arg(short, long, default_value = "new-boot.img")]
+        output: PathBuf,
+    },
+    /// Print the offsets of a hex pattern in the decompressed content of a block
+    ///
+    /// Spaces in the pattern are ignored and `??` matches any byte. Exits with 1
+    /// when nothing is found.
+    Search {
+        image: PathBuf,
+        #[arg(value_parser = BLOCK_NAMES)]
+        block: String,
+        pattern: String,
+    },
+    /// Print the SHA1 or SHA256 of a file as lowercase hex, SHA1 by default
+    Hash {
+        #[arg(long, conflicts_with = "sha1")]
+        sha256: bool,
+        #[arg(long)]
+        sha1: bool,
+        file: PathBuf,
+        /// Hash only the payload covered by the AVB footer of a boot image
+        #[arg(long, conflicts_with = "block")]
+        payload: bool,
+        /// Hash the decompressed content of a block of a boot image
+        #[arg(long)]
+        block: Option<String>,
+    },
+    /// Cross-check a repacked image against the original before flashing
+    ///
+    /// Exits with 1 when a difference is found that `--expect` does not cover.
+    Verify {
+        original: PathBuf,
+        patched: PathBuf,
+        /// Block or header field allowed to change, `header` allows every field
+        /// and `avb` a removed footer
+        #[arg(long)]
+        expect: Vec<String>,
+    },
+    /// Compare two cpio archives
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// Ignore mode and owner changes
+        #[arg(long)]
+        content_only: bool,
+    },
+    /// Print entry counts and sizes of a cpio archive
+    Stats {
+        cpio: PathBuf,
+        /// How many of the largest files to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Extract a cpio archive into a directory
+    #[cfg(unix)]
+    Extract {
+        cpio: PathBuf,
+        dir: PathBuf,
+        /// Create device nodes, needs root
+        #[arg(long)]
+        device_nodes: bool,
+    },
+    /// Pack a directory into an uncompressed cpio archive
+    #[cfg(unix)]
+    Pack {
+        dir: PathBuf,
+        cpio: PathBuf,
+        /// Keep on-disk owners instead of root
+        #[arg(long)]
+        keep_owner: bool,
+    },
+    /// Convert a cpio archive to ustar
+    #[cfg(feature = "tar")]
+    Totar { cpio: PathBuf, tar: PathBuf },
+    /// Convert a tar archive to an uncompressed cpio archive
+    #[cfg(feature = "tar")]
+    Fromtar { tar: PathBuf, cpio: PathBuf },
+    /// Read partitions of an OTA payload.bin
+    #[cfg(feature = "payload")]
+    Payload {
+        #[command(subcommand)]
+        command: PayloadCommand,
+    },
+    /// Run info, unpack or verify over the boot partitions of a directory
+    ///
+    /// Picks up boot.img, init_boot.img, vendor_boot.img and
+    /// vendor_kernel_boot.img, as in an unzipped factory image. A failing image
+    /// does not stop the others, the exit code reports it at the end.
+    Batch {
+        #[command(subcommand)]
+        command: BatchCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum BatchCommand {
+    /// Print the info of every image, `--json` prints one document for all
+    Info {
+        dir: PathBuf,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Unpack every image into a subdirectory named after its partition
+    ///
+    /// A summary.json listing the results is written to the output directory.
+    Unpack {
+        dir: PathBuf,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        #[arg(long)]
+        no_decompress: bool,
+    },
+    /// Verify the images of a patched directory against the original ones
+    Verify {
+        original: PathBuf,
+        patched: PathBuf,
+        #[arg(long)]
+        expect: Vec<String>,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[cfg(feature = "payload")]
+#[derive(Subcommand)]
+enum PayloadCommand {
+    /// List the partitions with their sizes
+    List { payload: PathBuf },
+    /// Write a partition of a full payload to a file, `<partition>.img` by default
+    Extract {
+        payload: PathBuf,
+        #[arg(short, long, default_value = "boot")]
+        partition: String,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AvbCommand {
+    /// Print the footer, vbmeta header and descriptors, as `avbtool info_image`
+    Info { image: PathBuf },
+    /// Remove vbmeta and footer, as `avbtool erase_footer`
+    Strip {
+        image: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Replace the vbmeta flags, 3 disables verity and verification
+    SetFlags {
+        image: PathBuf,
+        flags: u32,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Recompute the hash descriptor digest, exits with 1 on mismatch
+    Verify { image: PathBuf },
+}
+
+#[derive(Args)]
+struct CompressArgs {
+    /// Fastest compression level
+    #[arg(long, conflicts_with = "level")]
+    fast: bool,
+    /// Compression level, clamped to the range of the format
+    #[arg(long)]
+    level: Option<u32>,
+    #[arg(long, value_name = "BYTES")]
+    lz4_block_size: Option<usize>,
+}
+
+#[derive(Args)]
+struct RepackArgs {
+    source: PathBuf,
+    #[arg(short, long, default_value = "new-boot.img")]
+    output: PathBuf,
+    #[arg(long)]
+    kernel: Option<PathBuf>,
+    #[arg(long)]
+    ramdisk: Option<PathBuf>,
+    /// Replaces a vendor ramdisk by name, may be repeated
+    #[arg(long, value_name = "NAME=PATH")]
+    vendor_ramdisk: Vec<String>,
+    /// The dtb block, or the dtb appended to the kernel of older images
+    #[arg(long)]
+    dtb: Option<PathBuf>,
+    #[arg(long)]
+    cmdline: Option<String>,
+    /// A header text file as written by unpack, --cmdline takes precedence
+    #[arg(long)]
+    header: Option<PathBuf>,
+    /// Copy the source padding after blocks that are not replaced
+    #[arg(long)]
+    preserve_padding: bool,
+    #[command(flatten)]
+    compress: CompressArgs,
+    /// Re-sign the vbmeta with a PKCS#8 PEM key
+    #[cfg(feature = "sign")]
+    #[arg(long, value_name = "KEY")]
+    sign: Option<PathBuf>,
+    #[cfg(feature = "sign")]
+    #[arg(long)]
+    algorithm: Option<String>,
+    /// Add a hash footer, as `avbtool add_hash_footer`
+    #[arg(long, value_name = "NAME:PARTITION_SIZE")]
+    avb_footer: Option<String>,
+    #[arg(long, value_name = "HEX")]
+    salt: Option<String>,
+    /// Wrap the output in an LZ4 frame, as boot.img.lz4 for Odin
+    #[arg(long)]
+    lz4: bool,
+}
+
+#[derive(Args)]
+struct CreateArgs {
+    #[arg(long, default_value_t = 2)]
+    header_version: u32,
+    /// Create a vendor boot image, header version 3 or 4
+    #[arg(long)]
+    vendor: bool,
+    #[arg(short, long, default_value = "new-boot.img")]
+    output: PathBuf,
+    #[arg(long)]
+    kernel: Option<PathBuf>,
+    #[arg(long)]
+    ramdisk: Option<PathBuf>,
+    /// Adds a ramdisk to the table of a vendor boot v4 image, may be repeated.
+    /// TYPE is platform (the default), recovery, dlkm or none.
+    #[arg(long, value_name = "[TYPE:]NAME=PATH")]
+    vendor_ramdisk: Vec<String>,
+    #[arg(long)]
+    second: Option<PathBuf>,
+    #[arg(long)]
+    recovery_dtbo: Option<PathBuf>,
+    #[arg(long)]
+    dtb: Option<PathBuf>,
+    #[arg(long)]
+    bootconfig: Option<PathBuf>,
+    #[arg(long)]
+    signature: Option<PathBuf>,
+    /// A header text file as written by unpack, options below take precedence
+    #[arg(long)]
+    header: Option<PathBuf>,
+    #[arg(long)]
+    cmdline: Option<String>,
+    #[arg(long)]
+    name: Option<String>,
+    #[arg(long)]
+    os_version: Option<String>,
+    #[arg(long)]
+    os_patch_level: Option<String>,
+    /// Base of the load addresses, hex
+    #[arg(long)]
+    base: Option<String>,
+    #[arg(long)]
+    page_size: Option<u32>,
+}
+
 fn print_info(header: &BootHeader) -> Result<()> {
     macro_rules! print_info_item {
         ($name:ident) => {
@@ -23,11 +452,15 @@ fn print_info(header: &BootHeader) -> Result<()> {
     print_info_item! { second_size }
     print_info_item! { page_size }
     print_info_item! { header_version }
-    if header.has_os_version_raw() {
-        if let Some((os_version, patch_level)) = header.get_os_version() {
-            println!("os_version: {}", os_version);
-            println!("patch_level: {}", patch_level);
-        }
+    if header.has_name() {
+        println!("name: {}", header.name_str_lossy());
+    }
+    println!("cmdline: {}", header.cmdline_str_lossy());
+    if header.has_os_version_raw()
+        && let Some((os_version, patch_level)) = header.get_os_version()
+    {
+        println!("os_version: {}", os_version);
+        println!("patch_level: {}", patch_level);
     }
     print_info_item! { recovery_dtbo_size }
     print_info_item! { recovery_dtbo_offset }
@@ -44,100 +477,2066 @@ fn print_info(header: &BootHeader) -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
-    if let Some(s) = env::args().skip(1).next() {
-        let file = File::open(s)?;
-        let mem = unsafe { Mmap::map(&file)? };
-        let boot = BootImage::parse(&mem)?;
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-        let header = boot.get_header();
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        bail!("odd length hex string {}", s);
+    }
+    let nibble = |b: u8| match (b as char).to_digit(16) {
+        Some(n) => Ok(n as u8),
+        None => bail!("invalid hex string {}", s),
+    };
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok((nibble(pair[0])? << 4) | nibble(pair[1])?))
+        .collect()
+}
+
+// Same layout as `avbtool info_image`
+fn print_vbmeta(vbmeta: &VbMetaImage) -> Result<()> {
+    let header = vbmeta.header();
+    println!(
+        "Minimum libavb version:   {}.{}",
+        header.get_required_libavb_version_major(),
+        header.get_required_libavb_version_minor()
+    );
+    println!("Header Block:             256 bytes");
+    println!(
+        "Authentication Block:     {} bytes",
+        vbmeta.authentication_block().len()
+    );
+    println!(
+        "Auxiliary Block:          {} bytes",
+        vbmeta.auxiliary_block().len()
+    );
+    if let Some(key) = vbmeta.public_key()? {
+        println!(
+            "Public key (sha256):      {} ({} bits)",
+            hex(&key.fingerprint()),
+            key.key_bits()
+        );
+    }
+    match vbmeta.algorithm() {
+        Some(algorithm) => println!("Algorithm:                {}", algorithm.name()),
+        None => println!("Algorithm:                {}", vbmeta.algorithm_type()),
+    }
+    println!("Rollback Index:           {}", vbmeta.rollback_index());
+    println!("Flags:                    {}", vbmeta.flags());
+    println!(
+        "Rollback Index Location:  {}",
+        header.get_rollback_index_location()
+    );
+    println!("Release String:           '{}'", vbmeta.release_string());
+    println!("Descriptors:");
+    for descriptor in vbmeta.descriptors()? {
+        match descriptor {
+            AvbDescriptor::Property(prop) => println!(
+                "    Prop: {} -> '{}'",
+                prop.key,
+                String::from_utf8_lossy(prop.value)
+            ),
+            AvbDescriptor::Hash(hash) => {
+                println!("    Hash descriptor:");
+                println!("      Image Size:            {} bytes", hash.image_size);
+                println!("      Hash Algorithm:        {}", hash.hash_algorithm);
+                println!("      Partition Name:        {}", hash.partition_name);
+                println!("      Salt:                  {}", hex(hash.salt));
+                println!("      Digest:                {}", hex(hash.digest));
+                println!("      Flags:                 {}", hash.flags);
+            }
+            AvbDescriptor::Hashtree(tree) => {
+                println!("    Hashtree descriptor:");
+                println!("      Version of dm-verity:  {}", tree.dm_verity_version);
+                println!("      Image Size:            {} bytes", tree.image_size);
+                println!("      Tree Offset:           {}", tree.tree_offset);
+                println!("      Tree Size:             {} bytes", tree.tree_size);
+                println!(
+                    "      Data Block Size:       {} bytes",
+                    tree.data_block_size
+                );
+                println!(
+                    "      Hash Block Size:       {} bytes",
+                    tree.hash_block_size
+                );
+                println!("      FEC num roots:         {}", tree.fec_num_roots);
+                println!("      FEC offset:            {}", tree.fec_offset);
+                println!("      FEC size:              {} bytes", tree.fec_size);
+                println!("      Hash Algorithm:        {}", tree.hash_algorithm);
+                println!("      Partition Name:        {}", tree.partition_name);
+                println!("      Salt:                  {}", hex(tree.salt));
+                println!("      Root Digest:           {}", hex(tree.root_digest));
+                println!("      Flags:                 {}", tree.flags);
+            }
+            AvbDescriptor::KernelCmdline(cmdline) => {
+                println!("    Kernel Cmdline descriptor:");
+                println!("      Flags:                 {}", cmdline.flags);
+                println!("      Kernel Cmdline:        '{}'", cmdline.cmdline);
+            }
+            AvbDescriptor::ChainPartition(chain) => {
+                println!("    Chain Partition descriptor:");
+                println!("      Partition Name:          {}", chain.partition_name);
+                println!(
+                    "      Rollback Index Location: {}",
+                    chain.rollback_index_location
+                );
+                if let Ok(key) = AvbPublicKey::parse(chain.public_key) {
+                    println!("      Public key (sha256):     {}", hex(&key.fingerprint()));
+                }
+                println!("      Flags:                   {}", chain.flags);
+            }
+            AvbDescriptor::Unknown(raw) => {
+                println!(
+                    "    Unknown descriptor: tag {} size {}",
+                    raw.tag,
+                    raw.data.len()
+                )
+            }
+        }
+    }
+    Ok(())
+}
 
-        println!("version: {:?}", header.get_version());
-        println!("layout: {:?}", header.get_layout());
-        print_info(header)?;
+fn handle_vbmeta(image: &Path, flags: Option<u32>, output: &Path) -> Result<()> {
+    let data = read_input(image)?;
+    let vbmeta = VbMetaImage::parse(&data)?;
+    match flags {
+        Some(flags) => {
+            // The image itself may go to stdout
+            if !is_stdio(output) {
+                print_vbmeta(&vbmeta)?;
+            }
+            vbmeta.patch_flags(&mut create_output(output)?, flags)?
+        }
+        None => print_vbmeta(&vbmeta)?,
+    }
+    Ok(())
+}
 
-        macro_rules! dump_block_to_file {
-            ($block:ident, $filename:expr) => {
-                let mut output = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open($filename)?;
-                $block.dump(&mut output, false)?
+// Standalone vbmeta images and images with a footer are accepted except for strip and verify
+fn handle_avb(command: AvbCommand) -> Result<ExitCode> {
+    let image = match &command {
+        AvbCommand::Info { image }
+        | AvbCommand::Strip { image, .. }
+        | AvbCommand::SetFlags { image, .. }
+        | AvbCommand::Verify { image } => image,
+    };
+    let mem = read_input(image)?;
+    if mem.starts_with(b"AVB0") {
+        let vbmeta = VbMetaImage::parse(&mem)?;
+        match command {
+            AvbCommand::Info { .. } => print_vbmeta(&vbmeta)?,
+            AvbCommand::SetFlags { flags, output, .. } => {
+                vbmeta.patch_flags(&mut create_output(&output)?, flags)?
+            }
+            _ => bail!("{} is a vbmeta image without a footer", image.display()),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    let boot = BootImage::parse(&mem)?;
+    match command {
+        AvbCommand::Info { .. } => {
+            let Some(avb) = boot.info().avb else {
+                bail!("{} has no avb footer", image.display());
             };
+            println!("Footer version:           1.0");
+            println!("Image size:               {} bytes", avb.partition_size);
+            println!(
+                "Original image size:      {} bytes",
+                avb.original_image_size
+            );
+            println!("VBMeta offset:            {}", avb.vbmeta_offset);
+            println!("VBMeta size:              {} bytes", avb.vbmeta_size);
+            println!("--");
+            if let Some(vbmeta) = boot.get_vbmeta()? {
+                print_vbmeta(&vbmeta)?;
+            }
+        }
+        AvbCommand::Strip { output, .. } => boot.strip_avb(&mut create_output(&output)?)?,
+        AvbCommand::SetFlags { flags, output, .. } => {
+            boot.patch_avb_flags(&mut create_output(&output)?, flags)?
+        }
+        AvbCommand::Verify { .. } => match boot.verify_avb()? {
+            AvbHashStatus::Matched(digests) => {
+                println!("{}: {}", digests.hash_algorithm, hex(&digests.computed));
+                println!("digest matched");
+            }
+            AvbHashStatus::Mismatched(digests) => {
+                println!(
+                    "expected {}: {}",
+                    digests.hash_algorithm,
+                    hex(&digests.expected)
+                );
+                println!(
+                    "computed {}: {}",
+                    digests.hash_algorithm,
+                    hex(&digests.computed)
+                );
+                println!("digest mismatched, image was modified");
+                return Ok(ExitCode::FAILURE);
+            }
+            AvbHashStatus::NotApplicable => {
+                bail!("{} has no avb footer or hash descriptor", image.display())
+            }
+        },
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+const BLOCK_NAMES: [&str; 4] = ["kernel", "ramdisk", "dtb", "second"];
+
+fn block_content(boot: &BootImage, block: &str) -> Result<Vec<u8>> {
+    let blocks = boot.get_blocks();
+    let content = match block {
+        "kernel" => blocks
+            .get_kernel()
+            .map(|it| it.get_uncompressed())
+            .transpose()?
+            .map(|it| it.into_owned()),
+        "ramdisk" => match blocks.get_ramdisk() {
+            Some(ramdisk) => {
+                let mut data = Vec::new();
+                ramdisk.dump(&mut data, false)?;
+                Some(data)
+            }
+            None => None,
+        },
+        "dtb" => blocks.get_dtb().map(<[u8]>::to_vec),
+        "second" => blocks.get_second().map(<[u8]>::to_vec),
+        _ => bail!("unknown block {}", block),
+    };
+    match content {
+        Some(content) => Ok(content),
+        None => bail!("image has no {} block", block),
+    }
+}
+
+fn handle_search(image: &Path, block: &str, pattern: &str) -> Result<ExitCode> {
+    let mem = read_input(image)?;
+    let content = block_content(&BootImage::parse(&mem)?, block)?;
+    let found = HexPattern::parse(pattern)?.find_all(&content);
+    for offset in &found {
+        println!("{:#x}", offset);
+    }
+    Ok(if found.is_empty() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn handle_hexpatch(
+    image: &Path,
+    block: &str,
+    pattern: &str,
+    replacement: &str,
+    output: &Path,
+) -> Result<()> {
+    let mem = read_input(image)?;
+    let boot = BootImage::parse(&mem)?;
+    let replacement = HexPattern::parse(replacement)?.to_bytes()?;
+    let mut content = block_content(&boot, block)?;
+    let patched = hexpatch(&mut content, &HexPattern::parse(pattern)?, &replacement)?;
+    if patched.is_empty() {
+        bail!("pattern not found in {}", block);
+    }
+    for offset in &patched {
+        info!("patched {} at {:#x}", block, offset);
+    }
+
+    let mut patcher = BootImagePatchOption::new(&boot);
+    match block {
+        "kernel" => patcher.replace_kernel(Box::new(io::Cursor::new(content))),
+        "ramdisk" => patcher.replace_ramdisk(Box::new(io::Cursor::new(content))),
+        "dtb" => patcher.replace_dtb(content),
+        _ => patcher.replace_second(content),
+    };
+    write_patched(patcher, output, false)
+}
+
+fn handle_merge(
+    image: &Path,
+    kernel_from: Option<&Path>,
+    ramdisk_from: Option<&Path>,
+    output: &Path,
+) -> Result<()> {
+    let mem = read_input(image)?;
+    let boot = BootImage::parse(&mem)?;
+    let mut patcher = BootImagePatchOption::new(&boot);
+    if let Some(path) = kernel_from {
+        let mem = read_input(path)?;
+        patcher.replace_kernel_from(&BootImage::parse(&mem)?)?;
+    }
+    if let Some(path) = ramdisk_from {
+        let mem = read_input(path)?;
+        patcher.replace_ramdisk_from(&BootImage::parse(&mem)?)?;
+    }
+    write_patched(patcher, output, false)
+}
+
+fn handle_hash(
+    algorithm: DigestAlgorithm,
+    path: &Path,
+    payload: bool,
+    block: Option<&str>,
+) -> Result<()> {
+    let digest = if payload || block.is_some() {
+        let mem = read_input(path)?;
+        let boot = BootImage::parse(&mem)?;
+        match block {
+            Some(block) => boot.digest_block(block, algorithm)?,
+            None => boot.digest_avb_payload(algorithm)?,
+        }
+    } else if is_stdio(path) {
+        digest_reader(algorithm, &mut io::stdin().lock())?
+    } else {
+        digest_reader(algorithm, &mut File::open(path)?)?
+    };
+    println!("{}", hex(&digest));
+    Ok(())
+}
+
+fn handle_verify(original: &Path, patched: &Path, expect: &[String]) -> Result<ExitCode> {
+    let unexpected = verify_images(original, patched, expect, true)?;
+    for it in &unexpected {
+        println!("unexpected: {}", it);
+    }
+    Ok(if unexpected.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+// Differences left after `expect`, `print` lists the comparison on stdout
+fn verify_images(
+    original: &Path,
+    patched: &Path,
+    expect: &[String],
+    print: bool,
+) -> Result<Vec<String>> {
+    let original_mem = read_input(original)?;
+    let patched_mem = read_input(patched)?;
+    let comparison = BootImage::parse(&original_mem)?.diff(&BootImage::parse(&patched_mem)?)?;
+    let expect: Vec<&str> = expect.iter().map(String::as_str).collect();
+    let unexpected = comparison.unexpected(&expect);
+    if !print {
+        return Ok(unexpected);
+    }
+
+    for field in &comparison.header {
+        println!(
+            "header {}: {} -> {}",
+            field.name,
+            field.original.as_deref().unwrap_or("-"),
+            field.patched.as_deref().unwrap_or("-")
+        );
+    }
+    for block in &comparison.blocks {
+        println!("block {}: {}", block.name, block.change);
+    }
+    for field in &comparison.avb {
+        println!(
+            "avb {}: {} -> {}",
+            field.name,
+            field.original.as_deref().unwrap_or("-"),
+            field.patched.as_deref().unwrap_or("-")
+        );
+    }
+    match &comparison.avb_hash {
+        AvbHashStatus::Matched(digests) => {
+            println!("avb hash: matched ({})", digests.hash_algorithm)
+        }
+        AvbHashStatus::Mismatched(_) => println!("avb hash: mismatched"),
+        AvbHashStatus::NotApplicable => println!("avb hash: not present"),
+    }
+    if let Some(size) = comparison.partition_size {
+        println!(
+            "size: {} of {} bytes{}",
+            comparison.patched_size,
+            size,
+            if comparison.fits_partition() {
+                ""
+            } else {
+                ", too large"
+            }
+        );
+    }
+
+    if unexpected.is_empty() {
+        println!("ok");
+    }
+    Ok(unexpected)
+}
+
+fn parse_format(name: &str) -> Result<CompressFormat> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "raw" => CompressFormat::UNKNOWN,
+        "gzip" | "gz" => CompressFormat::GZIP,
+        "zopfli" => CompressFormat::ZOPFLI,
+        "xz" => CompressFormat::XZ,
+        "lzma" => CompressFormat::LZMA,
+        "bzip2" | "bz2" => CompressFormat::BZIP2,
+        "lz4" => CompressFormat::LZ4,
+        "lz4_legacy" => CompressFormat::LZ4_LEGACY,
+        "lz4_lg" => CompressFormat::LZ4_LG,
+        "zstd" => CompressFormat::ZSTD,
+        "zlib" => CompressFormat::ZLIB,
+        "raw_deflate" | "deflate" => CompressFormat::RAW_DEFLATE,
+        "lzop" => CompressFormat::LZOP,
+        _ => bail!("unknown compression format: {}", name),
+    })
+}
+
+impl CompressArgs {
+    fn options(&self) -> CompressOptions {
+        let mut options = if self.fast {
+            CompressOptions::fast()
+        } else {
+            CompressOptions::default()
+        };
+        if let Some(level) = self.level {
+            options.level = CompressLevel::Level(level);
+        }
+        if let Some(size) = self.lz4_block_size {
+            options.lz4_block_size = size;
+        }
+        options
+    }
+}
+
+// Extensions as magiskboot, the first one of a format is used when compressing
+const FORMAT_EXTENSIONS: &[(&str, CompressFormat)] = &[
+    ("gz", CompressFormat::GZIP),
+    ("gz", CompressFormat::ZOPFLI),
+    ("xz", CompressFormat::XZ),
+    ("lzma", CompressFormat::LZMA),
+    ("bz2", CompressFormat::BZIP2),
+    ("lz4", CompressFormat::LZ4),
+    ("lz4", CompressFormat::LZ4_LEGACY),
+    ("lz4", CompressFormat::LZ4_LG),
+    ("zst", CompressFormat::ZSTD),
+    ("zz", CompressFormat::ZLIB),
+    ("deflate", CompressFormat::RAW_DEFLATE),
+    ("lzo", CompressFormat::LZOP),
+];
+
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+enum InputData {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Deref for InputData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputData::Mapped(mem) => mem,
+            InputData::Read(data) => data,
+        }
+    }
+}
+
+// Parsing needs random access, so stdin is read into memory. Images in an
+// LZ4 frame or an Odin tar are unwrapped.
+fn read_input(path: &Path) -> Result<InputData> {
+    let data = if is_stdio(path) {
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data)?;
+        InputData::Read(data)
+    } else {
+        let file = File::open(path)?;
+        InputData::Mapped(unsafe { Mmap::map(&file)? })
+    };
+    let Some(image) = unwrap_image(&data, None)? else {
+        return Ok(data);
+    };
+    match &image.kind {
+        ContainerKind::Lz4 => info!("unwrapped lz4 frame"),
+        ContainerKind::OdinTar { entry, .. } => info!("unwrapped {} from odin tar", entry),
+    }
+    Ok(InputData::Read(image.data))
+}
+
+fn create_output(path: &Path) -> Result<Box<dyn Write>> {
+    Ok(if is_stdio(path) {
+        Box::new(BufWriter::new(io::stdout().lock()))
+    } else {
+        Box::new(File::create(path)?)
+    })
+}
+
+fn write_patched(patcher: BootImagePatchOption, path: &Path, lz4: bool) -> Result<()> {
+    if lz4 {
+        let mut image = Cursor::new(Vec::new());
+        patcher.patch(&mut image)?;
+        let mut output = create_output(path)?;
+        wrap_lz4(image.get_ref(), &mut output)?;
+        output.flush()?;
+        return Ok(());
+    }
+    if is_stdio(path) {
+        let mut stdout = io::stdout().lock();
+        patcher.patch_to_writer(&mut stdout)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    let mut output = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    patcher.patch(&mut output)?;
+    Ok(())
+}
+
+fn compressed_name(input: &Path, format: CompressFormat) -> Result<PathBuf> {
+    if is_stdio(input) {
+        return Ok(input.to_path_buf());
+    }
+    let Some((ext, _)) = FORMAT_EXTENSIONS.iter().find(|(_, it)| *it == format) else {
+        bail!("no extension for {:?}, pass the output name", format);
+    };
+    let mut name = input.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    Ok(name.into())
+}
+
+fn decompressed_name(input: &Path) -> Result<PathBuf> {
+    if is_stdio(input) {
+        return Ok(input.to_path_buf());
+    }
+    match input.extension().and_then(|it| it.to_str()) {
+        Some(ext) if FORMAT_EXTENSIONS.iter().any(|(it, _)| *it == ext) => {
+            Ok(input.with_extension(""))
+        }
+        _ => bail!(
+            "{} has no known compression extension, pass the output name",
+            input.display()
+        ),
+    }
+}
+
+// `src` None detects the input format
+fn handle_transcode(
+    src: Option<CompressFormat>,
+    format: CompressFormat,
+    input: &Path,
+    output: &Path,
+    options: &CompressOptions,
+) -> Result<()> {
+    let reader: Box<dyn Read> = if is_stdio(input) {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(input)?)
+    };
+    let (src, reader): (_, Box<dyn Read>) = match src {
+        Some(src) => (src, reader),
+        None => {
+            let (src, reader) = detect_format_from_reader(reader)?;
+            (src, Box::new(reader))
         }
+    };
+    let written = if is_stdio(output) {
+        transcode(src, format, reader, &mut io::stdout().lock(), options)?
+    } else {
+        transcode(src, format, reader, &mut File::create(output)?, options)?
+    };
+    info!("{:?} -> {:?}: {} bytes", src, format, written);
+    Ok(())
+}
+
+// Archives may be compressed
+fn load_cpio_file(path: &Path) -> Result<Cpio<'static>> {
+    let (format, reader) = detect_format_from_reader(File::open(path)?)?;
+    let reader: Box<dyn Read> = if format == CompressFormat::UNKNOWN {
+        Box::new(reader)
+    } else {
+        Box::new(LimitedDecoder::new(
+            get_decoder(format, reader)?,
+            DEFAULT_MAX_DECOMPRESSED_SIZE,
+        ))
+    };
+    Ok(Cpio::load_from_reader(reader, &CpioLoadOptions::default())?)
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    Ok(u32::from_str_radix(mode, 8)?)
+}
 
-        let blocks = boot.get_blocks();
-
-        if let Some(kernel) = blocks.get_kernel() {
-            println!("kernel format: {:?}", kernel.get_compress_format());
-            dump_block_to_file!(kernel, "kernel");
-        }
-
-        if let Some(ramdisk) = blocks.get_ramdisk() {
-            if ramdisk.is_vendor_ramdisk() {
-                println!("vendor ramdisk table");
-                for i in 0..ramdisk.get_vendor_ramdisk_num() {
-                    let entry = ramdisk.get_vendor_ramdisk(i).unwrap();
-                    if let Ok(name) = from_utf8(entry.get_name_raw()) {
-                        println!("name: {}", name);
-                        println!("type: {:?}", entry.get_entry_type());
-                        dump_block_to_file!(entry, &format!("vendor.{}.cpio", name));
-                        let mut data = Vec::<u8>::new();
-                        entry.dump(&mut data, false)?;
-                        let cpio = android_bootimg::cpio::Cpio::load_from_data(data.as_slice())?;
-                        cpio.ls("/", true);
-                    } else {
-                        println!("invalid ramdisk name: {:?}", entry.get_name_raw());
+// 0 for stock, 1 for Magisk and 2 for anything else, as `magiskboot cpio test`
+fn patch_status(cpio: &Cpio) -> u8 {
+    let findings = cpio.detect_patches();
+    if findings.iter().any(|it| it.kind == PatchKind::Magisk) {
+        1
+    } else if !findings.is_empty() {
+        2
+    } else {
+        0
+    }
+}
+
+// A missing archive starts empty. KEEPVERITY and KEEPFORCEENCRYPT set to
+// `true` in the environment are honoured by `patch`, as in magiskboot.
+fn handle_cpio(path: &Path, commands: &[String]) -> Result<ExitCode> {
+    let mut cpio = if path.exists() {
+        load_cpio_file(path)?
+    } else {
+        Cpio::new()
+    };
+    let mut modified = false;
+    for command in commands {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        match args.as_slice() {
+            ["exists", entry] => {
+                return Ok(if cpio.exists(entry) {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                });
+            }
+            ["test"] => return Ok(ExitCode::from(patch_status(&cpio))),
+            ["ls", args @ ..] => {
+                let (recursive, args) = match args {
+                    ["-r", args @ ..] => (true, args),
+                    _ => (false, args),
+                };
+                let dir = match args {
+                    [] => "/",
+                    [dir] => dir,
+                    _ => bail!("usage: ls [-r] [path]"),
+                };
+                for (name, entry) in cpio.ls_bytes(dir.as_bytes(), recursive) {
+                    println!("{entry}\t{}", display_name(name, entry));
+                }
+                continue;
+            }
+            #[cfg(unix)]
+            ["extract"] => {
+                cpio.extract_to(Path::new("."), &ExtractOptions::default())?;
+                continue;
+            }
+            #[cfg(unix)]
+            ["extract", entry, output] => {
+                cpio.extract_entry(entry, Path::new(output), &ExtractOptions::default())?;
+                continue;
+            }
+            ["add", mode, entry, file] => cpio.add(
+                entry,
+                CpioEntry::regular(parse_mode(mode)?, fs::read(file)?),
+            )?,
+            ["mkdir", mode, entry] => cpio.add(entry, CpioEntry::dir(parse_mode(mode)?))?,
+            ["ln", target, entry] => cpio.add(entry, CpioEntry::symlink(0o777, target))?,
+            ["rm", "-r", entry] => cpio.rm(entry, true),
+            ["rm", entry] => cpio.rm(entry, false),
+            ["mv", from, to] => cpio.mv(from, to)?,
+            ["patch"] => {
+                let flag = |name| std::env::var(name).is_ok_and(|it| it == "true");
+                let options = MagiskPatchOptions {
+                    keep_verity: flag("KEEPVERITY"),
+                    keep_force_encrypt: flag("KEEPFORCEENCRYPT"),
+                };
+                for change in cpio.magisk_patch(&options)? {
+                    println!("{}", change);
+                }
+            }
+            ["backup", original] => {
+                for change in cpio.create_backup(&load_cpio_file(Path::new(original))?)? {
+                    println!("{}", change);
+                }
+            }
+            ["restore"] => {
+                for change in cpio.restore()? {
+                    println!("{}", change);
+                }
+            }
+            _ => bail!("unknown cpio command: {}", command),
+        }
+        modified = true;
+    }
+    if modified {
+        cpio.dump(&mut File::create(path)?)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn handle_stats(input: &Path, top: usize) -> Result<()> {
+    let stats = load_cpio_file(input)?.stats(top);
+    println!("entries: {}", stats.entries);
+    println!("data: {} bytes", stats.data_bytes);
+    println!("archive: {} bytes", stats.serialized_size);
+    for (dir, bytes) in &stats.dir_bytes {
+        println!("{}\t/{}", bytes, String::from_utf8_lossy(dir));
+    }
+    for (name, bytes) in &stats.largest {
+        println!("largest: {}\t{}", bytes, String::from_utf8_lossy(name));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn handle_extract(input: &Path, dir: &Path, device_nodes: bool) -> Result<()> {
+    let cpio = load_cpio_file(input)?;
+    cpio.extract_to(dir, &ExtractOptions { device_nodes })?;
+    info!("extracted {} entries", cpio.entries().len());
+    Ok(())
+}
+
+#[cfg(unix)]
+fn handle_pack(dir: &Path, output: &Path, keep_owner: bool) -> Result<()> {
+    let mut cpio = Cpio::new();
+    let options = AddDirOptions {
+        keep_owner,
+        ..Default::default()
+    };
+    cpio.add_from_dir("", dir, &options)?;
+    cpio.dump(&mut File::create(output)?)?;
+    info!("packed {} entries", cpio.entries().len());
+    Ok(())
+}
+
+// `name -> target` for symlinks, like `ls -l`
+fn display_name(name: &[u8], entry: &CpioEntry) -> String {
+    let name = String::from_utf8_lossy(name);
+    match entry.symlink_target() {
+        Some(target) => format!("{name} -> {target}"),
+        None => name.into_owned(),
+    }
+}
+
+// File contents are skipped, only the listing is kept
+fn list_cpio(reader: impl Read) -> Result<()> {
+    let options = CpioLoadOptions {
+        metadata_only: true,
+        ..Default::default()
+    };
+    let cpio = Cpio::load_from_reader(reader, &options)?;
+    for (name, entry) in cpio.ls_bytes(b"/", true) {
+        println!("{entry}\t{}", display_name(name, entry));
+    }
+    for finding in cpio.detect_patches() {
+        println!("patch: {:?} ({})", finding.kind, finding.path);
+    }
+    Ok(())
+}
+
+fn handle_info(image: &Path, json: bool, all: bool, merge_vendor_ramdisks: bool) -> Result<()> {
+    let mem = read_input(image)?;
+    if mem.starts_with(b"AVB0") {
+        let vbmeta = VbMetaImage::parse(&mem)?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&vbmeta.info())?);
+            return Ok(());
+        }
+        return print_vbmeta(&vbmeta);
+    }
+    let boot = BootImage::parse(&mem)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&boot.info())?);
+        return Ok(());
+    }
+
+    let header = boot.get_header();
+
+    println!("version: {:?}", header.get_version());
+    println!("layout: {}", header.get_layout().name);
+    if all {
+        for field in header.get_layout().fields() {
+            println!("{}: {}", field.name, header.field_value(field));
+        }
+    }
+    print_info(header)?;
+    let kind = boot.kind();
+    println!("kind: {:?} ({})", kind.kind, kind.reason);
+    match boot.verify_id() {
+        IdStatus::Matched(hash) => println!("id: matched ({:?})", hash),
+        IdStatus::Mismatched => println!("id: mismatched, image was modified"),
+        IdStatus::NotPresent => {}
+    }
+
+    let blocks = boot.get_blocks();
+
+    if let Some(kernel) = blocks.get_kernel() {
+        println!("kernel format: {:?}", kernel.get_compress_format());
+        // Decompressed once for the arch, size and banner
+        match kernel.get_uncompressed() {
+            Ok(data) => {
+                println!("kernel arch: {:?}", detect_kernel_arch(&data));
+                println!("kernel uncompressed size: {}", data.len());
+                if let Some(banner) = find_linux_banner(&data) {
+                    println!("kernel version: {}", banner);
+                }
+            }
+            Err(e) => warn!("kernel: {}", e),
+        }
+        if let Some(dtb) = kernel.appended_dtb() {
+            println!("kernel appended dtb size: {}", dtb.len());
+        }
+    }
+
+    if let Some(ramdisk) = blocks.get_ramdisk() {
+        if ramdisk.is_vendor_ramdisk() {
+            println!("vendor ramdisk table");
+            for i in 0..ramdisk.get_vendor_ramdisk_num() {
+                let entry = ramdisk.get_vendor_ramdisk(i).unwrap();
+                if let Ok(name) = from_utf8(entry.get_name_raw()) {
+                    println!("name: {}", name);
+                    println!("type: {:?}", entry.get_entry_type());
+                    println!(
+                        "offset: {:#x} size: {}",
+                        entry.get_entry_offset(),
+                        entry.get_entry_size()
+                    );
+                    if let Some(size) =
+                        estimate_uncompressed_size(entry.get_compress_format(), entry.get_data())
+                    {
+                        println!("uncompressed size: {}", size);
+                    }
+                    println!("board_id: {:x?}", entry.board_id());
+                    match entry.get_content_kind() {
+                        Ok(kind) => {
+                            println!("content: {:?}", kind);
+                            if kind == RamdiskContentKind::Cpio
+                                && let Err(e) =
+                                    entry.reader().map_err(Into::into).and_then(list_cpio)
+                            {
+                                warn!("vendor ramdisk {}: {}", name, e);
+                            }
+                        }
+                        Err(e) => warn!("vendor ramdisk {}: {}", name, e),
                     }
+                } else {
+                    println!("invalid ramdisk name: {:?}", entry.get_name_raw());
+                }
+            }
+            if merge_vendor_ramdisks {
+                let (cpio, provenance) = ramdisk.merge_vendor_ramdisks()?;
+                for (name, entry) in cpio.ls_bytes(b"/", true) {
+                    println!(
+                        "{entry}\t{}\t(entry {})",
+                        display_name(name, entry),
+                        provenance[name]
+                    );
+                }
+            }
+        } else {
+            println!("ramdisk format: {:?}", ramdisk.get_compress_format());
+            for (i, segment) in ramdisk.segments().iter().enumerate() {
+                println!(
+                    "ramdisk segment {}: offset {:#x} size {} format {:?}",
+                    i,
+                    segment.offset,
+                    segment.data.len(),
+                    segment.compress_format
+                );
+                if let Some(size) =
+                    estimate_uncompressed_size(segment.compress_format, segment.data)
+                {
+                    println!("ramdisk segment {}: uncompressed size {}", i, size);
+                }
+            }
+            match ramdisk.get_content_kind() {
+                Ok(kind) => {
+                    println!("ramdisk content: {:?}", kind);
+                    if kind == RamdiskContentKind::Cpio
+                        && let Err(e) = ramdisk.reader().map_err(Into::into).and_then(list_cpio)
+                    {
+                        warn!("ramdisk: {}", e);
+                    }
+                }
+                Err(e) => warn!("ramdisk: {}", e),
+            }
+        }
+    }
+
+    match blocks.get_dtb_entries() {
+        Ok(entries) => {
+            for (i, entry) in entries.iter().enumerate() {
+                println!(
+                    "dtb {}: offset {:#x} size {} model {:?} compatible {:?}",
+                    i,
+                    entry.offset,
+                    entry.data.len(),
+                    entry.model,
+                    entry.compatible
+                );
+            }
+        }
+        Err(e) => warn!("{}", e),
+    }
+
+    match blocks.get_recovery_dtbo_table() {
+        Ok(Some(table)) => {
+            for (i, entry) in table.entries.iter().enumerate() {
+                println!(
+                    "dtbo {}: id {:#x} rev {:#x} size {}",
+                    i,
+                    entry.id,
+                    entry.rev,
+                    entry.data.len()
+                );
+            }
+        }
+        Ok(None) => {}
+        Err(e) => warn!("{}", e),
+    }
+
+    match blocks.get_bootconfig_params() {
+        Ok(Some(bootconfig)) => {
+            for param in bootconfig.params() {
+                println!("bootconfig: {} = {:?}", param.key, param.values);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => warn!("{}", e),
+    }
+
+    if let Some(vbmeta) = boot.get_vbmeta()? {
+        print_vbmeta(&vbmeta)?;
+    }
+    match boot.verify_avb() {
+        Ok(AvbHashStatus::Matched(digests)) => {
+            println!("avb hash: matched ({})", digests.hash_algorithm)
+        }
+        Ok(AvbHashStatus::Mismatched(digests)) => {
+            println!("avb hash: mismatched, image was modified");
+            println!("  expected {}", hex(&digests.expected));
+            println!("  computed {}", hex(&digests.computed));
+        }
+        Ok(AvbHashStatus::NotApplicable) => {}
+        Err(e) => warn!("avb hash: {}", e),
+    }
+
+    for (name, offset, len) in blocks.blocks() {
+        println!("block {}: offset {:#x} size {}", name, offset, len);
+    }
+
+    Ok(())
+}
+
+// File names match what repack expects by default
+fn handle_unpack(
+    image: &Path,
+    dir: &Path,
+    no_decompress: bool,
+    merge_vendor_ramdisks: bool,
+) -> Result<()> {
+    let mem = read_input(image)?;
+    let boot = BootImage::parse(&mem)?;
+    let header = boot.get_header();
+    let blocks = boot.get_blocks();
+
+    // Vendor ramdisk names come from the image and become file names, all of
+    // them are checked before anything is written
+    let mut vendor_ramdisk_names = Vec::new();
+    if let Some(ramdisk) = blocks.get_ramdisk() {
+        for i in 0..ramdisk.get_vendor_ramdisk_num() {
+            let raw = ramdisk.get_vendor_ramdisk(i).unwrap().get_name_raw();
+            let Ok(name) = from_utf8(raw) else {
+                bail!("vendor ramdisk name {:?} is not UTF-8", raw);
+            };
+            let mut components = Path::new(name).components();
+            let single = match (components.next(), components.next()) {
+                (None, _) => true,
+                (Some(Component::Normal(it)), None) => it == name,
+                _ => false,
+            };
+            if !single {
+                bail!("invalid vendor ramdisk name {:?}", name);
+            }
+            if vendor_ramdisk_names.contains(&name) {
+                bail!("duplicate vendor ramdisk name {:?}", name);
+            }
+            vendor_ramdisk_names.push(name);
+        }
+    }
+    fs::create_dir_all(dir)?;
+
+    let create = |name: &str| -> Result<File> {
+        info!("{}", name);
+        Ok(OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.join(name))?)
+    };
+    let mut files = Vec::new();
+
+    header.dump_text(&mut create("header")?)?;
+    files.push(ManifestFile::new("header", "header", None));
+
+    if let Some(kernel) = blocks.get_kernel() {
+        kernel.dump(&mut create("kernel")?, no_decompress)?;
+        files.push(ManifestFile::new(
+            "kernel",
+            "kernel",
+            Some(kernel.get_compress_format()),
+        ));
+        if let Some(dtb) = kernel.appended_dtb() {
+            create("kernel_dtb")?.write_all(dtb)?;
+            files.push(ManifestFile::new("kernel_dtb", "kernel_dtb", None));
+        }
+    }
+
+    if let Some(ramdisk) = blocks.get_ramdisk() {
+        if ramdisk.is_vendor_ramdisk() {
+            for (i, name) in vendor_ramdisk_names.into_iter().enumerate() {
+                let entry = ramdisk.get_vendor_ramdisk(i).unwrap();
+                let file = format!("vendor.{}.cpio", name);
+                entry.dump(&mut create(&file)?, no_decompress)?;
+                files.push(ManifestFile {
+                    entry: Some(ManifestEntry {
+                        name: name.to_string(),
+                        entry_type: entry.get_entry_type().to_string(),
+                        board_id: entry.board_id(),
+                    }),
+                    ..ManifestFile::new("vendor_ramdisk", &file, Some(entry.get_compress_format()))
+                });
+            }
+            if merge_vendor_ramdisks {
+                let (cpio, _) = ramdisk.merge_vendor_ramdisks()?;
+                cpio.dump(&mut create("vendor_ramdisk.cpio")?)?;
+            }
+        } else {
+            let segments = ramdisk.segments();
+            let mixed = segments
+                .iter()
+                .any(|it| it.compress_format != segments[0].compress_format);
+            if mixed && !no_decompress {
+                // One file per segment, each is compressed again with its format
+                for (i, segment) in segments.iter().enumerate() {
+                    let file = format!("ramdisk.{}.cpio", i);
+                    segment.dump(&mut create(&file)?, false)?;
+                    files.push(ManifestFile::new(
+                        "ramdisk",
+                        &file,
+                        Some(segment.compress_format),
+                    ));
                 }
             } else {
-                println!("ramdisk format: {:?}", ramdisk.get_compress_format());
-                dump_block_to_file!(ramdisk, "ramdisk.cpio");
-                let mut data = Vec::<u8>::new();
-                ramdisk.dump(&mut data, false)?;
-                let cpio = android_bootimg::cpio::Cpio::load_from_data(data.as_slice())?;
-                cpio.ls("/", true);
-            }
-        }
-
-        if let Some(s2) = env::args().skip(2).next() {
-            if s2 == "--patch" {
-                let mut patcher = BootImagePatchOption::new(&boot);
-                if blocks.get_kernel().is_some() {
-                    println!("adding kernel");
-                    patcher.replace_kernel(Box::new(File::open("kernel")?), false);
-                }
-                if let Some(ramdisk) = blocks.get_ramdisk() {
-                    if ramdisk.is_vendor_ramdisk() {
-                        println!("adding vendor ramdisk");
-                        for i in 0..ramdisk.get_vendor_ramdisk_num() {
-                            let entry = ramdisk.get_vendor_ramdisk(i).unwrap();
-                            let name = from_utf8(entry.get_name_raw())?;
-                            println!("name: {}", name);
-                            patcher.replace_vendor_ramdisk(
-                                i,
-                                Box::new(File::open(format!("vendor.{}.cpio", name))?),
-                                false,
-                            );
+                ramdisk.dump(&mut create("ramdisk.cpio")?, no_decompress)?;
+                files.push(ManifestFile::new(
+                    "ramdisk",
+                    "ramdisk.cpio",
+                    Some(ramdisk.get_compress_format()),
+                ));
+            }
+        }
+    }
+
+    macro_rules! dump_raw_block_to_file {
+        ($name:ident) => {
+            paste! {
+                if let Some(data) = blocks.[<get_ $name>]() {
+                    create(stringify!($name))?.write_all(data)?;
+                    files.push(ManifestFile::new(stringify!($name), stringify!($name), None));
+                }
+            }
+        };
+    }
+
+    dump_raw_block_to_file! { second }
+    dump_raw_block_to_file! { recovery_dtbo }
+    dump_raw_block_to_file! { dtb }
+    dump_raw_block_to_file! { signature }
+    dump_raw_block_to_file! { bootconfig }
+
+    if let Ok(entries) = blocks.get_dtb_entries() {
+        for (i, entry) in entries.iter().enumerate() {
+            create(&format!("dtb.{}", i))?.write_all(entry.data)?;
+        }
+    }
+
+    let (header_version, vendor) = match header.get_version() {
+        BootImageVersion::Android(version) => (version, false),
+        BootImageVersion::Vendor(version) => (version, true),
+    };
+    let manifest = Manifest {
+        header_version,
+        vendor,
+        page_size: header.page_size() as u32,
+        decompressed: !no_decompress,
+        image: serde_json::to_value(boot.info())?,
+        files,
+    };
+    let mut output = create(MANIFEST)?;
+    serde_json::to_writer_pretty(&mut output, &manifest)?;
+    writeln!(output)?;
+    Ok(())
+}
+
+const MANIFEST: &str = "manifest.json";
+
+// Everything repack needs to rebuild the image, `image` is informational
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    header_version: u32,
+    vendor: bool,
+    page_size: u32,
+    // Whether the files of compressed blocks have to be compressed again
+    decompressed: bool,
+    image: serde_json::Value,
+    files: Vec<ManifestFile>,
+}
+
+// `file` is relative to the manifest. `format` is the compression of the block
+// in the unpacked image, `entry` is only set for vendor ramdisks.
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    block: String,
+    file: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entry: Option<ManifestEntry>,
+}
+
+impl ManifestFile {
+    fn new(block: &str, file: &str, format: Option<CompressFormat>) -> Self {
+        Self {
+            block: block.to_string(),
+            file: file.to_string(),
+            format: format.map(format_name),
+            entry: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    name: String,
+    entry_type: String,
+    board_id: [u32; 16],
+}
+
+// A name parse_format accepts
+fn format_name(format: CompressFormat) -> String {
+    match format {
+        CompressFormat::UNKNOWN => "raw".to_string(),
+        format => format!("{:?}", format).to_ascii_lowercase(),
+    }
+}
+
+// Builds the image of an unpack directory from its manifest and files, the
+// header file is the template of the new header
+fn build_from_manifest(dir: &Path, options: &CompressOptions) -> Result<Vec<u8>> {
+    let manifest: Manifest = serde_json::from_slice(&fs::read(dir.join(MANIFEST))?)?;
+    let mut builder = BootImageBuilder::new(if manifest.vendor {
+        BootImageVersion::Vendor(manifest.header_version)
+    } else {
+        BootImageVersion::Android(manifest.header_version)
+    })?;
+    builder
+        .page_size(manifest.page_size)
+        .compress_options(*options);
+
+    let format = |file: &ManifestFile| match &file.format {
+        Some(name) if manifest.decompressed => parse_format(name),
+        _ => Ok(CompressFormat::UNKNOWN),
+    };
+    let mut kernel_dtb = Vec::new();
+    if let Some(file) = manifest.files.iter().find(|it| it.block == "kernel_dtb") {
+        kernel_dtb = fs::read(dir.join(&file.file))?;
+    }
+    // Segments of a mixed-format ramdisk are concatenated after compression
+    let mut ramdisk_segments = (manifest
+        .files
+        .iter()
+        .filter(|it| it.block == "ramdisk")
+        .count()
+        > 1)
+    .then(Vec::new);
+    for file in &manifest.files {
+        let path = dir.join(&file.file);
+        match (file.block.as_str(), &file.entry) {
+            ("header", _) => {
+                builder.header(parse_header_text(&fs::read_to_string(path)?)?);
+            }
+            ("kernel_dtb", _) => {}
+            // The dtb is appended after the compressed kernel
+            ("kernel", _) => {
+                let mut kernel = Vec::new();
+                transcode(
+                    CompressFormat::UNKNOWN,
+                    format(file)?,
+                    File::open(path)?,
+                    &mut kernel,
+                    options,
+                )?;
+                kernel.extend_from_slice(&kernel_dtb);
+                builder.add_block(
+                    "kernel",
+                    Box::new(Cursor::new(kernel)),
+                    CompressFormat::UNKNOWN,
+                )?;
+            }
+            ("ramdisk", _) if ramdisk_segments.is_some() => {
+                transcode(
+                    CompressFormat::UNKNOWN,
+                    format(file)?,
+                    File::open(path)?,
+                    ramdisk_segments.as_mut().unwrap(),
+                    options,
+                )?;
+            }
+            ("vendor_ramdisk", Some(entry)) => {
+                builder.add_vendor_ramdisk(
+                    &entry.name,
+                    entry.entry_type.parse()?,
+                    entry.board_id,
+                    Box::new(File::open(path)?),
+                    format(file)?,
+                )?;
+            }
+            (block, _) => {
+                builder.add_block(block, Box::new(File::open(path)?), format(file)?)?;
+            }
+        }
+    }
+    if let Some(ramdisk) = ramdisk_segments {
+        builder.add_block(
+            "ramdisk",
+            Box::new(Cursor::new(ramdisk)),
+            CompressFormat::UNKNOWN,
+        )?;
+    }
+    let mut image = Vec::new();
+    builder.build(&mut image)?;
+    Ok(image)
+}
+
+#[cfg(feature = "payload")]
+fn handle_payload(command: PayloadCommand) -> Result<()> {
+    match command {
+        PayloadCommand::List { payload } => {
+            let mem = read_input(&payload)?;
+            for partition in Payload::parse(&mem)?.partitions() {
+                let kind = if partition.is_full() { "full" } else { "delta" };
+                println!("{}\t{}\t{}", partition.name, partition.size, kind);
+            }
+        }
+        PayloadCommand::Extract {
+            payload,
+            partition,
+            output,
+        } => {
+            let mem = read_input(&payload)?;
+            let image = Payload::parse(&mem)?.extract(&partition)?;
+            let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.img", partition)));
+            create_output(&output)?.write_all(&image)?;
+        }
+    }
+    Ok(())
+}
+
+const BATCH_PARTITIONS: [&str; 4] = ["boot", "init_boot", "vendor_boot", "vendor_kernel_boot"];
+
+// One image of a batch run. `info` is set by info --json, `unexpected` by
+// verify.
+#[derive(Serialize)]
+struct BatchEntry {
+    partition: &'static str,
+    file: PathBuf,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unexpected: Vec<String>,
+}
+
+impl BatchEntry {
+    fn new(partition: &'static str, file: PathBuf) -> Self {
+        BatchEntry {
+            partition,
+            file,
+            ok: true,
+            error: None,
+            output: None,
+            info: None,
+            unexpected: Vec::new(),
+        }
+    }
+
+    fn fail(&mut self, error: anyhow::Error) {
+        error!("{}: {:?}", self.partition, error);
+        self.ok = false;
+        self.error = Some(format!("{:#}", error));
+    }
+}
+
+fn batch_images(dir: &Path) -> Result<Vec<(&'static str, PathBuf)>> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    let images: Vec<_> = BATCH_PARTITIONS
+        .iter()
+        .map(|&partition| (partition, dir.join(format!("{}.img", partition))))
+        .filter(|(_, path)| path.is_file())
+        .collect();
+    if images.is_empty() {
+        bail!("no boot images found in {}", dir.display());
+    }
+    Ok(images)
+}
+
+fn handle_batch(command: BatchCommand) -> Result<ExitCode> {
+    let mut entries = Vec::new();
+    let json = match command {
+        BatchCommand::Info { dir, json } => {
+            for (partition, path) in batch_images(&dir)? {
+                let mut entry = BatchEntry::new(partition, path);
+                let result = if json {
+                    read_input(&entry.file).and_then(|mem| {
+                        entry.info = Some(serde_json::to_value(BootImage::parse(&mem)?.info())?);
+                        Ok(())
+                    })
+                } else {
+                    println!("# {}", partition);
+                    handle_info(&entry.file, false, false, false)
+                };
+                if let Err(e) = result {
+                    entry.fail(e);
+                }
+                entries.push(entry);
+            }
+            json
+        }
+        BatchCommand::Unpack {
+            dir,
+            output,
+            no_decompress,
+        } => {
+            for (partition, path) in batch_images(&dir)? {
+                let mut entry = BatchEntry::new(partition, path);
+                let target = output.join(partition);
+                match handle_unpack(&entry.file, &target, no_decompress, false) {
+                    Ok(()) => entry.output = Some(target),
+                    Err(e) => entry.fail(e),
+                }
+                entries.push(entry);
+            }
+            fs::write(
+                output.join("summary.json"),
+                serde_json::to_string_pretty(&entries)?,
+            )?;
+            false
+        }
+        BatchCommand::Verify {
+            original,
+            patched,
+            expect,
+            json,
+        } => {
+            for (partition, path) in batch_images(&original)? {
+                let patched = patched.join(format!("{}.img", partition));
+                let mut entry = BatchEntry::new(partition, patched.clone());
+                if !json {
+                    println!("# {}", partition);
+                }
+                match verify_images(&path, &patched, &expect, !json) {
+                    Ok(unexpected) => {
+                        if !json {
+                            for it in &unexpected {
+                                println!("unexpected: {}", it);
+                            }
                         }
-                    } else {
-                        println!("adding ramdisk");
-                        patcher.replace_ramdisk(Box::new(File::open("ramdisk.cpio")?), false);
+                        entry.ok = unexpected.is_empty();
+                        entry.unexpected = unexpected;
                     }
+                    Err(e) => entry.fail(e),
                 }
-                // TODO: vendor ramdisk
-                let mut output = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open("new-boot.img")?;
-                patcher.patch(&mut output)?;
+                entries.push(entry);
             }
+            json
         }
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    }
+    let failed = entries.iter().filter(|it| !it.ok).count();
+    if failed == 0 {
+        return Ok(ExitCode::SUCCESS);
+    }
+    error!("{} of {} images failed", failed, entries.len());
+    Ok(ExitCode::FAILURE)
+}
+
+fn vendor_ramdisk_index(boot: &BootImage, name: &str) -> Result<usize> {
+    let Some(ramdisk) = boot
+        .get_blocks()
+        .get_ramdisk()
+        .filter(|it| it.is_vendor_ramdisk())
+    else {
+        bail!("image has no vendor ramdisk table");
+    };
+    match (0..ramdisk.get_vendor_ramdisk_num())
+        .find(|&i| ramdisk.get_vendor_ramdisk(i).unwrap().get_name_raw() == name.as_bytes())
+    {
+        Some(index) => Ok(index),
+        None => bail!("no vendor ramdisk named {}", name),
+    }
+}
+
+fn handle_create(args: &CreateArgs) -> Result<()> {
+    let version = if args.vendor {
+        BootImageVersion::Vendor(args.header_version)
+    } else {
+        BootImageVersion::Android(args.header_version)
+    };
+    let mut builder = BootImageBuilder::new(version)?;
+    if let Some(base) = &args.base {
+        builder.base(u32::from_str_radix(base.trim_start_matches("0x"), 16)?);
+    }
+    if let Some(page_size) = args.page_size {
+        builder.page_size(page_size);
+    }
+    if let Some(path) = &args.header {
+        builder.header(parse_header_text(&fs::read_to_string(path)?)?);
+    }
+    builder.header(HeaderOverrides {
+        name: args.name.clone(),
+        cmdline: args.cmdline.clone(),
+        os_version: args.os_version.as_deref().map(str::parse).transpose()?,
+        patch_level: args.os_patch_level.as_deref().map(str::parse).transpose()?,
+        ..Default::default()
+    });
+
+    let blocks = [
+        ("kernel", &args.kernel),
+        ("ramdisk", &args.ramdisk),
+        ("second", &args.second),
+        ("recovery_dtbo", &args.recovery_dtbo),
+        ("dtb", &args.dtb),
+        ("bootconfig", &args.bootconfig),
+        ("signature", &args.signature),
+    ];
+    for (name, path) in blocks {
+        if let Some(path) = path {
+            builder.add_block(name, Box::new(File::open(path)?), CompressFormat::UNKNOWN)?;
+        }
+    }
+    for arg in &args.vendor_ramdisk {
+        let Some((name, path)) = arg.split_once('=') else {
+            bail!("expected --vendor-ramdisk=[TYPE:]NAME=PATH");
+        };
+        let (entry_type, name) = match name.split_once(':') {
+            Some((entry_type, name)) => (entry_type.parse()?, name),
+            None => (VendorRamdiskTableEntryType::Platform, name),
+        };
+        builder.add_vendor_ramdisk(
+            name,
+            entry_type,
+            Default::default(),
+            Box::new(File::open(path)?),
+            CompressFormat::UNKNOWN,
+        )?;
+    }
+
+    let mut output = create_output(&args.output)?;
+    builder.build(&mut output)?;
+    Ok(())
+}
+
+fn handle_mkbootimg(args: &[String]) -> Result<()> {
+    let args = MkbootimgArgs::parse(args)?;
+    if let (Some(builder), Some(path)) = (args.boot_builder()?, &args.output) {
+        let mut image = Vec::new();
+        builder.build(&mut image)?;
+        create_output(path)?.write_all(&image)?;
+        if args.id {
+            let boot = BootImage::parse(&image)?;
+            if boot.get_header().has_id() {
+                println!("0x{}", hex(boot.get_header().get_id()));
+            }
+        }
+    }
+    if let (Some(builder), Some(path)) = (args.vendor_boot_builder()?, &args.vendor_boot) {
+        builder.build(&mut create_output(path)?)?;
+    }
+    Ok(())
+}
 
-        Ok(())
+fn handle_repack(args: &RepackArgs) -> Result<()> {
+    let mem = if args.source.is_dir() {
+        InputData::Read(build_from_manifest(&args.source, &args.compress.options())?)
     } else {
-        bail!("no file provided")
+        read_input(&args.source)?
+    };
+    let boot = BootImage::parse(&mem)?;
+    let blocks = boot.get_blocks();
+
+    let mut patcher = BootImagePatchOption::new(&boot);
+    if let Some(header) = &args.header {
+        patcher.override_header(parse_header_text(&fs::read_to_string(header)?)?);
+    }
+    if let Some(cmdline) = &args.cmdline {
+        patcher.override_header(HeaderOverrides {
+            cmdline: Some(cmdline.clone()),
+            ..Default::default()
+        });
+    }
+    patcher.preserve_padding(args.preserve_padding);
+    patcher.compress_options(args.compress.options());
+    if let Some(kernel) = &args.kernel {
+        patcher.replace_kernel(Box::new(File::open(kernel)?));
+    }
+    if let Some(ramdisk) = &args.ramdisk {
+        patcher.replace_ramdisk(Box::new(File::open(ramdisk)?));
+    }
+    for arg in &args.vendor_ramdisk {
+        let Some((name, path)) = arg.split_once('=') else {
+            bail!("expected --vendor-ramdisk=NAME=PATH");
+        };
+        let index = vendor_ramdisk_index(&boot, name)?;
+        patcher.replace_vendor_ramdisk(index, Box::new(File::open(path)?));
+    }
+    if let Some(dtb) = &args.dtb {
+        if blocks.get_dtb().is_some() {
+            patcher.replace_dtb(fs::read(dtb)?);
+        } else if blocks
+            .get_kernel()
+            .is_some_and(|it| it.appended_dtb().is_some())
+        {
+            patcher.appended_dtb(AppendedDtbAction::Replace(Box::new(File::open(dtb)?)));
+        } else {
+            bail!("{} has no dtb to replace", args.source.display());
+        }
+    }
+
+    #[allow(unused_mut)]
+    let mut footer_algorithm = AvbAlgorithm::None;
+    #[cfg(feature = "sign")]
+    if let Some(key) = &args.sign {
+        let key = AvbSigningKey::from_pkcs8_pem(&fs::read_to_string(key)?)?;
+        let algorithm = match &args.algorithm {
+            Some(name) => match AvbAlgorithm::from_name(name) {
+                Some(algorithm) => algorithm,
+                None => bail!("unknown algorithm {}", name),
+            },
+            None => key.default_algorithm(),
+        };
+        info!("signing with {}", algorithm.name());
+        footer_algorithm = algorithm;
+        patcher.sign_avb(key, algorithm);
+    }
+    if let Some(footer) = &args.avb_footer {
+        let Some((name, size)) = footer.split_once(':') else {
+            bail!("expected --avb-footer=NAME:PARTITION_SIZE");
+        };
+        patcher.add_avb_footer(AvbFooterOptions {
+            partition_name: name.to_string(),
+            partition_size: size.parse()?,
+            salt: args
+                .salt
+                .as_deref()
+                .map(unhex)
+                .transpose()?
+                .unwrap_or_default(),
+            algorithm: footer_algorithm,
+        });
+    }
+
+    write_patched(patcher, &args.output, args.lz4)
+}
+
+fn run(command: Command) -> Result<ExitCode> {
+    match command {
+        Command::Cpio { archive, commands } => handle_cpio(&archive, &commands),
+        Command::Avb { command } => handle_avb(command),
+        Command::Batch { command } => handle_batch(command),
+        Command::Verify {
+            original,
+            patched,
+            expect,
+        } => handle_verify(&original, &patched, &expect),
+        Command::Search {
+            image,
+            block,
+            pattern,
+        } => handle_search(&image, &block, &pattern),
+        command => run_command(command).map(|()| ExitCode::SUCCESS),
+    }
+}
+
+fn run_command(command: Command) -> Result<()> {
+    match command {
+        Command::Cpio { .. }
+        | Command::Avb { .. }
+        | Command::Batch { .. }
+        | Command::Verify { .. }
+        | Command::Search { .. } => unreachable!(),
+        Command::Hash {
+            sha256,
+            sha1: _,
+            file,
+            payload,
+            block,
+        } => {
+            let algorithm = if sha256 {
+                DigestAlgorithm::Sha256
+            } else {
+                DigestAlgorithm::Sha1
+            };
+            handle_hash(algorithm, &file, payload, block.as_deref())
+        }
+        Command::Hexpatch {
+            image,
+            block,
+            pattern,
+            replacement,
+            output,
+        } => handle_hexpatch(&image, &block, &pattern, &replacement, &output),
+        Command::Merge {
+            image,
+            kernel_from,
+            ramdisk_from,
+            output,
+        } => handle_merge(
+            &image,
+            kernel_from.as_deref(),
+            ramdisk_from.as_deref(),
+            &output,
+        ),
+        Command::Info {
+            image,
+            json,
+            all,
+            merge_vendor_ramdisks,
+        } => handle_info(&image, json, all, merge_vendor_ramdisks),
+        Command::Unpack {
+            image,
+            output,
+            no_decompress,
+            merge_vendor_ramdisks,
+        } => handle_unpack(&image, &output, no_decompress, merge_vendor_ramdisks),
+        Command::Create(args) => handle_create(&args),
+        Command::Mkbootimg { args } => handle_mkbootimg(&args),
+        Command::Repack(args) => handle_repack(&args),
+        Command::Vbmeta {
+            image,
+            flags,
+            output,
+        } => handle_vbmeta(&image, flags, &output),
+        Command::Compress {
+            format,
+            input,
+            output,
+            options,
+        } => {
+            let format = parse_format(&format)?;
+            let output = match output {
+                Some(output) => output,
+                None => compressed_name(&input, format)?,
+            };
+            handle_transcode(
+                Some(CompressFormat::UNKNOWN),
+                format,
+                &input,
+                &output,
+                &options.options(),
+            )
+        }
+        Command::Decompress { input, output } => {
+            let output = match output {
+                Some(output) => output,
+                None => decompressed_name(&input)?,
+            };
+            handle_transcode(
+                None,
+                CompressFormat::UNKNOWN,
+                &input,
+                &output,
+                &CompressOptions::default(),
+            )
+        }
+        Command::Diff {
+            old,
+            new,
+            content_only,
+        } => {
+            let diff =
+                load_cpio_file(&old)?.diff_with_options(&load_cpio_file(&new)?, content_only)?;
+            print!("{}", diff);
+            Ok(())
+        }
+        Command::Stats { cpio, top } => handle_stats(&cpio, top),
+        #[cfg(unix)]
+        Command::Extract {
+            cpio,
+            dir,
+            device_nodes,
+        } => handle_extract(&cpio, &dir, device_nodes),
+        #[cfg(unix)]
+        Command::Pack {
+            dir,
+            cpio,
+            keep_owner,
+        } => handle_pack(&dir, &cpio, keep_owner),
+        #[cfg(feature = "payload")]
+        Command::Payload { command } => handle_payload(command),
+        #[cfg(feature = "tar")]
+        Command::Totar { cpio, tar } => {
+            let cpio = load_cpio_file(&cpio)?;
+            cpio.to_tar(&mut File::create(tar)?)?;
+            info!("converted {} entries", cpio.entries().len());
+            Ok(())
+        }
+        #[cfg(feature = "tar")]
+        Command::Fromtar { tar, cpio: output } => {
+            let cpio = Cpio::from_tar(BufReader::new(File::open(tar)?))?;
+            cpio.dump(&mut File::create(output)?)?;
+            info!("converted {} entries", cpio.entries().len());
+            Ok(())
+        }
+    }
+}
+
+// clap exits with 2 for usage errors
+const EXIT_PARSE: u8 = 3;
+const EXIT_IO: u8 = 4;
+
+fn is_io_error(error: &BootImgError) -> bool {
+    match error {
+        BootImgError::Io(_) => true,
+        BootImgError::Context { error, .. } => is_io_error(error),
+        _ => false,
+    }
+}
+
+// 3 when an image or archive could not be parsed, 4 for I/O errors and 1 for
+// anything else
+fn exit_code(error: &anyhow::Error) -> ExitCode {
+    for cause in error.chain() {
+        if cause.is::<io::Error>() {
+            return ExitCode::from(EXIT_IO);
+        }
+        if let Some(error) = cause.downcast_ref::<BootImgError>() {
+            return ExitCode::from(if is_io_error(error) {
+                EXIT_IO
+            } else {
+                EXIT_PARSE
+            });
+        }
+    }
+    ExitCode::FAILURE
+}
+
+fn init_logger(quiet: bool, verbose: u8) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    // Info carries the status lines, printed as they are
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(
+                buf,
+                "{}: {}",
+                level.as_str().to_ascii_lowercase(),
+                record.args()
+            ),
+        })
+        .init();
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    init_logger(cli.quiet, cli.verbose);
+
+    match run(cli.command) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            exit_code(&e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_bootimg::testutil::{FIXTURE_VERSIONS, ImageFixture, ramdisk};
+
+    // A fresh directory under the system temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "android-bootimg-cli-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn unhex_input() {
+        assert_eq!(unhex("").unwrap(), b"");
+        assert_eq!(unhex("00fF7a").unwrap(), [0x00, 0xff, 0x7a]);
+        for bad in ["0", "0g", "+1", " 1", "é0", "0é", "éé"] {
+            assert!(unhex(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn manifest_roundtrip() {
+        for version in FIXTURE_VERSIONS {
+            let data = ImageFixture::new(version).build().unwrap();
+            let dir = temp_dir(&format!("{:?}", version));
+            let image = dir.join("boot.img");
+            fs::write(&image, &data).unwrap();
+            let unpacked = dir.join("unpacked");
+            handle_unpack(&image, &unpacked, false, false).unwrap();
+
+            // The directory holds everything, it can be moved without the image
+            let moved = dir.join("moved");
+            fs::rename(&unpacked, &moved).unwrap();
+            fs::remove_file(&image).unwrap();
+            let rebuilt = build_from_manifest(&moved, &CompressOptions::default()).unwrap();
+            assert!(rebuilt == data, "{:?}", version);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    // A gzip segment followed by an lz4 one is repacked segment by segment
+    #[test]
+    fn manifest_mixed_format_ramdisk() {
+        let options = CompressOptions::default();
+        let mut segments = Vec::new();
+        for (format, cpio) in [
+            (
+                CompressFormat::GZIP,
+                ramdisk(&[("init", b"first")]).unwrap(),
+            ),
+            (
+                CompressFormat::LZ4_LEGACY,
+                ramdisk(&[("overlay", b"second")]).unwrap(),
+            ),
+        ] {
+            transcode(
+                CompressFormat::UNKNOWN,
+                format,
+                &cpio[..],
+                &mut segments,
+                &options,
+            )
+            .unwrap();
+        }
+        let mut builder = BootImageBuilder::new(BootImageVersion::Android(2)).unwrap();
+        builder
+            .add_block(
+                "ramdisk",
+                Box::new(Cursor::new(segments)),
+                CompressFormat::UNKNOWN,
+            )
+            .unwrap();
+        let mut data = Vec::new();
+        builder.build(&mut data).unwrap();
+
+        let dir = temp_dir("mixed");
+        let image = dir.join("boot.img");
+        fs::write(&image, &data).unwrap();
+        let unpacked = dir.join("unpacked");
+        handle_unpack(&image, &unpacked, false, false).unwrap();
+        let manifest: Manifest =
+            serde_json::from_slice(&fs::read(unpacked.join(MANIFEST)).unwrap()).unwrap();
+        let files = manifest
+            .files
+            .iter()
+            .filter(|it| it.block == "ramdisk")
+            .map(|it| (it.file.as_str(), it.format.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [("ramdisk.0.cpio", "gzip"), ("ramdisk.1.cpio", "lz4_legacy")]
+        );
+        let rebuilt = build_from_manifest(&unpacked, &options).unwrap();
+        assert!(rebuilt == data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_records_vendor_table() {
+        let data = ImageFixture::new(BootImageVersion::Vendor(4))
+            .build()
+            .unwrap();
+        let dir = temp_dir("vendor-table");
+        let image = dir.join("vendor_boot.img");
+        fs::write(&image, &data).unwrap();
+        handle_unpack(&image, &dir, false, false).unwrap();
+
+        let manifest: Manifest =
+            serde_json::from_slice(&fs::read(dir.join(MANIFEST)).unwrap()).unwrap();
+        let entries = manifest
+            .files
+            .iter()
+            .filter_map(|it| {
+                let entry = it.entry.as_ref()?;
+                Some((
+                    entry.name.as_str(),
+                    entry.entry_type.as_str(),
+                    it.format.as_deref(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                ("", "platform", Some("gzip")),
+                ("recovery", "recovery", Some("gzip")),
+                ("dlkm", "dlkm", Some("gzip")),
+            ]
+        );
+        assert!(
+            manifest
+                .files
+                .iter()
+                .all(|it| !Path::new(&it.file).is_absolute())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The boot v2 invocation of android-bootimg/testdata/mkbootimg/goldens.sh
+    #[test]
+    fn mkbootimg_verb() {
+        let testdata =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../android-bootimg/testdata/mkbootimg");
+        let input = |name: &str| testdata.join(name).display().to_string();
+        let dir = temp_dir("mkbootimg");
+        let output = dir.join("boot.img");
+        let args = [
+            "--header_version",
+            "2",
+            "--kernel",
+            &input("kernel"),
+            "--ramdisk",
+            &input("ramdisk"),
+            "--second",
+            &input("second"),
+            "--recovery_dtbo",
+            &input("recovery_dtbo"),
+            "--dtb",
+            &input("dtb"),
+            "--cmdline",
+            "console=ttyMSM0,115200n8 androidboot.hardware=qcom",
+            "--board",
+            "golden",
+            "--base",
+            "0x40000000",
+            "--ramdisk_offset",
+            "0x02000000",
+            "--tags_offset",
+            "0x00000100",
+            "--dtb_offset",
+            "0x01f00000",
+            "--pagesize",
+            "2048",
+            "--os_version",
+            "12.1.0",
+            "--os_patch_level",
+            "2022-03",
+            "-o",
+            output.to_str().unwrap(),
+        ];
+        let Command::Mkbootimg { args } =
+            Cli::try_parse_from([["android-bootimg", "mkbootimg"].as_slice(), &args].concat())
+                .unwrap()
+                .command
+        else {
+            unreachable!()
+        };
+        handle_mkbootimg(&args).unwrap();
+        assert!(fs::read(&output).unwrap() == fs::read(testdata.join("boot-v2.img")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_vendor_ramdisk_paths() {
+        let fixture = ImageFixture::new(BootImageVersion::Vendor(4))
+            .build()
+            .unwrap();
+        let boot = BootImage::parse(&fixture).unwrap();
+        let (_, table, _) = boot
+            .get_blocks()
+            .blocks()
+            .find(|(name, _, _)| *name == "vendor_ramdisk_table")
+            .unwrap();
+        // The name of an entry, after size, offset and type
+        let name_offset = |index: usize| table + index * 108 + 12;
+
+        let dir = temp_dir("vendor-paths");
+        let unpacked = dir.join("unpacked");
+        let image = dir.join("vendor_boot.img");
+        // The third entry is renamed, the second one is already written by then
+        for (name, error) in [
+            (&b"../escape"[..], "invalid vendor ramdisk name"),
+            (b"a/b", "invalid vendor ramdisk name"),
+            (b"..", "invalid vendor ramdisk name"),
+            (b".", "invalid vendor ramdisk name"),
+            (b"recovery", "duplicate vendor ramdisk name"),
+            (b"", "duplicate vendor ramdisk name"),
+            (b"\xff", "is not UTF-8"),
+        ] {
+            let mut data = fixture.clone();
+            let offset = name_offset(2);
+            data[offset..offset + 32].fill(0);
+            data[offset..offset + name.len()].copy_from_slice(name);
+            fs::write(&image, &data).unwrap();
+
+            let err = handle_unpack(&image, &unpacked, false, false).unwrap_err();
+            assert!(err.to_string().contains(error), "{}", err);
+            assert!(!unpacked.exists(), "{:?}", name);
+            assert!(!dir.join("escape.cpio").exists());
+        }
+
+        // Dots inside a name are fine
+        let mut data = fixture.clone();
+        let offset = name_offset(2);
+        data[offset..offset + 5].copy_from_slice(b"a..b\0");
+        fs::write(&image, &data).unwrap();
+        handle_unpack(&image, &unpacked, false, false).unwrap();
+        assert!(unpacked.join("vendor.a..b.cpio").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A kernel that fails to decode and a zstd ramdisk, which the CLI can't
+    // decode without the zstd feature
+    #[test]
+    fn info_skips_undecodable_blocks() {
+        let kernel = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03truncated";
+        let ramdisk = include_bytes!("../../android-bootimg/testdata/plain.zst");
+        let mut builder = BootImageBuilder::new(BootImageVersion::Android(2)).unwrap();
+        builder
+            .add_block("kernel", Box::new(&kernel[..]), CompressFormat::UNKNOWN)
+            .unwrap()
+            .add_block("ramdisk", Box::new(&ramdisk[..]), CompressFormat::UNKNOWN)
+            .unwrap();
+        let mut data = Vec::new();
+        builder.build(&mut data).unwrap();
+
+        let dir = temp_dir("info-undecodable");
+        let image = dir.join("boot.img");
+        fs::write(&image, &data).unwrap();
+        handle_info(&image, false, false, false).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,3 @@
+from ._native import BootImage, BootImgError, Cpio
+
+__all__ = ["BootImage", "BootImgError", "Cpio"]
@@ -0,0 +1,211 @@
+use android_bootimg::cpio::{Cpio as RsCpio, CpioEntry};
+use android_bootimg::error::BootImgError as RsError;
+use android_bootimg::parser::OwnedBootImage;
+use android_bootimg::patcher::BootImagePatchOption;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::io::{Cursor, Read};
+
+create_exception!(_native, BootImgError, PyException);
+
+fn to_py(e: RsError) -> PyErr {
+    BootImgError::new_err(e.to_string())
+}
+
+// bytes, bytearray, memoryview or anything else bytes() accepts
+fn bytes_like(data: &Bound<'_, PyAny>) -> PyResult<Vec<u8>> {
+    if let Ok(bytes) = data.cast::<PyBytes>() {
+        return Ok(bytes.as_bytes().to_vec());
+    }
+    let bytes = data.py().get_type::<PyBytes>().call1((data,))?;
+    Ok(bytes.cast::<PyBytes>()?.as_bytes().to_vec())
+}
+
+#[pyclass(frozen)]
+struct BootImage {
+    image: OwnedBootImage,
+}
+
+#[pymethods]
+impl BootImage {
+    #[new]
+    fn new(data: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let image = OwnedBootImage::parse(bytes_like(data)?).map_err(to_py)?;
+        Ok(Self { image })
+    }
+
+    #[staticmethod]
+    fn from_file(path: &str) -> PyResult<Self> {
+        let image = OwnedBootImage::parse(std::fs::read(path)?).map_err(to_py)?;
+        Ok(Self { image })
+    }
+
+    // The JSON of the CLI info command as a dict
+    fn info<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let json = serde_json::to_string(&self.image.get().info())
+            .map_err(|e| BootImgError::new_err(e.to_string()))?;
+        py.import("json")?.call_method1("loads", (json,))
+    }
+
+    // Block names in file order
+    #[getter]
+    fn blocks(&self) -> Vec<&'static str> {
+        self.image.get_blocks().blocks().map(|it| it.0).collect()
+    }
+
+    // Decompressed content of a block, or the stored bytes with raw. None if
+    // the image has no such block.
+    #[pyo3(signature = (name, raw = false))]
+    fn block<'py>(
+        &self,
+        py: Python<'py>,
+        name: &str,
+        raw: bool,
+    ) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let image = self.image.get();
+        let data = if raw {
+            image
+                .get_blocks()
+                .blocks()
+                .find(|(it, _, _)| *it == name)
+                .map(|(_, off, len)| self.image.get_data()[off..off + len].to_vec())
+        } else {
+            match image.block_content(name).map_err(to_py)? {
+                Some(mut reader) => {
+                    let mut data = Vec::new();
+                    reader
+                        .read_to_end(&mut data)
+                        .map_err(|e| to_py(RsError::from(e)))?;
+                    Some(data)
+                }
+                None => None,
+            }
+        };
+        Ok(data.map(|it| PyBytes::new(py, &it)))
+    }
+
+    // The patched image. Uncompressed kernels and ramdisks are compressed like
+    // the blocks they replace.
+    #[pyo3(signature = (*, kernel = None, ramdisk = None, second = None, dtb = None,
+        recovery_dtbo = None, cmdline = None))]
+    #[allow(clippy::too_many_arguments)]
+    fn patch<'py>(
+        &self,
+        py: Python<'py>,
+        kernel: Option<&Bound<'py, PyAny>>,
+        ramdisk: Option<&Bound<'py, PyAny>>,
+        second: Option<&Bound<'py, PyAny>>,
+        dtb: Option<&Bound<'py, PyAny>>,
+        recovery_dtbo: Option<&Bound<'py, PyAny>>,
+        cmdline: Option<&str>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut patcher = BootImagePatchOption::new(self.image.get());
+        if let Some(kernel) = kernel {
+            patcher.replace_kernel(Box::new(Cursor::new(bytes_like(kernel)?)));
+        }
+        if let Some(ramdisk) = ramdisk {
+            patcher.replace_ramdisk(Box::new(Cursor::new(bytes_like(ramdisk)?)));
+        }
+        if let Some(second) = second {
+            patcher.replace_second(bytes_like(second)?);
+        }
+        if let Some(dtb) = dtb {
+            patcher.replace_dtb(bytes_like(dtb)?);
+        }
+        if let Some(recovery_dtbo) = recovery_dtbo {
+            patcher.replace_recovery_dtbo(bytes_like(recovery_dtbo)?);
+        }
+        if let Some(cmdline) = cmdline {
+            patcher.override_cmdline(cmdline.as_bytes());
+        }
+        let data = patcher.patch_to_vec().map_err(to_py)?;
+        Ok(PyBytes::new(py, &data))
+    }
+}
+
+#[pyclass]
+struct Cpio {
+    cpio: RsCpio<'static>,
+}
+
+#[pymethods]
+impl Cpio {
+    // An empty archive without data
+    #[new]
+    #[pyo3(signature = (data = None))]
+    fn new(data: Option<&Bound<'_, PyAny>>) -> PyResult<Self> {
+        let cpio = match data {
+            Some(data) => RsCpio::load_from_data(&bytes_like(data)?)
+                .map_err(to_py)?
+                .into_owned(),
+            None => RsCpio::new(),
+        };
+        Ok(Self { cpio })
+    }
+
+    // Entry names, not UTF-8 ones are decoded lossily
+    fn entries(&self) -> Vec<String> {
+        self.cpio
+            .entries()
+            .keys()
+            .map(|it| String::from_utf8_lossy(it).into_owned())
+            .collect()
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.cpio.exists(path)
+    }
+
+    // Data of a file or the target of a symlink
+    fn read<'py>(&self, py: Python<'py>, path: &str) -> Option<Bound<'py, PyBytes>> {
+        let entry = self.cpio.entry_by_name(path)?;
+        Some(PyBytes::new(py, entry.data().unwrap_or_default()))
+    }
+
+    // Permission and file type bits
+    fn mode(&self, path: &str) -> Option<u32> {
+        self.cpio.entry_by_name(path).map(|it| it.get_mode())
+    }
+
+    #[pyo3(signature = (path, data, mode = 0o644))]
+    fn add(&mut self, path: &str, data: &Bound<'_, PyAny>, mode: u32) -> PyResult<()> {
+        let entry = CpioEntry::regular(mode, bytes_like(data)?);
+        self.cpio.add_with_parents(path, entry).map_err(to_py)
+    }
+
+    #[pyo3(signature = (path, mode = 0o755))]
+    fn mkdir(&mut self, path: &str, mode: u32) -> PyResult<()> {
+        self.cpio.mkdirs(path, mode).map_err(to_py)
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> PyResult<()> {
+        let entry = CpioEntry::symlink(0o777, target);
+        self.cpio.add_with_parents(path, entry).map_err(to_py)
+    }
+
+    #[pyo3(signature = (path, recursive = false))]
+    fn rm(&mut self, path: &str, recursive: bool) {
+        self.cpio.rm(path, recursive);
+    }
+
+    fn mv(&mut self, source: &str, target: &str) -> PyResult<()> {
+        self.cpio.mv(source, target).map_err(to_py)
+    }
+
+    // The uncompressed archive
+    fn dump<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let mut data = Vec::new();
+        self.cpio.dump(&mut data).map_err(to_py)?;
+        Ok(PyBytes::new(py, &data))
+    }
+}
+
+#[pymodule]
+fn _native(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<BootImage>()?;
+    m.add_class::<Cpio>()?;
+    m.add("BootImgError", m.py().get_type::<BootImgError>())?;
+    Ok(())
+}
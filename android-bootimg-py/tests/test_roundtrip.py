@@ -0,0 +1,141 @@
+# Self-contained: the images are packed here with struct, nothing is read
+# from the Rust tests. The roundtrips mirror those of the crate: identity
+# repacks (patcher.rs identity_repack), cpio load and dump (cpio.rs
+# load_dump_roundtrip) and ramdisk replacement (patcher.rs deterministic_gzip).
+
+import gzip
+import hashlib
+import struct
+
+import pytest
+
+from android_bootimg import BootImage, BootImgError, Cpio
+
+PAGE_SIZE = 2048
+KERNEL = b"not really a kernel"
+DTB = b"\xd0\x0d\xfe\xed not really a dtb"
+
+
+def pad(data, page_size=PAGE_SIZE):
+    return data + b"\0" * (-len(data) % page_size)
+
+
+# The id of v0-v2 headers, like mkbootimg
+def image_id(*blocks):
+    sha = hashlib.sha1()
+    for block in blocks:
+        sha.update(block + struct.pack("<I", len(block)))
+    return sha.digest()
+
+
+# A boot image with a gzip compressed cpio ramdisk, laid out like mkbootimg
+def make_image(ramdisk, cmdline=b"", version=0):
+    if version >= 3:
+        header = struct.pack(
+            "<8s4I16sI1536sI",
+            b"ANDROID!",
+            len(KERNEL), len(ramdisk),
+            0, 1584, b"", version, cmdline, 0,
+        )
+        return pad(header, 4096) + pad(KERNEL, 4096) + pad(ramdisk, 4096)
+    blocks = [KERNEL, ramdisk, b""] + [b""] * (version > 0) + [DTB] * (version > 1)
+    header = struct.pack(
+        "<8s10I16s512s32s1024s",
+        b"ANDROID!",
+        len(KERNEL), 0x8000,
+        len(ramdisk), 0x1000000,
+        0, 0xF00000,
+        0x100,
+        PAGE_SIZE,
+        version, 0,
+        b"", cmdline, image_id(*blocks), b"",
+    )
+    if version > 0:
+        header += struct.pack("<IQI", 0, 0, [1648, 1660][version - 1])
+    if version > 1:
+        header += struct.pack("<IQ", len(DTB), 0x11F00000)
+    return b"".join(pad(it) for it in [header] + blocks)
+
+
+def make_ramdisk():
+    cpio = Cpio()
+    cpio.add("init", b"#!/system/bin/sh\n", 0o750)
+    cpio.mkdir("system/etc")
+    cpio.symlink("bin", "system/bin")
+    return cpio.dump()
+
+
+def test_parse():
+    image = BootImage(make_image(gzip.compress(make_ramdisk())))
+    assert image.blocks == ["kernel", "ramdisk"]
+    assert image.block("kernel") == KERNEL
+    assert image.block("second") is None
+    assert image.info()["page_size"] == PAGE_SIZE
+
+
+def test_bytes_like():
+    data = make_image(gzip.compress(make_ramdisk()))
+    assert BootImage(bytearray(data)).blocks == BootImage(memoryview(data)).blocks
+
+
+def test_not_a_boot_image():
+    with pytest.raises(BootImgError):
+        BootImage(b"\0" * PAGE_SIZE)
+
+
+def test_raw_block_is_compressed():
+    ramdisk = gzip.compress(make_ramdisk())
+    image = BootImage(make_image(ramdisk))
+    assert image.block("ramdisk", raw=True) == ramdisk
+    assert image.block("ramdisk") == make_ramdisk()
+
+
+def test_undecodable_block():
+    image = BootImage(make_image(gzip.compress(make_ramdisk())[:-16]))
+    with pytest.raises(BootImgError):
+        image.block("ramdisk")
+
+
+def test_cpio_load_dump_roundtrip():
+    data = make_ramdisk()
+    assert Cpio(data).dump() == data
+
+
+def test_cpio_roundtrip():
+    cpio = Cpio(make_ramdisk())
+    assert cpio.entries() == ["bin", "init", "system", "system/etc"]
+    assert cpio.read("init") == b"#!/system/bin/sh\n"
+    assert cpio.read("bin") == b"system/bin"
+    assert cpio.mode("init") & 0o777 == 0o750
+
+    cpio.mv("init", "init.real")
+    cpio.rm("system", recursive=True)
+    cpio = Cpio(cpio.dump())
+    assert cpio.entries() == ["bin", "init.real"]
+    assert not cpio.exists("init")
+
+
+def test_replace_ramdisk():
+    image = BootImage(make_image(gzip.compress(make_ramdisk())))
+    cpio = Cpio(image.block("ramdisk"))
+    cpio.add("overlay.d/init.custom.rc", b"on boot\n")
+
+    patched = BootImage(image.patch(ramdisk=cpio.dump()))
+    assert patched.block("kernel") == KERNEL
+    # Compressed like the original ramdisk
+    assert patched.block("ramdisk", raw=True)[:2] == b"\x1f\x8b"
+    assert Cpio(patched.block("ramdisk")).read("overlay.d/init.custom.rc") == b"on boot\n"
+
+
+@pytest.mark.parametrize("version", [0, 1, 2, 3, 4])
+def test_identity_repack(version):
+    data = make_image(gzip.compress(make_ramdisk()), b"console=ttyMSM0", version)
+    image = BootImage(data)
+    assert image.info()["header_version"] == version
+    assert image.patch() == data
+
+
+def test_override_cmdline():
+    image = BootImage(make_image(gzip.compress(make_ramdisk()), b"console=ttyMSM0"))
+    patched = BootImage(image.patch(cmdline="androidboot.selinux=permissive"))
+    assert patched.info()["cmdline"] == "androidboot.selinux=permissive"
@@ -0,0 +1,104 @@
+// Patching a vendor boot v4 image with four replaced fragments, which the
+// patcher compresses in parallel, against compressing the same fragments one
+// after another as patch() used to. Run with
+//   cargo bench -p android-bootimg --features testutil --bench vendor_ramdisk
+use android_bootimg::builder::BootImageBuilder;
+use android_bootimg::compress::{CompressFormat, CompressOptions, transcode};
+use android_bootimg::layouts::VendorRamdiskTableEntryType;
+use android_bootimg::parser::BootImage;
+use android_bootimg::parser::BootImageVersion::Vendor;
+use android_bootimg::patcher::BootImagePatchOption;
+use android_bootimg::testutil::{noise, ramdisk};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+const FRAGMENTS: usize = 4;
+const FRAGMENT_SIZE: usize = 4 << 20;
+const RUNS: usize = 5;
+// Max-level lz4 as stock vendor ramdisks
+const FORMAT: CompressFormat = CompressFormat::LZ4_LEGACY;
+
+// Half the entropy of random data, so the compressor has work to do
+fn fragment(seed: u64) -> Vec<u8> {
+    let content = noise(FRAGMENT_SIZE, seed)
+        .iter()
+        .map(|b| b'a' + b % 16)
+        .collect::<Vec<_>>();
+    ramdisk(&[("lib/modules/blob.ko", &content)]).unwrap()
+}
+
+fn image(fragments: &[Vec<u8>]) -> Vec<u8> {
+    let mut builder = BootImageBuilder::new(Vendor(4)).unwrap();
+    builder.compress_options(CompressOptions::fast());
+    for (i, fragment) in fragments.iter().enumerate() {
+        builder
+            .add_vendor_ramdisk(
+                &format!("fragment{}", i),
+                VendorRamdiskTableEntryType::Dlkm,
+                Default::default(),
+                Box::new(Cursor::new(fragment.clone())),
+                FORMAT,
+            )
+            .unwrap();
+    }
+    let mut data = Vec::new();
+    builder.build(&mut data).unwrap();
+    data
+}
+
+fn median(mut f: impl FnMut()) -> Duration {
+    let mut times = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect::<Vec<_>>();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn main() {
+    let fragments = (0..FRAGMENTS as u64).map(fragment).collect::<Vec<_>>();
+    let data = image(&fragments);
+    let source = BootImage::parse(&data).unwrap();
+    let options = CompressOptions::default();
+
+    let serial = median(|| {
+        for fragment in &fragments {
+            let mut out = Vec::new();
+            transcode(
+                CompressFormat::UNKNOWN,
+                FORMAT,
+                fragment.as_slice(),
+                &mut out,
+                &options,
+            )
+            .unwrap();
+        }
+    });
+    let parallel = median(|| {
+        let mut patcher = BootImagePatchOption::new(&source);
+        patcher.compress_options(options);
+        for (i, fragment) in fragments.iter().enumerate() {
+            patcher.replace_vendor_ramdisk(i, Box::new(Cursor::new(fragment.clone())));
+        }
+        patcher.patch_to_vec().unwrap();
+    });
+
+    // The patch can't be faster than the serial loop on a single core
+    let cores = std::thread::available_parallelism().map_or(1, |it| it.get());
+    println!(
+        "{} fragments of {} MiB, {:?}, median of {} runs on {} cores",
+        FRAGMENTS,
+        FRAGMENT_SIZE >> 20,
+        FORMAT,
+        RUNS,
+        cores
+    );
+    println!("serial compression: {:>8.1} ms", serial.as_secs_f64() * 1e3);
+    println!(
+        "parallel patch:     {:>8.1} ms",
+        parallel.as_secs_f64() * 1e3
+    );
+}
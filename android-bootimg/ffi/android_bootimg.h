@@ -0,0 +1,56 @@
+/*
+ * Opaque BootImg handles come from bootimg_parse and are released with
+ * bootimg_free. Functions report failures by returning NULL or false and, when
+ * `error` is not NULL, storing a message to release with bootimg_string_free.
+ * Block names in BootImgBlockSpan live as long as their image.
+ */
+
+#ifndef ANDROID_BOOTIMG_H
+#define ANDROID_BOOTIMG_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct BootImg BootImg;
+
+typedef struct {
+  const char *name;
+  size_t offset;
+  size_t len;
+} BootImgBlockSpan;
+
+typedef struct {
+  const char *block;
+  const uint8_t *data;
+  size_t len;
+} BootImgReplacement;
+
+BootImg *bootimg_parse(const uint8_t *buf, size_t len, char **error);
+
+void bootimg_free(BootImg *image);
+
+void bootimg_string_free(char *string);
+
+char *bootimg_header_field(const BootImg *image, const char *name, char **error);
+
+bool bootimg_header_field_u64(const BootImg *image,
+                              const char *name,
+                              uint64_t *value,
+                              char **error);
+
+size_t bootimg_block_count(const BootImg *image);
+
+bool bootimg_block_at(const BootImg *image, size_t index, BootImgBlockSpan *span);
+
+bool bootimg_find_block(const BootImg *image, const char *name, BootImgBlockSpan *span);
+
+bool bootimg_patch(const BootImg *image,
+                   const BootImgReplacement *replacements,
+                   size_t count,
+                   const char *output,
+                   char **error);
+
+#endif  /* ANDROID_BOOTIMG_H */
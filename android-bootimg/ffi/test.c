@@ -0,0 +1,114 @@
+/* Builds a v0 image, replaces its ramdisk through the C API and checks the result */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "android_bootimg.h"
+
+#define PAGE_SIZE 2048
+
+static const char KERNEL[] = "not really a kernel";
+static const char OLD_RAMDISK[] = "old ramdisk";
+static const char NEW_RAMDISK[] = "a longer replacement ramdisk";
+
+#define CHECK(cond)                                                           \
+    do {                                                                      \
+        if (!(cond)) {                                                        \
+            fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #cond); \
+            exit(1);                                                          \
+        }                                                                     \
+    } while (0)
+
+static void put_u32(uint8_t *p, uint32_t v) {
+    p[0] = v;
+    p[1] = v >> 8;
+    p[2] = v >> 16;
+    p[3] = v >> 24;
+}
+
+/* Header, kernel and ramdisk, one page each */
+static uint8_t *make_image(size_t *len) {
+    uint8_t *image = calloc(3, PAGE_SIZE);
+    memcpy(image, "ANDROID!", 8);
+    put_u32(image + 8, sizeof(KERNEL) - 1);
+    put_u32(image + 16, sizeof(OLD_RAMDISK) - 1);
+    put_u32(image + 36, PAGE_SIZE);
+    memcpy(image + PAGE_SIZE, KERNEL, sizeof(KERNEL) - 1);
+    memcpy(image + 2 * PAGE_SIZE, OLD_RAMDISK, sizeof(OLD_RAMDISK) - 1);
+    *len = 3 * PAGE_SIZE;
+    return image;
+}
+
+static uint8_t *read_file(const char *path, size_t *len) {
+    FILE *f = fopen(path, "rb");
+    CHECK(f != NULL);
+    fseek(f, 0, SEEK_END);
+    *len = ftell(f);
+    fseek(f, 0, SEEK_SET);
+    uint8_t *data = malloc(*len);
+    CHECK(fread(data, 1, *len, f) == *len);
+    fclose(f);
+    return data;
+}
+
+static BootImg *parse(const uint8_t *data, size_t len) {
+    char *error = NULL;
+    BootImg *image = bootimg_parse(data, len, &error);
+    if (image == NULL) {
+        fprintf(stderr, "parse: %s\n", error);
+        bootimg_string_free(error);
+        exit(1);
+    }
+    return image;
+}
+
+int main(int argc, char **argv) {
+    const char *output = argc > 1 ? argv[1] : "patched.img";
+    char *error = NULL;
+
+    size_t len;
+    uint8_t *data = make_image(&len);
+    BootImg *image = parse(data, len);
+    free(data);
+
+    uint64_t page_size = 0;
+    CHECK(bootimg_header_field_u64(image, "page_size", &page_size, &error));
+    CHECK(page_size == PAGE_SIZE);
+    CHECK(!bootimg_header_field_u64(image, "no_such_field", &page_size, &error));
+    CHECK(error != NULL);
+    bootimg_string_free(error);
+    error = NULL;
+
+    BootImgBlockSpan span;
+    CHECK(bootimg_block_count(image) == 2);
+    CHECK(bootimg_block_at(image, 0, &span));
+    CHECK(strcmp(span.name, "kernel") == 0);
+    CHECK(bootimg_find_block(image, "ramdisk", &span));
+    CHECK(span.offset == 2 * PAGE_SIZE && span.len == sizeof(OLD_RAMDISK) - 1);
+
+    BootImgReplacement ramdisk = {
+        .block = "ramdisk",
+        .data = (const uint8_t *)NEW_RAMDISK,
+        .len = sizeof(NEW_RAMDISK) - 1,
+    };
+    if (!bootimg_patch(image, &ramdisk, 1, output, &error)) {
+        fprintf(stderr, "patch: %s\n", error);
+        bootimg_string_free(error);
+        return 1;
+    }
+    bootimg_free(image);
+
+    data = read_file(output, &len);
+    image = parse(data, len);
+    CHECK(bootimg_find_block(image, "ramdisk", &span));
+    CHECK(span.len == sizeof(NEW_RAMDISK) - 1);
+    CHECK(memcmp(data + span.offset, NEW_RAMDISK, span.len) == 0);
+    CHECK(bootimg_find_block(image, "kernel", &span));
+    CHECK(memcmp(data + span.offset, KERNEL, span.len) == 0);
+    bootimg_free(image);
+    free(data);
+
+    printf("ok\n");
+    return 0;
+}
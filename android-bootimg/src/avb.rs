@@ -0,0 +1,794 @@
+use crate::constants::AVB_MAGIC;
+use crate::error::{BootImgError, Result};
+use crate::layouts::{
+    AVB_HEADER_SIZE, AvbChainPartitionDescriptorHeader, AvbHashDescriptorHeader,
+    AvbHashtreeDescriptorHeader, AvbKernelCmdlineDescriptorHeader, AvbPropertyDescriptorHeader,
+    AvbRSAPublicKeyHeader, AvbVBMetaBlocks, AvbVBMetaImageHeader,
+};
+use crate::parser::BootImage;
+use crate::utils::{SliceExt, align_to, trim_end};
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{Read, Write};
+
+// https://android.googlesource.com/platform/external/avb/+/refs/heads/main/libavb/avb_vbmeta_image.h
+pub const AVB_VBMETA_IMAGE_FLAGS_HASHTREE_DISABLED: u32 = 1 << 0;
+pub const AVB_VBMETA_IMAGE_FLAGS_VERIFICATION_DISABLED: u32 = 1 << 1;
+
+// tag and num_bytes_following
+const AVB_DESCRIPTOR_HEADER_SIZE: usize = 16;
+// Authentication and auxiliary blocks are padded to this
+const AVB_BLOCK_ALIGN: u64 = 64;
+const AVB_DESCRIPTOR_ALIGN: usize = 8;
+
+pub(crate) const AVB_RELEASE_STRING: &str = concat!("android-bootimg ", env!("CARGO_PKG_VERSION"));
+
+const AVB_DESCRIPTOR_TAG_PROPERTY: u64 = 0;
+const AVB_DESCRIPTOR_TAG_HASHTREE: u64 = 1;
+const AVB_DESCRIPTOR_TAG_HASH: u64 = 2;
+const AVB_DESCRIPTOR_TAG_KERNEL_CMDLINE: u64 = 3;
+const AVB_DESCRIPTOR_TAG_CHAIN_PARTITION: u64 = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AvbAlgorithm {
+    None = 0,
+    Sha256Rsa2048,
+    Sha256Rsa4096,
+    Sha256Rsa8192,
+    Sha512Rsa2048,
+    Sha512Rsa4096,
+    Sha512Rsa8192,
+}
+
+impl AvbAlgorithm {
+    pub fn from_raw(algorithm_type: u32) -> Option<Self> {
+        Some(match algorithm_type {
+            0 => AvbAlgorithm::None,
+            1 => AvbAlgorithm::Sha256Rsa2048,
+            2 => AvbAlgorithm::Sha256Rsa4096,
+            3 => AvbAlgorithm::Sha256Rsa8192,
+            4 => AvbAlgorithm::Sha512Rsa2048,
+            5 => AvbAlgorithm::Sha512Rsa4096,
+            6 => AvbAlgorithm::Sha512Rsa8192,
+            _ => return None,
+        })
+    }
+
+    // Same names as avbtool
+    pub fn name(&self) -> &'static str {
+        match self {
+            AvbAlgorithm::None => "NONE",
+            AvbAlgorithm::Sha256Rsa2048 => "SHA256_RSA2048",
+            AvbAlgorithm::Sha256Rsa4096 => "SHA256_RSA4096",
+            AvbAlgorithm::Sha256Rsa8192 => "SHA256_RSA8192",
+            AvbAlgorithm::Sha512Rsa2048 => "SHA512_RSA2048",
+            AvbAlgorithm::Sha512Rsa4096 => "SHA512_RSA4096",
+            AvbAlgorithm::Sha512Rsa8192 => "SHA512_RSA8192",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..=6)
+            .filter_map(Self::from_raw)
+            .find(|it| it.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn to_raw(&self) -> u32 {
+        *self as u32
+    }
+
+    // Also the algorithm name used in hash descriptors
+    pub fn hash_algorithm(&self) -> Option<&'static str> {
+        match self {
+            AvbAlgorithm::None => None,
+            AvbAlgorithm::Sha256Rsa2048
+            | AvbAlgorithm::Sha256Rsa4096
+            | AvbAlgorithm::Sha256Rsa8192 => Some("sha256"),
+            AvbAlgorithm::Sha512Rsa2048
+            | AvbAlgorithm::Sha512Rsa4096
+            | AvbAlgorithm::Sha512Rsa8192 => Some("sha512"),
+        }
+    }
+
+    pub fn key_bits(&self) -> u32 {
+        match self {
+            AvbAlgorithm::None => 0,
+            AvbAlgorithm::Sha256Rsa2048 | AvbAlgorithm::Sha512Rsa2048 => 2048,
+            AvbAlgorithm::Sha256Rsa4096 | AvbAlgorithm::Sha512Rsa4096 => 4096,
+            AvbAlgorithm::Sha256Rsa8192 | AvbAlgorithm::Sha512Rsa8192 => 8192,
+        }
+    }
+
+    pub(crate) fn hash_size(&self) -> usize {
+        match self.hash_algorithm() {
+            Some("sha256") => 32,
+            Some(_) => 64,
+            None => 0,
+        }
+    }
+
+    pub(crate) fn signature_size(&self) -> usize {
+        self.key_bits() as usize / 8
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RawDescriptor<'a> {
+    pub tag: u64,
+    // Everything after num_bytes_following
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub struct HashDescriptor<'a> {
+    pub image_size: u64,
+    pub hash_algorithm: String,
+    pub partition_name: String,
+    pub salt: &'a [u8],
+    pub digest: &'a [u8],
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct HashtreeDescriptor<'a> {
+    pub dm_verity_version: u32,
+    pub image_size: u64,
+    pub tree_offset: u64,
+    pub tree_size: u64,
+    pub data_block_size: u32,
+    pub hash_block_size: u32,
+    pub fec_num_roots: u32,
+    pub fec_offset: u64,
+    pub fec_size: u64,
+    pub hash_algorithm: String,
+    pub partition_name: String,
+    pub salt: &'a [u8],
+    pub root_digest: &'a [u8],
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainPartitionDescriptor<'a> {
+    pub rollback_index_location: u32,
+    pub partition_name: String,
+    pub public_key: &'a [u8],
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PropertyDescriptor<'a> {
+    pub key: String,
+    pub value: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub struct KernelCmdlineDescriptor {
+    pub flags: u32,
+    pub cmdline: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum AvbDescriptor<'a> {
+    Property(PropertyDescriptor<'a>),
+    Hashtree(HashtreeDescriptor<'a>),
+    Hash(HashDescriptor<'a>),
+    KernelCmdline(KernelCmdlineDescriptor),
+    ChainPartition(ChainPartitionDescriptor<'a>),
+    // Skipped by its recorded size
+    Unknown(RawDescriptor<'a>),
+}
+
+// AvbRSAPublicKeyHeader followed by n and rr, as embedded in the auxiliary block
+// and in chain partition descriptors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AvbPublicKey<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> AvbPublicKey<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let header = data
+            .get(..AvbRSAPublicKeyHeader::SIZE)
+            .map(|data| AvbRSAPublicKeyHeader { data })
+            .ok_or_else(|| invalid(format!("truncated public key: {} bytes", data.len())))?;
+        let bits = header.get_key_num_bits() as usize;
+        if bits == 0
+            || !bits.is_multiple_of(8)
+            || AvbRSAPublicKeyHeader::SIZE + bits / 8 * 2 > data.len()
+        {
+            return Err(invalid(format!(
+                "public key of {} bits does not fit in {} bytes",
+                bits,
+                data.len()
+            )));
+        }
+        Ok(Self { data })
+    }
+
+    pub fn get_data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn header(&self) -> AvbRSAPublicKeyHeader<'a> {
+        AvbRSAPublicKeyHeader {
+            data: &self.data[..AvbRSAPublicKeyHeader::SIZE],
+        }
+    }
+
+    pub fn key_bits(&self) -> u32 {
+        self.header().get_key_num_bits()
+    }
+
+    // Big endian
+    pub fn modulus(&self) -> &'a [u8] {
+        let start = AvbRSAPublicKeyHeader::SIZE;
+        &self.data[start..start + self.key_bits() as usize / 8]
+    }
+
+    // SHA-256 of the encoded key, comparable across images signed with the same key
+    pub fn fingerprint(&self) -> [u8; 32] {
+        Sha256::digest(self.data).into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvbDigests {
+    pub hash_algorithm: String,
+    // From the hash descriptor
+    pub expected: Vec<u8>,
+    // Over salt and the payload covered by the footer
+    pub computed: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AvbHashStatus {
+    Matched(AvbDigests),
+    Mismatched(AvbDigests),
+    // No footer or no hash descriptor
+    NotApplicable,
+}
+
+// Digest of a hash descriptor, algorithm names as written by avbtool
+pub(crate) fn hash_digest(
+    hash_algorithm: &str,
+    salt: &[u8],
+    payload: &mut dyn Read,
+) -> Result<Vec<u8>> {
+    macro_rules! digest {
+        ($t:ty) => {{
+            let mut hasher = <$t>::new();
+            hasher.update(salt);
+            std::io::copy(payload, &mut hasher)?;
+            hasher.finalize().to_vec()
+        }};
+    }
+    Ok(match hash_algorithm {
+        "sha256" => digest!(Sha256),
+        "sha512" => digest!(Sha512),
+        _ => {
+            return Err(invalid(format!(
+                "unsupported hash algorithm {}",
+                hash_algorithm
+            )));
+        }
+    })
+}
+
+// num_bytes_following is padded to 8
+fn encode_descriptor(tag: u64, data: &[u8]) -> Vec<u8> {
+    let len = align_to(data.len(), AVB_DESCRIPTOR_ALIGN);
+    let mut v = Vec::with_capacity(AVB_DESCRIPTOR_HEADER_SIZE + len);
+    v.extend_from_slice(&tag.to_be_bytes());
+    v.extend_from_slice(&(len as u64).to_be_bytes());
+    v.extend_from_slice(data);
+    v.resize(AVB_DESCRIPTOR_HEADER_SIZE + len, 0);
+    v
+}
+
+pub(crate) fn encode_hash_descriptor(
+    partition_name: &str,
+    image_size: u64,
+    hash_algorithm: &str,
+    salt: &[u8],
+    digest: &[u8],
+) -> Vec<u8> {
+    let mut data = AvbHashDescriptorHeader::create(
+        image_size,
+        hash_algorithm,
+        partition_name.len() as u32,
+        salt.len() as u32,
+        digest.len() as u32,
+    );
+    data.extend_from_slice(partition_name.as_bytes());
+    data.extend_from_slice(salt);
+    data.extend_from_slice(digest);
+    encode_descriptor(AVB_DESCRIPTOR_TAG_HASH, &data)
+}
+
+// Lays out header, authentication and auxiliary blocks like avbtool. The
+// authentication block is left zeroed for the signer to fill in.
+pub(crate) fn build_vbmeta(
+    header: &AvbVBMetaImageHeader,
+    descriptors: &[u8],
+    algorithm: AvbAlgorithm,
+    public_key: &[u8],
+) -> Vec<u8> {
+    let hash_size = algorithm.hash_size() as u64;
+    let signature_size = algorithm.signature_size() as u64;
+    let aux_size = align_to(
+        (descriptors.len() + public_key.len()) as u64,
+        AVB_BLOCK_ALIGN,
+    );
+    let blocks = AvbVBMetaBlocks {
+        authentication_data_block_size: align_to(hash_size + signature_size, AVB_BLOCK_ALIGN),
+        auxiliary_data_block_size: aux_size,
+        algorithm_type: algorithm.to_raw(),
+        hash_offset: 0,
+        hash_size,
+        signature_offset: hash_size,
+        signature_size,
+        public_key_offset: descriptors.len() as u64,
+        public_key_size: public_key.len() as u64,
+        public_key_metadata_offset: (descriptors.len() + public_key.len()) as u64,
+        public_key_metadata_size: 0,
+        descriptors_offset: 0,
+        descriptors_size: descriptors.len() as u64,
+    };
+    let mut v = header.patch_blocks(&blocks);
+    v.resize(
+        AVB_HEADER_SIZE + blocks.authentication_data_block_size as usize,
+        0,
+    );
+    v.extend_from_slice(descriptors);
+    v.extend_from_slice(public_key);
+    v.resize(
+        AVB_HEADER_SIZE + (blocks.authentication_data_block_size + aux_size) as usize,
+        0,
+    );
+    v
+}
+
+// Splits off the next `len` bytes of the variable part of a descriptor
+fn take<'a>(data: &mut &'a [u8], len: u64, what: &str) -> Result<&'a [u8]> {
+    if len > data.len() as u64 {
+        return Err(invalid(format!(
+            "descriptor {} size {} exceeds {} bytes",
+            what,
+            len,
+            data.len()
+        )));
+    }
+    let (head, tail) = data.split_at(len as usize);
+    *data = tail;
+    Ok(head)
+}
+
+fn lossy(data: &[u8]) -> String {
+    String::from_utf8_lossy(trim_end(data)).into_owned()
+}
+
+impl<'a> RawDescriptor<'a> {
+    pub fn decode(&self) -> Result<AvbDescriptor<'a>> {
+        macro_rules! fixed_header {
+            ($t:ident) => {{
+                let data = self.data.get(..$t::SIZE).ok_or_else(|| {
+                    invalid(format!(
+                        "descriptor with tag {} too short: {} bytes",
+                        self.tag,
+                        self.data.len()
+                    ))
+                })?;
+                ($t { data }, &self.data[$t::SIZE..])
+            }};
+        }
+
+        Ok(match self.tag {
+            AVB_DESCRIPTOR_TAG_PROPERTY => {
+                let (header, mut rest) = fixed_header!(AvbPropertyDescriptorHeader);
+                let key = take(&mut rest, header.get_key_num_bytes(), "key")?;
+                // Both are followed by a NUL
+                take(&mut rest, 1, "key")?;
+                let value = take(&mut rest, header.get_value_num_bytes(), "value")?;
+                AvbDescriptor::Property(PropertyDescriptor {
+                    key: lossy(key),
+                    value,
+                })
+            }
+            AVB_DESCRIPTOR_TAG_HASHTREE => {
+                let (header, mut rest) = fixed_header!(AvbHashtreeDescriptorHeader);
+                let partition_name =
+                    take(&mut rest, header.get_partition_name_len() as u64, "name")?;
+                let salt = take(&mut rest, header.get_salt_len() as u64, "salt")?;
+                let root_digest = take(
+                    &mut rest,
+                    header.get_root_digest_len() as u64,
+                    "root digest",
+                )?;
+                AvbDescriptor::Hashtree(HashtreeDescriptor {
+                    dm_verity_version: header.get_dm_verity_version(),
+                    image_size: header.get_image_size(),
+                    tree_offset: header.get_tree_offset(),
+                    tree_size: header.get_tree_size(),
+                    data_block_size: header.get_data_block_size(),
+                    hash_block_size: header.get_hash_block_size(),
+                    fec_num_roots: header.get_fec_num_roots(),
+                    fec_offset: header.get_fec_offset(),
+                    fec_size: header.get_fec_size(),
+                    hash_algorithm: lossy(header.get_hash_algorithm()),
+                    partition_name: lossy(partition_name),
+                    salt,
+                    root_digest,
+                    flags: header.get_flags(),
+                })
+            }
+            AVB_DESCRIPTOR_TAG_HASH => {
+                let (header, mut rest) = fixed_header!(AvbHashDescriptorHeader);
+                let partition_name =
+                    take(&mut rest, header.get_partition_name_len() as u64, "name")?;
+                let salt = take(&mut rest, header.get_salt_len() as u64, "salt")?;
+                let digest = take(&mut rest, header.get_digest_len() as u64, "digest")?;
+                AvbDescriptor::Hash(HashDescriptor {
+                    image_size: header.get_image_size(),
+                    hash_algorithm: lossy(header.get_hash_algorithm()),
+                    partition_name: lossy(partition_name),
+                    salt,
+                    digest,
+                    flags: header.get_flags(),
+                })
+            }
+            AVB_DESCRIPTOR_TAG_KERNEL_CMDLINE => {
+                let (header, mut rest) = fixed_header!(AvbKernelCmdlineDescriptorHeader);
+                let cmdline = take(
+                    &mut rest,
+                    header.get_kernel_cmdline_length() as u64,
+                    "cmdline",
+                )?;
+                AvbDescriptor::KernelCmdline(KernelCmdlineDescriptor {
+                    flags: header.get_flags(),
+                    cmdline: lossy(cmdline),
+                })
+            }
+            AVB_DESCRIPTOR_TAG_CHAIN_PARTITION => {
+                let (header, mut rest) = fixed_header!(AvbChainPartitionDescriptorHeader);
+                let partition_name =
+                    take(&mut rest, header.get_partition_name_len() as u64, "name")?;
+                let public_key = take(&mut rest, header.get_public_key_len() as u64, "public key")?;
+                AvbDescriptor::ChainPartition(ChainPartitionDescriptor {
+                    rollback_index_location: header.get_rollback_index_location(),
+                    partition_name: lossy(partition_name),
+                    public_key,
+                    flags: header.get_flags(),
+                })
+            }
+            _ => AvbDescriptor::Unknown(*self),
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct VbMetaImage<'a> {
+    // Header, authentication and auxiliary blocks
+    data: &'a [u8],
+    // Everything passed to parse, vbmeta partitions are padded
+    raw: &'a [u8],
+}
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidVbMeta(msg)
+}
+
+fn no_footer() -> BootImgError {
+    BootImgError::InvalidAvbFooter("image has no avb footer".to_string())
+}
+
+impl<'a> VbMetaImage<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if !data.starts_with(AVB_MAGIC) {
+            return Err(invalid("bad magic".to_string()));
+        }
+        if data.len() < AVB_HEADER_SIZE {
+            return Err(invalid(format!("truncated header: {} bytes", data.len())));
+        }
+        let header = AvbVBMetaImageHeader {
+            data: &data[..AVB_HEADER_SIZE],
+        };
+        let auth_size = header.get_authentication_data_block_size();
+        let aux_size = header.get_auxiliary_data_block_size();
+        let size = (AVB_HEADER_SIZE as u64)
+            .checked_add(auth_size)
+            .and_then(|it| it.checked_add(aux_size))
+            .filter(|it| *it <= data.len() as u64)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "authentication block {} and auxiliary block {} exceed {} bytes",
+                    auth_size,
+                    aux_size,
+                    data.len()
+                ))
+            })?;
+        Ok(Self {
+            data: &data[..size as usize],
+            raw: data,
+        })
+    }
+
+    pub fn header(&self) -> AvbVBMetaImageHeader<'a> {
+        AvbVBMetaImageHeader {
+            data: &self.data[..AVB_HEADER_SIZE],
+        }
+    }
+
+    pub fn get_data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn authentication_block(&self) -> &'a [u8] {
+        let size = self.header().get_authentication_data_block_size() as usize;
+        &self.data[AVB_HEADER_SIZE..AVB_HEADER_SIZE + size]
+    }
+
+    pub fn auxiliary_block(&self) -> &'a [u8] {
+        let start = AVB_HEADER_SIZE + self.authentication_block().len();
+        &self.data[start..]
+    }
+
+    pub fn algorithm_type(&self) -> u32 {
+        self.header().get_algorithm_type()
+    }
+
+    pub fn algorithm(&self) -> Option<AvbAlgorithm> {
+        AvbAlgorithm::from_raw(self.algorithm_type())
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.header().get_flags()
+    }
+
+    pub fn rollback_index(&self) -> u64 {
+        self.header().get_rollback_index()
+    }
+
+    pub fn release_string(&self) -> String {
+        String::from_utf8_lossy(trim_end(self.header().get_release_string())).into_owned()
+    }
+
+    // Slice of the auxiliary block at a header provided offset and size
+    pub(crate) fn aux_range(&self, offset: u64, size: u64, what: &str) -> Result<&'a [u8]> {
+        let aux = self.auxiliary_block();
+        offset
+            .checked_add(size)
+            .filter(|end| *end <= aux.len() as u64)
+            .map(|end| &aux[offset as usize..end as usize])
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{} off {} size {} out of auxiliary block",
+                    what, offset, size
+                ))
+            })
+    }
+
+    // None for unsigned images
+    pub fn public_key(&self) -> Result<Option<AvbPublicKey<'a>>> {
+        let header = self.header();
+        let size = header.get_public_key_size();
+        if size == 0 {
+            return Ok(None);
+        }
+        let data = self.aux_range(header.get_public_key_offset(), size, "public key")?;
+        AvbPublicKey::parse(data).map(Some)
+    }
+
+    pub fn raw_descriptors(&self) -> Result<Vec<RawDescriptor<'a>>> {
+        let header = self.header();
+        let mut data = self.aux_range(
+            header.get_descriptors_offset(),
+            header.get_descriptors_size(),
+            "descriptors",
+        )?;
+        let mut descriptors = Vec::new();
+        while !data.is_empty() {
+            let (Some(tag), Some(len)) = (data.u64_be_at(0), data.u64_be_at(8)) else {
+                return Err(invalid("truncated descriptor header".to_string()));
+            };
+            let end = (len as usize)
+                .checked_add(AVB_DESCRIPTOR_HEADER_SIZE)
+                .filter(|end| *end <= data.len())
+                .ok_or_else(|| invalid(format!("descriptor size {} out of range", len)))?;
+            descriptors.push(RawDescriptor {
+                tag,
+                data: &data[AVB_DESCRIPTOR_HEADER_SIZE..end],
+            });
+            data = &data[end..];
+        }
+        Ok(descriptors)
+    }
+
+    // Chained vbmeta may carry descriptors of other partitions, prefer the one
+    // covering payload_size
+    pub(crate) fn find_hash_descriptor(
+        &self,
+        payload_size: u64,
+    ) -> Result<Option<(usize, HashDescriptor<'a>)>> {
+        let hashes = self
+            .descriptors()?
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, it)| match it {
+                AvbDescriptor::Hash(hash) => Some((index, hash)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let index = hashes
+            .iter()
+            .position(|(_, it)| it.image_size == payload_size)
+            .unwrap_or(0);
+        Ok(hashes.into_iter().nth(index))
+    }
+
+    // New vbmeta for a rewritten payload: the hash descriptor covering the
+    // `original_size` bytes of the source gets the new size and digest, the
+    // blocks are laid out again for the algorithm and key
+    pub(crate) fn rehash(
+        &self,
+        original_size: u64,
+        payload_size: u64,
+        payload: &mut dyn Read,
+        algorithm: AvbAlgorithm,
+        public_key: &[u8],
+    ) -> Result<Vec<u8>> {
+        let Some((index, hash)) = self.find_hash_descriptor(original_size)? else {
+            return Err(invalid("no hash descriptor".to_string()));
+        };
+        let digest = hash_digest(&hash.hash_algorithm, hash.salt, payload)?;
+        if digest.len() != hash.digest.len() {
+            return Err(invalid(format!(
+                "digest size {} does not match descriptor {}",
+                digest.len(),
+                hash.digest.len()
+            )));
+        }
+
+        let mut descriptors = Vec::new();
+        for (i, raw) in self.raw_descriptors()?.iter().enumerate() {
+            if i != index {
+                descriptors.extend(encode_descriptor(raw.tag, raw.data));
+                continue;
+            }
+            let header = AvbHashDescriptorHeader {
+                data: &raw.data[..AvbHashDescriptorHeader::SIZE],
+            };
+            let mut data = header.patch_image_size(payload_size);
+            data.extend_from_slice(&raw.data[AvbHashDescriptorHeader::SIZE..]);
+            // Digest follows partition name and salt
+            let digest_off = AvbHashDescriptorHeader::SIZE
+                + header.get_partition_name_len() as usize
+                + header.get_salt_len() as usize;
+            data[digest_off..digest_off + digest.len()].copy_from_slice(&digest);
+            descriptors.extend(encode_descriptor(raw.tag, &data));
+        }
+
+        Ok(build_vbmeta(
+            &self.header(),
+            &descriptors,
+            algorithm,
+            public_key,
+        ))
+    }
+
+    pub fn descriptors(&self) -> Result<Vec<AvbDescriptor<'a>>> {
+        self.raw_descriptors()?
+            .iter()
+            .map(RawDescriptor::decode)
+            .collect()
+    }
+
+    // Copies the image with the flags replaced, e.g. 3 to disable verity and verification.
+    // The signature is left alone since the bootloader ignores it once unlocked.
+    pub fn patch_flags(&self, output: &mut dyn Write, flags: u32) -> Result<()> {
+        output.write_all(&self.header().patch_flags(flags))?;
+        output.write_all(&self.raw[AVB_HEADER_SIZE..])?;
+        Ok(())
+    }
+}
+
+impl BootImage<'_> {
+    // Recomputes the hash descriptor digest to tell whether the payload was modified
+    pub fn verify_avb(&self) -> Result<AvbHashStatus> {
+        let (Some(avb_info), Some(vbmeta)) = (&self.avb_info, self.get_vbmeta()?) else {
+            return Ok(AvbHashStatus::NotApplicable);
+        };
+        let payload_size = avb_info.avb_footer.get_original_image_size();
+        let Some((_, hash)) = vbmeta.find_hash_descriptor(payload_size)? else {
+            return Ok(AvbHashStatus::NotApplicable);
+        };
+        let payload = self.data.get(..hash.image_size as usize).ok_or_else(|| {
+            invalid(format!(
+                "hash descriptor image size {} exceeds {} bytes",
+                hash.image_size,
+                self.data.len()
+            ))
+        })?;
+        let digests = AvbDigests {
+            hash_algorithm: hash.hash_algorithm.clone(),
+            expected: hash.digest.to_vec(),
+            computed: hash_digest(&hash.hash_algorithm, hash.salt, &mut &payload[..])?,
+        };
+        Ok(if digests.expected == digests.computed {
+            AvbHashStatus::Matched(digests)
+        } else {
+            AvbHashStatus::Mismatched(digests)
+        })
+    }
+
+    // Writes the payload without vbmeta and footer, as `avbtool erase_footer`
+    pub fn strip_avb(&self, output: &mut dyn Write) -> Result<()> {
+        let avb_info = self.avb_info.as_ref().ok_or_else(no_footer)?;
+        let size = avb_info.avb_footer.get_original_image_size() as usize;
+        output.write_all(&self.data[..size])?;
+        Ok(())
+    }
+
+    // Copies the image with the flags of the footer's vbmeta replaced, see VbMetaImage::patch_flags
+    pub fn patch_avb_flags(&self, output: &mut dyn Write, flags: u32) -> Result<()> {
+        let avb_info = self.avb_info.as_ref().ok_or_else(no_footer)?;
+        let vbmeta = VbMetaImage::parse(avb_info.avb_header)?;
+        let off = avb_info.avb_footer.get_vbmeta_offset() as usize;
+        output.write_all(&self.data[..off])?;
+        output.write_all(&vbmeta.header().patch_flags(flags))?;
+        output.write_all(&self.data[off + AVB_HEADER_SIZE..])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_descriptors(vbmeta: &VbMetaImage) -> Vec<(String, u64, Vec<u8>)> {
+        vbmeta
+            .descriptors()
+            .unwrap()
+            .into_iter()
+            .filter_map(|it| match it {
+                AvbDescriptor::Hash(hash) => {
+                    Some((hash.partition_name, hash.image_size, hash.digest.to_vec()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rehash_picks_descriptor_of_source_payload() {
+        let salt = [1u8; 32];
+        let mut descriptors = encode_hash_descriptor("dtbo", 8192, "sha256", &salt, &[0xaa; 32]);
+        descriptors.extend(encode_hash_descriptor(
+            "boot",
+            4096,
+            "sha256",
+            &salt,
+            &[0xbb; 32],
+        ));
+        let header = AvbVBMetaImageHeader::create(AVB_RELEASE_STRING);
+        let data = build_vbmeta(
+            &AvbVBMetaImageHeader { data: &header },
+            &descriptors,
+            AvbAlgorithm::None,
+            &[],
+        );
+        let vbmeta = VbMetaImage::parse(&data).unwrap();
+
+        let payload = vec![0x5a; 12288];
+        let rehashed = vbmeta
+            .rehash(4096, 12288, &mut &payload[..], AvbAlgorithm::None, &[])
+            .unwrap();
+        let rehashed = VbMetaImage::parse(&rehashed).unwrap();
+        let digest = hash_digest("sha256", &salt, &mut &payload[..]).unwrap();
+        assert_eq!(
+            hash_descriptors(&rehashed),
+            [
+                ("dtbo".to_string(), 8192, vec![0xaa; 32]),
+                ("boot".to_string(), 12288, digest),
+            ]
+        );
+    }
+}
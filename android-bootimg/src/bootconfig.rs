@@ -0,0 +1,526 @@
+use crate::error::{BootImgError, Result};
+use crate::utils::SliceExt;
+use log::warn;
+
+// https://www.kernel.org/doc/html/latest/admin-guide/bootconfig.html
+pub const BOOTCONFIG_MAGIC: &[u8] = b"#BOOTCONFIG\n";
+// size (u32), checksum (u32), magic
+pub const BOOTCONFIG_TRAILER_SIZE: usize = 8 + BOOTCONFIG_MAGIC.len();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootconfigParam {
+    pub key: String,
+    // More than one value for arrays, empty for a bare key
+    pub values: Vec<String>,
+}
+
+impl BootconfigParam {
+    pub fn value(&self) -> &str {
+        self.values.first().map(String::as_str).unwrap_or("")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bootconfig<'a> {
+    // Serialized as-is until modified
+    raw: Option<&'a [u8]>,
+    params: Vec<BootconfigParam>,
+    has_trailer: bool,
+    checksum_valid: bool,
+}
+
+pub fn bootconfig_checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32))
+}
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidBootconfig(msg)
+}
+
+fn is_key_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.'
+}
+
+struct BootconfigParser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    // Full keys of the enclosing `key { ... }` blocks
+    prefix: Vec<String>,
+    params: Vec<BootconfigParam>,
+}
+
+impl BootconfigParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    // Spaces, newlines and comments
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ' | b'\t' | b'\r' | b'\n' | b'\0') => self.pos += 1,
+                Some(b'#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            if b == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn error(&self, msg: &str) -> BootImgError {
+        invalid(format!("{} at {}", msg, self.pos))
+    }
+
+    fn key(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_key_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected key"));
+        }
+        let key = String::from_utf8_lossy(&self.data[start..self.pos]);
+        Ok(match self.prefix.last() {
+            Some(parent) => format!("{}.{}", parent, key),
+            None => key.into_owned(),
+        })
+    }
+
+    fn value(&mut self) -> Result<String> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(q @ (b'"' | b'\'')) => {
+                self.pos += 1;
+                let start = self.pos;
+                let len = self.data[start..]
+                    .iter()
+                    .position(|&b| b == q)
+                    .ok_or_else(|| self.error("unterminated quote"))?;
+                self.pos += len + 1;
+                Ok(String::from_utf8_lossy(&self.data[start..start + len]).into_owned())
+            }
+            _ => {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|b| !matches!(b, b',' | b';' | b'\n' | b'#' | b'}'))
+                {
+                    self.pos += 1;
+                }
+                let value = String::from_utf8_lossy(&self.data[start..self.pos]);
+                Ok(value.trim().to_string())
+            }
+        }
+    }
+
+    fn values(&mut self) -> Result<Vec<String>> {
+        let mut values = vec![self.value()?];
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some(b',') => {
+                    self.pos += 1;
+                    // Arrays may continue on the next line
+                    self.skip_blank();
+                    values.push(self.value()?);
+                }
+                Some(b';' | b'\n') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'#') => {
+                    self.skip_comment();
+                    break;
+                }
+                Some(b'}') | None => break,
+                Some(_) => return Err(self.error("unexpected character after value")),
+            }
+        }
+        Ok(values)
+    }
+
+    fn add(&mut self, key: String, values: Vec<String>, append: bool) {
+        if let Some(param) = self.params.iter_mut().find(|p| p.key == key) {
+            if append {
+                param.values.extend(values);
+            } else {
+                param.values = values;
+            }
+        } else {
+            self.params.push(BootconfigParam { key, values });
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<BootconfigParam>> {
+        loop {
+            self.skip_blank();
+            let Some(b) = self.peek() else {
+                break;
+            };
+            if b == b'}' {
+                if self.prefix.pop().is_none() {
+                    return Err(self.error("unbalanced }"));
+                }
+                self.pos += 1;
+                continue;
+            }
+
+            let key = self.key()?;
+            self.skip_spaces();
+            match self.peek() {
+                Some(b'=') => {
+                    self.pos += 1;
+                    let values = self.values()?;
+                    self.add(key, values, false);
+                }
+                Some(op @ (b'+' | b':')) if self.data.get(self.pos + 1) == Some(&b'=') => {
+                    self.pos += 2;
+                    let values = self.values()?;
+                    self.add(key, values, op == b'+');
+                }
+                Some(b'{') => {
+                    self.pos += 1;
+                    self.prefix.push(key);
+                }
+                Some(b';' | b'\n') | None => {
+                    self.pos += 1;
+                    self.add(key, Vec::new(), false);
+                }
+                Some(b'#') => {
+                    self.skip_comment();
+                    self.add(key, Vec::new(), false);
+                }
+                Some(b'}') => self.add(key, Vec::new(), false),
+                Some(_) => return Err(self.error("unexpected character after key")),
+            }
+        }
+        if !self.prefix.is_empty() {
+            return Err(self.error("missing }"));
+        }
+        Ok(self.params)
+    }
+}
+
+impl<'a> Bootconfig<'a> {
+    // The trailer is optional: vendor_boot stores the bare parameters and the
+    // bootloader appends the trailer when loading them.
+    pub fn parse(data: &'a [u8], lenient: bool) -> Result<Self> {
+        let mut body = data;
+        let mut has_trailer = false;
+        let mut checksum_valid = true;
+        if data.ends_with(BOOTCONFIG_MAGIC) && data.len() >= BOOTCONFIG_TRAILER_SIZE {
+            let trailer = data.len() - BOOTCONFIG_TRAILER_SIZE;
+            let size = data.u32_at(trailer).unwrap() as usize;
+            let checksum = data.u32_at(trailer + 4).unwrap();
+            if size > trailer {
+                return Err(invalid(format!("invalid size {} in trailer", size)));
+            }
+            body = &data[trailer - size..trailer];
+            has_trailer = true;
+            let actual = bootconfig_checksum(body);
+            if actual != checksum {
+                let msg = format!("checksum mismatch: {:#x} != {:#x}", checksum, actual);
+                if !lenient {
+                    return Err(invalid(msg));
+                }
+                warn!("bootconfig {}", msg);
+                checksum_valid = false;
+            }
+        }
+
+        let params = BootconfigParser {
+            data: body,
+            pos: 0,
+            prefix: Vec::new(),
+            params: Vec::new(),
+        }
+        .parse()?;
+
+        Ok(Self {
+            raw: Some(data),
+            params,
+            has_trailer,
+            checksum_valid,
+        })
+    }
+
+    pub fn params(&self) -> &[BootconfigParam] {
+        &self.params
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BootconfigParam> {
+        self.params.iter().find(|p| p.key == key)
+    }
+
+    pub fn has_trailer(&self) -> bool {
+        self.has_trailer
+    }
+
+    pub fn is_checksum_valid(&self) -> bool {
+        self.checksum_valid
+    }
+
+    // Values can be quoted with either quote but not contain both
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        if value.contains('"') && value.contains('\'') {
+            return Err(invalid(format!("value of {} contains both quotes", key)));
+        }
+        self.raw = None;
+        if let Some(param) = self.params.iter_mut().find(|p| p.key == key) {
+            param.values = vec![value.to_string()];
+        } else {
+            self.params.push(BootconfigParam {
+                key: key.to_string(),
+                values: vec![value.to_string()],
+            });
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<BootconfigParam> {
+        let index = self.params.iter().position(|p| p.key == key)?;
+        self.raw = None;
+        Some(self.params.remove(index))
+    }
+
+    fn write_value(out: &mut Vec<u8>, value: &str) -> Result<()> {
+        let quote = match (value.contains('"'), value.contains('\'')) {
+            (true, true) => {
+                return Err(invalid(format!("cannot quote {:?}", value)));
+            }
+            (true, false) => Some(b'\''),
+            (false, true) => Some(b'"'),
+            (false, false) => {
+                let needs_quote = value.is_empty()
+                    || value.bytes().any(|b| {
+                        matches!(b, b' ' | b'\t' | b'\r' | b'\n' | b',' | b';' | b'#' | b'}')
+                    });
+                needs_quote.then_some(b'"')
+            }
+        };
+        if let Some(quote) = quote {
+            out.push(quote);
+            out.extend_from_slice(value.as_bytes());
+            out.push(quote);
+        } else {
+            out.extend_from_slice(value.as_bytes());
+        }
+        Ok(())
+    }
+
+    // One `key=value` line per parameter, the trailer is kept if the source had one
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if let Some(raw) = self.raw {
+            return Ok(raw.to_vec());
+        }
+        let mut out = Vec::new();
+        for param in &self.params {
+            out.extend_from_slice(param.key.as_bytes());
+            if !param.values.is_empty() {
+                out.push(b'=');
+                for (i, value) in param.values.iter().enumerate() {
+                    if i > 0 {
+                        out.extend_from_slice(b", ");
+                    }
+                    Self::write_value(&mut out, value)?;
+                }
+            }
+            out.push(b'\n');
+        }
+        if self.has_trailer {
+            let size = out.len() as u32;
+            let checksum = bootconfig_checksum(&out);
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&checksum.to_le_bytes());
+            out.extend_from_slice(BOOTCONFIG_MAGIC);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"# vendor bootconfig\n\
+androidboot.hardware = qcom\n\
+androidboot.serialconsole=1 ;  kernel.console = \"ttyMSM0,115200n8\"\n\
+androidboot {\n\
+    boot_devices = soc/1d84000.ufshc # ufs\n\
+    dtbo_idx = 1, 2,\n\
+        3\n\
+    verifiedbootstate = orange\n\
+}\n\
+androidboot.hardware := sm8550\n\
+androidboot.dtbo_idx += 4\n\
+androidboot.force_normal_boot\n";
+
+    fn with_trailer(body: &[u8]) -> Vec<u8> {
+        let mut data = body.to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend_from_slice(&bootconfig_checksum(body).to_le_bytes());
+        data.extend_from_slice(BOOTCONFIG_MAGIC);
+        data
+    }
+
+    fn values(config: &Bootconfig, key: &str) -> Vec<String> {
+        config.get(key).unwrap().values.clone()
+    }
+
+    #[test]
+    fn parse_syntax() {
+        let config = Bootconfig::parse(BODY, false).unwrap();
+        assert!(!config.has_trailer());
+        let keys = config
+            .params()
+            .iter()
+            .map(|p| p.key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "androidboot.hardware",
+                "androidboot.serialconsole",
+                "kernel.console",
+                "androidboot.boot_devices",
+                "androidboot.dtbo_idx",
+                "androidboot.verifiedbootstate",
+                "androidboot.force_normal_boot",
+            ]
+        );
+        // := overrides, += appends to the array
+        assert_eq!(values(&config, "androidboot.hardware"), ["sm8550"]);
+        assert_eq!(
+            values(&config, "androidboot.dtbo_idx"),
+            ["1", "2", "3", "4"]
+        );
+        assert_eq!(
+            config.get("kernel.console").unwrap().value(),
+            "ttyMSM0,115200n8"
+        );
+        assert_eq!(
+            config.get("androidboot.boot_devices").unwrap().value(),
+            "soc/1d84000.ufshc"
+        );
+        assert!(values(&config, "androidboot.force_normal_boot").is_empty());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for body in [
+            &b"a { b = 1\n"[..],
+            b"a = 1\n}\n",
+            b"a = \"1\n",
+            b"= 1\n",
+            b"a = \"1\" 2\n",
+            b"a ! 1\n",
+        ] {
+            assert!(
+                Bootconfig::parse(body, true).is_err(),
+                "{}",
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+
+    #[test]
+    fn unmodified_is_identical() {
+        for data in [BODY.to_vec(), with_trailer(BODY)] {
+            let config = Bootconfig::parse(&data, false).unwrap();
+            assert_eq!(config.to_bytes().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn trailer() {
+        let data = with_trailer(BODY);
+        let config = Bootconfig::parse(&data, false).unwrap();
+        assert!(config.has_trailer());
+        assert!(config.is_checksum_valid());
+        assert_eq!(
+            config.params(),
+            Bootconfig::parse(BODY, false).unwrap().params()
+        );
+
+        // The size counts back from the trailer, anything before is ignored
+        let mut padded = b"garbage".to_vec();
+        padded.extend_from_slice(&data);
+        let config = Bootconfig::parse(&padded, false).unwrap();
+        assert_eq!(values(&config, "androidboot.hardware"), ["sm8550"]);
+
+        let trailer = data.len() - BOOTCONFIG_TRAILER_SIZE;
+        let mut bad_size = data.clone();
+        bad_size[trailer..trailer + 4].copy_from_slice(&(trailer as u32 + 1).to_le_bytes());
+        assert!(Bootconfig::parse(&bad_size, true).is_err());
+
+        let mut bad_checksum = data.clone();
+        bad_checksum[trailer + 4] ^= 1;
+        assert!(matches!(
+            Bootconfig::parse(&bad_checksum, false),
+            Err(BootImgError::InvalidBootconfig(_))
+        ));
+        let config = Bootconfig::parse(&bad_checksum, true).unwrap();
+        assert!(!config.is_checksum_valid());
+        assert_eq!(values(&config, "androidboot.hardware"), ["sm8550"]);
+    }
+
+    #[test]
+    fn modify() {
+        let data = with_trailer(BODY);
+        let mut config = Bootconfig::parse(&data, false).unwrap();
+        config.set("androidboot.hardware", "qcom").unwrap();
+        config.set("androidboot.slot_suffix", "_a").unwrap();
+        assert!(config.remove("kernel.console").is_some());
+        assert!(config.remove("kernel.console").is_none());
+        let out = config.to_bytes().unwrap();
+
+        // Rewritten with a new trailer
+        let reparsed = Bootconfig::parse(&out, false).unwrap();
+        assert!(reparsed.has_trailer());
+        assert_eq!(reparsed.params(), config.params());
+        assert_eq!(values(&reparsed, "androidboot.hardware"), ["qcom"]);
+        assert_eq!(values(&reparsed, "androidboot.slot_suffix"), ["_a"]);
+        assert_eq!(
+            values(&reparsed, "androidboot.dtbo_idx"),
+            ["1", "2", "3", "4"]
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        let mut config = Bootconfig::parse(b"", false).unwrap();
+        for (value, quoted) in [
+            ("plain", "plain"),
+            ("", "\"\""),
+            ("a b", "\"a b\""),
+            ("a,b;c#d}e", "\"a,b;c#d}e\""),
+            ("it's", "\"it's\""),
+            ("say \"hi\"", "'say \"hi\"'"),
+        ] {
+            config.set("key", value).unwrap();
+            let out = config.to_bytes().unwrap();
+            assert_eq!(out, format!("key={}\n", quoted).as_bytes());
+            let reparsed = Bootconfig::parse(&out, false).unwrap();
+            assert_eq!(reparsed.get("key").unwrap().value(), value);
+        }
+        assert!(config.set("key", "\"'").is_err());
+
+        // Unquoted values may hold both, they can't be written back
+        let mut config = Bootconfig::parse(b"a = x\"y'z\n", false).unwrap();
+        config.set("b", "1").unwrap();
+        assert!(config.to_bytes().is_err());
+    }
+}
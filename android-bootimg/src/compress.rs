@@ -1,34 +1,65 @@
+use crate::error::{BootImgError, CompressOperation, Result};
+use crate::lzo::{LzopDecoder, lzop_len, lzop_uncompressed_size};
+use crate::utils::CountingWriter;
+#[cfg(feature = "lz4")]
 use crate::utils::{Chunker, ReadExt, WriteExt};
+#[cfg(feature = "bzip2")]
 use bzip2::Compression as BzCompression;
+#[cfg(feature = "bzip2")]
 use bzip2::read::BzDecoder;
+#[cfg(feature = "bzip2")]
 use bzip2::write::BzEncoder;
+#[cfg(feature = "gzip")]
 use flate2::Compression as GzCompression;
-use flate2::read::MultiGzDecoder;
-use flate2::write::GzEncoder;
+#[cfg(feature = "gzip")]
+use flate2::GzBuilder;
+#[cfg(feature = "gzip")]
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+#[cfg(feature = "gzip")]
+use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+#[cfg(feature = "lz4")]
 use lz4::block::CompressionMode;
+#[cfg(feature = "lz4")]
 use lz4::liblz4::BlockChecksum;
+#[cfg(feature = "lz4")]
 use lz4::{
     BlockMode, BlockSize, ContentChecksum, Decoder as LZ4FrameDecoder, Encoder as LZ4FrameEncoder,
     EncoderBuilder as LZ4FrameEncoderBuilder,
 };
-use lzma_rust2::{CheckType, LzmaOptions, LzmaReader, LzmaWriter, XzOptions, XzReader, XzWriter};
+#[cfg(feature = "xz")]
+use lzma_rust2::{
+    CheckType, LzmaOptions, LzmaReader, LzmaWriter, XzOptions, XzReader, XzWriter, XzWriterMt,
+};
 use std::cmp::min;
-use std::io::{BufWriter, Read, Write};
+#[cfg(feature = "zopfli")]
+use std::io::BufWriter;
+use std::io::{Read, Write};
+#[cfg(any(feature = "xz", feature = "zopfli"))]
 use std::num::NonZeroU64;
-use zopfli::{BlockType, GzipEncoder as ZopFliEncoder, Options as ZopfliOptions};
+#[cfg(feature = "zopfli")]
+use zopfli::{BlockType, DeflateEncoder as ZopfliDeflateEncoder, Options as ZopfliOptions};
 
 const GZIP1_MAGIC: &[u8] = b"\x1f\x8b";
 const GZIP2_MAGIC: &[u8] = b"\x1f\x9e";
 const LZOP_MAGIC: &[u8] = b"\x89LZO";
 const XZ_MAGIC: &[u8] = b"\xfd7zXZ";
 const BZIP_MAGIC: &[u8] = b"BZh";
+// BCD pi, or sqrt(pi) for an empty stream
+const BZIP_BLOCK_MAGIC: &[u8] = b"\x31\x41\x59\x26\x53\x59";
+const BZIP_EOS_MAGIC: &[u8] = b"\x17\x72\x45\x38\x50\x90";
+const GZIP_CM_DEFLATE: u8 = 8;
 const LZ4_LEG_MAGIC: &[u8] = b"\x02\x21\x4c\x18";
 const LZ41_MAGIC: &[u8] = b"\x03\x21\x4c\x18";
 const LZ42_MAGIC: &[u8] = b"\x04\x22\x4d\x18";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
 
 // https://github.com/topjohnwu/Magisk/blob/01cb75eaefbd14c2d10772ded3942660ebf0285f/native/src/boot/lib.rs#L25-L48
 // https://github.com/topjohnwu/Magisk/blob/01cb75eaefbd14c2d10772ded3942660ebf0285f/native/src/boot/format.rs#L62
+// Every format is detected whatever the enabled features, decoding and encoding
+// fail with UnsupportedCompression when its backend is compiled out.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum CompressFormat {
     UNKNOWN,
     GZIP,
@@ -40,12 +71,59 @@ pub enum CompressFormat {
     LZ4,
     #[allow(non_camel_case_types)]
     LZ4_LEGACY,
-    // LZ4_LG,
+    // LZ4_LEGACY with the uncompressed size appended, used by LG
+    #[allow(non_camel_case_types)]
+    LZ4_LG,
+    ZSTD,
+    // RFC 1950, detected from the 2-byte header: 0x78 with a valid FCHECK, no
+    // preset dictionary, and a first deflate block that inflates
+    ZLIB,
+    // RFC 1951 without any wrapper. It has no magic and is never detected, but
+    // can be decoded and encoded when the caller knows the format.
+    #[allow(non_camel_case_types)]
+    RAW_DEFLATE,
 }
 
 // https://github.com/topjohnwu/Magisk/blob/01cb75eaefbd14c2d10772ded3942660ebf0285f/native/src/boot/magiskboot.hpp#L21-L50
 // https://github.com/topjohnwu/Magisk/blob/01cb75eaefbd14c2d10772ded3942660ebf0285f/native/src/boot/bootimg.cpp#L69
 
+// The magic followed by the block size digit and the first block magic
+fn is_bzip2(data: &[u8]) -> bool {
+    data.starts_with(BZIP_MAGIC)
+        && data
+            .get(3)
+            .is_some_and(|level| (b'1'..=b'9').contains(level))
+        && data
+            .get(4..10)
+            .is_some_and(|magic| magic == BZIP_BLOCK_MAGIC || magic == BZIP_EOS_MAGIC)
+}
+
+// Without the gzip feature zlib is never detected, the header alone matches too
+// much uncompressed data
+#[cfg(not(feature = "gzip"))]
+fn guess_zlib(_data: &[u8]) -> bool {
+    false
+}
+
+#[cfg(feature = "gzip")]
+fn guess_zlib(data: &[u8]) -> bool {
+    let [cmf, flg, ..] = *data else {
+        return false;
+    };
+    // Deflate with a 32K window, FDICT unset
+    if cmf != 0x78 || flg & 0x20 != 0 || !u16::from_be_bytes([cmf, flg]).is_multiple_of(31) {
+        return false;
+    }
+    // Two bytes still match plenty of uncompressed data
+    let mut probe = [0u8; 64];
+    flate2::bufread::ZlibDecoder::new(data)
+        .read(&mut probe)
+        .is_ok_and(|len| len > 0)
+}
+
+// Larger sizes in the alone header are more likely random data than lzma
+const LZMA_MAX_PLAUSIBLE_SIZE: u64 = 1 << 30;
+
 fn guess_lzma(data: &[u8]) -> bool {
     if data.len() <= 13 {
         return false;
@@ -61,35 +139,390 @@ fn guess_lzma(data: &[u8]) -> bool {
         return false;
     }
 
-    &data[5..13] == b"\xff\xff\xff\xff\xff\xff\xff\xff"
+    // Unknown (all FF) as written by xz --format=lzma, or the real size as
+    // older mkbootimg-era tools did
+    let size = u64::from_le_bytes(data[5..13].try_into().unwrap());
+    size == u64::MAX || (1..=LZMA_MAX_PLAUSIBLE_SIZE).contains(&size)
 }
 
 pub fn parse_compress_format(data: &[u8]) -> CompressFormat {
-    if data.starts_with(GZIP1_MAGIC) || data.starts_with(GZIP2_MAGIC) {
+    if (data.starts_with(GZIP1_MAGIC) && data.get(2) == Some(&GZIP_CM_DEFLATE))
+        || data.starts_with(GZIP2_MAGIC)
+    {
         CompressFormat::GZIP
     } else if data.starts_with(LZOP_MAGIC) {
         CompressFormat::LZOP
     } else if data.starts_with(XZ_MAGIC) {
         CompressFormat::XZ
-    } else if data.starts_with(BZIP_MAGIC) {
+    } else if is_bzip2(data) {
         CompressFormat::BZIP2
     } else if data.starts_with(LZ41_MAGIC) || data.starts_with(LZ42_MAGIC) {
         CompressFormat::LZ4
     } else if data.starts_with(LZ4_LEG_MAGIC) {
-        CompressFormat::LZ4_LEGACY
+        if lz4_lg_trailer(data, &lz4_legacy_blocks(data)) {
+            CompressFormat::LZ4_LG
+        } else {
+            CompressFormat::LZ4_LEGACY
+        }
+    } else if data.starts_with(ZSTD_MAGIC) {
+        CompressFormat::ZSTD
     } else if guess_lzma(data) {
         CompressFormat::LZMA
+    } else if guess_zlib(data) {
+        CompressFormat::ZLIB
     } else {
         CompressFormat::UNKNOWN
     }
 }
 
+// Enough for every magic, the lzma heuristic and the first zlib block header
+const FORMAT_PROBE_SIZE: usize = 4096;
+
+// Replays the bytes buffered for format detection, then the rest of the stream
+pub struct PeekReader<R: Read> {
+    inner: R,
+    head: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for PeekReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos < self.head.len() {
+            let len = min(buf.len(), self.head.len() - self.pos);
+            buf[..len].copy_from_slice(&self.head[self.pos..self.pos + len]);
+            self.pos += len;
+            return Ok(len);
+        }
+        self.inner.read(buf)
+    }
+}
+
+// Same as parse_compress_format but for a stream, LZ4_LG is reported as
+// LZ4_LEGACY as its trailer can't be seen from the start
+pub fn detect_format_from_reader<R: Read>(
+    mut inner: R,
+) -> std::io::Result<(CompressFormat, PeekReader<R>)> {
+    let mut head = Vec::with_capacity(FORMAT_PROBE_SIZE);
+    (&mut inner)
+        .take(FORMAT_PROBE_SIZE as u64)
+        .read_to_end(&mut head)?;
+    let format = match parse_compress_format(&head) {
+        CompressFormat::LZ4_LG => CompressFormat::LZ4_LEGACY,
+        format => format,
+    };
+    Ok((
+        format,
+        PeekReader {
+            inner,
+            head,
+            pos: 0,
+        },
+    ))
+}
+
+// Fails with DecompressLimitExceeded once more than `limit` bytes were read
+pub struct LimitedDecoder<R: Read> {
+    inner: R,
+    limit: u64,
+    remaining: u64,
+}
+
+impl<R: Read> LimitedDecoder<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            limit,
+            remaining: limit,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // One byte past the limit tells a stream ending right at it apart
+        let max = min(buf.len() as u64, self.remaining + 1) as usize;
+        let len = self.inner.read(&mut buf[..max])?;
+        if len as u64 > self.remaining {
+            return Err(std::io::Error::other(
+                BootImgError::DecompressLimitExceeded(self.limit),
+            ));
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+// Returns how many bytes the first compressed stream of `data` occupies,
+// None if the format can't be walked or the stream is broken.
+pub(crate) fn compressed_stream_len(format: CompressFormat, data: &[u8]) -> Option<usize> {
+    match format {
+        #[cfg(feature = "gzip")]
+        CompressFormat::GZIP | CompressFormat::ZOPFLI => {
+            let mut decoder = flate2::bufread::GzDecoder::new(data);
+            std::io::copy(&mut decoder, &mut std::io::sink()).ok()?;
+            Some(data.len() - decoder.into_inner().len())
+        }
+        #[cfg(feature = "gzip")]
+        CompressFormat::ZLIB => {
+            let mut decoder = flate2::bufread::ZlibDecoder::new(data);
+            std::io::copy(&mut decoder, &mut std::io::sink()).ok()?;
+            Some(data.len() - decoder.into_inner().len())
+        }
+        #[cfg(feature = "gzip")]
+        CompressFormat::RAW_DEFLATE => {
+            let mut decoder = flate2::bufread::DeflateDecoder::new(data);
+            std::io::copy(&mut decoder, &mut std::io::sink()).ok()?;
+            Some(data.len() - decoder.into_inner().len())
+        }
+        #[cfg(feature = "bzip2")]
+        CompressFormat::BZIP2 => {
+            let mut decoder = bzip2::bufread::BzDecoder::new(data);
+            std::io::copy(&mut decoder, &mut std::io::sink()).ok()?;
+            Some(data.len() - decoder.into_inner().len())
+        }
+        CompressFormat::XZ => xz_stream_len(data),
+        CompressFormat::LZ4 => lz4_frame_len(data),
+        CompressFormat::LZ4_LEGACY | CompressFormat::LZ4_LG => lz4_legacy_len(data),
+        CompressFormat::LZOP => lzop_len(data),
+        #[cfg(feature = "zstd")]
+        CompressFormat::ZSTD => {
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(data)
+                .ok()?
+                .single_frame();
+            std::io::copy(&mut decoder, &mut std::io::sink()).ok()?;
+            Some(data.len() - decoder.finish().len())
+        }
+        _ => None,
+    }
+}
+
+// Reads the size recorded by the format without decompressing, None if it has
+// none (bzip2, zlib, raw deflate, plain LZ4_LEGACY, frames without a content
+// size). `data` should hold one stream: gzip records its size (mod 2^32) at the
+// very end, the others only describe the first stream or frame.
+pub fn estimate_uncompressed_size(format: CompressFormat, data: &[u8]) -> Option<u64> {
+    match format {
+        CompressFormat::GZIP | CompressFormat::ZOPFLI => {
+            let isize = data.get(data.len().checked_sub(4)?..)?;
+            Some(u32::from_le_bytes(isize.try_into().unwrap()) as u64)
+        }
+        CompressFormat::XZ => xz_uncompressed_size(data),
+        CompressFormat::LZMA => {
+            let size = u64::from_le_bytes(data.get(5..13)?.try_into().unwrap());
+            (size != u64::MAX).then_some(size)
+        }
+        CompressFormat::LZ4 => {
+            let flg = *data.get(4)?;
+            if flg & 0x08 == 0 {
+                return None;
+            }
+            Some(u64::from_le_bytes(data.get(6..14)?.try_into().unwrap()))
+        }
+        CompressFormat::LZ4_LG => {
+            let end = lz4_legacy_blocks(data).end;
+            let trailer = data.get(end..end + 4)?;
+            Some(u32::from_le_bytes(trailer.try_into().unwrap()) as u64)
+        }
+        CompressFormat::LZOP => lzop_uncompressed_size(data),
+        CompressFormat::ZSTD => zstd_content_size(data),
+        _ => None,
+    }
+}
+
+fn read_vli(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Sums the uncompressed sizes of the records in the index before the footer
+fn xz_uncompressed_size(data: &[u8]) -> Option<u64> {
+    let end = xz_stream_len(data)?;
+    let footer = &data[end - 12..end];
+    let backward_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as usize + 1) * 4;
+    let index = data.get(end.checked_sub(12 + backward_size)?..end - 12)?;
+    if index.first() != Some(&0) {
+        return None;
+    }
+    let mut pos = 1;
+    let records = read_vli(index, &mut pos)?;
+    let mut size = 0u64;
+    for _ in 0..records {
+        let _unpadded_size = read_vli(index, &mut pos)?;
+        size = size.checked_add(read_vli(index, &mut pos)?)?;
+    }
+    Some(size)
+}
+
+// https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#frame_header
+fn zstd_content_size(data: &[u8]) -> Option<u64> {
+    let fhd = *data.get(4)?;
+    let single_segment = fhd & 0x20 != 0;
+    let fcs_size = match fhd >> 6 {
+        0 if single_segment => 1,
+        0 => return None,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let did_size = [0, 1, 2, 4][(fhd & 3) as usize];
+    let pos = 5 + usize::from(!single_segment) + did_size;
+    let mut fcs = [0u8; 8];
+    fcs[..fcs_size].copy_from_slice(data.get(pos..pos + fcs_size)?);
+    let size = u64::from_le_bytes(fcs);
+    Some(if fcs_size == 2 { size + 256 } else { size })
+}
+
+// https://tukaani.org/xz/xz-file-format.txt
+// The stream footer is CRC32 | backward size | stream flags | "YZ", 4-byte aligned,
+// with the same stream flags as the header.
+fn xz_stream_len(data: &[u8]) -> Option<usize> {
+    const HEADER_SIZE: usize = 12;
+    let flags = data.get(6..8)?;
+    (HEADER_SIZE * 2..=data.len()).step_by(4).find(|&end| {
+        let footer = &data[end - HEADER_SIZE..end];
+        if &footer[10..] != b"YZ" || &footer[8..10] != flags {
+            return false;
+        }
+        crc32fast::hash(&footer[4..10]) == u32::from_le_bytes(footer[..4].try_into().unwrap())
+    })
+}
+
+// https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md
+fn lz4_frame_len(data: &[u8]) -> Option<usize> {
+    let flg = *data.get(4)?;
+    let mut pos = 4 + 2;
+    if flg & 0x08 != 0 {
+        // Content size
+        pos += 8;
+    }
+    if flg & 0x01 != 0 {
+        // Dictionary id
+        pos += 4;
+    }
+    // Header checksum
+    pos += 1;
+    loop {
+        let block_size = u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().unwrap());
+        pos += 4;
+        if block_size == 0 {
+            break;
+        }
+        pos += (block_size & 0x7fffffff) as usize;
+        if flg & 0x10 != 0 {
+            pos += 4;
+        }
+    }
+    if flg & 0x04 != 0 {
+        pos += 4;
+    }
+    (pos <= data.len()).then_some(pos)
+}
+
+struct Lz4LegacyBlocks<'a> {
+    end: usize,
+    count: usize,
+    first: &'a [u8],
+    last: &'a [u8],
+}
+
+impl Lz4LegacyBlocks<'_> {
+    // Every block but the last holds as much as the first, usually LZ4_BLOCK_SIZE
+    fn uncompressed_size(&self) -> Option<u64> {
+        if self.count == 0 {
+            return Some(0);
+        }
+        let mut out_buf = vec![0u8; LZ4_BLOCK_SIZE];
+        let mut decoded_len = |block| lz4_decode_block(block, &mut out_buf);
+        let last = decoded_len(self.last)? as u64;
+        if self.count == 1 {
+            return Some(last);
+        }
+        let first = decoded_len(self.first)? as u64;
+        Some((self.count as u64 - 1) * first + last)
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_decode_block(block: &[u8], out_buf: &mut [u8]) -> Option<usize> {
+    lz4::block::decompress_to_buffer(block, Some(LZ4_BLOCK_SIZE as i32), out_buf).ok()
+}
+
+// Blocks can't be checked, so LZ4_LG is never told apart from LZ4_LEGACY
+#[cfg(not(feature = "lz4"))]
+fn lz4_decode_block(_block: &[u8], _out_buf: &mut [u8]) -> Option<usize> {
+    None
+}
+
+// LZ4_COMPRESSBOUND from lz4.h
+const fn lz4_compress_bound(size: usize) -> usize {
+    size + size / 255 + 16
+}
+
+// The legacy format has no end mark, stop at the first word that can't be a block
+fn lz4_legacy_blocks(data: &[u8]) -> Lz4LegacyBlocks<'_> {
+    let max_block = lz4_compress_bound(LZ4_BLOCK_SIZE);
+    let mut out_buf = vec![0u8; LZ4_BLOCK_SIZE];
+    let mut blocks = Lz4LegacyBlocks {
+        end: LZ4_LEG_MAGIC.len(),
+        count: 0,
+        first: &[],
+        last: &[],
+    };
+    while let Some(word) = data.get(blocks.end..blocks.end + 4) {
+        let pos = blocks.end;
+        let block_size = u32::from_le_bytes(word.try_into().unwrap()) as usize;
+        if word == LZ4_LEG_MAGIC || block_size > max_block || pos + 4 + block_size > data.len() {
+            break;
+        }
+        let block = &data[pos + 4..pos + 4 + block_size];
+        // Could also be the start of the next segment, make sure it decodes
+        if cfg!(feature = "lz4")
+            && parse_compress_format(&data[pos..]) != CompressFormat::UNKNOWN
+            && lz4_decode_block(block, &mut out_buf).is_none()
+        {
+            break;
+        }
+        blocks.end = pos + 4 + block_size;
+        if blocks.count == 0 {
+            blocks.first = block;
+        }
+        blocks.count += 1;
+        blocks.last = block;
+    }
+    blocks
+}
+
+// LZ4_LG ends with the total uncompressed size
+fn lz4_lg_trailer(data: &[u8], blocks: &Lz4LegacyBlocks) -> bool {
+    data.get(blocks.end..blocks.end + 4).is_some_and(|word| {
+        blocks.uncompressed_size() == Some(u32::from_le_bytes(word.try_into().unwrap()) as u64)
+    })
+}
+
+fn lz4_legacy_len(data: &[u8]) -> Option<usize> {
+    let blocks = lz4_legacy_blocks(data);
+    if lz4_lg_trailer(data, &blocks) {
+        Some(blocks.end + 4)
+    } else {
+        Some(blocks.end)
+    }
+}
+
 pub trait WriteFinish<W: Write>: Write {
     fn finish(self: Box<Self>) -> std::io::Result<W>;
 }
 
 // Boilerplate for existing types
 
+#[cfg(any(feature = "gzip", feature = "bzip2", feature = "xz", feature = "zstd"))]
 macro_rules! finish_impl {
     ($($t:ty),*) => {$(
         impl<W: Write> WriteFinish<W> for $t {
@@ -100,15 +533,67 @@ macro_rules! finish_impl {
     )*}
 }
 
-finish_impl!(GzEncoder<W>, BzEncoder<W>, XzWriter<W>, LzmaWriter<W>);
+#[cfg(feature = "zstd")]
+finish_impl!(zstd::stream::write::Encoder<'static, W>);
+
+#[cfg(feature = "gzip")]
+finish_impl!(GzEncoder<W>, ZlibEncoder<W>, DeflateEncoder<W>);
+
+#[cfg(feature = "bzip2")]
+finish_impl!(BzEncoder<W>);
+
+#[cfg(feature = "xz")]
+finish_impl!(XzWriter<W>, XzWriterMt<W>, LzmaWriter<W>);
+
+// zopfli's own GzipEncoder hardcodes the header, so the gzip framing is done here
+#[cfg(feature = "zopfli")]
+struct ZopfliGzEncoder<W: Write> {
+    inner: BufWriter<ZopfliDeflateEncoder<W>>,
+    crc: crc32fast::Hasher,
+    amount: u32,
+}
+
+#[cfg(feature = "zopfli")]
+impl<W: Write> ZopfliGzEncoder<W> {
+    fn new(options: ZopfliOptions, header: GzipHeader, mut w: W) -> std::io::Result<Self> {
+        w.write_all(&[0x1f, 0x8b, 8, 0])?;
+        w.write_all(&header.mtime.to_le_bytes())?;
+        // XFL 2: maximum compression
+        w.write_all(&[2, header.operating_system])?;
+        Ok(Self {
+            inner: ZopfliDeflateEncoder::new_buffered(options, BlockType::Dynamic, w),
+            crc: crc32fast::Hasher::new(),
+            amount: 0,
+        })
+    }
+}
+
+#[cfg(feature = "zopfli")]
+impl<W: Write> Write for ZopfliGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc.update(&buf[..len]);
+        // ISIZE is the length mod 2^32
+        self.amount = self.amount.wrapping_add(len as u32);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-impl<W: Write> WriteFinish<W> for BufWriter<ZopFliEncoder<W>> {
+#[cfg(feature = "zopfli")]
+impl<W: Write> WriteFinish<W> for ZopfliGzEncoder<W> {
     fn finish(self: Box<Self>) -> std::io::Result<W> {
-        let inner = self.into_inner()?;
-        ZopFliEncoder::finish(inner)
+        let mut w = self.inner.into_inner()?.finish()?;
+        w.write_all(&self.crc.finalize().to_le_bytes())?;
+        w.write_all(&self.amount.to_le_bytes())?;
+        Ok(w)
     }
 }
 
+#[cfg(feature = "lz4")]
 impl<W: Write> WriteFinish<W> for LZ4FrameEncoder<W> {
     fn finish(self: Box<Self>) -> std::io::Result<W> {
         let (w, r) = Self::finish(*self);
@@ -124,35 +609,48 @@ impl<W: Write> WriteFinish<W> for LZ4FrameEncoder<W> {
 
 // LZ4BlockEncoder
 
-const LZ4_BLOCK_SIZE: usize = 0x800000;
-const LZ4HC_CLEVEL_MAX: i32 = 12;
+// Also the largest block decoders accept
+pub const LZ4_BLOCK_SIZE: usize = 0x800000;
+#[cfg(feature = "lz4")]
+const LZ4HC_CLEVEL_MAX: u32 = 12;
+#[cfg(feature = "lz4")]
 const LZ4_MAGIC: u32 = 0x184c2102;
 
+#[cfg(feature = "lz4")]
 struct LZ4BlockEncoder<W: Write> {
     write: W,
     chunker: Chunker,
     out_buf: Box<[u8]>,
     total: u32,
     is_lg: bool,
+    level: i32,
 }
 
+#[cfg(feature = "lz4")]
 impl<W: Write> LZ4BlockEncoder<W> {
-    fn new(write: W, is_lg: bool) -> Self {
-        let out_sz = lz4::block::compress_bound(LZ4_BLOCK_SIZE).unwrap_or(LZ4_BLOCK_SIZE);
+    fn new(write: W, is_lg: bool, level: u32, block_size: usize) -> Self {
+        let block_size = block_size.clamp(1, LZ4_BLOCK_SIZE);
+        let out_sz = lz4::block::compress_bound(block_size).unwrap_or(block_size);
         LZ4BlockEncoder {
             write,
-            chunker: Chunker::new(LZ4_BLOCK_SIZE),
+            chunker: Chunker::new(block_size),
             // SAFETY: all bytes will be initialized before it is used
             out_buf: unsafe { Box::new_uninit_slice(out_sz).assume_init() },
             total: 0,
             is_lg,
+            level: level.min(LZ4HC_CLEVEL_MAX) as i32,
         }
     }
 
-    fn encode_block(write: &mut W, out_buf: &mut [u8], chunk: &[u8]) -> std::io::Result<()> {
+    fn encode_block(
+        write: &mut W,
+        out_buf: &mut [u8],
+        level: i32,
+        chunk: &[u8],
+    ) -> std::io::Result<()> {
         let compressed_size = lz4::block::compress_to_buffer(
             chunk,
-            Some(CompressionMode::HIGHCOMPRESSION(LZ4HC_CLEVEL_MAX)),
+            Some(CompressionMode::HIGHCOMPRESSION(level)),
             false,
             out_buf,
         )?;
@@ -164,6 +662,7 @@ impl<W: Write> LZ4BlockEncoder<W> {
 
 // https://github.com/topjohnwu/Magisk/blob/0bbc7360519726f7e3b5004542c0131fa0c0c86f/native/src/base/misc.rs#L204-L260
 
+#[cfg(feature = "lz4")]
 impl<W: Write> Write for LZ4BlockEncoder<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.write_all(buf)?;
@@ -185,18 +684,19 @@ impl<W: Write> Write for LZ4BlockEncoder<W> {
             let (b, chunk) = self.chunker.add_data(buf);
             buf = b;
             if let Some(chunk) = chunk {
-                Self::encode_block(&mut self.write, &mut self.out_buf, chunk)?;
+                Self::encode_block(&mut self.write, &mut self.out_buf, self.level, chunk)?;
             }
         }
         Ok(())
     }
 }
 
+#[cfg(feature = "lz4")]
 impl<W: Write> WriteFinish<W> for LZ4BlockEncoder<W> {
     fn finish(mut self: Box<Self>) -> std::io::Result<W> {
         let chunk = self.chunker.get_available();
         if !chunk.is_empty() {
-            Self::encode_block(&mut self.write, &mut self.out_buf, chunk)?;
+            Self::encode_block(&mut self.write, &mut self.out_buf, self.level, chunk)?;
         }
         if self.is_lg {
             self.write.write_pod(&self.total)?;
@@ -207,67 +707,152 @@ impl<W: Write> WriteFinish<W> for LZ4BlockEncoder<W> {
 
 // LZ4BlockDecoder
 
+#[cfg(feature = "lz4")]
 struct LZ4BlockDecoder<R: Read> {
     read: R,
     in_buf: Box<[u8]>,
     out_buf: Box<[u8]>,
     out_len: usize,
     out_pos: usize,
+    is_lg: bool,
+    // Fail on anything that isn't a clean end instead of stopping there
+    strict: bool,
+    // Compressed bytes consumed, for error messages
+    offset: u64,
+    total: u64,
+    finished: bool,
 }
 
+#[cfg(feature = "lz4")]
 impl<R: Read> LZ4BlockDecoder<R> {
-    fn new(read: R) -> Self {
-        let compressed_sz = lz4::block::compress_bound(LZ4_BLOCK_SIZE).unwrap_or(LZ4_BLOCK_SIZE);
+    fn new(read: R, is_lg: bool, strict: bool) -> Self {
+        let compressed_sz = lz4_compress_bound(LZ4_BLOCK_SIZE);
         Self {
             read,
             in_buf: unsafe { Box::new_uninit_slice(compressed_sz).assume_init() },
             out_buf: unsafe { Box::new_uninit_slice(LZ4_BLOCK_SIZE).assume_init() },
             out_len: 0,
             out_pos: 0,
+            is_lg,
+            strict,
+            offset: 0,
+            total: 0,
+            finished: false,
         }
     }
-}
 
-impl<R: Read> Read for LZ4BlockDecoder<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.out_pos == self.out_len {
-            let mut block_size: u32 = 0;
-            if let Err(e) = self.read.read_pod(&mut block_size) {
-                return if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    Ok(0)
-                } else {
-                    Err(e)
-                };
-            }
-            if block_size == LZ4_MAGIC {
-                self.read.read_pod(&mut block_size)?;
-            }
+    fn invalid(&self, msg: String) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("lz4 legacy: {} at offset {}", msg, self.offset),
+        )
+    }
 
-            let block_size = block_size as usize;
+    // Ok(None) at the end of the stream
+    fn read_block_size(&mut self) -> std::io::Result<Option<u32>> {
+        let mut block_size: u32 = 0;
+        if let Err(e) = self.read.read_pod(&mut block_size) {
+            // The legacy format has no end mark, only LG expects a trailer
+            return if e.kind() == std::io::ErrorKind::UnexpectedEof && !self.is_lg {
+                Ok(None)
+            } else if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                Err(self.invalid("missing LZ4_LG trailer".to_string()))
+            } else {
+                Err(e)
+            };
+        }
+        self.offset += 4;
+        Ok(Some(block_size))
+    }
 
-            if block_size > self.in_buf.len() {
-                // This may be the LG format trailer, EOF
-                return Ok(0);
+    fn next_block(&mut self) -> std::io::Result<()> {
+        let Some(mut block_size) = self.read_block_size()? else {
+            self.finished = true;
+            return Ok(());
+        };
+        if block_size == LZ4_MAGIC {
+            let Some(size) = self.read_block_size()? else {
+                self.finished = true;
+                return Ok(());
+            };
+            block_size = size;
+        }
+
+        // The uncompressed size appended by LG or the kernel build, checked
+        // before the block size as it can be larger than any block
+        if block_size as u64 == self.total && (self.is_lg || self.total > 0) {
+            self.finished = true;
+            return Ok(());
+        }
+        if self.is_lg && block_size as usize > self.in_buf.len() {
+            return Err(self.invalid(format!(
+                "LZ4_LG trailer {} does not match the uncompressed size {}",
+                block_size, self.total
+            )));
+        }
+        // lz4 never writes empty blocks, this is padding
+        if block_size == 0 {
+            self.finished = true;
+            return Ok(());
+        }
+        let block_size = block_size as usize;
+        if block_size > self.in_buf.len() {
+            self.finished = true;
+            return if self.strict {
+                Err(self.invalid(format!("implausible block size {}", block_size)))
+            } else {
+                Ok(())
+            };
+        }
+
+        // Read the entire compressed block
+        let mut len = 0;
+        while len < block_size {
+            match self.read.read(&mut self.in_buf[len..block_size]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
             }
+        }
+        if len < block_size {
+            self.finished = true;
+            return if self.strict {
+                Err(self.invalid(format!("truncated block, {} of {} bytes", len, block_size)))
+            } else {
+                Ok(())
+            };
+        }
 
-            // Read the entire compressed block
-            let compressed_block = &mut self.in_buf[..block_size];
-            if let Ok(len) = self.read.read(compressed_block) {
-                if len == 0 {
-                    // We hit EOF, that's fine
-                    return Ok(0);
-                } else if len != block_size {
-                    let remain = &mut compressed_block[len..];
-                    self.read.read_exact(remain)?;
-                }
+        // LZ4_decompress_safe fails unless the block ends exactly at block_size,
+        // so a successful decode consumed all of it
+        self.out_len = match lz4::block::decompress_to_buffer(
+            &self.in_buf[..block_size],
+            Some(LZ4_BLOCK_SIZE as i32),
+            &mut self.out_buf,
+        ) {
+            Ok(len) => len,
+            Err(e) if self.strict => return Err(self.invalid(e.to_string())),
+            Err(_) => {
+                self.finished = true;
+                return Ok(());
             }
+        };
+        self.offset += block_size as u64;
+        self.out_pos = 0;
+        self.total += self.out_len as u64;
+        Ok(())
+    }
+}
 
-            self.out_len = lz4::block::decompress_to_buffer(
-                compressed_block,
-                Some(LZ4_BLOCK_SIZE as i32),
-                &mut self.out_buf,
-            )?;
-            self.out_pos = 0;
+#[cfg(feature = "lz4")]
+impl<R: Read> Read for LZ4BlockDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.out_pos == self.out_len {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_block()?;
         }
         let copy_len = min(buf.len(), self.out_len - self.out_pos);
         buf[..copy_len].copy_from_slice(&self.out_buf[self.out_pos..self.out_pos + copy_len]);
@@ -276,60 +861,633 @@ impl<R: Read> Read for LZ4BlockDecoder<R> {
     }
 }
 
-pub fn get_decoder<'a, R: Read + 'a>(
+pub fn get_decoder<'a, R: Read + 'a>(format: CompressFormat, r: R) -> Result<Box<dyn Read + 'a>> {
+    decoder(format, r, true)
+}
+
+// Stops quietly at damage instead of failing where the format allows it (LZ4
+// legacy), to recover what is left of broken images
+pub fn get_scavenging_decoder<'a, R: Read + 'a>(
+    format: CompressFormat,
+    r: R,
+) -> Result<Box<dyn Read + 'a>> {
+    decoder(format, r, false)
+}
+
+#[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+fn decoder<'a, R: Read + 'a>(
     format: CompressFormat,
     r: R,
-) -> anyhow::Result<Box<dyn Read + 'a>> {
+    strict: bool,
+) -> Result<Box<dyn Read + 'a>> {
     Ok(match format {
+        #[cfg(feature = "xz")]
         CompressFormat::XZ => Box::new(XzReader::new(r, true)),
+        #[cfg(feature = "xz")]
         CompressFormat::LZMA => Box::new(LzmaReader::new_mem_limit(r, u32::MAX, None)?),
+        #[cfg(feature = "bzip2")]
         CompressFormat::BZIP2 => Box::new(BzDecoder::new(r)),
+        #[cfg(feature = "lz4")]
         CompressFormat::LZ4 => Box::new(LZ4FrameDecoder::new(r)?),
-        CompressFormat::LZ4_LEGACY => Box::new(LZ4BlockDecoder::new(r)),
+        #[cfg(feature = "lz4")]
+        CompressFormat::LZ4_LEGACY => Box::new(LZ4BlockDecoder::new(r, false, strict)),
+        #[cfg(feature = "lz4")]
+        CompressFormat::LZ4_LG => Box::new(LZ4BlockDecoder::new(r, true, strict)),
+        // Zopfli output is plain gzip
+        #[cfg(feature = "gzip")]
         CompressFormat::ZOPFLI | CompressFormat::GZIP => Box::new(MultiGzDecoder::new(r)),
-        _ => unreachable!(),
+        CompressFormat::LZOP => Box::new(LzopDecoder::new(r)),
+        #[cfg(feature = "gzip")]
+        CompressFormat::ZLIB => Box::new(ZlibDecoder::new(r)),
+        #[cfg(feature = "gzip")]
+        CompressFormat::RAW_DEFLATE => Box::new(DeflateDecoder::new(r)),
+        #[cfg(feature = "zstd")]
+        CompressFormat::ZSTD => Box::new(zstd::stream::read::Decoder::new(r)?),
+        _ => {
+            return Err(BootImgError::UnsupportedCompression {
+                format,
+                operation: CompressOperation::Decode,
+            });
+        }
     })
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressLevel {
+    // Maximum settings of each format, zopfli for gzip and zstd 19 as AOSP
+    #[default]
+    Best,
+    // gzip 6, bzip2 6, xz/lzma 6, LZ4HC 9, zstd 3
+    Fast,
+    // Clamped to the range of the format, 0-9, LZ4HC 1-12 or zstd 1-22
+    Level(u32),
+}
+
+impl CompressLevel {
+    #[cfg_attr(not(any(feature = "gzip", feature = "lz4")), allow(unused))]
+    fn resolve(self, best: u32, fast: u32, max: u32) -> u32 {
+        match self {
+            CompressLevel::Best => best,
+            CompressLevel::Fast => fast,
+            CompressLevel::Level(level) => level.min(max),
+        }
+    }
+}
+
+// Written into gzip and zopfli headers. The default has no mtime and an
+// unknown OS as AOSP minigzip, so the output only depends on the content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GzipHeader {
+    pub mtime: u32,
+    pub operating_system: u8,
+}
+
+pub const GZIP_OS_UNIX: u8 = 3;
+pub const GZIP_OS_UNKNOWN: u8 = 0xff;
+
+impl Default for GzipHeader {
+    fn default() -> Self {
+        Self {
+            mtime: 0,
+            operating_system: GZIP_OS_UNKNOWN,
+        }
+    }
+}
+
+impl GzipHeader {
+    // Current time and the Unix OS byte, like gzip without -n
+    pub fn now() -> Self {
+        let mtime = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as u32);
+        Self {
+            mtime,
+            operating_system: GZIP_OS_UNIX,
+        }
+    }
+}
+
+// level: gzip, zopfli (anything but Best falls back to gzip), bzip2, xz,
+//        lzma, lz4, lz4 legacy/LG, zstd, zlib and raw deflate
+// threads: xz only, more than one splits the stream into independent blocks
+// favor_dec_speed: lz4 frames only, the lz4 tool ignores it for legacy blocks
+//                  too so AOSP's `lz4 -l -12 --favor-decSpeed` is matched
+// lz4_block_size: lz4 legacy/LG, at most LZ4_BLOCK_SIZE
+// gzip_header: gzip and zopfli
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressOptions {
+    pub level: CompressLevel,
+    pub threads: u32,
+    pub favor_dec_speed: bool,
+    pub lz4_block_size: usize,
+    pub gzip_header: GzipHeader,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            level: CompressLevel::Best,
+            threads: 1,
+            favor_dec_speed: false,
+            lz4_block_size: LZ4_BLOCK_SIZE,
+            gzip_header: GzipHeader::default(),
+        }
+    }
+}
+
+impl CompressOptions {
+    pub fn fast() -> Self {
+        Self {
+            level: CompressLevel::Fast,
+            ..Default::default()
+        }
+    }
+}
+
+// Without any encoder compiled in every format is an error
+#[cfg_attr(
+    not(any(feature = "gzip", feature = "lz4")),
+    allow(unused_variables, unreachable_code)
+)]
 pub fn get_encoder<'a, W: Write + ?Sized>(
     format: CompressFormat,
+    options: &CompressOptions,
     w: &'a mut W,
-) -> std::io::Result<Box<dyn WriteFinish<&'a mut W> + 'a>> {
+) -> Result<Box<dyn WriteFinish<&'a mut W> + 'a>> {
+    let level = options.level;
+    #[cfg(feature = "gzip")]
+    let gzip = |w| {
+        GzBuilder::new()
+            .mtime(options.gzip_header.mtime)
+            .operating_system(options.gzip_header.operating_system)
+            .write(w, GzCompression::new(level.resolve(9, 6, 9)))
+    };
     Ok(match format {
+        #[cfg(feature = "xz")]
         CompressFormat::XZ => {
-            let mut opt = XzOptions::with_preset(9);
+            let mut opt = XzOptions::with_preset(level.resolve(9, 6, 9));
             opt.set_check_sum_type(CheckType::Crc32);
-            Box::new(XzWriter::new(w, opt)?)
+            if options.threads > 1 {
+                // Same block size as xz -T
+                let block_size = opt.lzma_options.dict_size as u64 * 3;
+                opt.set_block_size(NonZeroU64::new(block_size));
+                Box::new(XzWriterMt::new(w, opt, options.threads)?)
+            } else {
+                Box::new(XzWriter::new(w, opt)?)
+            }
         }
+        #[cfg(feature = "xz")]
         CompressFormat::LZMA => Box::new(LzmaWriter::new_use_header(
             w,
-            &LzmaOptions::with_preset(9),
+            &LzmaOptions::with_preset(level.resolve(9, 6, 9)),
             None,
         )?),
-        CompressFormat::BZIP2 => Box::new(BzEncoder::new(w, BzCompression::best())),
+        #[cfg(feature = "bzip2")]
+        CompressFormat::BZIP2 => Box::new(BzEncoder::new(
+            w,
+            BzCompression::new(level.resolve(9, 6, 9).max(1)),
+        )),
+        #[cfg(feature = "lz4")]
         CompressFormat::LZ4 => {
             let encoder = LZ4FrameEncoderBuilder::new()
                 .block_size(BlockSize::Max4MB)
                 .block_mode(BlockMode::Independent)
                 .checksum(ContentChecksum::ChecksumEnabled)
                 .block_checksum(BlockChecksum::BlockChecksumEnabled)
-                .level(9)
+                .level(level.resolve(9, 9, LZ4HC_CLEVEL_MAX))
+                .favor_dec_speed(options.favor_dec_speed)
                 .auto_flush(true)
                 .build(w)?;
             Box::new(encoder)
         }
-        CompressFormat::LZ4_LEGACY => Box::new(LZ4BlockEncoder::new(w, false)),
-        // CompressFormat::LZ4_LG => Box::new(LZ4BlockEncoder::new(w, true)),
-        CompressFormat::ZOPFLI => {
+        #[cfg(feature = "lz4")]
+        CompressFormat::LZ4_LEGACY => Box::new(LZ4BlockEncoder::new(
+            w,
+            false,
+            level.resolve(LZ4HC_CLEVEL_MAX, 9, LZ4HC_CLEVEL_MAX),
+            options.lz4_block_size,
+        )),
+        #[cfg(feature = "lz4")]
+        CompressFormat::LZ4_LG => Box::new(LZ4BlockEncoder::new(
+            w,
+            true,
+            level.resolve(LZ4HC_CLEVEL_MAX, 9, LZ4HC_CLEVEL_MAX),
+            options.lz4_block_size,
+        )),
+        #[cfg(feature = "zopfli")]
+        CompressFormat::ZOPFLI if level == CompressLevel::Best => {
             // These options are already better than gzip -9
             let opt = ZopfliOptions {
                 iteration_count: unsafe { NonZeroU64::new_unchecked(1) },
                 maximum_block_splits: 1,
                 ..Default::default()
             };
-            Box::new(ZopFliEncoder::new_buffered(opt, BlockType::Dynamic, w)?)
+            Box::new(ZopfliGzEncoder::new(opt, options.gzip_header, w)?)
+        }
+        // Below the best level zopfli isn't worth its time, plain gzip is written
+        #[cfg(all(feature = "zopfli", feature = "gzip"))]
+        CompressFormat::ZOPFLI => Box::new(gzip(w)),
+        #[cfg(feature = "gzip")]
+        CompressFormat::GZIP => Box::new(gzip(w)),
+        #[cfg(feature = "gzip")]
+        CompressFormat::ZLIB => Box::new(ZlibEncoder::new(
+            w,
+            GzCompression::new(level.resolve(9, 6, 9)),
+        )),
+        #[cfg(feature = "gzip")]
+        CompressFormat::RAW_DEFLATE => Box::new(DeflateEncoder::new(
+            w,
+            GzCompression::new(level.resolve(9, 6, 9)),
+        )),
+        #[cfg(feature = "zstd")]
+        CompressFormat::ZSTD => {
+            let level = level.resolve(19, 3, 22).max(1) as i32;
+            let mut encoder = zstd::stream::write::Encoder::new(w, level)?;
+            // Same frame as the zstd tool
+            encoder.include_checksum(true)?;
+            Box::new(encoder)
+        }
+        CompressFormat::LZOP => {
+            return Err(BootImgError::InvalidOperation(
+                "LZOP can only be decoded, replace the block with data compressed in another format such as gzip"
+                    .to_string(),
+            ));
+        }
+        _ => {
+            return Err(BootImgError::UnsupportedCompression {
+                format,
+                operation: CompressOperation::Encode,
+            });
         }
-        CompressFormat::GZIP => Box::new(GzEncoder::new(w, GzCompression::best())),
-        _ => unreachable!(),
     })
 }
+
+// Decodes `reader` from `src` and encodes it to `dst`, UNKNOWN meaning raw data
+// on either side. The same format on both sides copies the stream as is.
+// Returns the number of bytes written.
+pub fn transcode<R: Read, W: Write + ?Sized>(
+    src: CompressFormat,
+    dst: CompressFormat,
+    mut reader: R,
+    writer: &mut W,
+    options: &CompressOptions,
+) -> Result<u64> {
+    if src == dst {
+        return Ok(std::io::copy(&mut reader, writer)?);
+    }
+    let mut decoder: Box<dyn Read + '_> = if src == CompressFormat::UNKNOWN {
+        Box::new(reader)
+    } else {
+        get_decoder(src, reader)?
+    };
+    let mut counter = CountingWriter::new(writer);
+    if dst == CompressFormat::UNKNOWN {
+        std::io::copy(&mut decoder, &mut counter)?;
+    } else {
+        let mut encoder = get_encoder(dst, options, &mut counter)?;
+        std::io::copy(&mut decoder, &mut encoder)?;
+        encoder.finish()?;
+    }
+    Ok(counter.count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::CPIO_NEWC_MAGIC;
+
+    fn encode(format: CompressFormat, options: &CompressOptions, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        transcode(CompressFormat::UNKNOWN, format, data, &mut out, options)?;
+        Ok(out)
+    }
+
+    #[allow(dead_code)]
+    fn decode(format: CompressFormat, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        get_decoder(format, data)
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[allow(dead_code)]
+    fn sample() -> Vec<u8> {
+        (0..64 * 1024u32)
+            .flat_map(|i| (i / 7).to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    #[cfg(not(feature = "zopfli"))]
+    fn zopfli_compiled_out() {
+        for options in [CompressOptions::default(), CompressOptions::fast()] {
+            let result = encode(CompressFormat::ZOPFLI, &options, b"data");
+            assert!(matches!(
+                result,
+                Err(BootImgError::UnsupportedCompression {
+                    format: CompressFormat::ZOPFLI,
+                    operation: CompressOperation::Encode,
+                })
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "zopfli", feature = "gzip"))]
+    fn zopfli_roundtrip() {
+        let data = sample();
+        for options in [CompressOptions::default(), CompressOptions::fast()] {
+            let encoded = encode(CompressFormat::ZOPFLI, &options, &data).unwrap();
+            assert_eq!(parse_compress_format(&encoded), CompressFormat::GZIP);
+            assert_eq!(decode(CompressFormat::GZIP, &encoded), data);
+        }
+    }
+
+    // Plain text of the testdata fixtures, which were written by the reference
+    // tools from this content
+    fn fixture_text() -> Vec<u8> {
+        b"android-bootimg compression fixture\n".repeat(256)
+    }
+
+    #[test]
+    fn zstd_detected() {
+        let fixture = include_bytes!("../testdata/plain.zst");
+        assert_eq!(parse_compress_format(fixture), CompressFormat::ZSTD);
+        assert_eq!(
+            estimate_uncompressed_size(CompressFormat::ZSTD, fixture),
+            Some(fixture_text().len() as u64)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn zstd_roundtrip() {
+        let data = sample();
+        for options in [CompressOptions::default(), CompressOptions::fast()] {
+            let mut encoded = encode(CompressFormat::ZSTD, &options, &data).unwrap();
+            assert_eq!(parse_compress_format(&encoded), CompressFormat::ZSTD);
+            let len = encoded.len();
+            encoded.extend_from_slice(b"trailing");
+            assert_eq!(
+                compressed_stream_len(CompressFormat::ZSTD, &encoded),
+                Some(len)
+            );
+            assert_eq!(decode(CompressFormat::ZSTD, &encoded[..len]), data);
+        }
+    }
+
+    // `zstd -19` 1.5.7
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn zstd_reference_fixture() {
+        let fixture = include_bytes!("../testdata/plain.zst");
+        assert_eq!(
+            compressed_stream_len(CompressFormat::ZSTD, fixture),
+            Some(fixture.len())
+        );
+        assert_eq!(decode(CompressFormat::ZSTD, fixture), fixture_text());
+    }
+
+    // `lzma -9` (xz 5.8.2) writes an unknown size. The sized variant is the
+    // same stream with the size field set to the real length, liblzma decodes
+    // it as well.
+    const LZMA_FIXTURE: &[u8] = include_bytes!("../testdata/plain.lzma");
+    const LZMA_SIZED_FIXTURE: &[u8] = include_bytes!("../testdata/plain-sized.lzma");
+
+    #[test]
+    fn lzma_detected() {
+        assert_eq!(parse_compress_format(LZMA_FIXTURE), CompressFormat::LZMA);
+        assert_eq!(
+            estimate_uncompressed_size(CompressFormat::LZMA, LZMA_FIXTURE),
+            None
+        );
+        assert_eq!(
+            parse_compress_format(LZMA_SIZED_FIXTURE),
+            CompressFormat::LZMA
+        );
+        assert_eq!(
+            estimate_uncompressed_size(CompressFormat::LZMA, LZMA_SIZED_FIXTURE),
+            Some(fixture_text().len() as u64)
+        );
+
+        let with_size = |size: u64| {
+            let mut data = LZMA_FIXTURE.to_vec();
+            data[5..13].copy_from_slice(&size.to_le_bytes());
+            data
+        };
+        for size in [0, LZMA_MAX_PLAUSIBLE_SIZE + 1, u64::MAX - 1] {
+            assert_eq!(
+                parse_compress_format(&with_size(size)),
+                CompressFormat::UNKNOWN,
+                "size {}",
+                size
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn lzma_reference_fixtures() {
+        for fixture in [LZMA_FIXTURE, LZMA_SIZED_FIXTURE] {
+            assert_eq!(decode(CompressFormat::LZMA, fixture), fixture_text());
+        }
+    }
+
+    fn with_tail(head: &[u8]) -> Vec<u8> {
+        let mut data = head.to_vec();
+        data.extend_from_slice(&[0x55; 32]);
+        data
+    }
+
+    // Each format next to near misses of its magic, which must stay raw data
+    #[test]
+    fn adversarial_prefixes() {
+        use CompressFormat::*;
+        let bzip2 = |level: u8, magic: &[u8]| [b"BZh", &[level][..], magic].concat();
+        let lzma = |props: u8, dict_size: u32| {
+            [&[props][..], &dict_size.to_le_bytes(), &[0xff; 8]].concat()
+        };
+        let cases: &[(Vec<u8>, CompressFormat)] = &[
+            (with_tail(b""), UNKNOWN),
+            (with_tail(CPIO_NEWC_MAGIC), UNKNOWN),
+            // gzip needs CM 8 after the magic
+            (with_tail(b"\x1f\x8b\x08"), GZIP),
+            (with_tail(b"\x1f\x8b\x07"), UNKNOWN),
+            (b"\x1f\x8b".to_vec(), UNKNOWN),
+            (with_tail(GZIP2_MAGIC), GZIP),
+            // bzip2 needs a level digit and a block or end of stream magic
+            (with_tail(&bzip2(b'9', BZIP_BLOCK_MAGIC)), BZIP2),
+            (with_tail(&bzip2(b'1', BZIP_EOS_MAGIC)), BZIP2),
+            (with_tail(b"BZh-something"), UNKNOWN),
+            (with_tail(&bzip2(b'0', BZIP_BLOCK_MAGIC)), UNKNOWN),
+            (
+                with_tail(&bzip2(b'9', b"\x31\x41\x59\x26\x53\x58")),
+                UNKNOWN,
+            ),
+            (bzip2(b'9', &BZIP_BLOCK_MAGIC[..5]), UNKNOWN),
+            (with_tail(XZ_MAGIC), XZ),
+            (with_tail(&XZ_MAGIC[..4]), UNKNOWN),
+            (with_tail(LZOP_MAGIC), LZOP),
+            (with_tail(&LZOP_MAGIC[..3]), UNKNOWN),
+            (with_tail(LZ41_MAGIC), LZ4),
+            (with_tail(LZ42_MAGIC), LZ4),
+            (with_tail(&LZ42_MAGIC[..3]), UNKNOWN),
+            (with_tail(LZ4_LEG_MAGIC), LZ4_LEGACY),
+            (with_tail(&LZ4_LEG_MAGIC[..3]), UNKNOWN),
+            (with_tail(ZSTD_MAGIC), ZSTD),
+            (with_tail(&ZSTD_MAGIC[..3]), UNKNOWN),
+            // lzma has no magic, the properties and dictionary size must be plausible
+            (with_tail(&lzma(0x5d, 1 << 23)), LZMA),
+            (lzma(0x5d, 1 << 23), UNKNOWN),
+            (with_tail(&lzma(0x5e, 1 << 23)), UNKNOWN),
+            (with_tail(&lzma(0x5d, 0)), UNKNOWN),
+            (with_tail(&lzma(0x5d, 3 << 20)), UNKNOWN),
+            // zlib header without a deflate stream behind it
+            (with_tail(b"\x78\x9c"), UNKNOWN),
+            (with_tail(b"\x78\x9d"), UNKNOWN),
+        ];
+        for (data, format) in cases {
+            assert_eq!(parse_compress_format(data), *format, "{:02x?}", data);
+            let (detected, _) = detect_format_from_reader(&data[..]).unwrap();
+            assert_eq!(detected, *format, "{:02x?}", data);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn zlib_prefixes() {
+        let options = CompressOptions::default();
+        let zlib = encode(CompressFormat::ZLIB, &options, &sample()).unwrap();
+        assert_eq!(parse_compress_format(&zlib), CompressFormat::ZLIB);
+        // A preset dictionary is not supported
+        let mut fdict = zlib.clone();
+        fdict[1] = (fdict[1] & 0xc0) | 0x20;
+        fdict[1] += (31 - u16::from_be_bytes([fdict[0], fdict[1]]) % 31) as u8 % 31;
+        assert_eq!(parse_compress_format(&fdict), CompressFormat::UNKNOWN);
+        // Text starting with "x" and a byte that happens to pass FCHECK
+        assert_eq!(
+            parse_compress_format(b"x\x01 plain text"),
+            CompressFormat::UNKNOWN
+        );
+    }
+
+    // `lz4 -l -12 --favor-decSpeed` (lz4 1.9.4), how AOSP compresses GKI ramdisks
+    const LZ4_LEGACY_FIXTURE: &[u8] = include_bytes!("../testdata/plain.lz4");
+
+    #[test]
+    fn lz4_legacy_detected() {
+        assert_eq!(
+            parse_compress_format(LZ4_LEGACY_FIXTURE),
+            CompressFormat::LZ4_LEGACY
+        );
+        assert_eq!(
+            compressed_stream_len(CompressFormat::LZ4_LEGACY, LZ4_LEGACY_FIXTURE),
+            Some(LZ4_LEGACY_FIXTURE.len())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn lz4_legacy_reference_fixture() {
+        assert_eq!(
+            decode(CompressFormat::LZ4_LEGACY, LZ4_LEGACY_FIXTURE),
+            fixture_text()
+        );
+        let options = CompressOptions {
+            favor_dec_speed: true,
+            ..Default::default()
+        };
+        let encoded = encode(CompressFormat::LZ4_LEGACY, &options, &fixture_text()).unwrap();
+        assert!(encoded == LZ4_LEGACY_FIXTURE, "{:02x?}", encoded);
+    }
+
+    // Written by testdata/lzop_ref.py
+    const LZOP_FIXTURE: &[u8] = include_bytes!("../testdata/plain.lzo");
+    const LZOP_MIXED_FIXTURE: &[u8] = include_bytes!("../testdata/mixed.lzo");
+
+    // mixed() of testdata/lzop_ref.py, which takes every match encoding
+    fn lzo_mixed_text() -> Vec<u8> {
+        let mut x = 1u32;
+        let mut out = Vec::new();
+        for i in 0..26 * 1024 {
+            if i == 8 * 1024 {
+                out.extend_from_slice(&b"0123456789".repeat(10));
+            }
+            x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fffffff;
+            out.push(b"ACGT"[(x >> 16) as usize & 3]);
+        }
+        out.extend_from_slice(&b"0123456789".repeat(10));
+        out
+    }
+
+    #[test]
+    fn lzop_reference_fixtures() {
+        for (fixture, text) in [
+            (LZOP_FIXTURE, fixture_text()),
+            (LZOP_MIXED_FIXTURE, lzo_mixed_text()),
+        ] {
+            assert_eq!(parse_compress_format(fixture), CompressFormat::LZOP);
+            assert!(decode(CompressFormat::LZOP, fixture) == text);
+            assert_eq!(
+                compressed_stream_len(CompressFormat::LZOP, &with_tail(fixture)),
+                Some(fixture.len())
+            );
+            assert_eq!(
+                estimate_uncompressed_size(CompressFormat::LZOP, fixture),
+                Some(text.len() as u64)
+            );
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    fn lz4_legacy_small_blocks() -> (Vec<u8>, Vec<u8>) {
+        let data = sample();
+        let options = CompressOptions {
+            lz4_block_size: 64 * 1024,
+            ..Default::default()
+        };
+        let encoded = encode(CompressFormat::LZ4_LEGACY, &options, &data).unwrap();
+        (data, encoded)
+    }
+
+    // Small blocks give a stream of several blocks
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn lz4_legacy_multiple_blocks() {
+        let (data, encoded) = lz4_legacy_small_blocks();
+        // Magic, then each block behind its u32 size
+        let mut blocks = 0;
+        let mut pos = 4;
+        while pos < encoded.len() {
+            pos += 4 + u32::from_le_bytes(encoded[pos..pos + 4].try_into().unwrap()) as usize;
+            blocks += 1;
+        }
+        assert_eq!(pos, encoded.len());
+        assert_eq!(blocks, data.len().div_ceil(64 * 1024));
+        assert_eq!(decode(CompressFormat::LZ4_LEGACY, &encoded), data);
+    }
+
+    // Needs the lz4 tool, run by CI with --ignored
+    #[test]
+    #[ignore]
+    #[cfg(feature = "lz4")]
+    fn lz4_legacy_reference_tool() {
+        use std::process::{Command, Stdio};
+
+        let (data, encoded) = lz4_legacy_small_blocks();
+        let mut child = Command::new("lz4")
+            .args(["-d", "-c"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("lz4 not found");
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = std::thread::spawn(move || stdin.write_all(&encoded));
+        let output = child.wait_with_output().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout == data);
+    }
+}
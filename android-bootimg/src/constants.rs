@@ -5,5 +5,12 @@ pub const BOOT_EXTRA_ARGS_SIZE: usize = 1024;
 pub const VENDOR_BOOT_ARGS_SIZE: usize = 2048;
 pub const VENDOR_RAMDISK_NAME_SIZE: usize = 32;
 pub const VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE: usize = 16;
-pub const AVB_FOOTER_MAGIC: &'static [u8] = b"AVBf";
-pub const AVB_MAGIC: &'static [u8] = b"AVB0";
+pub const AVB_FOOTER_MAGIC: &[u8] = b"AVBf";
+pub const AVB_MAGIC: &[u8] = b"AVB0";
+pub const AVB_PARTITION_ALIGN: usize = 4096;
+pub const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+pub const CPIO_NEWC_CRC_MAGIC: &[u8] = b"070702";
+pub const EROFS_SUPER_OFFSET: usize = 1024;
+pub const EROFS_SUPER_MAGIC: u32 = 0xe0f5e1e2;
+pub const EXT4_SUPER_MAGIC_OFFSET: usize = 1024 + 0x38;
+pub const EXT4_SUPER_MAGIC: u16 = 0xef53;
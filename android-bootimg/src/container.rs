@@ -0,0 +1,161 @@
+use crate::compress::{CompressFormat, CompressOptions, get_encoder, parse_compress_format};
+use crate::error::{BootImgError, Result};
+use crate::parser::{BOOT_MAGIC, ParseOptions, VENDOR_BOOT_MAGIC};
+use crate::utils::align_to;
+use std::io::{Read, Write};
+
+// Samsung firmware ships images as boot.img.lz4, usually inside an Odin
+// AP_*.tar.md5, which is a ustar archive followed by an md5sum line
+const TAR_BLOCK_SIZE: usize = 512;
+// Tried in order when no entry is named
+const ODIN_ENTRIES: &[&str] = &[
+    "boot.img.lz4",
+    "boot.img",
+    "init_boot.img.lz4",
+    "init_boot.img",
+    "vendor_boot.img.lz4",
+    "vendor_boot.img",
+    "recovery.img.lz4",
+    "recovery.img",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerKind {
+    Lz4,
+    // The entry of the archive the image was taken from
+    OdinTar { entry: String, lz4: bool },
+}
+
+pub struct UnwrappedImage {
+    pub kind: ContainerKind,
+    pub data: Vec<u8>,
+}
+
+fn is_boot_image(data: &[u8]) -> bool {
+    data.starts_with(BOOT_MAGIC) || data.starts_with(VENDOR_BOOT_MAGIC)
+}
+
+// The content of an LZ4 frame, if it decompresses to a boot image
+fn unwrap_lz4(data: &[u8], options: &ParseOptions) -> Result<Option<Vec<u8>>> {
+    if parse_compress_format(data) != CompressFormat::LZ4 {
+        return Ok(None);
+    }
+    let mut decoder = options.strict_decoder(CompressFormat::LZ4, data)?;
+    let mut image = Vec::new();
+    (&mut decoder)
+        .take(BOOT_MAGIC.len() as u64)
+        .read_to_end(&mut image)?;
+    if !is_boot_image(&image) {
+        return Ok(None);
+    }
+    decoder.read_to_end(&mut image)?;
+    Ok(Some(image))
+}
+
+fn octal(field: &[u8]) -> Option<usize> {
+    let s = str::from_utf8(field).ok()?;
+    usize::from_str_radix(s.trim_matches(|c: char| c == '\0' || c == ' '), 8).ok()
+}
+
+fn tar_entries(data: &[u8]) -> Result<Vec<(String, &[u8])>> {
+    let invalid = |msg: &str| BootImgError::InvalidOperation(format!("invalid odin tar: {}", msg));
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..pos + TAR_BLOCK_SIZE) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let raw = &header[range];
+            String::from_utf8_lossy(&raw[..raw.iter().position(|&b| b == 0).unwrap_or(raw.len())])
+                .into_owned()
+        };
+        let size = octal(&header[124..136]).ok_or_else(|| invalid("bad entry size"))?;
+        let start = pos + TAR_BLOCK_SIZE;
+        let content = data
+            .get(start..start + size)
+            .ok_or_else(|| invalid("truncated entry"))?;
+        let (prefix, name) = (field(345..500), field(0..100));
+        let name = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        // Regular files only
+        if matches!(header[156], b'0' | 0) {
+            entries.push((name, content));
+        }
+        pos = start + align_to(size, TAR_BLOCK_SIZE);
+    }
+    Ok(entries)
+}
+
+pub fn is_odin_tar(data: &[u8]) -> bool {
+    data.get(257..262) == Some(b"ustar".as_slice())
+}
+
+// Extracts the boot image from an LZ4 frame or an Odin tar. `entry` picks the
+// tar entry, by default the first of boot, init_boot, vendor_boot and recovery.
+// None if the data is not such a container.
+pub fn unwrap_image(data: &[u8], entry: Option<&str>) -> Result<Option<UnwrappedImage>> {
+    unwrap_image_with_options(data, entry, &ParseOptions::default())
+}
+
+// Decompressing past options.max_decompressed_size fails with
+// DecompressLimitExceeded
+pub fn unwrap_image_with_options(
+    data: &[u8],
+    entry: Option<&str>,
+    options: &ParseOptions,
+) -> Result<Option<UnwrappedImage>> {
+    if let Some(image) = unwrap_lz4(data, options)? {
+        return Ok(Some(UnwrappedImage {
+            kind: ContainerKind::Lz4,
+            data: image,
+        }));
+    }
+    if !is_odin_tar(data) {
+        return Ok(None);
+    }
+    let entries = tar_entries(data)?;
+    let names = match entry {
+        Some(entry) => vec![entry],
+        None => ODIN_ENTRIES.to_vec(),
+    };
+    let found = names.iter().find_map(|name| {
+        entries
+            .iter()
+            .find(|(it, _)| it.rsplit('/').next() == Some(name))
+    });
+    let Some((name, content)) = found else {
+        return Err(BootImgError::InvalidOperation(format!(
+            "odin tar has no {}",
+            names.join(" or ")
+        )));
+    };
+    let lz4 = parse_compress_format(content) == CompressFormat::LZ4;
+    let image = if lz4 {
+        let mut image = Vec::new();
+        options
+            .strict_decoder(CompressFormat::LZ4, *content)?
+            .read_to_end(&mut image)?;
+        image
+    } else {
+        content.to_vec()
+    };
+    Ok(Some(UnwrappedImage {
+        kind: ContainerKind::OdinTar {
+            entry: name.clone(),
+            lz4,
+        },
+        data: image,
+    }))
+}
+
+// An LZ4 frame as Odin flashes it
+pub fn wrap_lz4(data: &[u8], output: &mut dyn Write) -> Result<()> {
+    let mut encoder = get_encoder(CompressFormat::LZ4, &CompressOptions::default(), output)?;
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    Ok(())
+}
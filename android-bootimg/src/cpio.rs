@@ -1,23 +1,103 @@
-use crate::utils::{WriteExt, align_to};
-use anyhow::{Result, anyhow, bail};
+use crate::compress::{CompressFormat, parse_compress_format};
+use crate::constants::{CPIO_NEWC_CRC_MAGIC, CPIO_NEWC_MAGIC};
+use crate::error::{BootImgError, Result};
+use crate::parser::ParseOptions;
+use crate::utils::{CountingReader, WriteExt, align_to};
 use itertools::Itertools;
-use std::collections::BTreeMap;
+#[cfg(all(unix, feature = "std-fs"))]
+use log::warn;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+#[cfg(all(unix, feature = "std-fs"))]
+use std::ffi::{CString, OsStr};
 use std::fmt::{Display, Formatter};
-use std::io::{Cursor, Read, Write};
-use std::ops::Deref;
+#[cfg(all(unix, feature = "std-fs"))]
+use std::fs;
+#[cfg(feature = "std-fs")]
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::ops::{Deref, DerefMut, Range};
+#[cfg(all(unix, feature = "std-fs"))]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+#[cfg(all(unix, feature = "std-fs"))]
+use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+#[cfg(all(unix, feature = "std-fs"))]
+use std::path::Path;
+#[cfg(feature = "std-fs")]
+use std::path::PathBuf;
 use std::{io, str};
 
-pub struct Cpio {
-    entries: BTreeMap<String, Box<CpioEntry>>,
+// Loaded entries borrow their data from the archive buffer, see into_owned.
+// Names are kept as bytes, they are not always UTF-8.
+pub struct Cpio<'a> {
+    entries: BTreeMap<Vec<u8>, Box<CpioEntry<'a>>>,
+    variant: CpioVariant,
+    warnings: Vec<String>,
 }
 
-pub struct CpioEntry {
+// Header magic, the CRC variant stores a checksum of every file's data
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CpioVariant {
+    #[default]
+    Newc,
+    NewcCrc,
+}
+
+impl CpioVariant {
+    fn magic(self) -> &'static [u8] {
+        match self {
+            CpioVariant::Newc => CPIO_NEWC_MAGIC,
+            CpioVariant::NewcCrc => CPIO_NEWC_CRC_MAGIC,
+        }
+    }
+}
+
+// Sum of all bytes, which is all the "crc" of the CRC variant is
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32))
+}
+
+struct ChecksumWriter(u32);
+
+impl Write for ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 = self.0.wrapping_add(checksum(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+enum EntryData<'a> {
+    Memory(Cow<'a, [u8]>),
+    // Regular files from add_from_dir, streamed when dumping
+    #[cfg(feature = "std-fs")]
+    #[cfg_attr(not(unix), allow(dead_code))]
+    File {
+        path: PathBuf,
+        len: u64,
+    },
+    // Not kept by a metadata only load, offset is into the loaded stream
+    Skipped {
+        offset: u64,
+        len: u64,
+    },
+}
+
+pub struct CpioEntry<'a> {
     mode: u32,
     uid: u32,
     gid: u32,
     rdev_major: u32,
     rdev_minor: u32,
-    data: Option<Box<dyn AsRef<[u8]>>>,
+    // Kept from a loaded archive, assigned on dump for new entries
+    ino: Option<u32>,
+    nlink: Option<u32>,
+    mtime: u32,
+    data: Option<EntryData<'a>>,
 }
 
 pub const TYPE_MASK: u32 = 0o170000;
@@ -30,6 +110,35 @@ pub const TYPE_NETWORK_SPECIAL: u32 = 0o110000;
 pub const TYPE_SYMLINK: u32 = 0o120000;
 pub const TYPE_SOCKET: u32 = 0o140000;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Fifo,
+    Char,
+    Dir,
+    Block,
+    Regular,
+    NetworkSpecial,
+    Symlink,
+    Socket,
+    Unknown(u32),
+}
+
+impl FileType {
+    pub fn from_mode(mode: u32) -> Self {
+        match mode & TYPE_MASK {
+            TYPE_FIFO => FileType::Fifo,
+            TYPE_CHAR => FileType::Char,
+            TYPE_DIR => FileType::Dir,
+            TYPE_BLOCK => FileType::Block,
+            TYPE_REGULAR => FileType::Regular,
+            TYPE_NETWORK_SPECIAL => FileType::NetworkSpecial,
+            TYPE_SYMLINK => FileType::Symlink,
+            TYPE_SOCKET => FileType::Socket,
+            other => FileType::Unknown(other),
+        }
+    }
+}
+
 fn read_hex_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
     let mut bytes = [0u8; 8];
     reader.read_exact(&mut bytes)?;
@@ -42,291 +151,1627 @@ fn read_hex_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
         })
 }
 
-impl Cpio {
+// PATH_MAX, also keeps garbage headers from allocating gigabytes
+const CPIO_MAX_NAME_LEN: usize = 4096;
+
+struct Header {
+    variant: CpioVariant,
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    file_size: u32,
+    rdev_major: u32,
+    rdev_minor: u32,
+    checksum: u32,
+    name: Vec<u8>,
+}
+
+// Reads a header and the name after it, the caller skips the padding
+fn read_header<R: Read>(reader: &mut R) -> Result<Header> {
+    let mut magic = [0u8; 6];
+    reader.read_exact(&mut magic)?;
+    let variant = match magic.as_slice() {
+        CPIO_NEWC_MAGIC => CpioVariant::Newc,
+        CPIO_NEWC_CRC_MAGIC => CpioVariant::NewcCrc,
+        _ => {
+            return Err(BootImgError::CpioFormat(
+                "unsupported cpio header".to_string(),
+            ));
+        }
+    };
+
+    let ino = read_hex_u32(reader)?;
+    let mode = read_hex_u32(reader)?;
+    let uid = read_hex_u32(reader)?;
+    let gid = read_hex_u32(reader)?;
+    let nlink = read_hex_u32(reader)?;
+    let mtime = read_hex_u32(reader)?;
+    let file_size = read_hex_u32(reader)?;
+    let _dev_major = read_hex_u32(reader)?;
+    let _dev_minor = read_hex_u32(reader)?;
+    let rdev_major = read_hex_u32(reader)?;
+    let rdev_minor = read_hex_u32(reader)?;
+    let name_len = read_hex_u32(reader)? as usize;
+    let checksum = read_hex_u32(reader)?;
+
+    if name_len > CPIO_MAX_NAME_LEN {
+        return Err(BootImgError::CpioFormat(format!(
+            "entry name length {} is too long",
+            name_len
+        )));
+    }
+    // NUL-terminated name with length `name_len` (including NUL byte).
+    let mut name = vec![0u8; name_len];
+    reader.read_exact(&mut name)?;
+    if name.last() != Some(&0) {
+        return Err(BootImgError::CpioFormat(
+            "Entry name was not NUL-terminated".to_string(),
+        ));
+    }
+    name.pop();
+    while name.last() == Some(&0) {
+        name.pop();
+    }
+    Ok(Header {
+        variant,
+        ino,
+        mode,
+        uid,
+        gid,
+        nlink,
+        mtime,
+        file_size,
+        rdev_major,
+        rdev_minor,
+        checksum,
+        name,
+    })
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CpioLoadOptions {
+    // Checksum mismatches become warnings
+    pub lenient: bool,
+    // Only record where file data is, for listing archives too big to hold.
+    // Symlink targets are still read.
+    pub metadata_only: bool,
+    // Only zero padding may follow a trailer, instead of skipping anything up
+    // to the next magic
+    pub strict: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CpioStats {
+    pub entries: usize,
+    // Data of all entries, symlink targets included
+    pub data_bytes: u64,
+    // Regular file data under each top level directory, "" for the root
+    pub dir_bytes: BTreeMap<Vec<u8>, u64>,
+    // (name, size), biggest first
+    pub largest: Vec<(Vec<u8>, u64)>,
+    pub serialized_size: u64,
+}
+
+// One of several concatenated archives
+pub struct CpioSegment<'a> {
+    pub cpio: Cpio<'a>,
+    // The archive itself, up to the padded end of its trailer
+    pub range: Range<usize>,
+    // Bytes skipped before the next archive or the end of data
+    pub gap: Range<usize>,
+}
+
+// Hands out inodes from 300000 as mkbootfs does, skipping those in use
+struct InodeAllocator {
+    used: HashSet<u32>,
+    next: u32,
+}
+
+impl InodeAllocator {
+    fn new(used: HashSet<u32>) -> Self {
+        Self { used, next: 300000 }
+    }
+
+    fn alloc(&mut self) -> u32 {
+        while self.used.contains(&self.next) {
+            self.next += 1;
+        }
+        self.used.insert(self.next);
+        self.next
+    }
+}
+
+impl Default for Cpio<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Cpio<'a> {
     pub fn new() -> Self {
         Self {
             entries: BTreeMap::new(),
+            variant: CpioVariant::Newc,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn load_from_data(data: &'a [u8]) -> Result<Self> {
+        Self::load_from_data_with_options(data, &CpioLoadOptions::default())
+    }
+
+    // Concatenated archives are merged as the kernel unpacks them, later
+    // entries replace earlier ones. The variant of the first entry is kept for
+    // dump, archives may mix both. Data is always borrowed, metadata_only has
+    // no effect here.
+    pub fn load_from_data_with_options(data: &'a [u8], options: &CpioLoadOptions) -> Result<Self> {
+        let mut cpio = Cpio::new();
+        for (i, segment) in Self::load_segments(data, options)?.into_iter().enumerate() {
+            if i == 0 {
+                cpio.variant = segment.cpio.variant;
+            }
+            cpio.merge_segment(segment.cpio);
+        }
+        Ok(cpio)
+    }
+
+    // Every archive numbers its inodes on its own, mostly from 300000. Inodes
+    // already taken by earlier archives are renumbered so unrelated hardlinks
+    // don't end up sharing one.
+    fn merge_segment(&mut self, mut segment: Cpio<'a>) {
+        for name in segment.entries.keys() {
+            self.entries.remove(name);
+        }
+        let taken: HashSet<u32> = self.entries.values().filter_map(|it| it.ino).collect();
+        let own = segment.entries.values().filter_map(|it| it.ino);
+        let mut inodes = InodeAllocator::new(taken.iter().copied().chain(own).collect());
+        let mut renumbered = HashMap::new();
+        for entry in segment.entries.values_mut() {
+            if let Some(ino) = entry.ino
+                && taken.contains(&ino)
+            {
+                entry.ino = Some(*renumbered.entry(ino).or_insert_with(|| inodes.alloc()));
+            }
+        }
+        self.entries.append(&mut segment.entries);
+        self.warnings.append(&mut segment.warnings);
+    }
+
+    // Each concatenated archive on its own, such as the generic and vendor
+    // parts of a GKI ramdisk
+    pub fn load_segments(
+        data: &'a [u8],
+        options: &CpioLoadOptions,
+    ) -> Result<Vec<CpioSegment<'a>>> {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        loop {
+            let (cpio, end) = Self::load_archive(data, start, options.lenient)?;
+            let next = next_archive(data, end, options.strict)?;
+            segments.push(CpioSegment {
+                cpio,
+                range: start..end,
+                gap: end..next.unwrap_or(data.len()),
+            });
+            match next {
+                Some(next) => start = next,
+                None => return Ok(segments),
+            }
         }
     }
 
-    pub fn load_from_data(data: &[u8]) -> Result<Self> {
+    // Reads one archive from `start`, returning it with the offset after the
+    // trailer
+    fn load_archive(data: &'a [u8], start: usize, lenient: bool) -> Result<(Self, usize)> {
         let mut cpio = Cpio::new();
         let mut cursor = Cursor::new(data);
+        cursor.set_position(start as u64);
+        let mut first = true;
         loop {
-            let mut magic = [0u8; 6];
-            cursor.read_exact(&mut magic)?;
-            if magic.as_slice() != b"070701" {
-                bail!("unsupported cpio header")
-            }
-
-            let _ino = read_hex_u32(&mut cursor)?;
-            let mode = read_hex_u32(&mut cursor)?;
-            let uid = read_hex_u32(&mut cursor)?;
-            let gid = read_hex_u32(&mut cursor)?;
-            let _nlink = read_hex_u32(&mut cursor)?;
-            let _mtime = read_hex_u32(&mut cursor)?;
-            let file_size = read_hex_u32(&mut cursor)?;
-            let _dev_major = read_hex_u32(&mut cursor)?;
-            let _dev_minor = read_hex_u32(&mut cursor)?;
-            let rdev_major = read_hex_u32(&mut cursor)?;
-            let rdev_minor = read_hex_u32(&mut cursor)?;
-            let name_len = read_hex_u32(&mut cursor)? as usize;
-            let _checksum = read_hex_u32(&mut cursor)?;
-
-            // NUL-terminated name with length `name_len` (including NUL byte).
-            let mut name_bytes = vec![0u8; name_len];
-            cursor.read_exact(&mut name_bytes)?;
-            if name_bytes.last() != Some(&0) {
-                bail!("Entry name was not NUL-terminated")
-            }
-            name_bytes.pop();
-            while name_bytes.last() == Some(&0) {
-                name_bytes.pop();
-            }
-            let name = String::from_utf8(name_bytes)?;
+            let header = read_header(&mut cursor)?;
+            if first {
+                cpio.variant = header.variant;
+                first = false;
+            }
             cursor.set_position(align_to(cursor.position(), 4));
-            if name == "." || name == ".." {
+            if header.name == b"." || header.name == b".." {
                 continue;
             }
-            if name == "TRAILER!!!" {
-                match data[cursor.position() as usize..]
-                    .windows(6)
-                    .position(|h| h == b"070701")
-                {
-                    Some(x) => cursor.set_position(cursor.position() + x as u64),
-                    None => break,
-                }
-                continue;
+            if header.name == b"TRAILER!!!" {
+                let names: Vec<Vec<u8>> = cpio.entries.keys().cloned().collect();
+                cpio.link_hardlinks(names);
+                return Ok((cpio, (cursor.position() as usize).min(data.len())));
             }
-            let data = if file_size == 0 {
+            let data = if header.file_size == 0 {
                 None
             } else {
-                let mut file_data = vec![0u8; file_size as usize];
-                cursor.read_exact(&mut file_data)?;
+                let start = cursor.position() as usize;
+                let file_data = data
+                    .get(start..start + header.file_size as usize)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                cursor.set_position((start + file_data.len()) as u64);
                 Some(file_data)
             };
-            let entry = Box::new(CpioEntry {
-                mode,
-                uid,
-                gid,
-                rdev_major,
-                rdev_minor,
-                data: data.map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>),
-            });
-            cpio.entries.insert(name, entry);
+            if header.variant == CpioVariant::NewcCrc {
+                cpio.verify_checksum(&header, checksum(data.unwrap_or_default()), lenient)?;
+            }
+            let data = data.map(|d| EntryData::Memory(Cow::Borrowed(d)));
+            cpio.insert_loaded(header, data);
             cursor.set_position(align_to(cursor.position(), 4));
         }
-        Ok(cpio)
+    }
+
+    fn verify_checksum(&mut self, header: &Header, sum: u32, lenient: bool) -> Result<()> {
+        if sum == header.checksum {
+            return Ok(());
+        }
+        let msg = format!(
+            "checksum mismatch for {}: stored {:#x}, computed {:#x}",
+            String::from_utf8_lossy(&header.name),
+            header.checksum,
+            sum
+        );
+        if !lenient {
+            return Err(BootImgError::CpioFormat(msg));
+        }
+        self.warnings.push(msg);
+        Ok(())
+    }
+
+    // Hardlinked files carry their data on the last link only, the kernel links
+    // regular files by inode within one archive
+    fn link_hardlinks(&mut self, names: impl IntoIterator<Item = Vec<u8>>) {
+        let mut links = HashMap::<u32, Vec<Vec<u8>>>::new();
+        for name in names {
+            if let Some(entry) = self.entries.get(&name)
+                && entry.is_file()
+                && entry.nlink.is_some_and(|n| n > 1)
+                && let Some(ino) = entry.ino
+            {
+                links.entry(ino).or_default().push(name);
+            }
+        }
+        for names in links.into_values() {
+            let Some(data) = names
+                .iter()
+                .find_map(|name| self.entries[name].data.clone())
+            else {
+                continue;
+            };
+            for name in &names {
+                let entry = self.entries.get_mut(name).unwrap();
+                if entry.data.is_none() {
+                    entry.data = Some(data.clone());
+                }
+            }
+        }
+    }
+
+    // Finds regular files with identical contents. Borrowed duplicates then
+    // point at one copy of the data. With hardlinks they also become links to
+    // the same inode, written once by dump; changing the data of a link
+    // detaches it. Returns how many duplicates were found.
+    pub fn dedup(&mut self, hardlinks: bool) -> usize {
+        let mut groups = HashMap::<&[u8], Vec<Vec<u8>>>::new();
+        for (name, entry) in &self.entries {
+            if entry.is_file()
+                && let Some(data) = entry.data()
+                && !data.is_empty()
+            {
+                groups.entry(data).or_default().push(name.clone());
+            }
+        }
+        let mut groups: Vec<Vec<Vec<u8>>> = groups
+            .into_values()
+            .filter(|names| names.len() > 1)
+            .collect();
+        groups.sort();
+
+        let mut inodes =
+            InodeAllocator::new(self.entries.values().filter_map(|it| it.ino).collect());
+        let mut found = 0;
+        for names in groups {
+            found += names.len() - 1;
+            let first = &self.entries[&names[0]];
+            let shared = match &first.data {
+                Some(EntryData::Memory(Cow::Borrowed(data))) => Some(*data),
+                _ => None,
+            };
+            // A loaded inode may be shared with files outside the group
+            let ino = hardlinks.then(|| inodes.alloc());
+            for name in &names {
+                let entry = self.entries.get_mut(name).unwrap();
+                if let Some(data) = shared {
+                    entry.data = Some(EntryData::Memory(Cow::Borrowed(data)));
+                }
+                if let Some(ino) = ino {
+                    entry.ino = Some(ino);
+                    entry.nlink = Some(names.len() as u32);
+                }
+            }
+        }
+        found
+    }
+
+    fn insert_loaded(&mut self, header: Header, data: Option<EntryData<'a>>) {
+        let entry = Box::new(CpioEntry {
+            mode: header.mode,
+            uid: header.uid,
+            gid: header.gid,
+            rdev_major: header.rdev_major,
+            rdev_minor: header.rdev_minor,
+            ino: Some(header.ino),
+            nlink: Some(header.nlink),
+            mtime: header.mtime,
+            data,
+        });
+        self.entries.insert(header.name, entry);
     }
 
     pub fn dump(&self, mut output: &mut dyn Write) -> Result<()> {
         let mut pos = 0usize;
-        let mut inode = 300000i64;
+        // New entries get inodes that no loaded one uses
+        let mut inodes =
+            InodeAllocator::new(self.entries.values().filter_map(|it| it.ino).collect());
+
+        // Directories link to themselves, their parent and each subdirectory.
+        // Anything else keeps 1, the kernel treats regular files with more as
+        // hardlinks.
+        let mut subdirs = BTreeMap::<&[u8], u32>::new();
+        for (name, entry) in &self.entries {
+            if entry.is_dir() {
+                let parent = split_parent(name).map_or(&[][..], |(parent, _)| parent);
+                *subdirs.entry(parent).or_default() += 1;
+            }
+        }
 
+        let without_data = self.links_without_data();
         for (name, entry) in &self.entries {
+            let with_data = !without_data.contains(name.as_slice());
+            let nlink = entry.nlink.unwrap_or(if entry.is_dir() {
+                2 + subdirs.get(name.as_slice()).copied().unwrap_or(0)
+            } else {
+                1
+            });
+            let inode = entry.ino.unwrap_or_else(|| inodes.alloc());
+            pos += output.write_all_size(self.variant.magic())?;
             pos += output.write_all_size(
                 format!(
-                    "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+                    "{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
                     inode,
                     entry.mode,
                     entry.uid,
                     entry.gid,
-                    1,
-                    0,
-                    entry.len(),
+                    nlink,
+                    entry.mtime,
+                    if with_data { entry.len() } else { 0 },
                     0,
                     0,
                     entry.rdev_major,
                     entry.rdev_minor,
                     name.len() + 1,
-                    0
+                    match self.variant {
+                        CpioVariant::Newc => 0,
+                        CpioVariant::NewcCrc if !with_data => 0,
+                        CpioVariant::NewcCrc => {
+                            let mut sum = ChecksumWriter(0);
+                            entry.write_data(&mut sum)?;
+                            sum.0
+                        }
+                    }
                 ).as_bytes(),
             )?;
-            pos += output.write_all_size(name.as_bytes())?;
+            pos += output.write_all_size(name)?;
             pos += output.write_all_size(&[0])?;
             pos += output.write_zeros(align_to(pos, 4) - pos)?;
-            if let Some(data) = entry.data.as_ref() {
-                pos += output.write_all_size(data.as_ref().as_ref())?;
+            if with_data && entry.data.is_some() {
+                pos += entry.write_data(&mut output)? as usize;
                 pos += output.write_zeros(align_to(pos, 4) - pos)?;
             }
-            inode += 1;
         }
+        // Same trailer as GNU cpio and libarchive: everything zero but nlink
+        pos += output.write_all_size(self.variant.magic())?;
         pos += output.write_all_size(
-            format!("070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
-                    inode, 0o755, 0, 0, 1, 0, 0, 0, 0, 0, 0, 11, 0
-            ).as_bytes()
+            format!(
+                "{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+                0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 11, 0
+            )
+            .as_bytes(),
         )?;
         pos += output.write_all_size("TRAILER!!!\0".as_bytes())?;
         output.write_zeros(align_to(pos, 4) - pos)?;
         Ok(())
     }
 
+    // Only the last link of a hardlinked file carries the data
+    fn links_without_data(&self) -> HashSet<&[u8]> {
+        let mut last_links = HashMap::<u32, &[u8]>::new();
+        let mut links = Vec::new();
+        for (name, entry) in &self.entries {
+            if entry.is_file()
+                && entry.nlink.is_some_and(|n| n > 1)
+                && let Some(ino) = entry.ino
+            {
+                last_links.insert(ino, name);
+                links.push((ino, name.as_slice()));
+            }
+        }
+        links
+            .into_iter()
+            .filter(|(ino, name)| last_links[ino] != *name)
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    // Exactly how many bytes dump writes
+    pub fn serialized_size(&self) -> u64 {
+        let without_data = self.links_without_data();
+        let mut size = 0;
+        for (name, entry) in &self.entries {
+            size = align_to(size + 110 + name.len() as u64 + 1, 4);
+            if !without_data.contains(name.as_slice()) {
+                size = align_to(size + entry.len() as u64, 4);
+            }
+        }
+        align_to(size + 110 + "TRAILER!!!\0".len() as u64, 4)
+    }
+
+    // The largest `largest` files are listed, biggest first
+    pub fn stats(&self, largest: usize) -> CpioStats {
+        let mut stats = CpioStats {
+            entries: self.entries.len(),
+            serialized_size: self.serialized_size(),
+            ..Default::default()
+        };
+        let mut files = Vec::new();
+        for (name, entry) in &self.entries {
+            let len = entry.len() as u64;
+            stats.data_bytes += len;
+            if entry.is_file() {
+                let top = match name.iter().position(|&c| c == b'/') {
+                    Some(i) => &name[..i],
+                    None => &[][..],
+                };
+                *stats.dir_bytes.entry(top.to_vec()).or_default() += len;
+                files.push((name.clone(), len));
+            }
+        }
+        files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        files.truncate(largest);
+        stats.largest = files;
+        stats
+    }
+
+    pub fn get_variant(&self) -> CpioVariant {
+        self.variant
+    }
+
+    pub fn set_variant(&mut self, variant: CpioVariant) {
+        self.variant = variant;
+    }
+
+    pub fn get_warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // Drops loaded inodes, link counts and mtimes so the dump only depends on
+    // names, modes, owners and data
+    pub fn normalize(&mut self) {
+        for entry in self.entries.values_mut() {
+            entry.ino = None;
+            entry.nlink = None;
+            entry.mtime = 0;
+        }
+    }
+
     pub fn rm(&mut self, path: &str, recursive: bool) {
-        let path = norm_path(path);
+        self.remove_tree(norm_path(path).into_bytes(), recursive);
+    }
+
+    fn remove_tree(&mut self, path: Vec<u8>, recursive: bool) {
         self.entries.remove(&path);
         if recursive {
-            let path = path + "/";
-            self.entries
-                .retain(|k, _| if k.starts_with(&path) { false } else { true })
+            let prefix = [path.as_slice(), b"/"].concat();
+            self.entries.retain(|k, _| !k.starts_with(&prefix))
+        }
+    }
+
+    // Removes entries matching a shell-style pattern, see glob_match. With
+    // recursive, children of matched directories go as well. Returns how many
+    // entries were removed.
+    pub fn rm_glob(&mut self, pattern: &str, recursive: bool) -> usize {
+        let pattern = norm_path(pattern);
+        let matched: Vec<Vec<u8>> = self
+            .entries
+            .keys()
+            .filter(|name| glob_match(pattern.as_bytes(), name))
+            .cloned()
+            .collect();
+        let before = self.entries.len();
+        for name in matched {
+            self.remove_tree(name, recursive);
         }
+        before - self.entries.len()
     }
 
     pub fn exists(&self, path: &str) -> bool {
-        self.entries.contains_key(&norm_path(path))
+        self.entries.contains_key(norm_path(path).as_bytes())
     }
 
-    pub fn add(&mut self, path: &str, entry: CpioEntry) -> Result<()> {
+    pub fn add(&mut self, path: &str, entry: CpioEntry<'a>) -> Result<()> {
         if path.ends_with('/') {
-            bail!("path cannot end with / for add")
+            return Err(BootImgError::InvalidOperation(
+                "path cannot end with / for add".to_string(),
+            ));
+        }
+
+        self.entries
+            .insert(norm_path(path).into_bytes(), Box::new(entry));
+        Ok(())
+    }
+
+    // Like add, but creates missing parent directories with 0755 first
+    pub fn add_with_parents(&mut self, path: &str, entry: CpioEntry<'a>) -> Result<()> {
+        if let Some((parent, _)) = norm_path(path).rsplit_once('/') {
+            self.mkdirs(parent, 0o755)?;
         }
+        self.add(path, entry)
+    }
 
-        self.entries.insert(norm_path(path), Box::new(entry));
+    // mkdir -p, existing directories keep their mode
+    pub fn mkdirs(&mut self, path: &str, mode: u32) -> Result<()> {
+        let path = norm_path(path);
+        let mut end = 0;
+        while end < path.len() {
+            end = path[end + 1..]
+                .find('/')
+                .map_or(path.len(), |i| end + 1 + i);
+            let dir = &path[..end];
+            match self.entries.get(dir.as_bytes()) {
+                Some(entry) if entry.is_dir() => {}
+                Some(_) => {
+                    return Err(BootImgError::InvalidOperation(format!(
+                        "{} exists and is not a directory",
+                        dir
+                    )));
+                }
+                None => {
+                    self.entries
+                        .insert(dir.as_bytes().to_vec(), Box::new(CpioEntry::dir(mode)));
+                }
+            }
+        }
         Ok(())
     }
 
+    // Entries whose parent directory is missing or not a directory. add
+    // allows these, some overlays rely on the parents existing elsewhere.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for name in self.entries.keys() {
+            let Some((parent, _)) = split_parent(name) else {
+                continue;
+            };
+            let problem = match self.entries.get(parent) {
+                None => "is missing",
+                Some(entry) if !entry.is_dir() => "is not a directory",
+                _ => continue,
+            };
+            problems.push(format!(
+                "{}: parent {} {}",
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(parent),
+                problem
+            ));
+        }
+        problems
+    }
+
+    // Moving a directory takes its children along. That fails without changes
+    // if anything already exists at or below the destination.
     pub fn mv(&mut self, from: &str, to: &str) -> Result<()> {
-        let entry = self
+        let (from, to) = (norm_path(from).into_bytes(), norm_path(to).into_bytes());
+        let from_prefix = [from.as_slice(), b"/"].concat();
+        let is_dir = self.entries.get(&from).map_or_else(
+            || self.entries.keys().any(|k| k.starts_with(&from_prefix)),
+            |entry| entry.is_dir(),
+        );
+        if !is_dir {
+            let entry = self.entries.remove(&from).ok_or_else(|| {
+                BootImgError::NoSuchEntry(String::from_utf8_lossy(&from).into_owned())
+            })?;
+            self.entries.insert(to, entry);
+            return Ok(());
+        }
+        if from == to {
+            return Ok(());
+        }
+        let to_prefix = [to.as_slice(), b"/"].concat();
+        if to.starts_with(&from_prefix) {
+            return Err(BootImgError::InvalidOperation(format!(
+                "cannot move {} into itself",
+                String::from_utf8_lossy(&from)
+            )));
+        }
+        if let Some(conflict) = self
+            .entries
+            .keys()
+            .find(|k| **k == to || k.starts_with(&to_prefix))
+        {
+            return Err(BootImgError::InvalidOperation(format!(
+                "cannot move {} to {}: {} exists",
+                String::from_utf8_lossy(&from),
+                String::from_utf8_lossy(&to),
+                String::from_utf8_lossy(conflict)
+            )));
+        }
+        let moved: Vec<Vec<u8>> = self
             .entries
-            .remove(&norm_path(from))
-            .ok_or_else(|| anyhow!("No such entry {from}"))?;
-        self.entries.insert(norm_path(to), entry);
+            .keys()
+            .filter(|k| **k == from || k.starts_with(&from_prefix))
+            .cloned()
+            .collect();
+        for name in moved {
+            let entry = self.entries.remove(&name).unwrap();
+            self.entries
+                .insert([to.as_slice(), &name[from.len()..]].concat(), entry);
+        }
         Ok(())
     }
 
-    pub fn ls(&self, path: &str, recursive: bool) {
-        let path = norm_path(path);
-        let path = if path.is_empty() {
-            path
-        } else {
-            "/".to_string() + path.as_str()
-        };
+    // Skips names that are not UTF-8, ls_bytes lists everything
+    pub fn ls(&self, path: &str, recursive: bool) -> Vec<(&str, &CpioEntry<'a>)> {
+        self.ls_bytes(path.as_bytes(), recursive)
+            .into_iter()
+            .filter_map(|(name, entry)| str::from_utf8(name).ok().map(|name| (name, entry)))
+            .collect()
+    }
+
+    pub fn ls_bytes(&self, path: &[u8], recursive: bool) -> Vec<(&[u8], &CpioEntry<'a>)> {
+        let mut result = Vec::new();
+        let path: Vec<u8> = path
+            .split(|&c| c == b'/')
+            .filter(|x| !x.is_empty())
+            .flat_map(|x| [b"/".as_slice(), x])
+            .flatten()
+            .copied()
+            .collect();
         for (name, entry) in &self.entries {
-            let p = "/".to_string() + name.as_str();
-            let Some(p) = p.strip_prefix(&path) else {
+            let p = [b"/".as_slice(), name].concat();
+            let Some(p) = p.strip_prefix(path.as_slice()) else {
                 continue;
             };
-            if !p.is_empty() && !p.starts_with('/') {
+            if !p.is_empty() && !p.starts_with(b"/") {
                 continue;
             }
-            if !recursive && !p.is_empty() && p.matches('/').count() > 1 {
+            if !recursive && !p.is_empty() && p.iter().filter(|&&c| c == b'/').count() > 1 {
                 continue;
             }
-            println!("{entry}\t{name}");
+            result.push((name.as_slice(), entry.deref()));
         }
+        result
     }
 
-    pub fn entries(&self) -> &BTreeMap<String, Box<CpioEntry>> {
-        &self.entries
+    // Entries of `other` replace existing entries with the same path
+    pub fn merge(&mut self, other: Cpio<'a>) {
+        self.entries.extend(other.entries);
     }
 
-    pub fn entry_by_name(&self, name: &str) -> Option<&CpioEntry> {
-        self.entries.get(name).map(|x| x.deref())
+    pub fn entries(&self) -> &BTreeMap<Vec<u8>, Box<CpioEntry<'a>>> {
+        &self.entries
     }
-}
 
-impl Cpio {
-    pub fn is_magisk_patched(&self) -> bool {
-        for file in [
-            ".backup/.magisk",
-            "init.magisk.rc",
-            "overlay/init.magisk.rc",
-        ] {
-            if self.exists(file) {
-                return true;
-            }
-        }
-        false
+    pub fn entry_by_name(&self, name: &str) -> Option<&CpioEntry<'a>> {
+        self.entry_by_name_bytes(name.as_bytes())
     }
-}
 
-impl Display for CpioEntry {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}{}{}{}{}{}{}{}{}{}\t{}\t{}\t{}\t{}:{}",
-            match self.mode & TYPE_MASK {
-                TYPE_DIR => "d",
-                TYPE_REGULAR => "-",
-                TYPE_SYMLINK => "l",
-                TYPE_BLOCK => "b",
-                TYPE_CHAR => "c",
-                _ => "?",
-            },
-            if self.mode & 0o400 != 0 { "r" } else { "-" },
-            if self.mode & 0o200 != 0 { "w" } else { "-" },
-            if self.mode & 0o100 != 0 { "x" } else { "-" },
-            if self.mode & 0o040 != 0 { "r" } else { "-" },
-            if self.mode & 0o020 != 0 { "w" } else { "-" },
-            if self.mode & 0o010 != 0 { "x" } else { "-" },
-            if self.mode & 0o004 != 0 { "r" } else { "-" },
-            if self.mode & 0o002 != 0 { "w" } else { "-" },
-            if self.mode & 0o001 != 0 { "x" } else { "-" },
-            self.uid,
-            self.gid,
-            self.len(),
-            self.rdev_major,
-            self.rdev_minor,
-        )
+    pub fn entry_by_name_bytes(&self, name: &[u8]) -> Option<&CpioEntry<'a>> {
+        self.entries.get(name).map(|x| x.deref())
     }
-}
 
-#[inline(always)]
-fn norm_path(path: &str) -> String {
-    Itertools::intersperse(path.split('/').filter(|x| !x.is_empty()), "/").collect()
-}
+    pub fn entry_by_name_mut(&mut self, name: &str) -> Option<&mut CpioEntry<'a>> {
+        self.entry_by_name_bytes_mut(name.as_bytes())
+    }
 
-impl CpioEntry {
-    pub fn len(&self) -> usize {
-        self.data
-            .as_ref()
-            .map(|d| d.as_ref().as_ref().len())
-            .unwrap_or(0)
+    pub fn entry_by_name_bytes_mut(&mut self, name: &[u8]) -> Option<&mut CpioEntry<'a>> {
+        self.entries.get_mut(name).map(|x| x.deref_mut())
     }
 
-    pub fn data(&self) -> Option<&[u8]> {
-        self.data.as_ref().map(|x| x.deref().as_ref())
+    // Runs f on the contents of a regular file and stores the result back
+    pub fn patch_file(&mut self, path: &str, f: impl FnOnce(&mut Vec<u8>)) -> Result<()> {
+        self.patch_file_bytes(norm_path(path).as_bytes(), f)
     }
 
-    pub fn regular(mode: u32, data: Box<dyn AsRef<[u8]>>) -> Self {
-        Self {
-            mode: mode | TYPE_REGULAR,
-            uid: 0,
-            gid: 0,
-            rdev_major: 0,
-            rdev_minor: 0,
-            data: Some(data),
+    pub fn patch_file_bytes(&mut self, name: &[u8], f: impl FnOnce(&mut Vec<u8>)) -> Result<()> {
+        let entry = self
+            .entries
+            .get_mut(name)
+            .ok_or_else(|| BootImgError::NoSuchEntry(String::from_utf8_lossy(name).into_owned()))?;
+        if !entry.is_file() {
+            return Err(BootImgError::InvalidOperation(format!(
+                "{} is not a regular file",
+                String::from_utf8_lossy(name)
+            )));
         }
+        let mut data = Vec::with_capacity(entry.len());
+        entry.write_data(&mut data)?;
+        f(&mut data);
+        self.set_data_bytes(name, data)
     }
 
-    pub fn dir(mode: u32) -> Self {
-        Self {
-            mode: mode | TYPE_DIR,
-            uid: 0,
-            gid: 0,
-            rdev_major: 0,
-            rdev_minor: 0,
-            data: None,
+    // Replaces the data of a regular file. A hardlink is detached first and
+    // the remaining links of its inode lose one link.
+    pub fn set_data(&mut self, path: &str, data: impl Into<Cow<'a, [u8]>>) -> Result<()> {
+        self.set_data_bytes(norm_path(path).as_bytes(), data)
+    }
+
+    pub fn set_data_bytes(&mut self, name: &[u8], data: impl Into<Cow<'a, [u8]>>) -> Result<()> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| BootImgError::NoSuchEntry(String::from_utf8_lossy(name).into_owned()))?;
+        if entry.is_file()
+            && entry.nlink.is_some_and(|n| n > 1)
+            && let Some(ino) = entry.ino
+        {
+            for (other, entry) in self.entries.iter_mut() {
+                if other != name && entry.is_file() && entry.ino == Some(ino) {
+                    entry.nlink = entry.nlink.map(|n| n.saturating_sub(1));
+                }
+            }
         }
+        self.entries.get_mut(name).unwrap().set_data(data);
+        Ok(())
     }
 
-    pub fn symlink(mode: u32, src: &str) -> Self {
-        Self {
-            mode: mode | TYPE_SYMLINK,
-            uid: 0,
-            gid: 0,
-            rdev_major: 0,
-            rdev_minor: 0,
-            data: Some(Box::new(norm_path(src).as_bytes().to_vec())),
+    // Copies borrowed file data so the archive outlives its source buffer
+    pub fn into_owned(self) -> Cpio<'static> {
+        Cpio {
+            entries: self
+                .entries
+                .into_iter()
+                .map(|(name, entry)| (name, Box::new(entry.into_owned())))
+                .collect(),
+            variant: self.variant,
+            warnings: self.warnings,
         }
     }
+}
 
-    pub fn char(mode: u32, rdev_major: u32, rdev_minor: u32) -> Self {
+impl Cpio<'static> {
+    // Parses the archive as it is read, file data is copied unless skipped with
+    // metadata_only. Unlike load_from_data, only zero padding may follow a
+    // trailer before the next archive.
+    pub fn load_from_reader<R: Read>(reader: R, options: &CpioLoadOptions) -> Result<Self> {
+        let mut cpio = Cpio::new();
+        let mut reader = CountingReader::new(BufReader::new(reader));
+        let mut first = true;
+        let mut archive_names = Vec::new();
+        loop {
+            let header = read_header(&mut reader)?;
+            if first {
+                cpio.variant = header.variant;
+                first = false;
+            }
+            skip_padding(&mut reader)?;
+            if header.name == b"." || header.name == b".." {
+                continue;
+            }
+            if header.name == b"TRAILER!!!" {
+                cpio.link_hardlinks(archive_names.drain(..));
+                // Zeros up to the next magic, or to the end
+                loop {
+                    match reader.fill_buf()?.first() {
+                        None => return Ok(cpio),
+                        Some(0) => reader.consume(1),
+                        Some(_) => break,
+                    }
+                }
+                continue;
+            }
+            let offset = reader.count();
+            let len = header.file_size as u64;
+            let mut sum = ChecksumWriter(0);
+            let data = if len == 0 {
+                None
+            } else if options.metadata_only && header.mode & TYPE_MASK != TYPE_SYMLINK {
+                let skipped = io::copy(&mut (&mut reader).take(len), &mut sum)?;
+                if skipped != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                Some(EntryData::Skipped { offset, len })
+            } else {
+                let mut file_data = Vec::new();
+                (&mut reader).take(len).read_to_end(&mut file_data)?;
+                if file_data.len() as u64 != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                sum.write_all(&file_data)?;
+                Some(EntryData::Memory(Cow::Owned(file_data)))
+            };
+            if header.variant == CpioVariant::NewcCrc {
+                cpio.verify_checksum(&header, sum.0, options.lenient)?;
+            }
+            archive_names.push(header.name.clone());
+            cpio.insert_loaded(header, data);
+            skip_padding(&mut reader)?;
+        }
+    }
+}
+
+fn skip_padding<R: Read>(reader: &mut CountingReader<R>) -> io::Result<()> {
+    let padding = align_to(reader.count(), 4) - reader.count();
+    io::copy(&mut reader.take(padding), &mut io::sink())?;
+    Ok(())
+}
+
+impl Cpio<'_> {
+    pub fn is_magisk_patched(&self) -> bool {
+        self.detect_patches()
+            .iter()
+            .any(|finding| finding.kind == PatchKind::Magisk)
+    }
+
+    // Everything that looks like a root solution or a modified boot flow, with
+    // the entry that gave it away
+    pub fn detect_patches(&self) -> Vec<PatchFinding> {
+        let mut findings = Vec::new();
+        let mut found = |kind, name: &[u8]| {
+            findings.push(PatchFinding {
+                kind,
+                path: String::from_utf8_lossy(name).into_owned(),
+            })
+        };
+        for (name, entry) in &self.entries {
+            let file_name = split_parent(name).map_or(name.as_slice(), |(_, file_name)| file_name);
+            if let Some(kind) = PATCH_EVIDENCE
+                .iter()
+                .find(|(_, pattern)| {
+                    let target = if pattern.contains('/') {
+                        name
+                    } else {
+                        file_name
+                    };
+                    glob_match(pattern.as_bytes(), target)
+                })
+                .map(|(kind, _)| *kind)
+            {
+                found(kind, name);
+            } else if name.starts_with(b"overlay.d/") && !entry.is_dir() {
+                found(PatchKind::Overlay, name);
+            }
+        }
+        if let Some(init) = self.entries.get(b"init".as_slice())
+            && init.is_symlink()
+            && !matches!(init.data(), Some(b"/system/bin/init" | b"system/bin/init"))
+        {
+            found(PatchKind::ModifiedInit, b"init");
+        }
+        findings
+    }
+
+    // Same as `magiskboot cpio <ramdisk> patch`, except that fstabs named by
+    // mount_all in init*.rc are patched as well. Returns what was changed.
+    pub fn magisk_patch(&mut self, options: &MagiskPatchOptions) -> Result<Vec<String>> {
+        let mut changes = Vec::new();
+        if !options.keep_verity || !options.keep_force_encrypt {
+            for name in self.fstab_names()? {
+                let mut removed = Vec::new();
+                self.patch_file_bytes(&name, |data| {
+                    if !options.keep_verity {
+                        removed.extend(remove_patterns(data, skip_verity_pattern));
+                    }
+                    if !options.keep_force_encrypt {
+                        removed.extend(remove_patterns(data, skip_encryption_pattern));
+                    }
+                })?;
+                changes.extend(removed.iter().map(|pattern| {
+                    format!(
+                        "{}: removed {}",
+                        String::from_utf8_lossy(&name),
+                        String::from_utf8_lossy(pattern)
+                    )
+                }));
+            }
+        }
+        if !options.keep_verity && self.entries.remove(b"verity_key".as_slice()).is_some() {
+            changes.push("removed verity_key".to_string());
+        }
+        Ok(changes)
+    }
+
+    // Top level fstab* files as magiskboot picks them, and whatever mount_all
+    // lines in init*.rc point to. ${...} properties match anything.
+    fn fstab_names(&self) -> Result<BTreeSet<Vec<u8>>> {
+        let is_regular = |entry: &CpioEntry| entry.is_file();
+        let mut names: BTreeSet<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|(name, entry)| name.starts_with(b"fstab") && is_regular(entry))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for (name, entry) in &self.entries {
+            let file_name = split_parent(name).map_or(name.as_slice(), |(_, file_name)| file_name);
+            if name.starts_with(b".backup")
+                || !is_regular(entry)
+                || !glob_match(b"init*.rc", file_name)
+            {
+                continue;
+            }
+            let mut rc = Vec::with_capacity(entry.len());
+            entry.write_data(&mut rc)?;
+            for line in rc.split(|&c| c == b'\n') {
+                let mut words = line
+                    .split(|c| c.is_ascii_whitespace())
+                    .filter(|word| !word.is_empty());
+                if words.next() != Some(b"mount_all".as_slice()) {
+                    continue;
+                }
+                let Some(path) = words.find(|word| !word.starts_with(b"--")) else {
+                    continue;
+                };
+                let pattern = property_pattern(path);
+                names.extend(
+                    self.entries
+                        .iter()
+                        .filter(|(name, entry)| {
+                            !name.starts_with(b".backup")
+                                && is_regular(entry)
+                                && glob_match(&pattern, name)
+                        })
+                        .map(|(name, _)| name.clone()),
+                );
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PatchKind {
+    Magisk,
+    KernelSu,
+    APatch,
+    // Files in overlay.d that no known tool accounts for
+    Overlay,
+    // init is a symlink to something other than the system init
+    ModifiedInit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PatchFinding {
+    pub kind: PatchKind,
+    pub path: String,
+}
+
+// Checked in order, glob patterns as in rm_glob. Patterns without a / match
+// the file name at any depth.
+const PATCH_EVIDENCE: &[(PatchKind, &str)] = &[
+    (PatchKind::Magisk, ".backup/.magisk"),
+    (PatchKind::Magisk, ".backup/init"),
+    (PatchKind::Magisk, "init.magisk.rc"),
+    (PatchKind::Magisk, "overlay.d/sepolicy.rules"),
+    (PatchKind::Magisk, "overlay.d/sbin/magisk*"),
+    (PatchKind::Magisk, "overlay.d/sbin/stub.xz"),
+    (PatchKind::Magisk, "overlay.d/sbin/init-ld*"),
+    // LKM mode, init is replaced by ksuinit which loads the module
+    (PatchKind::KernelSu, "kernelsu.ko"),
+    (PatchKind::KernelSu, "ksuinit"),
+    (PatchKind::KernelSu, "ksud"),
+    // APatch patches the kernel, these only show up if its tools were added
+    (PatchKind::APatch, "apd"),
+    (PatchKind::APatch, "kpimg"),
+];
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EntryChanges {
+    // File type
+    pub kind: bool,
+    // Permission bits
+    pub mode: bool,
+    pub owner: bool,
+    pub device: bool,
+    pub data: bool,
+}
+
+impl EntryChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == EntryChanges::default()
+    }
+}
+
+impl Display for EntryChanges {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (self.kind, "type"),
+            (self.mode, "mode"),
+            (self.owner, "owner"),
+            (self.device, "device"),
+            (self.data, "data"),
+        ];
+        let changed = names
+            .iter()
+            .filter(|(changed, _)| *changed)
+            .map(|(_, name)| *name);
+        f.write_str(&Itertools::intersperse(changed, ", ").collect::<String>())
+    }
+}
+
+// From one archive to another, paths in archive order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpioDiff {
+    pub added: Vec<Vec<u8>>,
+    pub removed: Vec<Vec<u8>>,
+    pub modified: Vec<(Vec<u8>, EntryChanges)>,
+}
+
+impl CpioDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+// One line per path: + added, - removed, M modified with what changed
+impl Display for CpioDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines: Vec<(&[u8], char, Option<&EntryChanges>)> = Vec::new();
+        lines.extend(self.added.iter().map(|name| (name.as_slice(), '+', None)));
+        lines.extend(self.removed.iter().map(|name| (name.as_slice(), '-', None)));
+        lines.extend(
+            self.modified
+                .iter()
+                .map(|(name, changes)| (name.as_slice(), 'M', Some(changes))),
+        );
+        lines.sort_by_key(|(name, _, _)| *name);
+        for (name, mark, changes) in lines {
+            write!(f, "{} {}", mark, String::from_utf8_lossy(name))?;
+            match changes {
+                Some(changes) => writeln!(f, " ({})", changes)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Cpio<'_> {
+    // self is the old archive, other the new one
+    pub fn diff(&self, other: &Cpio) -> Result<CpioDiff> {
+        self.diff_with_options(other, false)
+    }
+
+    // content_only ignores permission bits and owners, for vendors that
+    // change them between builds
+    pub fn diff_with_options(&self, other: &Cpio, content_only: bool) -> Result<CpioDiff> {
+        let mut diff = CpioDiff::default();
+        for (name, entry) in &self.entries {
+            let Some(new) = other.entries.get(name) else {
+                diff.removed.push(name.clone());
+                continue;
+            };
+            let mut changes = entry.changes(new)?;
+            if content_only {
+                changes.mode = false;
+                changes.owner = false;
+            }
+            if !changes.is_empty() {
+                diff.modified.push((name.clone(), changes));
+            }
+        }
+        diff.added = other
+            .entries
+            .keys()
+            .filter(|name| !self.entries.contains_key(*name))
+            .cloned()
+            .collect();
+        Ok(diff)
+    }
+}
+
+impl<'a> Cpio<'a> {
+    // Stores what changed since original under .backup/ like magiskboot: the
+    // original of every changed or removed entry, and the names of added
+    // entries NUL-separated in .backup/.rmlist. Existing backups are replaced.
+    pub fn create_backup(&mut self, original: &Cpio) -> Result<Vec<String>> {
+        let mut changes = Vec::new();
+        self.rm(BACKUP_DIR, true);
+        let mut backups = BTreeMap::new();
+        for (name, entry) in &original.entries {
+            if is_backup_name(name) {
+                continue;
+            }
+            let unchanged = match self.entries.get(name) {
+                Some(current) => current.changes(entry)?.is_empty(),
+                None => false,
+            };
+            if !unchanged {
+                let backup = [BACKUP_DIR.as_bytes(), b"/", name].concat();
+                changes.push(format!(
+                    "backup {} -> {}",
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(&backup)
+                ));
+                backups.insert(backup, Box::new(entry.to_owned_entry()));
+            }
+        }
+        let mut rm_list = Vec::new();
+        for name in self.entries.keys() {
+            if !original.entries.contains_key(name) {
+                changes.push(format!(
+                    "record new entry {}",
+                    String::from_utf8_lossy(name)
+                ));
+                rm_list.extend_from_slice(name);
+                rm_list.push(0);
+            }
+        }
+        self.entries
+            .insert(BACKUP_DIR.as_bytes().to_vec(), Box::new(CpioEntry::dir(0)));
+        if !rm_list.is_empty() {
+            self.entries.insert(
+                BACKUP_RMLIST.as_bytes().to_vec(),
+                Box::new(CpioEntry::regular(0, rm_list)),
+            );
+        }
+        self.entries.extend(backups);
+        Ok(changes)
+    }
+
+    // Undoes create_backup and drops the backup entries. Backups compressed
+    // with xz by magiskboot are decompressed.
+    pub fn restore(&mut self) -> Result<Vec<String>> {
+        self.restore_with_options(&ParseOptions::default())
+    }
+
+    // Decompressing a backup past options.max_decompressed_size fails with
+    // DecompressLimitExceeded
+    pub fn restore_with_options(&mut self, options: &ParseOptions) -> Result<Vec<String>> {
+        let mut changes = Vec::new();
+        let prefix = [BACKUP_DIR.as_bytes(), b"/"].concat();
+        let names: Vec<Vec<u8>> = self
+            .entries
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .cloned()
+            .collect();
+        let mut rm_list = Vec::new();
+        let mut restored = BTreeMap::new();
+        for name in names {
+            let mut entry = self.entries.remove(&name).unwrap();
+            if name == BACKUP_RMLIST.as_bytes() {
+                entry.write_data(&mut rm_list)?;
+                continue;
+            }
+            if name == BACKUP_MAGISK_CONFIG.as_bytes() {
+                continue;
+            }
+            let mut target = &name[prefix.len()..];
+            if let Some(stem) = target.strip_suffix(b".xz")
+                && let Some(data) = entry.data()
+                && parse_compress_format(data) == CompressFormat::XZ
+            {
+                let mut decompressed = Vec::new();
+                options
+                    .strict_decoder(CompressFormat::XZ, data)?
+                    .read_to_end(&mut decompressed)?;
+                entry.set_data(decompressed);
+                target = stem;
+            }
+            changes.push(format!(
+                "restore {} -> {}",
+                String::from_utf8_lossy(&name),
+                String::from_utf8_lossy(target)
+            ));
+            restored.insert(target.to_vec(), entry);
+        }
+        self.entries.remove(BACKUP_DIR.as_bytes());
+        // magiskboot treats a backup without anything in it as a ramdisk it
+        // created from nothing
+        if rm_list.is_empty() && restored.is_empty() {
+            self.entries.clear();
+            return Ok(changes);
+        }
+        for name in rm_list.split(|&c| c == 0).filter(|name| !name.is_empty()) {
+            changes.push(format!("remove {}", String::from_utf8_lossy(name)));
+            self.entries.remove(name);
+        }
+        self.entries.extend(restored);
+        Ok(changes)
+    }
+}
+
+const BACKUP_DIR: &str = ".backup";
+const BACKUP_RMLIST: &str = ".backup/.rmlist";
+const BACKUP_MAGISK_CONFIG: &str = ".backup/.magisk";
+
+fn is_backup_name(name: &[u8]) -> bool {
+    name == BACKUP_DIR.as_bytes()
+        || name
+            .strip_prefix(BACKUP_DIR.as_bytes())
+            .is_some_and(|rest| rest.starts_with(b"/"))
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MagiskPatchOptions {
+    // KEEPVERITY, otherwise verify/avb flags and verity_key are removed
+    pub keep_verity: bool,
+    // KEEPFORCEENCRYPT, otherwise forceencrypt/fileencryption flags are removed
+    pub keep_force_encrypt: bool,
+}
+
+// A mount_all path as an archive name pattern, ${ro.hardware} and the like
+// become *
+fn property_pattern(path: &[u8]) -> Vec<u8> {
+    let mut pattern = Vec::new();
+    let mut rest = path.strip_prefix(b"/").unwrap_or(path);
+    while let Some(start) = rest.windows(2).position(|w| w == b"${") {
+        pattern.extend_from_slice(&rest[..start]);
+        pattern.push(b'*');
+        rest = match rest[start..].iter().position(|&c| c == b'}') {
+            Some(end) => &rest[start + end + 1..],
+            None => &[],
+        };
+    }
+    pattern.extend_from_slice(rest);
+    pattern
+}
+
+fn is_fstab_delimiter(c: Option<&u8>) -> bool {
+    matches!(c, None | Some(b'\0' | b' ' | b'\n' | b','))
+}
+
+fn skip_to_delimiter(s: &[u8], mut skip: usize) -> usize {
+    while !is_fstab_delimiter(s.get(skip)) {
+        skip += 1;
+    }
+    skip
+}
+
+// The patterns magiskboot removes, matched anywhere in the file with an
+// optional leading comma. Returns how many bytes to drop.
+fn skip_verity_pattern(s: &[u8]) -> Option<usize> {
+    let mut skip = usize::from(s.first() == Some(&b','));
+    let rest = &s[skip..];
+    if rest.starts_with(b"verifyatboot") {
+        skip += 12;
+    } else if rest.starts_with(b"verify") {
+        skip += 6;
+    } else if rest.starts_with(b"avb_keys") {
+        skip = skip_to_delimiter(s, skip + 8);
+    } else if rest.starts_with(b"avb") {
+        skip += 3;
+    } else if rest.starts_with(b"support_scfs") {
+        skip += 12;
+    } else if rest.starts_with(b"fsverity") {
+        skip += 8;
+    } else {
+        return None;
+    }
+    if s.get(skip) == Some(&b'=') {
+        skip = skip_to_delimiter(s, skip);
+    }
+    Some(skip)
+}
+
+fn skip_encryption_pattern(s: &[u8]) -> Option<usize> {
+    let mut skip = usize::from(s.first() == Some(&b','));
+    let rest = &s[skip..];
+    if rest.starts_with(b"forceencrypt") {
+        skip += 12;
+    } else if rest.starts_with(b"forcefdeorfbe") {
+        skip += 13;
+    } else if rest.starts_with(b"fileencryption") {
+        skip += 14;
+    } else {
+        return None;
+    }
+    Some(skip_to_delimiter(s, skip))
+}
+
+fn remove_patterns(data: &mut Vec<u8>, skip: fn(&[u8]) -> Option<usize>) -> Vec<Vec<u8>> {
+    let mut removed = Vec::new();
+    let mut result = Vec::with_capacity(data.len());
+    let mut read = 0;
+    while read < data.len() {
+        match skip(&data[read..]) {
+            Some(len) => {
+                removed.push(data[read..read + len].to_vec());
+                read += len;
+            }
+            None => {
+                result.push(data[read]);
+                read += 1;
+            }
+        }
+    }
+    *data = result;
+    removed
+}
+
+impl Display for CpioEntry<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}{}{}{}{}{}{}{}\t{}\t{}\t{}\t{}:{}",
+            match self.mode & TYPE_MASK {
+                TYPE_DIR => "d",
+                TYPE_REGULAR => "-",
+                TYPE_SYMLINK => "l",
+                TYPE_BLOCK => "b",
+                TYPE_CHAR => "c",
+                _ => "?",
+            },
+            if self.mode & 0o400 != 0 { "r" } else { "-" },
+            if self.mode & 0o200 != 0 { "w" } else { "-" },
+            if self.mode & 0o100 != 0 { "x" } else { "-" },
+            if self.mode & 0o040 != 0 { "r" } else { "-" },
+            if self.mode & 0o020 != 0 { "w" } else { "-" },
+            if self.mode & 0o010 != 0 { "x" } else { "-" },
+            if self.mode & 0o004 != 0 { "r" } else { "-" },
+            if self.mode & 0o002 != 0 { "w" } else { "-" },
+            if self.mode & 0o001 != 0 { "x" } else { "-" },
+            self.uid,
+            self.gid,
+            self.len(),
+            self.rdev_major,
+            self.rdev_minor,
+        )
+    }
+}
+
+// Where the archive after a trailer at `pos` starts, if any
+fn next_archive(data: &[u8], pos: usize, strict: bool) -> Result<Option<usize>> {
+    let rest = &data[pos..];
+    if strict {
+        return match rest.iter().position(|&c| c != 0) {
+            None => Ok(None),
+            Some(x)
+                if rest[x..].starts_with(CPIO_NEWC_MAGIC)
+                    || rest[x..].starts_with(CPIO_NEWC_CRC_MAGIC) =>
+            {
+                Ok(Some(pos + x))
+            }
+            Some(x) => Err(BootImgError::CpioFormat(format!(
+                "unexpected data after trailer at offset {:#x}",
+                pos + x
+            ))),
+        };
+    }
+    Ok(rest
+        .windows(6)
+        .position(|h| h == CPIO_NEWC_MAGIC || h == CPIO_NEWC_CRC_MAGIC)
+        .map(|x| pos + x))
+}
+
+// Shell-style matching on whole paths: `*` is any run of characters and `?`
+// any single character, neither crossing a `/`. `[abc]`, `[a-z]` and `[!a-z]`
+// match one character from (or not from) a set.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => {
+            for i in 0..=name.len() {
+                if glob_match(rest, &name[i..]) {
+                    return true;
+                }
+                if name.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some((b'?', rest)) => {
+            matches!(name.split_first(), Some((c, name)) if *c != b'/' && glob_match(rest, name))
+        }
+        Some((b'[', rest)) => {
+            let Some(end) = rest.iter().skip(1).position(|&c| c == b']').map(|i| i + 1) else {
+                return name.first() == Some(&b'[') && glob_match(rest, &name[1..]);
+            };
+            let Some((&c, name)) = name.split_first() else {
+                return false;
+            };
+            let (negate, set) = match &rest[..end] {
+                [b'!', set @ ..] => (true, set),
+                set => (false, set),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == b'-' {
+                    found |= (set[i]..=set[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            c != b'/' && found != negate && glob_match(&rest[end + 1..], name)
+        }
+        Some((&p, rest)) => name.first() == Some(&p) && glob_match(rest, &name[1..]),
+    }
+}
+
+fn split_parent(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    let i = name.iter().rposition(|&c| c == b'/')?;
+    Some((&name[..i], &name[i + 1..]))
+}
+
+#[inline(always)]
+fn norm_path(path: &str) -> String {
+    Itertools::intersperse(path.split('/').filter(|x| !x.is_empty()), "/").collect()
+}
+
+impl<'a> CpioEntry<'a> {
+    pub fn len(&self) -> usize {
+        match &self.data {
+            None => 0,
+            Some(EntryData::Memory(data)) => data.len(),
+            #[cfg(feature = "std-fs")]
+            Some(EntryData::File { len, .. }) => *len as usize,
+            Some(EntryData::Skipped { len, .. }) => *len as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // None for files added from a directory, which stay on disk until written
+    pub fn data(&self) -> Option<&[u8]> {
+        match &self.data {
+            Some(EntryData::Memory(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn write_data(&self, output: &mut dyn Write) -> Result<u64> {
+        match &self.data {
+            None => Ok(0),
+            Some(EntryData::Memory(data)) => {
+                output.write_all(data)?;
+                Ok(data.len() as u64)
+            }
+            Some(EntryData::Skipped { .. }) => Err(BootImgError::InvalidOperation(
+                "entry data was not loaded".to_string(),
+            )),
+            #[cfg(feature = "std-fs")]
+            Some(EntryData::File { path, len }) => {
+                let copied = io::copy(&mut File::open(path)?.take(*len), output)?;
+                if copied != *len {
+                    return Err(BootImgError::InvalidOperation(format!(
+                        "{} shrank since it was added",
+                        path.display()
+                    )));
+                }
+                Ok(copied)
+            }
+        }
+    }
+
+    fn to_owned_entry(&self) -> CpioEntry<'static> {
+        CpioEntry {
+            data: self.data.as_ref().map(|data| match data {
+                EntryData::Memory(data) => EntryData::Memory(Cow::Owned(data.to_vec())),
+                #[cfg(feature = "std-fs")]
+                EntryData::File { path, len } => EntryData::File {
+                    path: path.clone(),
+                    len: *len,
+                },
+                EntryData::Skipped { offset, len } => EntryData::Skipped {
+                    offset: *offset,
+                    len: *len,
+                },
+            }),
+            ..*self
+        }
+    }
+
+    // What differs from other, inode numbers, link counts and mtimes aside
+    pub fn changes(&self, other: &CpioEntry) -> Result<EntryChanges> {
+        Ok(EntryChanges {
+            kind: self.mode & TYPE_MASK != other.mode & TYPE_MASK,
+            mode: self.mode & !TYPE_MASK != other.mode & !TYPE_MASK,
+            owner: (self.uid, self.gid) != (other.uid, other.gid),
+            device: (self.rdev_major, self.rdev_minor) != (other.rdev_major, other.rdev_minor),
+            data: !self.same_data(other)?,
+        })
+    }
+
+    fn same_data(&self, other: &CpioEntry) -> Result<bool> {
+        if self.len() != other.len() {
+            return Ok(false);
+        }
+        if let (Some(a), Some(b)) = (self.data(), other.data()) {
+            return Ok(a == b);
+        }
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        self.write_data(&mut a)?;
+        other.write_data(&mut b)?;
+        Ok(a == b)
+    }
+
+    // Where the data starts in the stream, for entries loaded metadata only
+    pub fn get_data_offset(&self) -> Option<u64> {
+        match self.data {
+            Some(EntryData::Skipped { offset, .. }) => Some(offset),
+            _ => None,
+        }
+    }
+
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.data, Some(EntryData::Memory(Cow::Borrowed(_))))
+    }
+
+    pub fn into_owned(self) -> CpioEntry<'static> {
+        CpioEntry {
+            data: self.data.map(|d| match d {
+                EntryData::Memory(data) => EntryData::Memory(Cow::Owned(data.into_owned())),
+                #[cfg(feature = "std-fs")]
+                EntryData::File { path, len } => EntryData::File { path, len },
+                EntryData::Skipped { offset, len } => EntryData::Skipped { offset, len },
+            }),
+            ..self
+        }
+    }
+
+    pub fn regular(mode: u32, data: impl Into<Cow<'a, [u8]>>) -> Self {
+        Self {
+            mode: mode | TYPE_REGULAR,
+            uid: 0,
+            gid: 0,
+            rdev_major: 0,
+            rdev_minor: 0,
+            ino: None,
+            nlink: None,
+            mtime: 0,
+            data: Some(EntryData::Memory(data.into())),
+        }
+    }
+
+    pub fn dir(mode: u32) -> Self {
+        Self {
+            mode: mode | TYPE_DIR,
+            uid: 0,
+            gid: 0,
+            rdev_major: 0,
+            rdev_minor: 0,
+            ino: None,
+            nlink: None,
+            mtime: 0,
+            data: None,
+        }
+    }
+
+    pub fn symlink(mode: u32, src: &str) -> Self {
+        Self {
+            mode: mode | TYPE_SYMLINK,
+            uid: 0,
+            gid: 0,
+            rdev_major: 0,
+            rdev_minor: 0,
+            ino: None,
+            nlink: None,
+            mtime: 0,
+            data: Some(EntryData::Memory(Cow::Owned(norm_path(src).into_bytes()))),
+        }
+    }
+
+    pub fn char(mode: u32, rdev_major: u32, rdev_minor: u32) -> Self {
         Self {
             mode: mode | TYPE_CHAR,
             uid: 0,
             gid: 0,
             rdev_major,
             rdev_minor,
+            ino: None,
+            nlink: None,
+            mtime: 0,
             data: None,
         }
     }
@@ -338,4 +1783,976 @@ impl CpioEntry {
     pub fn gid(self, gid: u32) -> Self {
         Self { gid, ..self }
     }
+
+    pub fn mtime(self, mtime: u32) -> Self {
+        Self { mtime, ..self }
+    }
+
+    pub fn ino(self, ino: u32) -> Self {
+        Self {
+            ino: Some(ino),
+            ..self
+        }
+    }
+
+    pub fn nlink(self, nlink: u32) -> Self {
+        Self {
+            nlink: Some(nlink),
+            ..self
+        }
+    }
+
+    // Permission bits only, the file type stays
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = (self.mode & TYPE_MASK) | (mode & !TYPE_MASK);
+    }
+
+    pub fn set_uid(&mut self, uid: u32) {
+        self.uid = uid;
+    }
+
+    pub fn set_gid(&mut self, gid: u32) {
+        self.gid = gid;
+    }
+
+    // A hardlink is detached, Cpio::set_data also fixes the link count of the
+    // other links
+    pub fn set_data(&mut self, data: impl Into<Cow<'a, [u8]>>) {
+        if self.is_file() && self.nlink.is_some_and(|n| n > 1) {
+            self.ino = None;
+            self.nlink = None;
+        }
+        self.data = Some(EntryData::Memory(data.into()));
+    }
+
+    pub fn get_mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn get_uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn get_gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn get_mtime(&self) -> u32 {
+        self.mtime
+    }
+
+    pub fn get_ino(&self) -> Option<u32> {
+        self.ino
+    }
+
+    pub fn get_nlink(&self) -> Option<u32> {
+        self.nlink
+    }
+
+    // (major, minor)
+    pub fn get_rdev(&self) -> (u32, u32) {
+        (self.rdev_major, self.rdev_minor)
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & TYPE_MASK == TYPE_DIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & TYPE_MASK == TYPE_REGULAR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & TYPE_MASK == TYPE_SYMLINK
+    }
+
+    pub fn is_char(&self) -> bool {
+        self.mode & TYPE_MASK == TYPE_CHAR
+    }
+
+    // None for other types, or targets that are not UTF-8
+    pub fn symlink_target(&self) -> Option<&str> {
+        if !self.is_symlink() {
+            return None;
+        }
+        str::from_utf8(self.data()?).ok()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    // Create character and block devices, only possible as root
+    pub device_nodes: bool,
+}
+
+pub type ExcludeFilter = Box<dyn Fn(&str) -> bool>;
+
+#[derive(Default)]
+pub struct AddDirOptions {
+    // Keep on-disk owners instead of root
+    pub keep_owner: bool,
+    // Called with the archive path, excluded directories are not descended into
+    pub exclude: Option<ExcludeFilter>,
+}
+
+#[cfg(all(unix, feature = "std-fs"))]
+// Joins an entry name below dir, refusing anything that could leave it.
+// Leading slashes are dropped as cpio -i does.
+fn sanitize_entry_path(dir: &Path, name: &[u8]) -> Result<PathBuf> {
+    let error = |problem: &str| {
+        BootImgError::CpioFormat(format!(
+            "entry name {:?} {}",
+            String::from_utf8_lossy(name),
+            problem
+        ))
+    };
+    if name.contains(&0) {
+        return Err(error("contains NUL"));
+    }
+    let mut path = dir.to_path_buf();
+    let mut depth = 0;
+    for component in name.split(|&c| c == b'/') {
+        match component {
+            b"" | b"." => continue,
+            b".." => return Err(error("escapes the destination")),
+            _ => {
+                // An extracted symlink must not redirect later entries
+                if depth > 0 && fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink()) {
+                    return Err(error("goes through a symlink"));
+                }
+                path.push(OsStr::from_bytes(component));
+                depth += 1;
+            }
+        }
+    }
+    if depth == 0 {
+        return Err(error("is empty"));
+    }
+    Ok(path)
+}
+
+#[cfg(all(unix, feature = "std-fs"))]
+impl Cpio<'_> {
+    pub fn extract_to(&self, dir: &Path, options: &ExtractOptions) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        // Directories stay writable until their contents are in place
+        let mut dirs = Vec::new();
+        for (name, entry) in &self.entries {
+            let path = sanitize_entry_path(dir, name)?;
+            if entry.is_dir() {
+                if fs::symlink_metadata(&path).is_ok_and(|m| !m.is_dir()) {
+                    fs::remove_file(&path)?;
+                }
+                fs::create_dir_all(&path)?;
+                dirs.push((path, entry.mode));
+            } else {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                entry.extract(&String::from_utf8_lossy(name), &path, options)?;
+            }
+        }
+        for (path, mode) in dirs.iter().rev() {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        Ok(())
+    }
+
+    pub fn extract_entry(&self, name: &str, path: &Path, options: &ExtractOptions) -> Result<()> {
+        let entry = self
+            .entries
+            .get(norm_path(name).as_bytes())
+            .ok_or_else(|| BootImgError::NoSuchEntry(name.to_string()))?;
+        if entry.is_dir() {
+            fs::create_dir_all(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(entry.mode & 0o7777))?;
+            return Ok(());
+        }
+        entry.extract(name, path, options)
+    }
+
+    // Adds the contents of dir below prefix, file data is read on dump
+    pub fn add_from_dir(
+        &mut self,
+        prefix: &str,
+        dir: &Path,
+        options: &AddDirOptions,
+    ) -> Result<()> {
+        self.add_dir_contents(norm_path(prefix).as_bytes(), dir, options)
+    }
+
+    fn add_dir_contents(
+        &mut self,
+        prefix: &[u8],
+        dir: &Path,
+        options: &AddDirOptions,
+    ) -> Result<()> {
+        for item in fs::read_dir(dir)? {
+            let item = item?;
+            let file_name = item.file_name();
+            let name = if prefix.is_empty() {
+                file_name.as_bytes().to_vec()
+            } else {
+                [prefix, b"/", file_name.as_bytes()].concat()
+            };
+            if options
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude(&String::from_utf8_lossy(&name)))
+            {
+                continue;
+            }
+            let path = item.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            let mode = metadata.mode();
+            let data = match mode & TYPE_MASK {
+                TYPE_REGULAR => Some(EntryData::File {
+                    path: path.clone(),
+                    len: metadata.len(),
+                }),
+                TYPE_SYMLINK => Some(EntryData::Memory(Cow::Owned(
+                    fs::read_link(&path)?.into_os_string().into_vec(),
+                ))),
+                TYPE_DIR | TYPE_CHAR | TYPE_BLOCK | TYPE_FIFO => None,
+                _ => {
+                    warn!("skipping special file {}", path.display());
+                    continue;
+                }
+            };
+            let (uid, gid) = if options.keep_owner {
+                (metadata.uid(), metadata.gid())
+            } else {
+                (0, 0)
+            };
+            let rdev = metadata.rdev();
+            self.entries.insert(
+                name.clone(),
+                Box::new(CpioEntry {
+                    mode,
+                    uid,
+                    gid,
+                    rdev_major: libc::major(rdev as _) as u32,
+                    rdev_minor: libc::minor(rdev as _) as u32,
+                    ino: None,
+                    nlink: None,
+                    mtime: 0,
+                    data,
+                }),
+            );
+            if mode & TYPE_MASK == TYPE_DIR {
+                self.add_dir_contents(&name, &path, options)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, feature = "std-fs"))]
+impl CpioEntry<'_> {
+    fn extract(&self, name: &str, path: &Path, options: &ExtractOptions) -> Result<()> {
+        // Replace whatever is there, never write through an existing symlink
+        if fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+            fs::remove_file(path)?;
+        }
+        match self.mode & TYPE_MASK {
+            TYPE_REGULAR => {
+                self.write_data(&mut File::create(path)?)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(self.mode & 0o7777))?;
+            }
+            TYPE_SYMLINK => {
+                symlink(OsStr::from_bytes(self.data().unwrap_or_default()), path)?;
+            }
+            TYPE_CHAR | TYPE_BLOCK if options.device_nodes && unsafe { libc::geteuid() } == 0 => {
+                let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+                    BootImgError::CpioFormat(format!("entry name {:?} contains NUL", name))
+                })?;
+                let dev = libc::makedev(self.rdev_major as _, self.rdev_minor as _);
+                if unsafe { libc::mknod(c_path.as_ptr(), self.mode as libc::mode_t, dev) } != 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+                // mknod applies the umask
+                fs::set_permissions(path, fs::Permissions::from_mode(self.mode & 0o7777))?;
+            }
+            _ => warn!("skipping special file {}", name),
+        }
+        Ok(())
+    }
+}
+
+// Writes `value` NUL padded into a ustar field
+#[cfg(feature = "tar")]
+fn set_ustar_field(field: &mut [u8], value: &[u8]) -> bool {
+    if value.len() > field.len() {
+        return false;
+    }
+    field.fill(0);
+    field[..value.len()].copy_from_slice(value);
+    true
+}
+
+// Names over 100 bytes are split into prefix and name at a `/`
+#[cfg(feature = "tar")]
+fn set_ustar_path(header: &mut tar::Header, name: &[u8]) -> Result<()> {
+    let ustar = header.as_ustar_mut().unwrap();
+    if set_ustar_field(&mut ustar.name, name) {
+        return Ok(());
+    }
+    let fits = name
+        .iter()
+        .enumerate()
+        .filter(|&(_, &c)| c == b'/')
+        .map(|(i, _)| i)
+        .find(|&i| i <= ustar.prefix.len() && name.len() - i - 1 <= ustar.name.len());
+    match fits {
+        Some(i) => {
+            set_ustar_field(&mut ustar.prefix, &name[..i]);
+            set_ustar_field(&mut ustar.name, &name[i + 1..]);
+            Ok(())
+        }
+        None => Err(BootImgError::InvalidOperation(format!(
+            "{} is too long for a ustar header",
+            String::from_utf8_lossy(name)
+        ))),
+    }
+}
+
+#[cfg(feature = "tar")]
+impl Cpio<'_> {
+    // Every entry becomes a ustar entry, hardlinks are written as separate
+    // files. Sockets and names or link targets ustar cannot hold are errors.
+    pub fn to_tar(&self, output: &mut dyn Write) -> Result<()> {
+        let mut builder = tar::Builder::new(output);
+        for (name, entry) in &self.entries {
+            let mut header = tar::Header::new_ustar();
+            let entry_type = match entry.file_type() {
+                FileType::Regular => tar::EntryType::Regular,
+                FileType::Dir => tar::EntryType::Directory,
+                FileType::Symlink => tar::EntryType::Symlink,
+                FileType::Char => tar::EntryType::Char,
+                FileType::Block => tar::EntryType::Block,
+                FileType::Fifo => tar::EntryType::Fifo,
+                _ => {
+                    return Err(BootImgError::InvalidOperation(format!(
+                        "{} has a type tar cannot hold: {:o}",
+                        String::from_utf8_lossy(name),
+                        entry.mode & TYPE_MASK
+                    )));
+                }
+            };
+            if entry.is_dir() {
+                set_ustar_path(&mut header, &[name.as_slice(), b"/"].concat())?;
+            } else {
+                set_ustar_path(&mut header, name)?;
+            }
+            header.set_entry_type(entry_type);
+            header.set_mode(entry.mode & !TYPE_MASK);
+            header.set_uid(entry.uid as u64);
+            header.set_gid(entry.gid as u64);
+            header.set_mtime(entry.mtime as u64);
+            if matches!(entry_type, tar::EntryType::Char | tar::EntryType::Block) {
+                header.set_device_major(entry.rdev_major)?;
+                header.set_device_minor(entry.rdev_minor)?;
+            }
+            let mut data = Vec::new();
+            entry.write_data(&mut data)?;
+            if entry.is_symlink() {
+                if !set_ustar_field(&mut header.as_ustar_mut().unwrap().linkname, &data) {
+                    return Err(BootImgError::InvalidOperation(format!(
+                        "link target of {} is too long for a ustar header",
+                        String::from_utf8_lossy(name)
+                    )));
+                }
+                data.clear();
+            }
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data.as_slice())?;
+        }
+        builder.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tar")]
+impl Cpio<'static> {
+    // Reads ustar, GNU and pax archives. Leading `./` is dropped from names,
+    // hardlinks get a copy of the data of their target.
+    pub fn from_tar<R: Read>(reader: R) -> Result<Self> {
+        let mut cpio = Cpio::new();
+        let mut archive = tar::Archive::new(reader);
+        for file in archive.entries()? {
+            let mut file = file?;
+            let name = norm_path_bytes(&file.path_bytes());
+            if name.is_empty() {
+                continue;
+            }
+            let header = file.header();
+            let kind = match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::Link => {
+                    TYPE_REGULAR
+                }
+                tar::EntryType::Directory => TYPE_DIR,
+                tar::EntryType::Symlink => TYPE_SYMLINK,
+                tar::EntryType::Char => TYPE_CHAR,
+                tar::EntryType::Block => TYPE_BLOCK,
+                tar::EntryType::Fifo => TYPE_FIFO,
+                other => {
+                    log::warn!(
+                        "skipping {} of tar type {:?}",
+                        String::from_utf8_lossy(&name),
+                        other
+                    );
+                    continue;
+                }
+            };
+            let (rdev_major, rdev_minor) = if kind == TYPE_CHAR || kind == TYPE_BLOCK {
+                (
+                    header.device_major()?.unwrap_or(0),
+                    header.device_minor()?.unwrap_or(0),
+                )
+            } else {
+                (0, 0)
+            };
+            let to_u32 = |value: u64| {
+                u32::try_from(value).map_err(|_| {
+                    BootImgError::CpioFormat(format!(
+                        "{} has a value too large for cpio: {}",
+                        String::from_utf8_lossy(&name),
+                        value
+                    ))
+                })
+            };
+            let mut entry = CpioEntry {
+                mode: kind | (header.mode()? & !TYPE_MASK),
+                uid: to_u32(header.uid()?)?,
+                gid: to_u32(header.gid()?)?,
+                rdev_major,
+                rdev_minor,
+                ino: None,
+                nlink: None,
+                mtime: to_u32(header.mtime()?)?,
+                data: None,
+            };
+            let data = match header.entry_type() {
+                tar::EntryType::Symlink => file.link_name_bytes().map(|it| it.into_owned()),
+                tar::EntryType::Link => {
+                    let target = norm_path_bytes(&file.link_name_bytes().unwrap_or_default());
+                    let target = cpio.entries.get(&target).ok_or_else(|| {
+                        BootImgError::NoSuchEntry(String::from_utf8_lossy(&target).into_owned())
+                    })?;
+                    let mut data = Vec::new();
+                    target.write_data(&mut data)?;
+                    Some(data)
+                }
+                _ if kind == TYPE_REGULAR => {
+                    let mut data = Vec::new();
+                    file.read_to_end(&mut data)?;
+                    Some(data)
+                }
+                _ => None,
+            };
+            entry.data = data
+                .filter(|it| !it.is_empty())
+                .map(|it| EntryData::Memory(Cow::Owned(it)));
+            cpio.entries.insert(name, Box::new(entry));
+        }
+        Ok(cpio)
+    }
+}
+
+// Tar names often start with `./`
+#[cfg(feature = "tar")]
+fn norm_path_bytes(path: &[u8]) -> Vec<u8> {
+    path.split(|&c| c == b'/')
+        .filter(|it| !it.is_empty() && *it != b".")
+        .collect::<Vec<_>>()
+        .join(&b'/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(files: &[(&str, &[u8])]) -> Cpio<'static> {
+        let mut cpio = Cpio::new();
+        for (name, data) in files {
+            let entry = CpioEntry::regular(0o644, data.to_vec());
+            cpio.add_with_parents(name, entry).unwrap();
+        }
+        cpio
+    }
+
+    fn dump(cpio: &Cpio) -> Vec<u8> {
+        let mut data = Vec::new();
+        cpio.dump(&mut data).unwrap();
+        data
+    }
+
+    fn file<'a>(cpio: &'a Cpio, name: &str) -> &'a [u8] {
+        cpio.entry_by_name(name).unwrap().data().unwrap()
+    }
+
+    // Two archives numbering their hardlinks from the same inode
+    fn concatenated_hardlinks() -> Vec<u8> {
+        let mut data = Vec::new();
+        for files in [
+            [("a1", b"AAAA"), ("a2", b"AAAA")],
+            [("b1", b"BBBB"), ("b2", b"BBBB")],
+        ] {
+            let mut cpio = archive(&files.map(|(name, data)| (name, &data[..])));
+            assert_eq!(cpio.dedup(true), 1);
+            data.extend(dump(&cpio));
+        }
+        data
+    }
+
+    #[test]
+    fn merged_segments_keep_hardlinks_apart() {
+        let data = concatenated_hardlinks();
+        let cpio = Cpio::load_from_data(&data).unwrap();
+        let ino = |name| cpio.entry_by_name(name).unwrap().get_ino();
+        assert_eq!(ino("a1"), ino("a2"));
+        assert_eq!(ino("b1"), ino("b2"));
+        assert_ne!(ino("a1"), ino("b1"));
+
+        let reloaded = dump(&cpio);
+        let reloaded = Cpio::load_from_data(&reloaded).unwrap();
+        for (name, data) in [
+            ("a1", b"AAAA"),
+            ("a2", b"AAAA"),
+            ("b1", b"BBBB"),
+            ("b2", b"BBBB"),
+        ] {
+            assert_eq!(file(&reloaded, name), data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn dedup_allocates_fresh_inodes() {
+        let data = concatenated_hardlinks();
+        let mut cpio = Cpio::load_from_data(&data).unwrap().into_owned();
+        cpio.add("c1", CpioEntry::regular(0o644, b"CCCC".to_vec()))
+            .unwrap();
+        cpio.add("c2", CpioEntry::regular(0o644, b"CCCC".to_vec()))
+            .unwrap();
+        assert_eq!(cpio.dedup(true), 3);
+
+        let mut inodes = HashSet::new();
+        for group in [["a1", "a2"], ["b1", "b2"], ["c1", "c2"]] {
+            let ino = cpio.entry_by_name(group[0]).unwrap().get_ino().unwrap();
+            assert_eq!(cpio.entry_by_name(group[1]).unwrap().get_ino(), Some(ino));
+            assert!(inodes.insert(ino), "inode {} shared by two groups", ino);
+        }
+
+        let reloaded = dump(&cpio);
+        let reloaded = Cpio::load_from_data(&reloaded).unwrap();
+        for (name, data) in [("a2", b"AAAA"), ("b2", b"BBBB"), ("c2", b"CCCC")] {
+            assert_eq!(file(&reloaded, name), data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn set_data_detaches_hardlink() {
+        let mut cpio = archive(&[("x", b"same"), ("y", b"same"), ("z", b"same")]);
+        cpio.dedup(true);
+        cpio.set_data("y", b"changed".to_vec()).unwrap();
+        assert_eq!(cpio.entry_by_name("x").unwrap().get_nlink(), Some(2));
+        assert_eq!(cpio.entry_by_name("z").unwrap().get_nlink(), Some(2));
+        assert_eq!(cpio.entry_by_name("y").unwrap().get_nlink(), None);
+
+        let reloaded = dump(&cpio);
+        let reloaded = Cpio::load_from_data(&reloaded).unwrap();
+        assert_eq!(file(&reloaded, "x"), b"same");
+        assert_eq!(file(&reloaded, "y"), b"changed");
+        assert_eq!(file(&reloaded, "z"), b"same");
+        assert_eq!(reloaded.entry_by_name("x").unwrap().get_nlink(), Some(2));
+    }
+
+    // Written by bsdtar 3.8.2 (`bsdtar --format newc -cf golden.cpio @spec`)
+    // from this mtree spec, with dir/file holding "hello\n":
+    //   dir type=dir mode=0755 uid=0 gid=0 time=0.0 nlink=2
+    //   file type=file mode=0644 uid=0 gid=0 time=0.0 nlink=1 contents=...
+    //   ..
+    //   link type=link mode=0777 uid=0 gid=0 time=0.0 nlink=1 link=dir/file
+    const GOLDEN: &[u8] = include_bytes!("../testdata/golden.cpio");
+
+    // Clears the inode and device of every header. bsdtar copies them from the
+    // file it read, dump numbers inodes like magiskboot and has no device.
+    fn mask_inodes(mut data: Vec<u8>) -> Vec<u8> {
+        let mut pos = 0;
+        while pos + 110 <= data.len() {
+            let field = |data: &[u8], index: usize| {
+                let start = pos + 6 + index * 8;
+                usize::from_str_radix(std::str::from_utf8(&data[start..start + 8]).unwrap(), 16)
+                    .unwrap()
+            };
+            let (file_size, name_size) = (field(&data, 6), field(&data, 11));
+            for index in [0, 7, 8] {
+                let start = pos + 6 + index * 8;
+                data[start..start + 8].copy_from_slice(b"00000000");
+            }
+            pos = align_to(align_to(pos + 110 + name_size, 4) + file_size, 4);
+        }
+        data
+    }
+
+    fn golden_archive() -> Cpio<'static> {
+        let mut cpio = Cpio::new();
+        cpio.add("dir", CpioEntry::dir(0o755)).unwrap();
+        cpio.add("dir/file", CpioEntry::regular(0o644, b"hello\n".to_vec()))
+            .unwrap();
+        cpio.add("link", CpioEntry::symlink(0o777, "dir/file"))
+            .unwrap();
+        cpio
+    }
+
+    #[test]
+    fn golden_newc() {
+        let dumped = dump(&golden_archive());
+        assert_eq!(dumped.len(), GOLDEN.len());
+        assert!(mask_inodes(dumped) == mask_inodes(GOLDEN.to_vec()));
+    }
+
+    #[test]
+    fn load_dump_roundtrip() {
+        let loaded = Cpio::load_from_data(GOLDEN).unwrap();
+        let expected = golden_archive();
+        assert_eq!(
+            loaded.entries().keys().collect::<Vec<_>>(),
+            expected.entries().keys().collect::<Vec<_>>()
+        );
+        for (name, entry) in expected.entries() {
+            let other = loaded.entry_by_name_bytes(name).unwrap();
+            assert!(entry.changes(other).unwrap().is_empty(), "{:?}", name);
+        }
+        assert_eq!(file(&loaded, "dir/file"), b"hello\n");
+
+        // A loaded archive keeps its inodes, so dumps are stable from there
+        let dumped = dump(&loaded);
+        let reloaded = Cpio::load_from_data(&dumped).unwrap();
+        assert!(dump(&reloaded) == dumped);
+        assert!(mask_inodes(dumped) == mask_inodes(GOLDEN.to_vec()));
+    }
+
+    fn nested_tree() -> Cpio<'static> {
+        archive(&[
+            ("init.rc", b"on init"),
+            ("init.zygote.rc", b"service zygote"),
+            ("overlay.d/init.custom.rc", b"on boot"),
+            ("overlay.d/sbin/tool", b"ELF"),
+            ("overlay.d/sbin/lib/libtool.so", b"ELF"),
+            ("system/etc/init/hw/init.rc", b"import"),
+            ("system/etc/init/a.rc", b"service a"),
+            ("system/etc/init/b.rc", b"service b"),
+        ])
+    }
+
+    fn names(cpio: &Cpio) -> Vec<String> {
+        cpio.entries()
+            .keys()
+            .map(|it| String::from_utf8_lossy(it).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn glob_match_rules() {
+        for (pattern, name, matched) in [
+            ("*.rc", "init.rc", true),
+            ("*.rc", "overlay.d/init.custom.rc", false),
+            ("*/*.rc", "overlay.d/init.custom.rc", true),
+            ("init.?.rc", "init.a.rc", true),
+            ("init.?.rc", "init.ab.rc", false),
+            ("init.[a-c].rc", "init.b.rc", true),
+            ("init.[!a-c].rc", "init.b.rc", false),
+            ("init.[!a-c].rc", "init.d.rc", true),
+            ("overlay.d", "overlay.d/sbin", false),
+            ("*", "", true),
+        ] {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), name.as_bytes()),
+                matched,
+                "{} {}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rm_glob_nested() {
+        let mut cpio = nested_tree();
+        // Only the top level, `*` does not cross a /
+        assert_eq!(cpio.rm_glob("*.rc", false), 2);
+        assert!(!cpio.exists("init.rc") && !cpio.exists("init.zygote.rc"));
+        assert!(cpio.exists("overlay.d/init.custom.rc"));
+
+        assert_eq!(cpio.rm_glob("system/etc/init/*.rc", false), 2);
+        assert!(cpio.exists("system/etc/init/hw/init.rc"));
+
+        // Directories go with their children only when recursive
+        let mut kept = nested_tree();
+        kept.rm_glob("overlay.d/s*", false);
+        assert!(!kept.exists("overlay.d/sbin"));
+        assert!(kept.exists("overlay.d/sbin/lib/libtool.so"));
+        cpio.rm_glob("overlay.d/s*", true);
+        assert_eq!(
+            names(&cpio)
+                .into_iter()
+                .filter(|it| it.starts_with("overlay.d"))
+                .collect::<Vec<_>>(),
+            ["overlay.d", "overlay.d/init.custom.rc"]
+        );
+    }
+
+    #[test]
+    fn mv_nested_directory() {
+        let mut cpio = nested_tree();
+        let before = names(&cpio);
+        cpio.mv("overlay.d", "overlay.d.bak").unwrap();
+        let expected = before
+            .iter()
+            .map(|it| match it.strip_prefix("overlay.d") {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                    format!("overlay.d.bak{}", rest)
+                }
+                _ => it.clone(),
+            })
+            .collect::<BTreeSet<_>>();
+        assert_eq!(names(&cpio).into_iter().collect::<BTreeSet<_>>(), expected);
+        assert_eq!(file(&cpio, "overlay.d.bak/sbin/lib/libtool.so"), b"ELF");
+
+        // A nested directory moves with its subtree only
+        cpio.mv("system/etc/init", "system/etc/init.old").unwrap();
+        assert!(cpio.exists("system/etc/init.old/hw/init.rc"));
+        assert!(!cpio.exists("system/etc/init"));
+        assert!(cpio.exists("system/etc"));
+    }
+
+    #[test]
+    fn mv_conflict_rolls_back() {
+        let mut cpio = nested_tree();
+        cpio.add_with_parents(
+            "overlay.d.bak/sbin/tool",
+            CpioEntry::regular(0o644, b"old".to_vec()),
+        )
+        .unwrap();
+        let before = dump(&cpio);
+        assert!(cpio.mv("overlay.d", "overlay.d.bak").is_err());
+        assert!(dump(&cpio) == before);
+        assert!(cpio.mv("overlay.d", "overlay.d/sbin/inner").is_err());
+        assert!(dump(&cpio) == before);
+    }
+
+    // From a Nexus 6P, dm-verity and full disk encryption
+    const FSTAB_VERITY: &str = "\
+/dev/block/platform/soc.0/f9824900.sdhci/by-name/system /system ext4 ro,barrier=1 wait,verify=/dev/block/platform/soc.0/f9824900.sdhci/by-name/metadata
+/dev/block/platform/soc.0/f9824900.sdhci/by-name/userdata /data ext4 noatime,nosuid,nodev,barrier=1,data=ordered,nomblk_io_submit,noauto_da_alloc,errors=panic wait,check,forceencrypt=/dev/block/platform/soc.0/f9824900.sdhci/by-name/metadata
+";
+
+    // From a Pixel, AVB with first stage mount and file based encryption
+    const FSTAB_AVB: &str = "\
+system /system ext4 ro,barrier=1 wait,slotselect,avb=vbmeta,logical,first_stage_mount,avb_keys=/avb/q-gsi.avbpubkey:/avb/r-gsi.avbpubkey
+vendor /vendor ext4 ro,barrier=1 wait,slotselect,avb,logical,first_stage_mount
+/dev/block/by-name/userdata /data f2fs noatime,nosuid,nodev,inlinecrypt latemount,wait,check,quota,fileencryption=aes-256-xts:aes-256-cts:v2+inlinecrypt_optimized,keydirectory=/metadata/vold/metadata_encryption,checkpoint=fs
+";
+
+    fn text<'a>(cpio: &'a Cpio, name: &str) -> &'a str {
+        std::str::from_utf8(file(cpio, name)).unwrap()
+    }
+
+    #[test]
+    fn magisk_patch_verity_fstab() {
+        let mut cpio = archive(&[
+            ("fstab.angler", FSTAB_VERITY.as_bytes()),
+            ("verity_key", b"key"),
+        ]);
+        let changes = cpio.magisk_patch(&MagiskPatchOptions::default()).unwrap();
+        assert_eq!(
+            text(&cpio, "fstab.angler"),
+            "\
+/dev/block/platform/soc.0/f9824900.sdhci/by-name/system /system ext4 ro,barrier=1 wait
+/dev/block/platform/soc.0/f9824900.sdhci/by-name/userdata /data ext4 noatime,nosuid,nodev,barrier=1,data=ordered,nomblk_io_submit,noauto_da_alloc,errors=panic wait,check
+"
+        );
+        assert!(!cpio.exists("verity_key"));
+        assert_eq!(
+            changes,
+            [
+                "fstab.angler: removed ,verify=/dev/block/platform/soc.0/f9824900.sdhci/by-name/metadata",
+                "fstab.angler: removed ,forceencrypt=/dev/block/platform/soc.0/f9824900.sdhci/by-name/metadata",
+                "removed verity_key",
+            ]
+        );
+    }
+
+    #[test]
+    fn magisk_patch_avb_fstab() {
+        let mut cpio = archive(&[("fstab.sunfish", FSTAB_AVB.as_bytes())]);
+        cpio.magisk_patch(&MagiskPatchOptions::default()).unwrap();
+        assert_eq!(
+            text(&cpio, "fstab.sunfish"),
+            "\
+system /system ext4 ro,barrier=1 wait,slotselect,logical,first_stage_mount
+vendor /vendor ext4 ro,barrier=1 wait,slotselect,logical,first_stage_mount
+/dev/block/by-name/userdata /data f2fs noatime,nosuid,nodev,inlinecrypt latemount,wait,check,quota,keydirectory=/metadata/vold/metadata_encryption,checkpoint=fs
+"
+        );
+    }
+
+    #[test]
+    fn magisk_patch_keep_flags() {
+        let mut cpio = archive(&[("fstab.sunfish", FSTAB_AVB.as_bytes())]);
+        let options = MagiskPatchOptions {
+            keep_verity: true,
+            keep_force_encrypt: true,
+        };
+        assert!(cpio.magisk_patch(&options).unwrap().is_empty());
+        assert_eq!(text(&cpio, "fstab.sunfish"), FSTAB_AVB);
+
+        // Only the encryption flags
+        let options = MagiskPatchOptions {
+            keep_verity: true,
+            keep_force_encrypt: false,
+        };
+        let changes = cpio.magisk_patch(&options).unwrap();
+        assert_eq!(changes.len(), 1);
+        let patched = text(&cpio, "fstab.sunfish");
+        assert!(patched.contains("avb=vbmeta") && !patched.contains("fileencryption"));
+    }
+
+    // fstabs outside the top level are found through mount_all, backups are
+    // left alone
+    #[test]
+    fn magisk_patch_mount_all_fstab() {
+        let mut cpio = archive(&[
+            (
+                "init.target.rc",
+                b"on fs\n    mount_all /vendor/etc/fstab.${ro.boot.hardware} --early\n",
+            ),
+            ("vendor/etc/fstab.qcom", FSTAB_VERITY.as_bytes()),
+            (".backup/fstab.qcom", FSTAB_VERITY.as_bytes()),
+            ("system/etc/fstab.unused", FSTAB_VERITY.as_bytes()),
+        ]);
+        let changes = cpio.magisk_patch(&MagiskPatchOptions::default()).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(!text(&cpio, "vendor/etc/fstab.qcom").contains("verify"));
+        assert_eq!(text(&cpio, ".backup/fstab.qcom"), FSTAB_VERITY);
+        assert_eq!(text(&cpio, "system/etc/fstab.unused"), FSTAB_VERITY);
+    }
+
+    // Inodes differ once entries are added or removed, everything else must
+    // come back
+    fn assert_same_archive(mut a: Cpio, mut b: Cpio) {
+        a.normalize();
+        b.normalize();
+        assert_eq!(names(&a), names(&b));
+        assert!(dump(&a) == dump(&b));
+    }
+
+    #[test]
+    fn backup_restore_roundtrip() {
+        let stock_data = dump(&nested_tree());
+        let stock = Cpio::load_from_data(&stock_data).unwrap();
+
+        let mut patched = Cpio::load_from_data(&stock_data).unwrap();
+        patched
+            .set_data("init.rc", b"on init\n    exec magisk".to_vec())
+            .unwrap();
+        patched.rm("system/etc/init/b.rc", false);
+        patched
+            .add_with_parents("sbin/magisk", CpioEntry::regular(0o755, b"ELF".to_vec()))
+            .unwrap();
+        patched
+            .add(
+                "overlay.d/sbin/tool",
+                CpioEntry::regular(0o700, b"ELF".to_vec()),
+            )
+            .unwrap();
+
+        let changes = patched.create_backup(&stock).unwrap();
+        assert_eq!(
+            changes,
+            [
+                "backup init.rc -> .backup/init.rc",
+                "backup overlay.d/sbin/tool -> .backup/overlay.d/sbin/tool",
+                "backup system/etc/init/b.rc -> .backup/system/etc/init/b.rc",
+                "record new entry sbin",
+                "record new entry sbin/magisk",
+            ]
+        );
+        assert_eq!(file(&patched, ".backup/.rmlist"), b"sbin\0sbin/magisk\0");
+
+        // The backup goes through a dump like a flashed ramdisk would
+        let patched = dump(&patched);
+        let mut restored = Cpio::load_from_data(&patched).unwrap();
+        restored.restore().unwrap();
+        assert!(!restored.exists(".backup"));
+        assert_same_archive(restored, stock);
+    }
+
+    #[test]
+    fn restore_ramdisk_from_nothing() {
+        let mut cpio = nested_tree();
+        cpio.create_backup(&Cpio::new()).unwrap();
+        cpio.restore().unwrap();
+        assert!(cpio.entries().is_empty());
+    }
+
+    // Every name and data length modulo 4, hardlinks that only carry data on
+    // the last link, symlinks and directories
+    #[test]
+    fn serialized_size_matches_dump() {
+        let mut cpio = Cpio::new();
+        assert_eq!(cpio.serialized_size(), dump(&cpio).len() as u64);
+        for name_len in 1..=8 {
+            for data_len in 0..=5 {
+                let name = format!("d{}/{}", data_len, "n".repeat(name_len));
+                let entry = CpioEntry::regular(0o644, vec![b'x'; data_len]);
+                cpio.add_with_parents(&name, entry).unwrap();
+            }
+        }
+        cpio.add("link", CpioEntry::symlink(0o777, "d0/nnn"))
+            .unwrap();
+        assert_eq!(cpio.serialized_size(), dump(&cpio).len() as u64);
+
+        cpio.dedup(true);
+        assert!(!cpio.links_without_data().is_empty());
+        assert_eq!(cpio.serialized_size(), dump(&cpio).len() as u64);
+
+        // Also after a load, which keeps the inodes
+        let dumped = dump(&cpio);
+        let loaded = Cpio::load_from_data(&dumped).unwrap();
+        assert_eq!(loaded.serialized_size(), dumped.len() as u64);
+    }
+
+    #[test]
+    fn stats() {
+        let mut cpio = nested_tree();
+        cpio.add("link", CpioEntry::symlink(0o777, "init.rc"))
+            .unwrap();
+        let stats = cpio.stats(2);
+        assert_eq!(stats.entries, cpio.entries().len());
+        assert_eq!(stats.serialized_size, dump(&cpio).len() as u64);
+        // 58 bytes of files, 7 of symlink target
+        assert_eq!(stats.data_bytes, 65);
+        assert_eq!(
+            stats.dir_bytes,
+            BTreeMap::from([
+                (b"".to_vec(), 21),
+                (b"overlay.d".to_vec(), 13),
+                (b"system".to_vec(), 24),
+            ])
+        );
+        assert_eq!(
+            stats.largest,
+            [
+                (b"init.zygote.rc".to_vec(), 14),
+                (b"system/etc/init/a.rc".to_vec(), 9),
+            ]
+        );
+    }
 }
@@ -0,0 +1,75 @@
+use crate::error::{BootImgError, Result};
+use crate::parser::{BootImage, block_reader};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DigestAlgorithm {
+    // What `magiskboot sha1` prints
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+pub fn digest_reader(algorithm: DigestAlgorithm, reader: &mut dyn Read) -> Result<Vec<u8>> {
+    macro_rules! digest {
+        ($t:ty) => {{
+            let mut hasher = <$t>::new();
+            std::io::copy(reader, &mut hasher)?;
+            hasher.finalize().to_vec()
+        }};
+    }
+    Ok(match algorithm {
+        DigestAlgorithm::Sha1 => digest!(Sha1),
+        DigestAlgorithm::Sha256 => digest!(Sha256),
+    })
+}
+
+impl<'a> BootImage<'a> {
+    // The data covered by the AVB footer, without vbmeta, footer and padding
+    pub fn avb_payload(&self) -> Option<&'a [u8]> {
+        self.avb_info
+            .as_ref()
+            .map(|it| &self.data[..it.avb_footer.get_original_image_size() as usize])
+    }
+
+    // Decompressed content of a block named as in BootImageBlocks::blocks. The
+    // kernel is followed by its appended dtb and vendor ramdisks are concatenated.
+    pub fn block_content(&self, name: &str) -> Result<Option<Box<dyn Read + '_>>> {
+        let blocks = self.get_blocks();
+        let Some((name, off, len)) = blocks.blocks().find(|(it, _, _)| *it == name) else {
+            return Ok(None);
+        };
+        let reader: Box<dyn Read> = match (name, blocks.get_kernel(), blocks.get_ramdisk()) {
+            ("kernel", Some(kernel), _) => Box::new(
+                block_reader(name, kernel.get_pure_data(), &blocks.options)?
+                    .chain(kernel.appended_dtb().unwrap_or_default()),
+            ),
+            ("ramdisk", _, Some(ramdisk)) if ramdisk.is_vendor_ramdisk() => {
+                let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+                for entry in ramdisk.iter_vendor_ramdisk() {
+                    reader = Box::new(reader.chain(entry.reader()?));
+                }
+                reader
+            }
+            ("ramdisk", _, Some(ramdisk)) => ramdisk.reader()?,
+            _ => block_reader(name, &self.data[off..off + len], &blocks.options)?,
+        };
+        Ok(Some(reader))
+    }
+
+    pub fn digest_avb_payload(&self, algorithm: DigestAlgorithm) -> Result<Vec<u8>> {
+        let payload = self
+            .avb_payload()
+            .ok_or_else(|| BootImgError::InvalidAvbFooter("image has no avb footer".to_string()))?;
+        digest_reader(algorithm, &mut &payload[..])
+    }
+
+    pub fn digest_block(&self, name: &str, algorithm: DigestAlgorithm) -> Result<Vec<u8>> {
+        let mut reader = self.block_content(name)?.ok_or_else(|| {
+            BootImgError::InvalidOperation(format!("image has no {} block", name))
+        })?;
+        digest_reader(algorithm, &mut reader)
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::{BootImgError, Result};
+use crate::utils::{SliceExt, align_to, trim_end};
+
+// https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+pub(crate) const FDT_MAGIC: u32 = 0xd00dfeed;
+pub(crate) const FDT_HEADER_SIZE: usize = 40;
+
+pub(crate) const FDT_BEGIN_NODE: u32 = 0x1;
+pub(crate) const FDT_END_NODE: u32 = 0x2;
+pub(crate) const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+pub(crate) const FDT_END: u32 = 0x9;
+
+#[derive(Debug, Clone)]
+pub struct DtbEntry<'a> {
+    pub offset: usize,
+    pub data: &'a [u8],
+    pub model: Option<String>,
+    pub compatible: Vec<String>,
+}
+
+// Quick check used when scanning for a dtb inside other data
+pub(crate) fn is_fdt(data: &[u8]) -> bool {
+    if data.u32_be_at(0) != Some(FDT_MAGIC) || data.len() < FDT_HEADER_SIZE {
+        return false;
+    }
+    let total_size = data.u32_be_at(4).unwrap() as usize;
+    let off_dt_struct = data.u32_be_at(8).unwrap() as usize;
+    total_size <= data.len()
+        && off_dt_struct < total_size
+        && data.u32_be_at(off_dt_struct) == Some(FDT_BEGIN_NODE)
+}
+
+fn invalid(offset: usize, msg: &str) -> BootImgError {
+    BootImgError::InvalidDtb(format!("fdt at {:#x}: {}", offset, msg))
+}
+
+// Returns (model, compatible) of the root node
+fn parse_root_props(fdt: &[u8], offset: usize) -> Result<(Option<String>, Vec<String>)> {
+    let off_dt_struct = fdt.u32_be_at(8).unwrap() as usize;
+    let off_dt_strings = fdt.u32_be_at(12).unwrap() as usize;
+    let strings = fdt
+        .get(off_dt_strings..)
+        .ok_or_else(|| invalid(offset, "strings block out of range"))?;
+
+    let mut pos = off_dt_struct;
+    macro_rules! next_u32 {
+        () => {{
+            let v = fdt
+                .u32_be_at(pos)
+                .ok_or_else(|| invalid(offset, "unexpected end of struct block"))?;
+            pos += 4;
+            v
+        }};
+    }
+
+    if next_u32!() != FDT_BEGIN_NODE {
+        return Err(invalid(offset, "root node not found"));
+    }
+    // Skip the (empty) root node name
+    let name_len = fdt[pos..].iter().position(|&b| b == 0).unwrap_or(0);
+    pos = align_to(pos + name_len + 1, 4);
+
+    let mut model = None;
+    let mut compatible = Vec::new();
+    loop {
+        match next_u32!() {
+            FDT_PROP => {
+                let len = next_u32!() as usize;
+                let name_off = next_u32!() as usize;
+                let value = pos
+                    .checked_add(len)
+                    .and_then(|end| fdt.get(pos..end))
+                    .ok_or_else(|| invalid(offset, "property out of range"))?;
+                pos = align_to(pos + len, 4);
+                let name = strings
+                    .get(name_off..)
+                    .map(trim_end)
+                    .ok_or_else(|| invalid(offset, "property name out of range"))?;
+                match name {
+                    b"model" => model = Some(String::from_utf8_lossy(trim_end(value)).into_owned()),
+                    b"compatible" => {
+                        compatible = value
+                            .split(|&b| b == 0)
+                            .filter(|s| !s.is_empty())
+                            .map(|s| String::from_utf8_lossy(s).into_owned())
+                            .collect()
+                    }
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            // Properties always come before subnodes
+            FDT_BEGIN_NODE | FDT_END_NODE | FDT_END => break,
+            tag => return Err(invalid(offset, &format!("unknown tag {:#x}", tag))),
+        }
+    }
+
+    Ok((model, compatible))
+}
+
+// Walks a block of concatenated FDTs, trailing zero padding is ignored
+pub fn parse_dtb_entries(data: &[u8]) -> Result<Vec<DtbEntry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        if rest.iter().all(|&b| b == 0) {
+            break;
+        }
+        if rest.u32_be_at(0) != Some(FDT_MAGIC) {
+            return Err(invalid(offset, "bad magic"));
+        }
+        if rest.len() < FDT_HEADER_SIZE {
+            return Err(invalid(offset, "truncated header"));
+        }
+        let total_size = rest.u32_be_at(4).unwrap() as usize;
+        if total_size < FDT_HEADER_SIZE || total_size > rest.len() {
+            return Err(invalid(
+                offset,
+                &format!("invalid totalsize {}", total_size),
+            ));
+        }
+        let fdt = &rest[..total_size];
+        let (model, compatible) = parse_root_props(fdt, offset)?;
+        entries.push(DtbEntry {
+            offset,
+            data: fdt,
+            model,
+            compatible,
+        });
+        offset += total_size;
+    }
+    Ok(entries)
+}
@@ -0,0 +1,304 @@
+use crate::error::{BootImgError, Result};
+use crate::layouts::{DtTableEntry, DtTableHeader};
+use crate::utils::SliceExt;
+use std::borrow::Cow;
+use std::io::Write;
+
+// https://source.android.com/docs/core/architecture/dto/partitions
+pub const DT_TABLE_MAGIC: u32 = 0xd7b7ab1e;
+// Default of mkdtboimg
+const DT_TABLE_DEFAULT_PAGE_SIZE: u32 = 2048;
+
+#[derive(Debug, Clone)]
+pub struct DtboEntry<'a> {
+    pub id: u32,
+    pub rev: u32,
+    // For version 1 tables custom[0] holds the flags
+    pub custom: [u32; 4],
+    pub data: Cow<'a, [u8]>,
+}
+
+impl<'a> DtboEntry<'a> {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            id: 0,
+            rev: 0,
+            custom: [0; 4],
+            data: Cow::Owned(data),
+        }
+    }
+
+    pub fn into_owned(self) -> DtboEntry<'static> {
+        DtboEntry {
+            data: Cow::Owned(self.data.into_owned()),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DtboTable<'a> {
+    pub page_size: u32,
+    pub version: u32,
+    pub entries: Vec<DtboEntry<'a>>,
+}
+
+impl Default for DtboTable<'_> {
+    fn default() -> Self {
+        Self {
+            page_size: DT_TABLE_DEFAULT_PAGE_SIZE,
+            version: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidDtbo(msg)
+}
+
+impl<'a> DtboTable<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.u32_be_at(0) != Some(DT_TABLE_MAGIC) {
+            return Err(invalid("bad magic".to_string()));
+        }
+        if data.len() < DtTableHeader::SIZE {
+            return Err(invalid("truncated header".to_string()));
+        }
+        let header = DtTableHeader {
+            data: &data[..DtTableHeader::SIZE],
+        };
+        let total_size = header.get_total_size() as usize;
+        if total_size > data.len() || (header.get_header_size() as usize) < DtTableHeader::SIZE {
+            return Err(invalid(format!(
+                "invalid total size {} or header size {}",
+                total_size,
+                header.get_header_size()
+            )));
+        }
+        let data = &data[..total_size];
+
+        let entry_size = header.get_dt_entry_size() as usize;
+        if entry_size < DtTableEntry::SIZE {
+            return Err(invalid(format!("invalid entry size {}", entry_size)));
+        }
+        let entries_off = header.get_dt_entries_offset() as usize;
+        let entry_count = header.get_dt_entry_count() as usize;
+
+        let mut entries = Vec::new();
+        for i in 0..entry_count {
+            let entry = i
+                .checked_mul(entry_size)
+                .and_then(|off| off.checked_add(entries_off))
+                .and_then(|off| data.get(off..off.checked_add(DtTableEntry::SIZE)?))
+                .map(|data| DtTableEntry { data })
+                .ok_or_else(|| invalid(format!("entry {} out of range", i)))?;
+            let dt_offset = entry.get_dt_offset() as usize;
+            let dt_size = entry.get_dt_size() as usize;
+            let dt = dt_offset
+                .checked_add(dt_size)
+                .and_then(|end| data.get(dt_offset..end))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "entry {} dt off {} size {} out of range",
+                        i, dt_offset, dt_size
+                    ))
+                })?;
+            let custom = entry.get_custom();
+            entries.push(DtboEntry {
+                id: entry.get_id(),
+                rev: entry.get_rev(),
+                custom: std::array::from_fn(|i| custom.u32_be_at(i * 4).unwrap()),
+                data: Cow::Borrowed(dt),
+            });
+        }
+
+        Ok(Self {
+            page_size: header.get_page_size(),
+            version: header.get_version(),
+            entries,
+        })
+    }
+
+    // Same layout as mkdtboimg: header, entries, then the dt blobs back to back.
+    // Identical blobs are only stored once.
+    pub fn write(&self, out: &mut dyn Write) -> Result<()> {
+        let to_u32 = |value: usize, what: &str| {
+            u32::try_from(value)
+                .map_err(|_| invalid(format!("{} {} does not fit in 32 bits", what, value)))
+        };
+        let entries_off = DtTableHeader::SIZE;
+        let mut dt_off = entries_off + DtTableEntry::SIZE * self.entries.len();
+        let mut blobs: Vec<(&[u8], usize)> = Vec::new();
+        let mut entry_table = Vec::with_capacity(DtTableEntry::SIZE * self.entries.len());
+        for entry in &self.entries {
+            let data = entry.data.as_ref();
+            let off = if let Some((_, off)) = blobs.iter().find(|(blob, _)| *blob == data) {
+                *off
+            } else {
+                blobs.push((data, dt_off));
+                dt_off += data.len();
+                dt_off - data.len()
+            };
+            entry_table.extend_from_slice(&to_u32(data.len(), "dt size")?.to_be_bytes());
+            entry_table.extend_from_slice(&to_u32(off, "dt offset")?.to_be_bytes());
+            entry_table.extend_from_slice(&entry.id.to_be_bytes());
+            entry_table.extend_from_slice(&entry.rev.to_be_bytes());
+            for c in entry.custom {
+                entry_table.extend_from_slice(&c.to_be_bytes());
+            }
+        }
+
+        let header = [
+            DT_TABLE_MAGIC,
+            to_u32(dt_off, "total size")?,
+            DtTableHeader::SIZE as u32,
+            DtTableEntry::SIZE as u32,
+            to_u32(self.entries.len(), "entry count")?,
+            entries_off as u32,
+            self.page_size,
+            self.version,
+        ];
+        for v in header {
+            out.write_all(&v.to_be_bytes())?;
+        }
+        out.write_all(&entry_table)?;
+        for (blob, _) in blobs {
+            out.write_all(blob)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut v = Vec::new();
+        self.write(&mut v)?;
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{FIXTURE_MODEL, dtb};
+
+    fn entry(id: u32, data: Vec<u8>) -> DtboEntry<'static> {
+        DtboEntry {
+            id,
+            rev: id + 1,
+            custom: [id, 0, 0, 0xffff_ffff],
+            data: Cow::Owned(data),
+        }
+    }
+
+    fn table() -> DtboTable<'static> {
+        DtboTable {
+            page_size: 4096,
+            version: 1,
+            entries: vec![
+                entry(1, dtb(FIXTURE_MODEL)),
+                entry(2, dtb("other")),
+                entry(3, dtb(FIXTURE_MODEL)),
+            ],
+        }
+    }
+
+    fn with_u32(data: &[u8], offset: usize, value: u32) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        data
+    }
+
+    fn assert_invalid(data: &[u8], what: &str) {
+        let result = DtboTable::parse(data);
+        assert!(
+            matches!(result, Err(BootImgError::InvalidDtbo(_))),
+            "{}: {:?}",
+            what,
+            result
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let table = table();
+        let data = table.to_bytes().unwrap();
+        let parsed = DtboTable::parse(&data).unwrap();
+        assert_eq!(parsed.page_size, 4096);
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.entries.len(), 3);
+        for (parsed, entry) in parsed.entries.iter().zip(&table.entries) {
+            assert_eq!(parsed.id, entry.id);
+            assert_eq!(parsed.rev, entry.rev);
+            assert_eq!(parsed.custom, entry.custom);
+            assert_eq!(parsed.data, entry.data);
+        }
+        assert_eq!(parsed.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn identical_blobs_stored_once() {
+        let table = table();
+        let data = table.to_bytes().unwrap();
+        let blobs = DtTableHeader::SIZE + 3 * DtTableEntry::SIZE;
+        let unique = dtb(FIXTURE_MODEL).len() + dtb("other").len();
+        assert_eq!(data.len(), blobs + unique);
+        assert_eq!(data.u32_be_at(4), Some(data.len() as u32));
+        let dt_offset = |i: usize| data.u32_be_at(DtTableHeader::SIZE + i * DtTableEntry::SIZE + 4);
+        assert_eq!(dt_offset(0), Some(blobs as u32));
+        assert_eq!(dt_offset(2), dt_offset(0));
+        assert_ne!(dt_offset(1), dt_offset(0));
+    }
+
+    // Replacing and adding entries rebuilds a valid table
+    #[test]
+    fn rebuild() {
+        let data = table().to_bytes().unwrap();
+        let mut parsed = DtboTable::parse(&data).unwrap();
+        parsed.entries[1].data = Cow::Owned(dtb("replaced"));
+        parsed.entries.push(DtboEntry::new(b"overlay".to_vec()));
+        let rebuilt = parsed.to_bytes().unwrap();
+        let reparsed = DtboTable::parse(&rebuilt).unwrap();
+        let blobs = reparsed
+            .entries
+            .iter()
+            .map(|it| it.data.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blobs,
+            [
+                dtb(FIXTURE_MODEL),
+                dtb("replaced"),
+                dtb(FIXTURE_MODEL),
+                b"overlay".to_vec()
+            ]
+        );
+        assert_eq!(reparsed.entries[3].id, 0);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let data = table().to_bytes().unwrap();
+        let entry = |i: usize, field: usize| DtTableHeader::SIZE + i * DtTableEntry::SIZE + field;
+
+        assert_invalid(&with_u32(&data, 0, 0), "magic");
+        assert_invalid(&data[..DtTableHeader::SIZE - 1], "truncated header");
+        assert_invalid(&data[..data.len() - 1], "truncated blobs");
+        assert_invalid(&with_u32(&data, 8, 4), "header size");
+        assert_invalid(&with_u32(&data, 12, 4), "entry size");
+        assert_invalid(&with_u32(&data, 16, 0x1000_0000), "entry count");
+        assert_invalid(&with_u32(&data, 20, data.len() as u32), "entries offset");
+        assert_invalid(&with_u32(&data, entry(1, 0), data.len() as u32), "dt size");
+        assert_invalid(
+            &with_u32(&data, entry(2, 4), data.len() as u32),
+            "dt offset",
+        );
+        assert_invalid(
+            &with_u32(
+                &with_u32(&data, entry(0, 0), u32::MAX),
+                entry(0, 4),
+                u32::MAX,
+            ),
+            "dt end",
+        );
+    }
+}
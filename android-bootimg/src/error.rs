@@ -0,0 +1,116 @@
+use crate::compress::CompressFormat;
+use std::fmt::{Display, Formatter};
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompressOperation {
+    Decode,
+    Encode,
+}
+
+impl Display for CompressOperation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CompressOperation::Decode => "decode",
+            CompressOperation::Encode => "encode",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BootImgError {
+    #[error("not a boot image")]
+    NotBootImage,
+    #[error("unsupported {} version {found}", if *.vendor { "vendor boot" } else { "boot" })]
+    UnsupportedHeaderVersion { found: u32, vendor: bool },
+    #[error("truncated header: need {size} bytes, got {available}")]
+    TruncatedHeader { size: usize, available: usize },
+    #[error("invalid page size {0}")]
+    InvalidPageSize(usize),
+    #[error("invalid block {name} off {offset} size {size} (available {available})")]
+    TruncatedBlock {
+        name: &'static str,
+        offset: usize,
+        size: usize,
+        available: usize,
+    },
+    #[error("invalid vendor ramdisk table: {0}")]
+    InvalidVendorRamdiskTable(String),
+    #[error("invalid avb footer: {0}")]
+    InvalidAvbFooter(String),
+    #[error("invalid vbmeta: {0}")]
+    InvalidVbMeta(String),
+    #[error("invalid signing key: {0}")]
+    InvalidSigningKey(String),
+    #[error("invalid dtb: {0}")]
+    InvalidDtb(String),
+    #[error("invalid dtbo: {0}")]
+    InvalidDtbo(String),
+    #[error("invalid bootconfig: {0}")]
+    InvalidBootconfig(String),
+    #[error("invalid header text: {0}")]
+    InvalidHeaderText(String),
+    #[error("invalid os version: {0}")]
+    InvalidOsVersion(String),
+    #[error("invalid hex pattern: {0}")]
+    InvalidHexPattern(String),
+    #[error("invalid payload: {0}")]
+    InvalidPayload(String),
+    #[error("invalid mkbootimg arguments: {0}")]
+    InvalidMkbootimgArgs(String),
+    #[error("cannot {operation} compression format {format:?}")]
+    UnsupportedCompression {
+        format: CompressFormat,
+        operation: CompressOperation,
+    },
+    #[error("image and vbmeta of {size} bytes do not fit in partition size {partition_size}")]
+    PayloadTooLarge { size: u64, partition_size: u64 },
+    #[error("decompressed data exceeds the limit of {0} bytes")]
+    DecompressLimitExceeded(u64),
+    #[error("invalid cpio archive: {0}")]
+    CpioFormat(String),
+    #[error("no such entry {0}")]
+    NoSuchEntry(String),
+    #[error("{0}")]
+    InvalidOperation(String),
+    // Not a #[source], the message already includes it
+    #[error("{context}: {error}")]
+    Context {
+        context: String,
+        error: Box<BootImgError>,
+    },
+    #[error(transparent)]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+// Errors raised inside Read implementations, e.g. by LimitedDecoder, come back
+// as they were instead of as Io
+impl From<io::Error> for BootImgError {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|it| it.is::<BootImgError>()) {
+            return *e.into_inner().unwrap().downcast::<BootImgError>().unwrap();
+        }
+        BootImgError::Io(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for BootImgError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        BootImgError::Utf8(e.utf8_error())
+    }
+}
+
+impl BootImgError {
+    // e.g. "while decompressing kernel"
+    pub(crate) fn with_context(self, context: String) -> Self {
+        BootImgError::Context {
+            context,
+            error: Box::new(self),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, BootImgError>;
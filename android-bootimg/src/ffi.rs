@@ -0,0 +1,276 @@
+// extern "C" API for C and C++ tools, see ffi/android_bootimg.h. Every call
+// catches panics, failures return NULL or false and store a message in `*error`
+// unless it is NULL. Strings returned to C are freed with bootimg_string_free.
+#![allow(clippy::missing_safety_doc)]
+
+use crate::error::{BootImgError, Result};
+use crate::parser::{BootImage, FieldValue, OwnedBootImage};
+use crate::patcher::BootImagePatchOption;
+use std::ffi::{CStr, CString, c_char};
+use std::fs::OpenOptions;
+use std::io::Cursor;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::ptr::null_mut;
+
+// Opaque to C, owns a copy of the buffer it was parsed from
+pub struct BootImg {
+    image: OwnedBootImage,
+    // NUL-terminated block names handed out in BootImgBlockSpan
+    blocks: Vec<(CString, usize, usize)>,
+}
+
+#[repr(C)]
+pub struct BootImgBlockSpan {
+    // Valid as long as the image
+    pub name: *const c_char,
+    pub offset: usize,
+    pub len: usize,
+}
+
+// Uncompressed data is compressed in the format of the replaced block,
+// compressed data is written as is
+#[repr(C)]
+pub struct BootImgReplacement {
+    // kernel, ramdisk, second, dtb or recovery_dtbo
+    pub block: *const c_char,
+    pub data: *const u8,
+    pub len: usize,
+}
+
+fn set_error(error: *mut *mut c_char, message: String) {
+    if error.is_null() {
+        return;
+    }
+    // Interior NULs can't be represented, the message is cut there
+    let message = CString::new(message).unwrap_or_else(|e| {
+        let end = e.nul_position();
+        CString::new(&e.into_vec()[..end]).unwrap_or_default()
+    });
+    unsafe { *error = message.into_raw() };
+}
+
+// Runs `f` without letting a panic unwind into C
+fn guard<T>(error: *mut *mut c_char, failed: T, f: impl FnOnce() -> Result<T>) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            set_error(error, e.to_string());
+            failed
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|it| it.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_error(error, format!("panic: {}", message));
+            failed
+        }
+    }
+}
+
+fn non_null<T>(ptr: *const T, what: &str) -> Result<*const T> {
+    if ptr.is_null() {
+        Err(BootImgError::InvalidOperation(format!("{} is NULL", what)))
+    } else {
+        Ok(ptr)
+    }
+}
+
+unsafe fn c_str<'a>(ptr: *const c_char, what: &str) -> Result<&'a str> {
+    let ptr = non_null(ptr, what)?;
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| BootImgError::InvalidOperation(format!("{} is not UTF-8", what)))
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize, what: &str) -> Result<&'a [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { std::slice::from_raw_parts(non_null(data, what)?, len) })
+}
+
+fn field_value<'a>(image: &'a BootImage, name: &str) -> Result<FieldValue<'a>> {
+    let header = image.get_header();
+    header
+        .get_layout()
+        .fields()
+        .iter()
+        .find(|it| it.name == name)
+        .map(|field| header.field_value(field))
+        .ok_or_else(|| BootImgError::InvalidOperation(format!("no header field {}", name)))
+}
+
+// Copies `len` bytes from `buf`, the buffer can be released afterwards
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_parse(
+    buf: *const u8,
+    len: usize,
+    error: *mut *mut c_char,
+) -> *mut BootImg {
+    guard(error, null_mut(), || {
+        let data = unsafe { bytes(buf, len, "buf") }?.to_vec();
+        let image = OwnedBootImage::parse(data)?;
+        let blocks = image
+            .get_blocks()
+            .blocks()
+            .map(|(name, offset, len)| (CString::new(name).unwrap(), offset, len))
+            .collect();
+        Ok(Box::into_raw(Box::new(BootImg { image, blocks })))
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_free(image: *mut BootImg) {
+    if !image.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(unsafe { Box::from_raw(image) })));
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+// Header field as printed by the info command, numbers in hex
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_header_field(
+    image: *const BootImg,
+    name: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    guard(error, null_mut(), || {
+        let image = unsafe { &*non_null(image, "image")? }.image.get();
+        let name = unsafe { c_str(name, "name") }?;
+        let value = field_value(image, name)?.to_string();
+        Ok(CString::new(value).unwrap_or_default().into_raw())
+    })
+}
+
+// Numeric header fields only, false for names, cmdlines and ids
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_header_field_u64(
+    image: *const BootImg,
+    name: *const c_char,
+    value: *mut u64,
+    error: *mut *mut c_char,
+) -> bool {
+    guard(error, false, || {
+        let image = unsafe { &*non_null(image, "image")? }.image.get();
+        let name = unsafe { c_str(name, "name") }?;
+        let value = non_null(value, "value")? as *mut u64;
+        let number = match field_value(image, name)? {
+            FieldValue::U32(v) => v as u64,
+            FieldValue::U64(v) => v,
+            FieldValue::Bytes(_) => {
+                return Err(BootImgError::InvalidOperation(format!(
+                    "header field {} is not a number",
+                    name
+                )));
+            }
+        };
+        unsafe { *value = number };
+        Ok(true)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_block_count(image: *const BootImg) -> usize {
+    guard(null_mut(), 0, || {
+        Ok(unsafe { &*non_null(image, "image")? }.blocks.len())
+    })
+}
+
+// Blocks in file order, offsets are from the start of the image
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_block_at(
+    image: *const BootImg,
+    index: usize,
+    span: *mut BootImgBlockSpan,
+) -> bool {
+    guard(null_mut(), false, || {
+        let image = unsafe { &*non_null(image, "image")? };
+        let span = non_null(span, "span")? as *mut BootImgBlockSpan;
+        let Some((name, offset, len)) = image.blocks.get(index) else {
+            return Ok(false);
+        };
+        unsafe {
+            *span = BootImgBlockSpan {
+                name: name.as_ptr(),
+                offset: *offset,
+                len: *len,
+            }
+        };
+        Ok(true)
+    })
+}
+
+// False if the image has no such block
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_find_block(
+    image: *const BootImg,
+    name: *const c_char,
+    span: *mut BootImgBlockSpan,
+) -> bool {
+    guard(null_mut(), false, || {
+        let boot = unsafe { &*non_null(image, "image")? };
+        let name = unsafe { c_str(name, "name") }?;
+        let Some(index) = boot
+            .blocks
+            .iter()
+            .position(|(it, _, _)| it.to_bytes() == name.as_bytes())
+        else {
+            return Ok(false);
+        };
+        Ok(unsafe { bootimg_block_at(image, index, span) })
+    })
+}
+
+// Writes the image with the blocks in `replacements` swapped to `output`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bootimg_patch(
+    image: *const BootImg,
+    replacements: *const BootImgReplacement,
+    count: usize,
+    output: *const c_char,
+    error: *mut *mut c_char,
+) -> bool {
+    guard(error, false, || {
+        let image = unsafe { &*non_null(image, "image")? }.image.get();
+        let output = unsafe { c_str(output, "output") }?;
+        let replacements = if count == 0 {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(non_null(replacements, "replacements")?, count) }
+        };
+        let mut patcher = BootImagePatchOption::new(image);
+        for replacement in replacements {
+            let block = unsafe { c_str(replacement.block, "block") }?;
+            let data = unsafe { bytes(replacement.data, replacement.len, "data") }?.to_vec();
+            match block {
+                "kernel" => patcher.replace_kernel(Box::new(Cursor::new(data))),
+                "ramdisk" => patcher.replace_ramdisk(Box::new(Cursor::new(data))),
+                "second" => patcher.replace_second(data),
+                "dtb" => patcher.replace_dtb(data),
+                "recovery_dtbo" => patcher.replace_recovery_dtbo(data),
+                _ => {
+                    return Err(BootImgError::InvalidOperation(format!(
+                        "cannot replace block {}",
+                        block
+                    )));
+                }
+            };
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output)?;
+        patcher.patch(&mut file)?;
+        Ok(true)
+    })
+}
@@ -0,0 +1,130 @@
+use crate::error::{BootImgError, Result};
+use crate::parser::{BootHeader, OsVersion, PatchLevel};
+use log::warn;
+use num_traits::Num;
+use paste::paste;
+use std::io::Write;
+
+// key=value lines, same keys as the `header` file of magiskboot unpack plus
+// the load addresses and the informational size fields
+
+// Fields of a header text file that are applied when patching
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderOverrides {
+    pub name: Option<String>,
+    pub cmdline: Option<String>,
+    pub os_version: Option<OsVersion>,
+    pub patch_level: Option<PatchLevel>,
+    pub kernel_addr: Option<u32>,
+    pub ramdisk_addr: Option<u32>,
+    pub second_addr: Option<u32>,
+    pub tags_addr: Option<u32>,
+    pub dtb_addr: Option<u64>,
+}
+
+// Written by dump_text but recomputed on patch
+const INFO_KEYS: &[&str] = &[
+    "header_version",
+    "page_size",
+    "header_size",
+    "kernel_size",
+    "ramdisk_size",
+    "second_size",
+    "recovery_dtbo_size",
+    "recovery_dtbo_offset",
+    "dtb_size",
+    "signature_size",
+    "vendor_ramdisk_table_size",
+    "vendor_ramdisk_table_entry_num",
+    "vendor_ramdisk_table_entry_size",
+    "bootconfig_size",
+];
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidHeaderText(msg)
+}
+
+// Decimal or 0x prefixed hex
+fn parse_int<T: Num>(key: &str, value: &str) -> Result<T> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => T::from_str_radix(hex, 16),
+        None => T::from_str_radix(value, 10),
+    };
+    parsed.map_err(|_| invalid(format!("invalid value {} for {}", value, key)))
+}
+
+impl BootHeader<'_> {
+    pub fn dump_text(&self, out: &mut dyn Write) -> Result<()> {
+        if self.has_name() {
+            writeln!(out, "name={}", self.name_str_lossy())?;
+        }
+        writeln!(out, "cmdline={}", self.cmdline_str_lossy())?;
+        if self.has_os_version_raw()
+            && let Some((os_version, patch_level)) = self.get_os_version()
+        {
+            writeln!(out, "os_version={}", os_version)?;
+            writeln!(out, "os_patch_level={}", patch_level)?;
+        }
+
+        macro_rules! dump_addr {
+            ($($name:ident),*) => {
+                paste! {
+                    $(
+                        if self.[<has_ $name>]() {
+                            writeln!(out, "{}={:#010x}", stringify!($name), self.[<get_ $name>]())?;
+                        }
+                    )*
+                }
+            };
+        }
+        dump_addr! { kernel_addr, ramdisk_addr, second_addr, tags_addr, dtb_addr }
+
+        macro_rules! dump_info {
+            ($($name:ident),*) => {
+                paste! {
+                    $(
+                        if self.[<has_ $name>]() {
+                            writeln!(out, "{}={}", stringify!($name), self.[<get_ $name>]())?;
+                        }
+                    )*
+                }
+            };
+        }
+        dump_info! {
+            header_version, page_size, header_size, kernel_size, ramdisk_size, second_size,
+            recovery_dtbo_size, recovery_dtbo_offset, dtb_size, signature_size,
+            vendor_ramdisk_table_size, vendor_ramdisk_table_entry_num,
+            vendor_ramdisk_table_entry_size, bootconfig_size
+        }
+        Ok(())
+    }
+}
+
+// Unknown keys are ignored with a warning, like magiskboot does
+pub fn parse_header_text(text: &str) -> Result<HeaderOverrides> {
+    let mut overrides = HeaderOverrides::default();
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("missing = in line {:?}", line)))?;
+        let key = key.trim();
+        match key {
+            "name" => overrides.name = Some(value.to_string()),
+            "cmdline" => overrides.cmdline = Some(value.to_string()),
+            "os_version" => overrides.os_version = Some(value.trim().parse()?),
+            "os_patch_level" => overrides.patch_level = Some(value.trim().parse()?),
+            "kernel_addr" => overrides.kernel_addr = Some(parse_int(key, value.trim())?),
+            "ramdisk_addr" => overrides.ramdisk_addr = Some(parse_int(key, value.trim())?),
+            "second_addr" => overrides.second_addr = Some(parse_int(key, value.trim())?),
+            "tags_addr" => overrides.tags_addr = Some(parse_int(key, value.trim())?),
+            "dtb_addr" => overrides.dtb_addr = Some(parse_int(key, value.trim())?),
+            _ if INFO_KEYS.contains(&key) => {}
+            _ => warn!("ignoring unknown header key {}", key),
+        }
+    }
+    Ok(overrides)
+}
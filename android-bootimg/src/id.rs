@@ -0,0 +1,135 @@
+use crate::parser::BootImage;
+use crate::parser::BootImageVersion::Android;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::io::Write;
+
+// https://android.googlesource.com/platform/system/tools/mkbootimg/+/refs/heads/main/mkbootimg.py
+pub const BOOT_ID_SIZE: usize = 32;
+const SHA1_DIGEST_SIZE: usize = 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdHashKind {
+    Sha1,
+    Sha256,
+}
+
+impl IdHashKind {
+    // mkbootimg stores a zero padded SHA1, some vendor tools use the whole field for SHA256
+    pub fn detect(id: &[u8]) -> Option<Self> {
+        if id.iter().all(|&b| b == 0) {
+            None
+        } else if id[SHA1_DIGEST_SIZE.min(id.len())..].iter().all(|&b| b == 0) {
+            Some(IdHashKind::Sha1)
+        } else {
+            Some(IdHashKind::Sha256)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdStatus {
+    Matched(IdHashKind),
+    Mismatched,
+    NotPresent,
+}
+
+// Both digests are computed since the kind is only known from the source id
+#[derive(Clone, Default)]
+pub(crate) struct IdHasher {
+    sha1: Sha1,
+    sha256: Sha256,
+}
+
+impl IdHasher {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.sha1.update(data);
+        self.sha256.update(data);
+    }
+
+    // Every block is followed by its size, absent blocks only contribute the size
+    pub(crate) fn end_block(&mut self, size: u64) {
+        self.update(&(size as u32).to_le_bytes());
+    }
+
+    pub(crate) fn add_block(&mut self, data: Option<&[u8]>) {
+        let data = data.unwrap_or_default();
+        self.update(data);
+        self.end_block(data.len() as u64);
+    }
+
+    // Blocks after kernel and ramdisk covered by the id of the given header version
+    pub(crate) fn add_tail_blocks(
+        &mut self,
+        version: u32,
+        second: Option<&[u8]>,
+        recovery_dtbo: Option<&[u8]>,
+        dtb: Option<&[u8]>,
+    ) {
+        self.add_block(second);
+        if version > 0 {
+            self.add_block(recovery_dtbo);
+        }
+        if version > 1 {
+            self.add_block(dtb);
+        }
+    }
+
+    pub(crate) fn finish(self, kind: IdHashKind) -> [u8; BOOT_ID_SIZE] {
+        let mut id = [0u8; BOOT_ID_SIZE];
+        match kind {
+            IdHashKind::Sha1 => id[..SHA1_DIGEST_SIZE].copy_from_slice(&self.sha1.finalize()),
+            IdHashKind::Sha256 => id.copy_from_slice(&self.sha256.finalize()),
+        }
+        id
+    }
+}
+
+// Passes writes through while hashing them
+pub(crate) struct IdWriter<'a, W: Write + ?Sized> {
+    pub(crate) inner: &'a mut W,
+    pub(crate) hasher: Option<&'a mut IdHasher>,
+}
+
+impl<W: Write + ?Sized> Write for IdWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(hasher) = self.hasher.as_deref_mut() {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl BootImage<'_> {
+    // Recomputes the id over the blocks and compares it to the one in the header
+    pub fn verify_id(&self) -> IdStatus {
+        let header = &self.header;
+        let Android(version) = header.version else {
+            return IdStatus::NotPresent;
+        };
+        if !header.has_id() {
+            return IdStatus::NotPresent;
+        }
+        let stored = header.get_id();
+        let Some(kind) = IdHashKind::detect(stored) else {
+            return IdStatus::NotPresent;
+        };
+
+        let blocks = &self.blocks;
+        let mut hasher = IdHasher::default();
+        hasher.add_block(blocks.kernel.as_ref().map(|it| it.get_data()));
+        hasher.add_block(blocks.ramdisk.as_ref().map(|it| it.get_data()));
+        hasher.add_tail_blocks(version, blocks.second, blocks.recovery_dtbo, blocks.dtb);
+
+        if hasher.finish(kind) == stored {
+            IdStatus::Matched(kind)
+        } else {
+            IdStatus::Mismatched
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use crate::avb::{AvbDescriptor, AvbPublicKey, VbMetaImage};
+use crate::compress::{CompressFormat, estimate_uncompressed_size};
+use crate::layouts::{AvbVBMetaImageHeader, VendorRamdiskTableEntryType};
+use crate::parser::BootImageVersion::{Android, Vendor};
+use crate::parser::{BootImage, ImageKind};
+use paste::paste;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Summary of a parsed image, for machine-readable output
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BlockInfo {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+    pub compress_format: Option<CompressFormat>,
+    // From the compressed metadata, see estimate_uncompressed_size
+    pub uncompressed_size: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct VendorRamdiskInfo {
+    pub name: String,
+    pub entry_type: VendorRamdiskTableEntryType,
+    pub offset: u64,
+    pub size: u64,
+    pub compress_format: CompressFormat,
+    pub uncompressed_size: Option<u64>,
+    pub board_id: Vec<u32>,
+}
+
+// Digests and keys are hex
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum AvbDescriptorInfo {
+    Property {
+        key: String,
+        value: String,
+    },
+    Hash {
+        partition_name: String,
+        hash_algorithm: String,
+        image_size: u64,
+        salt: String,
+        digest: String,
+        flags: u32,
+    },
+    Hashtree {
+        partition_name: String,
+        hash_algorithm: String,
+        image_size: u64,
+        salt: String,
+        root_digest: String,
+        flags: u32,
+    },
+    KernelCmdline {
+        cmdline: String,
+        flags: u32,
+    },
+    ChainPartition {
+        partition_name: String,
+        rollback_index_location: u32,
+        public_key_sha256: Option<String>,
+        flags: u32,
+    },
+    Unknown {
+        tag: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct VbMetaInfo {
+    pub flags: u32,
+    pub algorithm_type: u32,
+    pub algorithm: Option<&'static str>,
+    pub rollback_index: u64,
+    pub release_string: Option<String>,
+    pub public_key_bits: Option<u32>,
+    // Hex SHA-256 of the embedded public key
+    pub public_key_sha256: Option<String>,
+    pub descriptors: Vec<AvbDescriptorInfo>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AvbInfo {
+    pub original_image_size: u64,
+    pub partition_size: u64,
+    pub vbmeta_offset: u64,
+    pub vbmeta_size: u64,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub vbmeta: VbMetaInfo,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BootInfo {
+    pub header_version: u32,
+    pub vendor: bool,
+    pub kind: ImageKind,
+    pub kind_reason: &'static str,
+    pub page_size: usize,
+    // Every numeric header field the layout has
+    pub fields: BTreeMap<&'static str, u64>,
+    pub name: Option<String>,
+    pub cmdline: String,
+    pub os_version: Option<String>,
+    pub patch_level: Option<String>,
+    pub blocks: Vec<BlockInfo>,
+    pub vendor_ramdisks: Vec<VendorRamdiskInfo>,
+    pub avb: Option<AvbInfo>,
+}
+
+impl BootImage<'_> {
+    pub fn info(&self) -> BootInfo {
+        let header = &self.header;
+        let blocks = &self.blocks;
+
+        let mut fields = BTreeMap::new();
+        macro_rules! add_field {
+            ($($name:ident),*) => {
+                paste! {
+                    $(
+                        if header.[<has_ $name>]() {
+                            fields.insert(stringify!($name), header.[<get_ $name>]() as u64);
+                        }
+                    )*
+                }
+            };
+        }
+        add_field! {
+            kernel_size, ramdisk_size, second_size, page_size, header_version, os_version_raw,
+            recovery_dtbo_size, recovery_dtbo_offset, header_size, dtb_size, signature_size,
+            vendor_ramdisk_table_size, vendor_ramdisk_table_entry_num,
+            vendor_ramdisk_table_entry_size, bootconfig_size, kernel_addr, ramdisk_addr,
+            second_addr, tags_addr, dtb_addr
+        }
+
+        let (os_version, patch_level) = match header.get_os_version() {
+            Some((os_version, patch_level)) if header.has_os_version_raw() => {
+                (Some(os_version.to_string()), Some(patch_level.to_string()))
+            }
+            _ => (None, None),
+        };
+
+        let format_of = |name: &str| match name {
+            "kernel" => blocks
+                .kernel
+                .as_ref()
+                .map(|it| (it.compress_format, it.get_pure_data())),
+            "ramdisk" => blocks
+                .ramdisk
+                .as_ref()
+                .filter(|it| !it.is_vendor_ramdisk())
+                .map(|it| (it.compress_format, it.data)),
+            _ => None,
+        };
+        let block_infos = blocks
+            .blocks()
+            .map(|(name, offset, size)| {
+                let format = format_of(name);
+                BlockInfo {
+                    name,
+                    offset,
+                    size,
+                    compress_format: format.map(|(format, _)| format),
+                    uncompressed_size: format
+                        .and_then(|(format, data)| estimate_uncompressed_size(format, data)),
+                }
+            })
+            .collect();
+
+        let vendor_ramdisks = blocks
+            .ramdisk
+            .as_ref()
+            .map(|it| {
+                it.iter_vendor_ramdisk()
+                    .map(|entry| VendorRamdiskInfo {
+                        name: String::from_utf8_lossy(entry.get_name_raw()).into_owned(),
+                        entry_type: entry.get_entry_type(),
+                        offset: entry.get_entry_offset(),
+                        size: entry.get_entry_size(),
+                        compress_format: entry.get_compress_format(),
+                        uncompressed_size: estimate_uncompressed_size(
+                            entry.get_compress_format(),
+                            entry.get_data(),
+                        ),
+                        board_id: entry.board_id().to_vec(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let avb = self.avb_info.as_ref().map(|avb_info| {
+            let vbmeta = match VbMetaImage::parse(avb_info.avb_header) {
+                Ok(vbmeta) => vbmeta.info(),
+                // Only what the header has
+                Err(_) => {
+                    let header = AvbVBMetaImageHeader {
+                        data: avb_info.avb_header,
+                    };
+                    VbMetaInfo {
+                        flags: header.get_flags(),
+                        algorithm_type: header.get_algorithm_type(),
+                        algorithm: None,
+                        rollback_index: header.get_rollback_index(),
+                        release_string: None,
+                        public_key_bits: None,
+                        public_key_sha256: None,
+                        descriptors: Vec::new(),
+                    }
+                }
+            };
+            AvbInfo {
+                original_image_size: avb_info.avb_footer.get_original_image_size(),
+                partition_size: avb_info.partition_size as u64,
+                vbmeta_offset: avb_info.avb_footer.get_vbmeta_offset(),
+                vbmeta_size: avb_info.avb_footer.get_vbmeta_size(),
+                vbmeta,
+            }
+        });
+
+        let (header_version, vendor) = match header.version {
+            Android(v) => (v, false),
+            Vendor(v) => (v, true),
+        };
+        let kind = self.kind();
+
+        BootInfo {
+            header_version,
+            vendor,
+            kind: kind.kind,
+            kind_reason: kind.reason,
+            page_size: header.page_size(),
+            fields,
+            name: header
+                .has_name()
+                .then(|| header.name_str_lossy().into_owned()),
+            cmdline: header.cmdline_str_lossy().into_owned(),
+            os_version,
+            patch_level,
+            blocks: block_infos,
+            vendor_ramdisks,
+            avb,
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl VbMetaImage<'_> {
+    // No descriptors when they fail to parse
+    pub fn info(&self) -> VbMetaInfo {
+        let public_key = self.public_key().ok().flatten();
+        let descriptors = self
+            .descriptors()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|descriptor| match descriptor {
+                AvbDescriptor::Property(prop) => AvbDescriptorInfo::Property {
+                    key: prop.key,
+                    value: String::from_utf8_lossy(prop.value).into_owned(),
+                },
+                AvbDescriptor::Hash(hash) => AvbDescriptorInfo::Hash {
+                    partition_name: hash.partition_name,
+                    hash_algorithm: hash.hash_algorithm,
+                    image_size: hash.image_size,
+                    salt: hex(hash.salt),
+                    digest: hex(hash.digest),
+                    flags: hash.flags,
+                },
+                AvbDescriptor::Hashtree(tree) => AvbDescriptorInfo::Hashtree {
+                    partition_name: tree.partition_name,
+                    hash_algorithm: tree.hash_algorithm,
+                    image_size: tree.image_size,
+                    salt: hex(tree.salt),
+                    root_digest: hex(tree.root_digest),
+                    flags: tree.flags,
+                },
+                AvbDescriptor::KernelCmdline(cmdline) => AvbDescriptorInfo::KernelCmdline {
+                    cmdline: cmdline.cmdline,
+                    flags: cmdline.flags,
+                },
+                AvbDescriptor::ChainPartition(chain) => AvbDescriptorInfo::ChainPartition {
+                    partition_name: chain.partition_name,
+                    rollback_index_location: chain.rollback_index_location,
+                    public_key_sha256: AvbPublicKey::parse(chain.public_key)
+                        .ok()
+                        .map(|key| hex(&key.fingerprint())),
+                    flags: chain.flags,
+                },
+                AvbDescriptor::Unknown(raw) => AvbDescriptorInfo::Unknown { tag: raw.tag },
+            })
+            .collect();
+        VbMetaInfo {
+            flags: self.flags(),
+            algorithm_type: self.algorithm_type(),
+            algorithm: self.algorithm().map(|it| it.name()),
+            rollback_index: self.rollback_index(),
+            release_string: Some(self.release_string()),
+            public_key_bits: public_key.map(|it| it.key_bits()),
+            public_key_sha256: public_key.map(|it| hex(&it.fingerprint())),
+            descriptors,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::compress::{CompressFormat, LimitedDecoder, get_decoder};
+use crate::dtb::is_fdt;
+use crate::error::Result;
+use crate::utils::SliceExt;
+use std::io::Read;
+
+// https://www.kernel.org/doc/html/latest/arch/arm64/booting.html
+const ARM64_MAGIC_OFFSET: usize = 0x38;
+const ARM64_MAGIC: &[u8] = b"ARM\x64";
+// https://www.kernel.org/doc/html/latest/arch/riscv/boot-image-header.html
+const RISCV_MAGIC_OFFSET: usize = 0x30;
+const RISCV_MAGIC: &[u8] = b"RISCV\0\0\0";
+const RISCV_MAGIC2_OFFSET: usize = 0x38;
+const RISCV_MAGIC2: &[u8] = b"RSC\x05";
+// arch/arm/boot/compressed/head.S
+const ZIMAGE_MAGIC_OFFSET: usize = 0x24;
+const ZIMAGE_MAGIC: u32 = 0x016f2818;
+// https://www.kernel.org/doc/html/latest/arch/x86/boot.html
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+
+const LINUX_BANNER: &[u8] = b"Linux version ";
+const LINUX_BANNER_MAX_LEN: usize = 512;
+// kernel/configs.c
+const IKCONFIG_START: &[u8] = b"IKCFG_ST";
+const IKCONFIG_END: &[u8] = b"IKCFG_ED";
+
+const QCDT_MAGIC: &[u8] = b"QCDT";
+const DTBH_MAGIC: &[u8] = b"DTBH";
+
+// Enough to cover every header recognized by detect_kernel_arch
+pub(crate) const KERNEL_HEADER_PROBE_SIZE: usize = 0x400;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KernelArch {
+    Arm64 {
+        text_offset: u64,
+        image_size: u64,
+        flags: u64,
+    },
+    ArmZImage {
+        start: u32,
+        end: u32,
+    },
+    X86BzImage {
+        protocol_version: u16,
+    },
+    RiscV {
+        text_offset: u64,
+        image_size: u64,
+        version: u32,
+    },
+    Unknown,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct KernelInfo {
+    pub arch: KernelArch,
+    pub compress_format: CompressFormat,
+    pub uncompressed_size: u64,
+}
+
+fn has_magic(data: &[u8], offset: usize, magic: &[u8]) -> bool {
+    data.get(offset..offset + magic.len()) == Some(magic)
+}
+
+// `data` is the start of an uncompressed kernel image
+pub fn detect_kernel_arch(data: &[u8]) -> KernelArch {
+    if has_magic(data, ARM64_MAGIC_OFFSET, ARM64_MAGIC) {
+        KernelArch::Arm64 {
+            text_offset: data.u64_at(8).unwrap_or(0),
+            image_size: data.u64_at(16).unwrap_or(0),
+            flags: data.u64_at(24).unwrap_or(0),
+        }
+    } else if has_magic(data, RISCV_MAGIC2_OFFSET, RISCV_MAGIC2)
+        || has_magic(data, RISCV_MAGIC_OFFSET, RISCV_MAGIC)
+    {
+        KernelArch::RiscV {
+            text_offset: data.u64_at(8).unwrap_or(0),
+            image_size: data.u64_at(16).unwrap_or(0),
+            version: data.u32_at(32).unwrap_or(0),
+        }
+    } else if data.u32_at(ZIMAGE_MAGIC_OFFSET) == Some(ZIMAGE_MAGIC) {
+        KernelArch::ArmZImage {
+            start: data.u32_at(ZIMAGE_MAGIC_OFFSET + 4).unwrap_or(0),
+            end: data.u32_at(ZIMAGE_MAGIC_OFFSET + 8).unwrap_or(0),
+        }
+    } else if has_magic(data, BZIMAGE_MAGIC_OFFSET, BZIMAGE_MAGIC) {
+        KernelArch::X86BzImage {
+            protocol_version: data.u16_at(BZIMAGE_MAGIC_OFFSET + 4).unwrap_or(0),
+        }
+    } else {
+        KernelArch::Unknown
+    }
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|w| w == pattern)
+}
+
+// `data` is an uncompressed kernel image
+pub fn find_linux_banner(data: &[u8]) -> Option<String> {
+    let start = find(data, LINUX_BANNER)?;
+    let data = &data[start..data.len().min(start + LINUX_BANNER_MAX_LEN)];
+    let end = data
+        .iter()
+        .position(|&b| b == 0 || b == b'\n')
+        .unwrap_or(data.len());
+    Some(String::from_utf8_lossy(&data[..end]).into_owned())
+}
+
+// Returns the decompressed kernel config embedded with CONFIG_IKCONFIG
+pub fn extract_ikconfig(data: &[u8], max_size: u64) -> Result<Option<Vec<u8>>> {
+    let Some(start) = find(data, IKCONFIG_START) else {
+        return Ok(None);
+    };
+    let data = &data[start + IKCONFIG_START.len()..];
+    let Some(end) = find(data, IKCONFIG_END) else {
+        return Ok(None);
+    };
+    let mut config = Vec::new();
+    LimitedDecoder::new(get_decoder(CompressFormat::GZIP, &data[..end])?, max_size)
+        .read_to_end(&mut config)?;
+    Ok(Some(config))
+}
+
+// QCDT and DTBH share the same prefix: magic, version, num_entries
+fn is_dt_table(data: &[u8]) -> bool {
+    (has_magic(data, 0, QCDT_MAGIC) || has_magic(data, 0, DTBH_MAGIC))
+        && data
+            .u32_at(4)
+            .is_some_and(|version| (1..=3).contains(&version))
+        && data.u32_at(8).is_some_and(|num| num > 0)
+}
+
+// Same heuristic as Magisk's find_dtb_offset, extended to QCDT/DTBH tables
+// `data` is the kernel block, returns the offset where the appended dtb starts
+pub(crate) fn find_appended_dtb(data: &[u8]) -> Option<usize> {
+    (1..data.len().saturating_sub(4)).find(|&off| {
+        let data = &data[off..];
+        is_fdt(data) || is_dt_table(data)
+    })
+}
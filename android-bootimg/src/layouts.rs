@@ -1,8 +1,12 @@
+use crate::error::{BootImgError, Result};
 use paste::paste;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 use crate::constants::{
-    BOOT_ARGS_SIZE, BOOT_EXTRA_ARGS_SIZE, BOOT_ID_SIZE, BOOT_NAME_SIZE, VENDOR_BOOT_ARGS_SIZE,
-    VENDOR_RAMDISK_NAME_SIZE, VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE,
+    AVB_FOOTER_MAGIC, AVB_MAGIC, BOOT_ARGS_SIZE, BOOT_EXTRA_ARGS_SIZE, BOOT_ID_SIZE,
+    BOOT_NAME_SIZE, VENDOR_BOOT_ARGS_SIZE, VENDOR_RAMDISK_NAME_SIZE,
+    VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE,
 };
 
 macro_rules! def_boot_header_layout {
@@ -19,7 +23,8 @@ macro_rules! def_boot_header_layout {
                     pub [<offset_ $name2>]: u16,
                     pub [<size_ $name2>]: u16,
                 )+
-                pub total_size: u16
+                pub total_size: u16,
+                field_descs: &'static [FieldDesc],
             }
 
             #[allow(unused)]
@@ -32,7 +37,8 @@ macro_rules! def_boot_header_layout {
                     [<offset_ $name2>]: 0,
                     [<size_ $name2>]: 0,
                 )+
-                total_size: 0
+                total_size: 0,
+                field_descs: &[],
             };
         }
     };
@@ -62,6 +68,13 @@ def_boot_header_layout! {
         vendor_ramdisk_table_entry_num u32,
         vendor_ramdisk_table_entry_size u32,
         bootconfig_size u32,
+
+        // load addresses, v0-v2 and vendor only
+        kernel_addr u32,
+        ramdisk_addr u32,
+        second_addr u32,
+        tags_addr u32,
+        dtb_addr u64,
     },
     {
         name,
@@ -71,6 +84,64 @@ def_boot_header_layout! {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FieldKind {
+    U32,
+    U64,
+    Bytes,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FieldDesc {
+    pub name: &'static str,
+    pub offset: u16,
+    pub size: u16,
+    pub kind: FieldKind,
+}
+
+impl BootHeaderLayout {
+    // Every field of the header struct in order, including the magic and reserved space
+    pub fn fields(&self) -> &'static [FieldDesc] {
+        self.field_descs
+    }
+}
+
+const MAGIC_FIELDS: &[FieldDesc] = &[FieldDesc {
+    name: "magic",
+    offset: 0,
+    size: 8,
+    kind: FieldKind::Bytes,
+}];
+
+// Fields of the inherited layout followed by the new ones
+const fn concat_fields<const N: usize>(a: &[FieldDesc], b: &[FieldDesc]) -> [FieldDesc; N] {
+    assert!(a.len() + b.len() == N);
+    let mut out = [MAGIC_FIELDS[0]; N];
+    let mut i = 0;
+    while i < a.len() {
+        out[i] = a[i];
+        i += 1;
+    }
+    let mut j = 0;
+    while j < b.len() {
+        out[i + j] = b[j];
+        j += 1;
+    }
+    out
+}
+
+macro_rules! struct_item_kind {
+    (u32) => {
+        FieldKind::U32
+    };
+    (u64) => {
+        FieldKind::U64
+    };
+    ($sz:expr) => {
+        FieldKind::Bytes
+    };
+}
+
 macro_rules! struct_item_size {
     (u32) => {
         4
@@ -140,6 +211,7 @@ macro_rules! define_boot_header_layout_common {
         $struct_name:ident,
         initial_offset $initial_offset:tt,
         default_layout $default_layout:ident,
+        parent_fields $parent_fields:expr,
         structure {$($name:ident $t:tt),+ $(,)?},
         ifields {$($ifield:ident),* $(,)?},
         sfields {$($sfield:ident),* $(,)?}$(,)?
@@ -150,6 +222,19 @@ macro_rules! define_boot_header_layout_common {
             structure { $($name $t),+ }
         }
         paste! {
+            const [<$struct_name _OWN_FIELDS>]: &[FieldDesc] = &[
+                $(
+                    FieldDesc {
+                        name: stringify!($name),
+                        offset: [<mod_offsets_ $struct_name>]::[<offset_ $name>] as u16,
+                        size: struct_item_size! { $t } as u16,
+                        kind: struct_item_kind! { $t },
+                    },
+                )+
+            ];
+            const [<$struct_name _FIELDS>]: [FieldDesc; $parent_fields.len() + [<$struct_name _OWN_FIELDS>].len()] =
+                concat_fields($parent_fields, [<$struct_name _OWN_FIELDS>]);
+
             pub const $struct_name: BootHeaderLayout = BootHeaderLayout {
                 name: stringify!($struct_name),
                 $(
@@ -160,6 +245,7 @@ macro_rules! define_boot_header_layout_common {
                     [<size_ $sfield>]: [<mod_offsets_ $struct_name>]::[<size_ $sfield>] as u16,
                 )*
                 total_size: [<mod_offsets_ $struct_name>]::total_size as u16,
+                field_descs: &[<$struct_name _FIELDS>],
                 ..$default_layout
             };
         }
@@ -177,6 +263,7 @@ macro_rules! define_boot_header_layout {
             $struct_name,
             initial_offset 8,
             default_layout DEFAULT_LAYOUT,
+            parent_fields MAGIC_FIELDS,
             structure { $($name $t),+ },
             ifields { $($ifield),* },
             sfields { $($sfield),* },
@@ -196,6 +283,7 @@ macro_rules! define_boot_header_layout_inherits {
             $struct_name,
             initial_offset ($inherited_name.total_size as usize),
             default_layout $inherited_name,
+            parent_fields $inherited_name.field_descs,
             structure { $($name $t),+ },
             ifields { $($ifield),* },
             sfields { $($sfield),* },
@@ -223,8 +311,12 @@ define_boot_header_layout! {
     },
     ifields {
         kernel_size,
+        kernel_addr,
         ramdisk_size,
+        ramdisk_addr,
         second_size,
+        second_addr,
+        tags_addr,
         page_size,
         header_version,
         os_version
@@ -233,6 +325,7 @@ define_boot_header_layout! {
         name,
         cmdline,
         id,
+        extra_cmdline,
     },
 }
 
@@ -259,6 +352,7 @@ define_boot_header_layout_inherits! {
     },
     ifields {
         dtb_size,
+        dtb_addr,
     },
     sfields {}
 }
@@ -279,6 +373,7 @@ define_boot_header_layout! {
         ramdisk_size,
         header_version,
         os_version,
+        header_size,
     },
     sfields {
         cmdline,
@@ -313,12 +408,18 @@ define_boot_header_layout! {
     },
     ifields {
         page_size,
+        kernel_addr,
+        ramdisk_addr,
         ramdisk_size,
+        tags_addr,
         header_version,
+        header_size,
         dtb_size,
+        dtb_addr,
     },
     sfields {
         cmdline,
+        name,
     },
 }
 
@@ -393,11 +494,13 @@ pub struct VendorRamdiskTableEntryV4<'a> {
     pub data: &'a [u8],
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum VendorRamdiskTableEntryType {
     None,
     Platform,
     Recovery,
+    Dlkm,
     Unknown(u32),
 }
 
@@ -411,13 +514,23 @@ impl VendorRamdiskTableEntryV4<'_> {
     pub const SIZE: usize = mod_offsets_VendorRamdiskTableEntryV4::total_size;
 
     pub fn get_ramdisk_type(&self) -> VendorRamdiskTableEntryType {
-        let raw = self.get_ramdisk_type_raw();
-        match raw {
-            0 => VendorRamdiskTableEntryType::None,
-            1 => VendorRamdiskTableEntryType::Platform,
-            2 => VendorRamdiskTableEntryType::Recovery,
-            _ => VendorRamdiskTableEntryType::Unknown(raw),
-        }
+        VendorRamdiskTableEntryType::from_raw(self.get_ramdisk_type_raw())
+    }
+
+    // A new entry with zero size and offset, to be filled in with patch
+    pub fn build(
+        name: &str,
+        entry_type: VendorRamdiskTableEntryType,
+        board_id: &[u32; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE],
+    ) -> Result<Vec<u8>> {
+        let mut entry = OwnedVendorRamdiskTableEntryV4 {
+            data: vec![0; Self::SIZE],
+        };
+        entry
+            .set_name(name)?
+            .set_ramdisk_type(entry_type)
+            .set_board_id(board_id);
+        Ok(entry.data)
     }
 
     pub fn patch(&self, ramdisk_size: u32, ramdisk_offset: u32) -> Vec<u8> {
@@ -434,6 +547,134 @@ impl VendorRamdiskTableEntryV4<'_> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedVendorRamdiskTableEntryV4 {
+    pub data: Vec<u8>,
+}
+
+impl OwnedVendorRamdiskTableEntryV4 {
+    pub fn as_entry(&self) -> VendorRamdiskTableEntryV4<'_> {
+        VendorRamdiskTableEntryV4 { data: &self.data }
+    }
+
+    fn put(&mut self, offset: usize, value: &[u8]) {
+        self.data[offset..offset + value.len()].copy_from_slice(value);
+    }
+
+    // NUL padded, one byte is kept for the terminator
+    pub fn set_name(&mut self, name: &str) -> Result<&mut Self> {
+        if name.len() >= VENDOR_RAMDISK_NAME_SIZE || name.contains('\0') {
+            return Err(BootImgError::InvalidOperation(format!(
+                "invalid vendor ramdisk name {:?}",
+                name
+            )));
+        }
+        let mut field = name.as_bytes().to_vec();
+        field.resize(VENDOR_RAMDISK_NAME_SIZE, 0);
+        self.put(
+            mod_offsets_VendorRamdiskTableEntryV4::offset_ramdisk_name,
+            &field,
+        );
+        Ok(self)
+    }
+
+    pub fn set_ramdisk_type(&mut self, entry_type: VendorRamdiskTableEntryType) -> &mut Self {
+        self.put(
+            mod_offsets_VendorRamdiskTableEntryV4::offset_ramdisk_type,
+            &entry_type.to_raw().to_le_bytes(),
+        );
+        self
+    }
+
+    pub fn set_board_id(
+        &mut self,
+        board_id: &[u32; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE],
+    ) -> &mut Self {
+        let bytes: Vec<u8> = board_id.iter().flat_map(|it| it.to_le_bytes()).collect();
+        self.put(
+            mod_offsets_VendorRamdiskTableEntryV4::offset_board_id,
+            &bytes,
+        );
+        self
+    }
+
+    pub fn set_ramdisk_size(&mut self, size: u32) -> &mut Self {
+        self.put(
+            mod_offsets_VendorRamdiskTableEntryV4::offset_ramdisk_size,
+            &size.to_le_bytes(),
+        );
+        self
+    }
+
+    // Relative to the start of the vendor ramdisk block
+    pub fn set_ramdisk_offset(&mut self, offset: u32) -> &mut Self {
+        self.put(
+            mod_offsets_VendorRamdiskTableEntryV4::offset_ramdisk_offset,
+            &offset.to_le_bytes(),
+        );
+        self
+    }
+}
+
+impl From<VendorRamdiskTableEntryV4<'_>> for OwnedVendorRamdiskTableEntryV4 {
+    fn from(entry: VendorRamdiskTableEntryV4<'_>) -> Self {
+        Self {
+            data: entry.data.to_vec(),
+        }
+    }
+}
+
+impl VendorRamdiskTableEntryType {
+    pub fn from_raw(raw: u32) -> Self {
+        match raw {
+            0 => VendorRamdiskTableEntryType::None,
+            1 => VendorRamdiskTableEntryType::Platform,
+            2 => VendorRamdiskTableEntryType::Recovery,
+            3 => VendorRamdiskTableEntryType::Dlkm,
+            _ => VendorRamdiskTableEntryType::Unknown(raw),
+        }
+    }
+
+    pub fn to_raw(&self) -> u32 {
+        match self {
+            VendorRamdiskTableEntryType::None => 0,
+            VendorRamdiskTableEntryType::Platform => 1,
+            VendorRamdiskTableEntryType::Recovery => 2,
+            VendorRamdiskTableEntryType::Dlkm => 3,
+            VendorRamdiskTableEntryType::Unknown(raw) => *raw,
+        }
+    }
+}
+
+impl Display for VendorRamdiskTableEntryType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VendorRamdiskTableEntryType::None => f.write_str("none"),
+            VendorRamdiskTableEntryType::Platform => f.write_str("platform"),
+            VendorRamdiskTableEntryType::Recovery => f.write_str("recovery"),
+            VendorRamdiskTableEntryType::Dlkm => f.write_str("dlkm"),
+            VendorRamdiskTableEntryType::Unknown(raw) => write!(f, "{}", raw),
+        }
+    }
+}
+
+impl FromStr for VendorRamdiskTableEntryType {
+    type Err = BootImgError;
+
+    // The names printed by Display, or the raw value
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "none" => VendorRamdiskTableEntryType::None,
+            "platform" => VendorRamdiskTableEntryType::Platform,
+            "recovery" => VendorRamdiskTableEntryType::Recovery,
+            "dlkm" => VendorRamdiskTableEntryType::Dlkm,
+            raw => Self::from_raw(raw.parse().map_err(|_| {
+                BootImgError::InvalidOperation(format!("unknown vendor ramdisk type {}", s))
+            })?),
+        })
+    }
+}
+
 const AVB_FOOTER_MAGIC_LEN: usize = 4;
 const AVB_MAGIC_LEN: usize = 4;
 const AVB_RELEASE_STRING_SIZE: usize = 48;
@@ -462,7 +703,19 @@ impl AvbFooter<'_> {
 
     pub const SIZE: usize = mod_offsets_AvbFooterLayout::total_size;
 
-    pub fn patch(&self, original_image_size: u64, vbmeta_offset: u64) -> Vec<u8> {
+    // Version 1.0 like avbtool writes
+    pub fn create(original_image_size: u64, vbmeta_offset: u64, vbmeta_size: u64) -> Vec<u8> {
+        let mut v = vec![0u8; Self::SIZE];
+
+        v[..AVB_FOOTER_MAGIC_LEN].copy_from_slice(AVB_FOOTER_MAGIC);
+        v[mod_offsets_AvbFooterLayout::offset_version_major
+            ..mod_offsets_AvbFooterLayout::offset_version_major + 4]
+            .copy_from_slice(&1u32.to_be_bytes());
+
+        AvbFooter { data: &v }.patch(original_image_size, vbmeta_offset, vbmeta_size)
+    }
+
+    pub fn patch(&self, original_image_size: u64, vbmeta_offset: u64, vbmeta_size: u64) -> Vec<u8> {
         let mut v = self.data.to_owned();
 
         v[mod_offsets_AvbFooterLayout::offset_original_image_size
@@ -471,6 +724,9 @@ impl AvbFooter<'_> {
         v[mod_offsets_AvbFooterLayout::offset_vbmeta_offset
             ..mod_offsets_AvbFooterLayout::offset_vbmeta_offset + 8]
             .copy_from_slice(&vbmeta_offset.to_be_bytes());
+        v[mod_offsets_AvbFooterLayout::offset_vbmeta_size
+            ..mod_offsets_AvbFooterLayout::offset_vbmeta_size + 8]
+            .copy_from_slice(&vbmeta_size.to_be_bytes());
 
         v
     }
@@ -504,3 +760,363 @@ define_layout_common! {
 }
 
 pub const AVB_HEADER_SIZE: usize = mod_offsets_AvbVBMetaImageHeaderLayout::total_size;
+
+pub struct AvbVBMetaImageHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl AvbVBMetaImageHeader<'_> {
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u32, required_libavb_version_major }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u32, required_libavb_version_minor }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, authentication_data_block_size }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, auxiliary_data_block_size }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u32, algorithm_type }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, hash_offset }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, hash_size }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, signature_offset }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, signature_size }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, public_key_offset }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, public_key_size }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, public_key_metadata_offset }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, public_key_metadata_size }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, descriptors_offset }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, descriptors_size }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u64, rollback_index }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u32, flags }
+    impl_ifield_accessor_be! { pub, AvbVBMetaImageHeaderLayout, u32, rollback_index_location }
+    impl_sfield_accessor! { pub, AvbVBMetaImageHeaderLayout, release_string }
+
+    // Requires libavb 1.0, block fields are set by patch_blocks
+    pub fn create(release_string: &str) -> Vec<u8> {
+        let mut v = vec![0u8; AVB_HEADER_SIZE];
+
+        v[..AVB_MAGIC_LEN].copy_from_slice(AVB_MAGIC);
+        v[mod_offsets_AvbVBMetaImageHeaderLayout::offset_required_libavb_version_major
+            ..mod_offsets_AvbVBMetaImageHeaderLayout::offset_required_libavb_version_major + 4]
+            .copy_from_slice(&1u32.to_be_bytes());
+        let release_string =
+            &release_string.as_bytes()[..release_string.len().min(AVB_RELEASE_STRING_SIZE - 1)];
+        v[mod_offsets_AvbVBMetaImageHeaderLayout::offset_release_string
+            ..mod_offsets_AvbVBMetaImageHeaderLayout::offset_release_string + release_string.len()]
+            .copy_from_slice(release_string);
+
+        v
+    }
+
+    pub fn patch_flags(&self, flags: u32) -> Vec<u8> {
+        let mut v = self.data.to_owned();
+
+        v[mod_offsets_AvbVBMetaImageHeaderLayout::offset_flags
+            ..mod_offsets_AvbVBMetaImageHeaderLayout::offset_flags + 4]
+            .copy_from_slice(&flags.to_be_bytes());
+
+        v
+    }
+
+    pub fn patch_blocks(&self, blocks: &AvbVBMetaBlocks) -> Vec<u8> {
+        let mut v = self.data.to_owned();
+
+        macro_rules! patch_field {
+            ($($name:ident),*) => {
+                paste! {
+                    $(
+                        let bytes = blocks.$name.to_be_bytes();
+                        v[mod_offsets_AvbVBMetaImageHeaderLayout::[<offset_ $name>]
+                            ..mod_offsets_AvbVBMetaImageHeaderLayout::[<offset_ $name>] + bytes.len()]
+                            .copy_from_slice(&bytes);
+                    )*
+                }
+            };
+        }
+        patch_field! {
+            authentication_data_block_size, auxiliary_data_block_size, algorithm_type,
+            hash_offset, hash_size, signature_offset, signature_size,
+            public_key_offset, public_key_size, public_key_metadata_offset,
+            public_key_metadata_size, descriptors_offset, descriptors_size
+        }
+
+        v
+    }
+}
+
+// Block sizes and offsets of a vbmeta image, written by AvbVBMetaImageHeader::patch_blocks
+#[derive(Debug, Clone, Default)]
+pub struct AvbVBMetaBlocks {
+    pub authentication_data_block_size: u64,
+    pub auxiliary_data_block_size: u64,
+    pub algorithm_type: u32,
+    pub hash_offset: u64,
+    pub hash_size: u64,
+    pub signature_offset: u64,
+    pub signature_size: u64,
+    pub public_key_offset: u64,
+    pub public_key_size: u64,
+    pub public_key_metadata_offset: u64,
+    pub public_key_metadata_size: u64,
+    pub descriptors_offset: u64,
+    pub descriptors_size: u64,
+}
+
+// https://android.googlesource.com/platform/external/avb/+/refs/heads/main/libavb/avb_descriptor.h
+// Offsets are relative to the end of the tag and num_bytes_following
+const AVB_HASH_ALGORITHM_SIZE: usize = 32;
+
+define_layout_common! {
+    AvbHashDescriptorLayout,
+    initial_offset 0,
+    structure {
+        image_size u64,
+        hash_algorithm AVB_HASH_ALGORITHM_SIZE,
+        partition_name_len u32,
+        salt_len u32,
+        digest_len u32,
+        flags u32,
+        reserved 60,
+    },
+}
+
+pub struct AvbHashDescriptorHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl AvbHashDescriptorHeader<'_> {
+    impl_ifield_accessor_be! { pub, AvbHashDescriptorLayout, u64, image_size }
+    impl_sfield_accessor! { pub, AvbHashDescriptorLayout, hash_algorithm }
+    impl_ifield_accessor_be! { pub, AvbHashDescriptorLayout, u32, partition_name_len }
+    impl_ifield_accessor_be! { pub, AvbHashDescriptorLayout, u32, salt_len }
+    impl_ifield_accessor_be! { pub, AvbHashDescriptorLayout, u32, digest_len }
+    impl_ifield_accessor_be! { pub, AvbHashDescriptorLayout, u32, flags }
+
+    pub const SIZE: usize = mod_offsets_AvbHashDescriptorLayout::total_size;
+
+    pub fn create(
+        image_size: u64,
+        hash_algorithm: &str,
+        partition_name_len: u32,
+        salt_len: u32,
+        digest_len: u32,
+    ) -> Vec<u8> {
+        let mut v = AvbHashDescriptorHeader {
+            data: &[0u8; Self::SIZE],
+        }
+        .patch_image_size(image_size);
+
+        let hash_algorithm =
+            &hash_algorithm.as_bytes()[..hash_algorithm.len().min(AVB_HASH_ALGORITHM_SIZE)];
+        v[mod_offsets_AvbHashDescriptorLayout::offset_hash_algorithm
+            ..mod_offsets_AvbHashDescriptorLayout::offset_hash_algorithm + hash_algorithm.len()]
+            .copy_from_slice(hash_algorithm);
+        v[mod_offsets_AvbHashDescriptorLayout::offset_partition_name_len
+            ..mod_offsets_AvbHashDescriptorLayout::offset_partition_name_len + 4]
+            .copy_from_slice(&partition_name_len.to_be_bytes());
+        v[mod_offsets_AvbHashDescriptorLayout::offset_salt_len
+            ..mod_offsets_AvbHashDescriptorLayout::offset_salt_len + 4]
+            .copy_from_slice(&salt_len.to_be_bytes());
+        v[mod_offsets_AvbHashDescriptorLayout::offset_digest_len
+            ..mod_offsets_AvbHashDescriptorLayout::offset_digest_len + 4]
+            .copy_from_slice(&digest_len.to_be_bytes());
+
+        v
+    }
+
+    pub fn patch_image_size(&self, image_size: u64) -> Vec<u8> {
+        let mut v = self.data.to_owned();
+
+        v[mod_offsets_AvbHashDescriptorLayout::offset_image_size
+            ..mod_offsets_AvbHashDescriptorLayout::offset_image_size + 8]
+            .copy_from_slice(&image_size.to_be_bytes());
+
+        v
+    }
+}
+
+define_layout_common! {
+    AvbHashtreeDescriptorLayout,
+    initial_offset 0,
+    structure {
+        dm_verity_version u32,
+        image_size u64,
+        tree_offset u64,
+        tree_size u64,
+        data_block_size u32,
+        hash_block_size u32,
+        fec_num_roots u32,
+        fec_offset u64,
+        fec_size u64,
+        hash_algorithm AVB_HASH_ALGORITHM_SIZE,
+        partition_name_len u32,
+        salt_len u32,
+        root_digest_len u32,
+        flags u32,
+        reserved 60,
+    },
+}
+
+pub struct AvbHashtreeDescriptorHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl AvbHashtreeDescriptorHeader<'_> {
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, dm_verity_version }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u64, image_size }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u64, tree_offset }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u64, tree_size }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, data_block_size }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, hash_block_size }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, fec_num_roots }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u64, fec_offset }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u64, fec_size }
+    impl_sfield_accessor! { pub, AvbHashtreeDescriptorLayout, hash_algorithm }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, partition_name_len }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, salt_len }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, root_digest_len }
+    impl_ifield_accessor_be! { pub, AvbHashtreeDescriptorLayout, u32, flags }
+
+    pub const SIZE: usize = mod_offsets_AvbHashtreeDescriptorLayout::total_size;
+}
+
+define_layout_common! {
+    AvbChainPartitionDescriptorLayout,
+    initial_offset 0,
+    structure {
+        rollback_index_location u32,
+        partition_name_len u32,
+        public_key_len u32,
+        flags u32,
+        reserved 60,
+    },
+}
+
+pub struct AvbChainPartitionDescriptorHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl AvbChainPartitionDescriptorHeader<'_> {
+    impl_ifield_accessor_be! { pub, AvbChainPartitionDescriptorLayout, u32, rollback_index_location }
+    impl_ifield_accessor_be! { pub, AvbChainPartitionDescriptorLayout, u32, partition_name_len }
+    impl_ifield_accessor_be! { pub, AvbChainPartitionDescriptorLayout, u32, public_key_len }
+    impl_ifield_accessor_be! { pub, AvbChainPartitionDescriptorLayout, u32, flags }
+
+    pub const SIZE: usize = mod_offsets_AvbChainPartitionDescriptorLayout::total_size;
+}
+
+define_layout_common! {
+    AvbPropertyDescriptorLayout,
+    initial_offset 0,
+    structure {
+        key_num_bytes u64,
+        value_num_bytes u64,
+    },
+}
+
+pub struct AvbPropertyDescriptorHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl AvbPropertyDescriptorHeader<'_> {
+    impl_ifield_accessor_be! { pub, AvbPropertyDescriptorLayout, u64, key_num_bytes }
+    impl_ifield_accessor_be! { pub, AvbPropertyDescriptorLayout, u64, value_num_bytes }
+
+    pub const SIZE: usize = mod_offsets_AvbPropertyDescriptorLayout::total_size;
+}
+
+define_layout_common! {
+    AvbKernelCmdlineDescriptorLayout,
+    initial_offset 0,
+    structure {
+        flags u32,
+        kernel_cmdline_length u32,
+    },
+}
+
+pub struct AvbKernelCmdlineDescriptorHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl AvbKernelCmdlineDescriptorHeader<'_> {
+    impl_ifield_accessor_be! { pub, AvbKernelCmdlineDescriptorLayout, u32, flags }
+    impl_ifield_accessor_be! { pub, AvbKernelCmdlineDescriptorLayout, u32, kernel_cmdline_length }
+
+    pub const SIZE: usize = mod_offsets_AvbKernelCmdlineDescriptorLayout::total_size;
+}
+
+// Followed by the modulus n and rr = (2^num_bits)^2 mod n, num_bits / 8 bytes each
+define_layout_common! {
+    AvbRSAPublicKeyHeaderLayout,
+    initial_offset 0,
+    structure {
+        key_num_bits u32,
+        n0inv u32,
+    },
+}
+
+pub struct AvbRSAPublicKeyHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl AvbRSAPublicKeyHeader<'_> {
+    impl_ifield_accessor_be! { pub, AvbRSAPublicKeyHeaderLayout, u32, key_num_bits }
+    impl_ifield_accessor_be! { pub, AvbRSAPublicKeyHeaderLayout, u32, n0inv }
+
+    pub const SIZE: usize = mod_offsets_AvbRSAPublicKeyHeaderLayout::total_size;
+}
+
+// https://source.android.com/docs/core/architecture/dto/partitions
+const DT_TABLE_MAGIC_LEN: usize = 4;
+
+define_layout_common! {
+    DtTableHeaderLayout,
+    initial_offset DT_TABLE_MAGIC_LEN,
+    structure {
+        total_size u32,
+        header_size u32,
+        dt_entry_size u32,
+        dt_entry_count u32,
+        dt_entries_offset u32,
+        page_size u32,
+        version u32,
+    },
+}
+
+pub struct DtTableHeader<'a> {
+    pub data: &'a [u8],
+}
+
+impl DtTableHeader<'_> {
+    impl_ifield_accessor_be! { pub, DtTableHeaderLayout, u32, total_size }
+    impl_ifield_accessor_be! { pub, DtTableHeaderLayout, u32, header_size }
+    impl_ifield_accessor_be! { pub, DtTableHeaderLayout, u32, dt_entry_size }
+    impl_ifield_accessor_be! { pub, DtTableHeaderLayout, u32, dt_entry_count }
+    impl_ifield_accessor_be! { pub, DtTableHeaderLayout, u32, dt_entries_offset }
+    impl_ifield_accessor_be! { pub, DtTableHeaderLayout, u32, page_size }
+    impl_ifield_accessor_be! { pub, DtTableHeaderLayout, u32, version }
+
+    pub const SIZE: usize = mod_offsets_DtTableHeaderLayout::total_size;
+}
+
+define_layout_common! {
+    DtTableEntryLayout,
+    initial_offset 0,
+    structure {
+        dt_size u32,
+        dt_offset u32,
+        id u32,
+        rev u32,
+        // v1 uses the first word as flags
+        custom 16,
+    },
+}
+
+pub struct DtTableEntry<'a> {
+    pub data: &'a [u8],
+}
+
+impl DtTableEntry<'_> {
+    impl_ifield_accessor_be! { pub, DtTableEntryLayout, u32, dt_size }
+    impl_ifield_accessor_be! { pub, DtTableEntryLayout, u32, dt_offset }
+    impl_ifield_accessor_be! { pub, DtTableEntryLayout, u32, id }
+    impl_ifield_accessor_be! { pub, DtTableEntryLayout, u32, rev }
+    impl_sfield_accessor! { pub, DtTableEntryLayout, custom }
+
+    pub const SIZE: usize = mod_offsets_DtTableEntryLayout::total_size;
+}
@@ -1,7 +1,32 @@
-mod compress;
+pub mod avb;
+pub mod bootconfig;
+pub mod builder;
+pub mod compress;
 mod constants;
+pub mod container;
 pub mod cpio;
+pub mod digest;
+pub mod dtb;
+pub mod dtbo;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod header_text;
+pub mod id;
+pub mod info;
+pub mod kernel;
 pub mod layouts;
+mod lzo;
+#[cfg(feature = "std-fs")]
+pub mod mkbootimg;
 pub mod parser;
 pub mod patcher;
+pub mod pattern;
+#[cfg(feature = "payload")]
+pub mod payload;
+#[cfg(feature = "sign")]
+pub mod sign;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
 mod utils;
+pub mod verify;
@@ -0,0 +1,485 @@
+use std::io;
+use std::io::Read;
+
+// Decode only, the patcher never writes LZOP
+// https://github.com/nemequ/lzop/blob/master/src/lzop.c
+// https://github.com/torvalds/linux/blob/master/lib/lzo/lzo1x_decompress_safe.c
+
+const LZOP_MAGIC: &[u8] = b"\x89LZO\x00\x0d\x0a\x1a\x0a";
+const F_ADLER32_D: u32 = 0x1;
+const F_ADLER32_C: u32 = 0x2;
+const F_H_EXTRA_FIELD: u32 = 0x40;
+const F_CRC32_D: u32 = 0x100;
+const F_CRC32_C: u32 = 0x200;
+const F_H_FILTER: u32 = 0x800;
+// lzop writes 256K blocks, anything much bigger is garbage
+const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+const M2_MAX_OFFSET: usize = 0x800;
+const M4_BASE_OFFSET: usize = 0x4000;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("lzop: {}", msg))
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn skip(r: &mut impl Read, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut r.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+// Returns the flags, the header checksum is not verified
+fn read_header(r: &mut impl Read) -> io::Result<u32> {
+    let mut magic = [0u8; 9];
+    r.read_exact(&mut magic)?;
+    if magic != LZOP_MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    let version = read_u16(r)?;
+    let _lib_version = read_u16(r)?;
+    if version >= 0x0940 {
+        let _version_needed = read_u16(r)?;
+    }
+    // LZO1X_1, LZO1X_1_15 and LZO1X_999 share the decompressor
+    let method = read_u8(r)?;
+    if !(1..=3).contains(&method) {
+        return Err(invalid(&format!("unsupported method {}", method)));
+    }
+    if version >= 0x0940 {
+        let _level = read_u8(r)?;
+    }
+    let flags = read_u32(r)?;
+    if flags & F_H_FILTER != 0 {
+        return Err(invalid("filters are not supported"));
+    }
+    // mode, mtime low and high
+    skip(r, if version >= 0x0940 { 12 } else { 8 })?;
+    let name_len = read_u8(r)?;
+    // name and header checksum
+    skip(r, name_len as u64 + 4)?;
+    if flags & F_H_EXTRA_FIELD != 0 {
+        let len = read_u32(r)?;
+        skip(r, len as u64 + 4)?;
+    }
+    Ok(flags)
+}
+
+struct BlockHeader {
+    dst_len: usize,
+    src_len: usize,
+    adler32: Option<u32>,
+    crc32: Option<u32>,
+}
+
+// None at the end mark
+fn read_block_header(r: &mut impl Read, flags: u32) -> io::Result<Option<BlockHeader>> {
+    let dst_len = read_u32(r)? as usize;
+    if dst_len == 0 {
+        return Ok(None);
+    }
+    let src_len = read_u32(r)? as usize;
+    if dst_len > MAX_BLOCK_SIZE || src_len > dst_len {
+        return Err(invalid("bad block size"));
+    }
+    let adler32 = (flags & F_ADLER32_D != 0)
+        .then(|| read_u32(r))
+        .transpose()?;
+    let crc32 = (flags & F_CRC32_D != 0).then(|| read_u32(r)).transpose()?;
+    if src_len < dst_len {
+        let checksums = [F_ADLER32_C, F_CRC32_C]
+            .iter()
+            .filter(|&&flag| flags & flag != 0)
+            .count();
+        skip(r, checksums as u64 * 4)?;
+    }
+    Ok(Some(BlockHeader {
+        dst_len,
+        src_len,
+        adler32,
+        crc32,
+    }))
+}
+
+pub(crate) fn lzop_len(data: &[u8]) -> Option<usize> {
+    let mut r = data;
+    let flags = read_header(&mut r).ok()?;
+    while let Some(block) = read_block_header(&mut r, flags).ok()? {
+        r = r.get(block.src_len..)?;
+    }
+    Some(data.len() - r.len())
+}
+
+// Sum of the block sizes in the headers, nothing is decompressed
+pub(crate) fn lzop_uncompressed_size(data: &[u8]) -> Option<u64> {
+    let mut r = data;
+    let flags = read_header(&mut r).ok()?;
+    let mut size = 0u64;
+    while let Some(block) = read_block_header(&mut r, flags).ok()? {
+        size += block.dst_len as u64;
+        r = r.get(block.src_len..)?;
+    }
+    Some(size)
+}
+
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Input<'_> {
+    fn byte(&mut self) -> io::Result<usize> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| invalid("input overrun"))?;
+        self.pos += 1;
+        Ok(b as usize)
+    }
+
+    fn le16(&mut self) -> io::Result<usize> {
+        Ok(self.byte()? | (self.byte()? << 8))
+    }
+
+    // Long lengths are zero bytes worth 255 each, then the last byte
+    fn length(&mut self, base: usize) -> io::Result<usize> {
+        let mut len = base;
+        loop {
+            match self.byte()? {
+                0 => len += 255,
+                b => return Ok(len + b),
+            }
+        }
+    }
+}
+
+// Appends exactly `dst_len` bytes decoded from the LZO1X stream `src` to `dst`
+pub(crate) fn lzo1x_decompress(src: &[u8], dst: &mut Vec<u8>, dst_len: usize) -> io::Result<()> {
+    let start = dst.len();
+    let limit = start + dst_len;
+    let mut input = Input { data: src, pos: 0 };
+
+    macro_rules! literals {
+        ($n:expr) => {
+            let n = $n;
+            let end = input.pos + n;
+            if end > src.len() {
+                return Err(invalid("input overrun"));
+            }
+            if dst.len() + n > limit {
+                return Err(invalid("output overrun"));
+            }
+            dst.extend_from_slice(&src[input.pos..end]);
+            input.pos = end;
+        };
+    }
+
+    // Literals copied by the last instruction: 0, 1-3 after a match, 4 after a run
+    let mut state = 0;
+    if let Some(&first) = src.first()
+        && first > 17
+    {
+        input.pos = 1;
+        let n = first as usize - 17;
+        literals!(n);
+        state = n.min(4);
+    }
+
+    loop {
+        let t = input.byte()?;
+        let (dist, len, next);
+        if t < 16 {
+            match state {
+                0 => {
+                    let n = if t == 0 { input.length(15)? } else { t };
+                    literals!(n + 3);
+                    state = 4;
+                    continue;
+                }
+                4 => {
+                    dist = 1 + M2_MAX_OFFSET + (t >> 2) + (input.byte()? << 2);
+                    len = 3;
+                }
+                _ => {
+                    dist = 1 + (t >> 2) + (input.byte()? << 2);
+                    len = 2;
+                }
+            }
+            next = t & 3;
+        } else if t >= 64 {
+            dist = 1 + ((t >> 2) & 7) + (input.byte()? << 3);
+            len = (t >> 5) + 1;
+            next = t & 3;
+        } else if t >= 32 {
+            len = (if t & 31 == 0 {
+                input.length(31)?
+            } else {
+                t & 31
+            }) + 2;
+            let d = input.le16()?;
+            dist = 1 + (d >> 2);
+            next = d & 3;
+        } else {
+            len = (if t & 7 == 0 { input.length(7)? } else { t & 7 }) + 2;
+            let d = input.le16()?;
+            let offset = ((t & 8) << 11) + (d >> 2);
+            if offset == 0 {
+                // The end of stream is always 0x11 0x00 0x00
+                if t != 0x11 || d != 0 {
+                    return Err(invalid("bad end of stream"));
+                }
+                break;
+            }
+            dist = offset + M4_BASE_OFFSET;
+            next = d & 3;
+        }
+
+        if dist > dst.len() - start {
+            return Err(invalid("lookbehind overrun"));
+        }
+        if dst.len() + len > limit {
+            return Err(invalid("output overrun"));
+        }
+        for _ in 0..len {
+            dst.push(dst[dst.len() - dist]);
+        }
+        literals!(next);
+        state = next;
+    }
+
+    if input.pos != src.len() {
+        return Err(invalid("input not consumed"));
+    }
+    if dst.len() != limit {
+        return Err(invalid("output underrun"));
+    }
+    Ok(())
+}
+
+pub(crate) struct LzopDecoder<R: Read> {
+    read: R,
+    flags: Option<u32>,
+    in_buf: Vec<u8>,
+    out_buf: Vec<u8>,
+    out_pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> LzopDecoder<R> {
+    pub(crate) fn new(read: R) -> Self {
+        Self {
+            read,
+            flags: None,
+            in_buf: Vec::new(),
+            out_buf: Vec::new(),
+            out_pos: 0,
+            finished: false,
+        }
+    }
+
+    fn next_block(&mut self) -> io::Result<()> {
+        let flags = match self.flags {
+            Some(flags) => flags,
+            None => *self.flags.insert(read_header(&mut self.read)?),
+        };
+        self.out_buf.clear();
+        self.out_pos = 0;
+        let Some(block) = read_block_header(&mut self.read, flags)? else {
+            self.finished = true;
+            return Ok(());
+        };
+
+        self.in_buf.resize(block.src_len, 0);
+        self.read.read_exact(&mut self.in_buf)?;
+        if block.src_len == block.dst_len {
+            // Stored
+            self.out_buf.extend_from_slice(&self.in_buf);
+        } else {
+            lzo1x_decompress(&self.in_buf, &mut self.out_buf, block.dst_len)?;
+        }
+
+        if let Some(sum) = block.adler32
+            && adler32(&self.out_buf) != sum
+        {
+            return Err(invalid("adler32 mismatch"));
+        }
+        if let Some(sum) = block.crc32
+            && crc32fast::hash(&self.out_buf) != sum
+        {
+            return Err(invalid("crc32 mismatch"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for LzopDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out_buf.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_block()?;
+        }
+        let len = buf.len().min(self.out_buf.len() - self.out_pos);
+        buf[..len].copy_from_slice(&self.out_buf[self.out_pos..self.out_pos + len]);
+        self.out_pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written by testdata/lzop_ref.py, the text of fixture_text in compress.rs
+    const FIXTURE: &[u8] = include_bytes!("../testdata/plain.lzo");
+    // Magic, versions, method, level, flags, mode, mtime, "plain" and checksum
+    const FIXTURE_HEADER_LEN: usize = 43;
+    const FIXTURE_FLAGS: usize = 17;
+
+    fn text() -> Vec<u8> {
+        b"android-bootimg compression fixture\n".repeat(256)
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        LzopDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn assert_invalid(data: &[u8], msg: &str) {
+        let err = decode(data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(msg), "{}", err);
+    }
+
+    // The fixture header with other flags and blocks, its checksum is not
+    // verified
+    fn with_blocks(flags: u32, blocks: &[u8]) -> Vec<u8> {
+        let mut data = FIXTURE[..FIXTURE_HEADER_LEN].to_vec();
+        data[FIXTURE_FLAGS..FIXTURE_FLAGS + 4].copy_from_slice(&flags.to_be_bytes());
+        data.extend_from_slice(blocks);
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    // lzop stores a block when it does not compress
+    fn stored_block(data: &[u8], adler32: u32, crc32: u32) -> Vec<u8> {
+        let len = (data.len() as u32).to_be_bytes();
+        [
+            &len[..],
+            &len,
+            &adler32.to_be_bytes(),
+            &crc32.to_be_bytes(),
+            data,
+        ]
+        .concat()
+    }
+
+    // The LZO1X stream of the only fixture block
+    fn fixture_block() -> &'static [u8] {
+        let block = &FIXTURE[FIXTURE_HEADER_LEN..];
+        let src_len = u32::from_be_bytes(block[4..8].try_into().unwrap()) as usize;
+        &block[12..12 + src_len]
+    }
+
+    #[test]
+    fn fixture_layout() {
+        assert_eq!(read_header(&mut &FIXTURE[..]).unwrap(), 0x03000001);
+        let mut out = Vec::new();
+        lzo1x_decompress(fixture_block(), &mut out, text().len()).unwrap();
+        assert!(out == text());
+    }
+
+    #[test]
+    fn stored_block_checksums() {
+        let data = b"stored, too short to compress";
+        let block = stored_block(data, adler32(data), crc32fast::hash(data));
+        let stream = with_blocks(F_ADLER32_D | F_CRC32_D, &block);
+        assert_eq!(decode(&stream).unwrap(), data);
+        assert_eq!(lzop_len(&stream), Some(stream.len()));
+        assert_eq!(lzop_uncompressed_size(&stream), Some(data.len() as u64));
+
+        let block = stored_block(data, adler32(data) ^ 1, crc32fast::hash(data));
+        assert_invalid(
+            &with_blocks(F_ADLER32_D | F_CRC32_D, &block),
+            "adler32 mismatch",
+        );
+        let block = stored_block(data, adler32(data), crc32fast::hash(data) ^ 1);
+        assert_invalid(
+            &with_blocks(F_ADLER32_D | F_CRC32_D, &block),
+            "crc32 mismatch",
+        );
+    }
+
+    #[test]
+    fn adler32_mismatch() {
+        let mut data = FIXTURE.to_vec();
+        // After the block sizes
+        data[FIXTURE_HEADER_LEN + 8] ^= 1;
+        assert_invalid(&data, "adler32 mismatch");
+    }
+
+    #[test]
+    fn truncated() {
+        for len in [
+            FIXTURE_HEADER_LEN - 1,
+            FIXTURE_HEADER_LEN + 6,
+            FIXTURE.len() - 10,
+        ] {
+            let err = decode(&FIXTURE[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{}", len);
+            assert_eq!(lzop_len(&FIXTURE[..len]), None);
+        }
+
+        // Without the end of stream
+        let block = fixture_block();
+        let mut out = Vec::new();
+        let err = lzo1x_decompress(&block[..block.len() - 3], &mut out, text().len());
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn end_of_stream() {
+        let block = fixture_block();
+        assert_eq!(&block[block.len() - 3..], b"\x11\x00\x00");
+        // M4 with a zero offset but another length or low bits
+        for end in [b"\x12\x00\x00", b"\x11\x01\x00"] {
+            let block = [&block[..block.len() - 3], end].concat();
+            let mut out = Vec::new();
+            let err = lzo1x_decompress(&block, &mut out, text().len()).unwrap_err();
+            assert!(err.to_string().contains("bad end of stream"), "{}", err);
+        }
+    }
+}
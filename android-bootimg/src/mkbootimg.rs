@@ -0,0 +1,473 @@
+use crate::builder::BootImageBuilder;
+use crate::compress::CompressFormat;
+use crate::constants::VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE;
+use crate::error::{BootImgError, Result};
+use crate::header_text::HeaderOverrides;
+use crate::layouts::VendorRamdiskTableEntryType;
+use crate::parser::BootImageVersion::{Android, Vendor};
+use crate::parser::{OsVersion, PatchLevel};
+use std::fs::File;
+use std::path::PathBuf;
+
+// Arguments of AOSP mkbootimg.py, see
+// https://android.googlesource.com/platform/system/tools/mkbootimg/+/refs/heads/main/mkbootimg.py
+//
+// Supported: the block files, --cmdline, --vendor_cmdline, --board, --base and
+// the *_offset addresses, --pagesize, --os_version, --os_patch_level,
+// --header_version, -o/--output, --vendor_boot, the vendor ramdisk fragment
+// arguments and --id. GKI signing (--gki_signing_*) is not. The invocations in
+// testdata/mkbootimg/goldens.sh are checked byte for byte.
+
+#[derive(Debug, Clone)]
+pub struct VendorRamdiskFragment {
+    pub path: PathBuf,
+    pub name: String,
+    pub entry_type: VendorRamdiskTableEntryType,
+    pub board_id: [u32; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE],
+}
+
+#[derive(Debug, Clone)]
+pub struct MkbootimgArgs {
+    pub header_version: u32,
+    pub kernel: Option<PathBuf>,
+    pub ramdisk: Option<PathBuf>,
+    pub second: Option<PathBuf>,
+    pub dtb: Option<PathBuf>,
+    pub recovery_dtbo: Option<PathBuf>,
+    pub boot_signature: Option<PathBuf>,
+    pub cmdline: String,
+    pub vendor_cmdline: String,
+    pub board: String,
+    pub base: u32,
+    pub kernel_offset: u32,
+    pub ramdisk_offset: u32,
+    pub second_offset: u32,
+    pub tags_offset: u32,
+    pub dtb_offset: u64,
+    pub pagesize: u32,
+    pub os_version: Option<OsVersion>,
+    pub os_patch_level: Option<PatchLevel>,
+    pub output: Option<PathBuf>,
+    pub vendor_boot: Option<PathBuf>,
+    pub vendor_ramdisk: Option<PathBuf>,
+    pub vendor_bootconfig: Option<PathBuf>,
+    pub vendor_ramdisk_fragments: Vec<VendorRamdiskFragment>,
+    // Print the image id of v0-v2 boot images
+    pub id: bool,
+}
+
+impl Default for MkbootimgArgs {
+    fn default() -> Self {
+        Self {
+            header_version: 0,
+            kernel: None,
+            ramdisk: None,
+            second: None,
+            dtb: None,
+            recovery_dtbo: None,
+            boot_signature: None,
+            cmdline: String::new(),
+            vendor_cmdline: String::new(),
+            board: String::new(),
+            base: 0x10000000,
+            kernel_offset: 0x00008000,
+            ramdisk_offset: 0x01000000,
+            second_offset: 0x00f00000,
+            tags_offset: 0x00000100,
+            dtb_offset: 0x01f00000,
+            pagesize: 2048,
+            os_version: None,
+            os_patch_level: None,
+            output: None,
+            vendor_boot: None,
+            vendor_ramdisk: None,
+            vendor_bootconfig: None,
+            vendor_ramdisk_fragments: Vec::new(),
+            id: false,
+        }
+    }
+}
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidMkbootimgArgs(msg)
+}
+
+// Python's int(value, 0)
+fn parse_int(key: &str, value: &str) -> Result<u64> {
+    let lower = value.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(oct) = lower.strip_prefix("0o") {
+        u64::from_str_radix(oct, 8)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| invalid(format!("invalid value {} for {}", value, key)))
+}
+
+fn parse_u32(key: &str, value: &str) -> Result<u32> {
+    u32::try_from(parse_int(key, value)?)
+        .map_err(|_| invalid(format!("{} out of range for {}", value, key)))
+}
+
+impl MkbootimgArgs {
+    // `--key value` and `--key=value`, without the program name. The ramdisk
+    // type, name and board ids apply to the next --vendor_ramdisk_fragment.
+    pub fn parse<I, S>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut parsed = MkbootimgArgs::default();
+        let mut fragment_name = String::new();
+        let mut fragment_type = VendorRamdiskTableEntryType::None;
+        let mut fragment_board_id = [0u32; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE];
+
+        let args: Vec<String> = args.into_iter().map(|it| it.as_ref().to_string()).collect();
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            let (key, inline) = match arg.split_once('=') {
+                Some((key, value)) if key.starts_with("--") => (key.to_string(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+            if key == "--id" {
+                parsed.id = true;
+                continue;
+            }
+            let value = match inline {
+                Some(value) => value.to_string(),
+                None => iter
+                    .next()
+                    .ok_or_else(|| invalid(format!("missing value for {}", key)))?,
+            };
+            let path = || Some(PathBuf::from(&value));
+            match key.as_str() {
+                "--kernel" => parsed.kernel = path(),
+                "--ramdisk" => parsed.ramdisk = path(),
+                "--second" => parsed.second = path(),
+                "--dtb" => parsed.dtb = path(),
+                "--recovery_dtbo" | "--recovery_acpio" => parsed.recovery_dtbo = path(),
+                "--boot_signature" => parsed.boot_signature = path(),
+                "--cmdline" => parsed.cmdline = value,
+                "--vendor_cmdline" => parsed.vendor_cmdline = value,
+                "--board" => parsed.board = value,
+                "--base" => parsed.base = parse_u32(&key, &value)?,
+                "--kernel_offset" => parsed.kernel_offset = parse_u32(&key, &value)?,
+                "--ramdisk_offset" => parsed.ramdisk_offset = parse_u32(&key, &value)?,
+                "--second_offset" => parsed.second_offset = parse_u32(&key, &value)?,
+                "--tags_offset" => parsed.tags_offset = parse_u32(&key, &value)?,
+                "--dtb_offset" => parsed.dtb_offset = parse_int(&key, &value)?,
+                "--pagesize" => parsed.pagesize = parse_u32(&key, &value)?,
+                "--os_version" => parsed.os_version = Some(value.parse()?),
+                "--os_patch_level" => parsed.os_patch_level = Some(value.parse()?),
+                "--header_version" => parsed.header_version = parse_u32(&key, &value)?,
+                "-o" | "--output" | "--out" => parsed.output = path(),
+                "--vendor_boot" => parsed.vendor_boot = path(),
+                "--vendor_ramdisk" => parsed.vendor_ramdisk = path(),
+                "--vendor_bootconfig" => parsed.vendor_bootconfig = path(),
+                "--ramdisk_type" => fragment_type = value.parse()?,
+                "--ramdisk_name" => fragment_name = value,
+                "--vendor_ramdisk_fragment" => {
+                    parsed.vendor_ramdisk_fragments.push(VendorRamdiskFragment {
+                        path: PathBuf::from(value),
+                        name: std::mem::take(&mut fragment_name),
+                        entry_type: std::mem::replace(
+                            &mut fragment_type,
+                            VendorRamdiskTableEntryType::None,
+                        ),
+                        board_id: std::mem::take(&mut fragment_board_id),
+                    });
+                }
+                _ if key.starts_with("--gki_signing") => {
+                    return Err(invalid(format!("{} is not supported", key)));
+                }
+                _ => match key
+                    .strip_prefix("--board_id")
+                    .and_then(|it| it.parse::<usize>().ok())
+                {
+                    Some(index) if index < VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE => {
+                        fragment_board_id[index] = parse_u32(&key, &value)?;
+                    }
+                    _ => return Err(invalid(format!("unknown argument {}", key))),
+                },
+            }
+        }
+
+        if parsed.output.is_none() && parsed.vendor_boot.is_none() {
+            return Err(invalid("no --output or --vendor_boot given".to_string()));
+        }
+        if parsed.vendor_boot.is_some() && parsed.header_version < 3 {
+            return Err(invalid(format!(
+                "--vendor_boot needs header version 3 or later, got {}",
+                parsed.header_version
+            )));
+        }
+        Ok(parsed)
+    }
+
+    fn addr(&self, offset: u32) -> u32 {
+        self.base.wrapping_add(offset)
+    }
+
+    fn add_file(builder: &mut BootImageBuilder, name: &str, path: &Option<PathBuf>) -> Result<()> {
+        if let Some(path) = path {
+            builder.add_block(name, Box::new(File::open(path)?), CompressFormat::UNKNOWN)?;
+        }
+        Ok(())
+    }
+
+    // The image written to --output, None without it
+    pub fn boot_builder(&self) -> Result<Option<BootImageBuilder>> {
+        if self.output.is_none() {
+            return Ok(None);
+        }
+        let mut builder = BootImageBuilder::new(Android(self.header_version))?;
+        builder.page_size(self.pagesize).header(HeaderOverrides {
+            cmdline: Some(self.cmdline.clone()),
+            os_version: self.os_version,
+            patch_level: self.os_patch_level,
+            ..Default::default()
+        });
+        if self.header_version < 3 {
+            builder
+                .name(&self.board)
+                .kernel_addr(self.addr(self.kernel_offset))
+                .ramdisk_addr(self.addr(self.ramdisk_offset))
+                .second_addr(self.addr(self.second_offset))
+                .tags_addr(self.addr(self.tags_offset))
+                .dtb_addr(self.base as u64 + self.dtb_offset);
+            Self::add_file(&mut builder, "second", &self.second)?;
+            if self.header_version > 0 {
+                Self::add_file(&mut builder, "recovery_dtbo", &self.recovery_dtbo)?;
+            }
+            if self.header_version > 1 {
+                Self::add_file(&mut builder, "dtb", &self.dtb)?;
+            }
+        } else if self.header_version > 3 {
+            Self::add_file(&mut builder, "signature", &self.boot_signature)?;
+        }
+        Self::add_file(&mut builder, "kernel", &self.kernel)?;
+        Self::add_file(&mut builder, "ramdisk", &self.ramdisk)?;
+        Ok(Some(builder))
+    }
+
+    // The image written to --vendor_boot, None without it
+    pub fn vendor_boot_builder(&self) -> Result<Option<BootImageBuilder>> {
+        if self.vendor_boot.is_none() {
+            return Ok(None);
+        }
+        let mut builder = BootImageBuilder::new(Vendor(self.header_version))?;
+        builder
+            .page_size(self.pagesize)
+            .name(&self.board)
+            .cmdline(&self.vendor_cmdline)
+            .kernel_addr(self.addr(self.kernel_offset))
+            .ramdisk_addr(self.addr(self.ramdisk_offset))
+            .tags_addr(self.addr(self.tags_offset))
+            .dtb_addr(self.base as u64 + self.dtb_offset);
+        Self::add_file(&mut builder, "dtb", &self.dtb)?;
+        if self.header_version > 3 {
+            // mkbootimg puts --vendor_ramdisk first, as an unnamed platform ramdisk
+            if let Some(path) = &self.vendor_ramdisk {
+                builder.add_vendor_ramdisk(
+                    "",
+                    VendorRamdiskTableEntryType::Platform,
+                    [0; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE],
+                    Box::new(File::open(path)?),
+                    CompressFormat::UNKNOWN,
+                )?;
+            }
+            for fragment in &self.vendor_ramdisk_fragments {
+                builder.add_vendor_ramdisk(
+                    &fragment.name,
+                    fragment.entry_type,
+                    fragment.board_id,
+                    Box::new(File::open(&fragment.path)?),
+                    CompressFormat::UNKNOWN,
+                )?;
+            }
+            Self::add_file(&mut builder, "bootconfig", &self.vendor_bootconfig)?;
+        } else {
+            Self::add_file(&mut builder, "ramdisk", &self.vendor_ramdisk)?;
+        }
+        Ok(Some(builder))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The invocations of testdata/mkbootimg/goldens.sh, run from that directory
+    const GOLDENS: &[(&str, &str)] = &[
+        (
+            "boot-v0.img",
+            "--header_version 0 --kernel kernel --ramdisk ramdisk --second second \
+             --cmdline $CMDLINE --board golden --base 0x80000000 --pagesize 2048 \
+             --os_version 10.0.0 --os_patch_level 2020-01 -o boot-v0.img",
+        ),
+        (
+            "boot-v1.img",
+            "--header_version 1 --kernel kernel --ramdisk ramdisk \
+             --recovery_dtbo recovery_dtbo --cmdline $CMDLINE --base 0x00000000 \
+             --kernel_offset 0x00080000 --pagesize 4096 --os_version 11 \
+             --os_patch_level 2021-06 -o boot-v1.img",
+        ),
+        (
+            "boot-v2.img",
+            "--header_version 2 --kernel kernel --ramdisk ramdisk --second second \
+             --recovery_dtbo recovery_dtbo --dtb dtb --cmdline $CMDLINE --board golden \
+             --base 0x40000000 --ramdisk_offset 0x02000000 --tags_offset 0x00000100 \
+             --dtb_offset 0x01f00000 --pagesize 2048 --os_version 12.1.0 \
+             --os_patch_level 2022-03 -o boot-v2.img",
+        ),
+        (
+            "boot-v3.img",
+            "--header_version 3 --kernel kernel --ramdisk ramdisk \
+             --cmdline $CMDLINE --os_version 12 --os_patch_level 2022-05 -o boot-v3.img",
+        ),
+        (
+            "boot-v4.img",
+            "--header_version 4 --kernel kernel --ramdisk ramdisk \
+             --cmdline $CMDLINE --os_version 13.0.0 --os_patch_level 2023-05 -o boot-v4.img",
+        ),
+        (
+            "vendor_boot-v3.img",
+            "--header_version 3 --vendor_boot vendor_boot-v3.img \
+             --vendor_ramdisk vendor_ramdisk --dtb dtb --vendor_cmdline $CMDLINE \
+             --board golden --base 0x00000000 --kernel_offset 0x00008000 --pagesize 2048",
+        ),
+        (
+            "vendor_boot-v4.img",
+            "--header_version 4 --vendor_boot vendor_boot-v4.img \
+             --vendor_ramdisk vendor_ramdisk --dtb dtb --vendor_cmdline $CMDLINE \
+             --board golden --pagesize 4096 --vendor_bootconfig bootconfig \
+             --ramdisk_type dlkm --ramdisk_name dlkm --board_id0 0x1234 \
+             --vendor_ramdisk_fragment dlkm",
+        ),
+    ];
+    const CMDLINE: &str = "console=ttyMSM0,115200n8 androidboot.hardware=qcom";
+    const INPUTS: &[&str] = &[
+        "--kernel",
+        "--ramdisk",
+        "--second",
+        "--recovery_dtbo",
+        "--dtb",
+        "--vendor_ramdisk",
+        "--vendor_bootconfig",
+        "--vendor_ramdisk_fragment",
+    ];
+
+    #[test]
+    fn golden_invocations() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/mkbootimg");
+        for (golden, invocation) in GOLDENS {
+            let mut args: Vec<String> = Vec::new();
+            for arg in invocation.split_whitespace() {
+                let arg = match args.last() {
+                    _ if arg == "$CMDLINE" => CMDLINE.to_string(),
+                    Some(key) if INPUTS.contains(&key.as_str()) => {
+                        dir.join(arg).display().to_string()
+                    }
+                    _ => arg.to_string(),
+                };
+                args.push(arg);
+            }
+            let args = MkbootimgArgs::parse(args).unwrap();
+            let builder = match args.boot_builder().unwrap() {
+                Some(builder) => builder,
+                None => args.vendor_boot_builder().unwrap().unwrap(),
+            };
+            let mut image = Vec::new();
+            builder.build(&mut image).unwrap();
+            assert!(
+                image == std::fs::read(dir.join(golden)).unwrap(),
+                "{}",
+                golden
+            );
+        }
+    }
+
+    #[test]
+    fn parse_values() {
+        let args = MkbootimgArgs::parse([
+            "--base=0x80000000",
+            "--kernel_offset",
+            "0o100000",
+            "--ramdisk_offset=0b1",
+            "--tags_offset",
+            "256",
+            "--dtb_offset",
+            "0x1F00000",
+            "--cmdline=a=b c",
+            "--id",
+            "-o",
+            "boot.img",
+        ])
+        .unwrap();
+        assert_eq!(args.base, 0x80000000);
+        assert_eq!(args.addr(args.kernel_offset), 0x80008000);
+        assert_eq!(args.addr(args.ramdisk_offset), 0x80000001);
+        assert_eq!(args.addr(args.tags_offset), 0x80000100);
+        assert_eq!(args.dtb_offset, 0x1f00000);
+        assert_eq!(args.cmdline, "a=b c");
+        assert!(args.id);
+        assert_eq!(args.output, Some(PathBuf::from("boot.img")));
+        // Defaults of mkbootimg.py
+        assert_eq!(args.pagesize, 2048);
+        assert_eq!(args.header_version, 0);
+        assert_eq!(args.second_offset, 0x00f00000);
+    }
+
+    // Type, name and board ids only apply to the next fragment
+    #[test]
+    fn parse_fragments() {
+        let args = MkbootimgArgs::parse([
+            "--header_version=4",
+            "--vendor_boot=vendor_boot.img",
+            "--ramdisk_type=recovery",
+            "--ramdisk_name=recovery",
+            "--board_id15=7",
+            "--vendor_ramdisk_fragment=recovery.cpio",
+            "--vendor_ramdisk_fragment=none.cpio",
+        ])
+        .unwrap();
+        let [first, second] = &args.vendor_ramdisk_fragments[..] else {
+            panic!("{:?}", args.vendor_ramdisk_fragments);
+        };
+        assert_eq!(first.path, PathBuf::from("recovery.cpio"));
+        assert_eq!(first.name, "recovery");
+        assert_eq!(first.entry_type, VendorRamdiskTableEntryType::Recovery);
+        assert_eq!(first.board_id[15], 7);
+        assert_eq!(second.name, "");
+        assert_eq!(second.entry_type, VendorRamdiskTableEntryType::None);
+        assert_eq!(
+            second.board_id,
+            [0; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for args in [
+            &["--kernel", "kernel"][..],
+            &["-o", "boot.img", "--unknown", "1"],
+            &["-o", "boot.img", "--board_id16", "1"],
+            &["-o", "boot.img", "--gki_signing_key", "key.pem"],
+            &["-o", "boot.img", "--base", "0x100000000"],
+            &["-o", "boot.img", "--pagesize", "4k"],
+            &["-o", "boot.img", "--cmdline"],
+            &["--header_version", "2", "--vendor_boot", "vendor_boot.img"],
+        ] {
+            let err = MkbootimgArgs::parse(args).unwrap_err();
+            assert!(
+                matches!(err, BootImgError::InvalidMkbootimgArgs(_)),
+                "{:?}: {}",
+                args,
+                err
+            );
+        }
+    }
+}
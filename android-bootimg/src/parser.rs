@@ -1,55 +1,279 @@
-use crate::compress::{CompressFormat, get_decoder, parse_compress_format};
-use crate::constants::{AVB_FOOTER_MAGIC, AVB_MAGIC};
+use crate::avb::VbMetaImage;
+use crate::bootconfig::Bootconfig;
+use crate::compress::{
+    CompressFormat, LimitedDecoder, compressed_stream_len, get_decoder, get_scavenging_decoder,
+    parse_compress_format,
+};
+use crate::constants::{
+    AVB_FOOTER_MAGIC, AVB_MAGIC, AVB_PARTITION_ALIGN, CPIO_NEWC_CRC_MAGIC, CPIO_NEWC_MAGIC,
+    EROFS_SUPER_MAGIC, EROFS_SUPER_OFFSET, EXT4_SUPER_MAGIC, EXT4_SUPER_MAGIC_OFFSET,
+    VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE,
+};
+use crate::cpio::{Cpio, CpioLoadOptions};
+use crate::dtb::{DtbEntry, parse_dtb_entries};
+use crate::dtbo::DtboTable;
+use crate::error::{BootImgError, Result};
+use crate::kernel::{
+    KERNEL_HEADER_PROBE_SIZE, KernelInfo, detect_kernel_arch, extract_ikconfig, find_appended_dtb,
+    find_linux_banner,
+};
 use crate::layouts::{
-    AvbFooter, BOOT_HEADER_V0, BOOT_HEADER_V1, BOOT_HEADER_V2, BOOT_HEADER_V3, BOOT_HEADER_V4,
-    BootHeaderLayout, VENDOR_BOOT_HEADER_V3, VENDOR_BOOT_HEADER_V4, VendorRamdiskTableEntryType,
-    VendorRamdiskTableEntryV4,
+    AVB_HEADER_SIZE, AvbFooter, BOOT_HEADER_V0, BOOT_HEADER_V1, BOOT_HEADER_V2, BOOT_HEADER_V3,
+    BOOT_HEADER_V4, BootHeaderLayout, FieldDesc, FieldKind, VENDOR_BOOT_HEADER_V3,
+    VENDOR_BOOT_HEADER_V4, VendorRamdiskTableEntryType, VendorRamdiskTableEntryV4,
 };
 use crate::parser::BootImageVersion::{Android, Vendor};
 use crate::utils::{SliceExt, align_to, trim_end};
-use anyhow::bail;
+use log::{debug, warn};
 use paste::paste;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
-use std::io::Write;
+use std::io::{Read, Write};
+use std::mem::ManuallyDrop;
+use std::ptr::NonNull;
 use std::slice::Iter;
-use std::str::from_utf8;
+use std::str::{FromStr, from_utf8};
 
-const BOOT_MAGIC: &[u8] = b"ANDROID!";
-const VENDOR_BOOT_MAGIC: &[u8] = b"VNDRBOOT";
+pub(crate) const BOOT_MAGIC: &[u8] = b"ANDROID!";
+pub(crate) const VENDOR_BOOT_MAGIC: &[u8] = b"VNDRBOOT";
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct OsVersion {
     a: u32,
     b: u32,
     c: u32,
 }
 
+impl OsVersion {
+    // Each component is stored in 7 bits
+    pub fn new(a: u32, b: u32, c: u32) -> Result<Self> {
+        if a > 0x7f || b > 0x7f || c > 0x7f {
+            return Err(BootImgError::InvalidOsVersion(format!(
+                "{}.{}.{} out of range",
+                a, b, c
+            )));
+        }
+        Ok(Self { a, b, c })
+    }
+
+    pub fn encode_raw(&self) -> u32 {
+        (self.a << 14) | (self.b << 7) | self.c
+    }
+}
+
+impl FromStr for OsVersion {
+    type Err = BootImgError;
+
+    // "13", "13.0" or "13.0.0"
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BootImgError::InvalidOsVersion(s.to_string());
+        let mut parts = [0u32; 3];
+        for (i, part) in s.split('.').enumerate() {
+            *parts.get_mut(i).ok_or_else(invalid)? = part.parse().map_err(|_| invalid())?;
+        }
+        Self::new(parts[0], parts[1], parts[2])
+    }
+}
+
 impl Display for OsVersion {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{}.{}.{}", self.a, self.b, self.c))
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct PatchLevel {
     year: u32,
     month: u32,
 }
 
+impl PatchLevel {
+    // The year is stored as an offset from 2000 in 7 bits
+    pub fn new(year: u32, month: u32) -> Result<Self> {
+        if !(2000..2000 + 0x80).contains(&year) || !(1..=12).contains(&month) {
+            return Err(BootImgError::InvalidOsVersion(format!(
+                "patch level {}-{} out of range",
+                year, month
+            )));
+        }
+        Ok(Self { year, month })
+    }
+
+    pub fn encode_raw(&self) -> u32 {
+        ((self.year - 2000) << 4) | self.month
+    }
+
+    fn days_in_month(&self) -> u32 {
+        let leap = self.year.is_multiple_of(4)
+            && (!self.year.is_multiple_of(100) || self.year.is_multiple_of(400));
+        match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+}
+
+impl FromStr for PatchLevel {
+    type Err = BootImgError;
+
+    // "2023-08" or "2023-08-05", the day is not stored in the header
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BootImgError::InvalidOsVersion(s.to_string());
+        let mut parts = s.split('-');
+        let year = parts.next().ok_or_else(invalid)?;
+        let month = parts.next().ok_or_else(invalid)?;
+        let day = parts.next();
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        let level = Self::new(
+            year.parse().map_err(|_| invalid())?,
+            month.parse().map_err(|_| invalid())?,
+        )?;
+        if let Some(day) = day {
+            let day = day.parse::<u32>().map_err(|_| invalid())?;
+            if !(1..=level.days_in_month()).contains(&day) {
+                return Err(invalid());
+            }
+        }
+        Ok(level)
+    }
+}
+
 impl Display for PatchLevel {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}-{}", self.year, self.month))
+        f.write_fmt(format_args!("{}-{:02}", self.year, self.month))
     }
 }
 
+// Packed value of the os_version header field
+pub fn encode_os_version(os_version: &OsVersion, patch_level: &PatchLevel) -> u32 {
+    (os_version.encode_raw() << 11) | patch_level.encode_raw()
+}
+
 #[derive(Debug, Copy, Clone)]
+pub struct ParseOptions {
+    // Recover from inconsistencies (truncated blocks, misplaced footers, ...) and
+    // record them as warnings instead of failing
+    pub lenient: bool,
+    // Parse newer Android versions with the v4 layout (vendor: VENDOR_BOOT_HEADER_V4)
+    // instead of failing. Such images can only be patched after opting in again.
+    pub allow_unknown_versions: bool,
+    // Decompressing a single block past this fails with DecompressLimitExceeded
+    pub max_decompressed_size: u64,
+}
+
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            lenient: false,
+            allow_unknown_versions: false,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+impl ParseOptions {
+    // Lenient parsing also decodes damaged streams as far as they go
+    pub(crate) fn decoder<'a>(
+        &self,
+        format: CompressFormat,
+        data: &'a [u8],
+    ) -> Result<Box<dyn Read + 'a>> {
+        let decoder = if self.lenient {
+            get_scavenging_decoder(format, data)?
+        } else {
+            get_decoder(format, data)?
+        };
+        Ok(Box::new(LimitedDecoder::new(
+            decoder,
+            self.max_decompressed_size,
+        )))
+    }
+
+    // Bounded like decoder, but damage fails even in lenient mode. For
+    // containers, backups and payloads, whose content is checked as a whole.
+    pub(crate) fn strict_decoder<'a, R: Read + 'a>(
+        &self,
+        format: CompressFormat,
+        r: R,
+    ) -> Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(LimitedDecoder::new(
+            get_decoder(format, r)?,
+            self.max_decompressed_size,
+        )))
+    }
+}
+
+// How far from the end of the data the AVB footer is searched in lenient mode
+const LENIENT_AVB_FOOTER_SEARCH: usize = 0x4000;
+// How much zero padding after the partition end is skipped in lenient mode
+const LENIENT_AVB_TRAILING_ZEROS: usize = 16 << 20;
+
+pub(crate) struct ParseContext {
+    pub(crate) options: ParseOptions,
+    pub(crate) warnings: Vec<String>,
+}
+
+impl ParseContext {
+    pub(crate) fn new(options: ParseOptions) -> Self {
+        Self {
+            options,
+            warnings: Vec::new(),
+        }
+    }
+
+    // Returns the error in strict mode, otherwise records it as a warning
+    pub(crate) fn recover(&mut self, err: BootImgError) -> Result<()> {
+        if self.options.lenient {
+            self.warn(err.to_string());
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    pub(crate) fn warn(&mut self, msg: String) {
+        warn!("{}", msg);
+        self.warnings.push(msg);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum BootImageVersion {
     Android(u32),
     Vendor(u32),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue<'a> {
+    U32(u32),
+    U64(u64),
+    Bytes(&'a [u8]),
+}
+
+impl Display for FieldValue<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::U32(v) => write!(f, "{:#x}", v),
+            FieldValue::U64(v) => write!(f, "{:#x}", v),
+            FieldValue::Bytes(v) => write!(f, "{:?}", String::from_utf8_lossy(trim_end(v))),
+        }
+    }
+}
+
 pub struct BootHeader<'a> {
     pub(crate) data: &'a [u8],
     pub(crate) layout: &'static BootHeaderLayout,
     pub(crate) version: BootImageVersion,
+    pub(crate) page_size: usize,
+    pub(crate) unknown_version: bool,
+    pub(crate) hdr_space: usize,
 }
 
 macro_rules! impl_ifield_accessor {
@@ -85,6 +309,14 @@ macro_rules! impl_sfield_accessor {
     };
 }
 
+fn header_slice<'a>(data: &'a [u8], layout: &BootHeaderLayout) -> Result<&'a [u8]> {
+    let size = layout.total_size as usize;
+    data.get(..size).ok_or(BootImgError::TruncatedHeader {
+        size,
+        available: data.len(),
+    })
+}
+
 impl<'a> BootHeader<'a> {
     impl_ifield_accessor! { pub, u32, kernel_size }
     impl_ifield_accessor! { pub, u32, ramdisk_size }
@@ -101,11 +333,77 @@ impl<'a> BootHeader<'a> {
     impl_ifield_accessor! { pub, u32, vendor_ramdisk_table_entry_num }
     impl_ifield_accessor! { pub, u32, vendor_ramdisk_table_entry_size }
     impl_ifield_accessor! { pub, u32, bootconfig_size }
+    impl_ifield_accessor! { pub, u32, kernel_addr }
+    impl_ifield_accessor! { pub, u32, ramdisk_addr }
+    impl_ifield_accessor! { pub, u32, second_addr }
+    impl_ifield_accessor! { pub, u32, tags_addr }
+    impl_ifield_accessor! { pub, u64, dtb_addr }
     impl_sfield_accessor! { pub, name }
     impl_sfield_accessor! { pub, cmdline }
     impl_sfield_accessor! { pub, id }
     impl_sfield_accessor! { pub, extra_cmdline }
 
+    // cmdline followed by extra_cmdline (v0-v2), without the NUL padding
+    pub fn cmdline_str(&self) -> Result<Cow<'a, str>> {
+        let cmdline = from_utf8(trim_end(self.cmdline_field()))?;
+        let extra = from_utf8(trim_end(self.extra_cmdline_field()))?;
+        Ok(if extra.is_empty() {
+            Cow::Borrowed(cmdline)
+        } else {
+            Cow::Owned(format!("{}{}", cmdline, extra))
+        })
+    }
+
+    pub fn cmdline_str_lossy(&self) -> Cow<'a, str> {
+        let cmdline = String::from_utf8_lossy(trim_end(self.cmdline_field()));
+        let extra = trim_end(self.extra_cmdline_field());
+        if extra.is_empty() {
+            cmdline
+        } else {
+            Cow::Owned(cmdline.into_owned() + &String::from_utf8_lossy(extra))
+        }
+    }
+
+    pub fn name_str(&self) -> Result<&'a str> {
+        Ok(from_utf8(trim_end(self.name_field()))?)
+    }
+
+    pub fn name_str_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(trim_end(self.name_field()))
+    }
+
+    // Same as the sfield accessors but bound to the image lifetime
+    fn cmdline_field(&self) -> &'a [u8] {
+        let offset = self.layout.offset_cmdline as usize;
+        &self.data[offset..offset + self.layout.size_cmdline as usize]
+    }
+
+    fn extra_cmdline_field(&self) -> &'a [u8] {
+        let offset = self.layout.offset_extra_cmdline as usize;
+        &self.data[offset..offset + self.layout.size_extra_cmdline as usize]
+    }
+
+    fn name_field(&self) -> &'a [u8] {
+        let offset = self.layout.offset_name as usize;
+        &self.data[offset..offset + self.layout.size_name as usize]
+    }
+
+    // The whole header struct of this version
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    // `field` must come from the layout of this header
+    pub fn field_value(&self, field: &FieldDesc) -> FieldValue<'a> {
+        let offset = field.offset as usize;
+        let data = &self.data[offset..offset + field.size as usize];
+        match field.kind {
+            FieldKind::U32 => FieldValue::U32(data.u32_at(0).unwrap()),
+            FieldKind::U64 => FieldValue::U64(data.u64_at(0).unwrap()),
+            FieldKind::Bytes => FieldValue::Bytes(data),
+        }
+    }
+
     pub fn get_layout(&self) -> &'static BootHeaderLayout {
         self.layout
     }
@@ -133,68 +431,149 @@ impl<'a> BootHeader<'a> {
     }
 
     pub fn page_size(&self) -> usize {
-        match self.version {
-            Android(v) => {
-                if v >= 3 {
-                    return 4096;
-                }
-            }
-            _ => {}
+        self.page_size
+    }
+
+    fn raw_page_size(layout: &BootHeaderLayout, version: BootImageVersion, data: &[u8]) -> usize {
+        if let Android(v) = version
+            && v >= 3
+        {
+            return 4096;
         }
 
-        self.get_page_size() as usize
+        data.u32_at(layout.offset_page_size as usize).unwrap_or(0) as usize
     }
 
     pub fn hdr_space(&self) -> usize {
-        // TODO: only vendor boot has page count > 1
-        align_to(self.layout.total_size as usize, self.page_size())
-    }
-
-    pub fn parse(data: &'a [u8]) -> anyhow::Result<Self> {
-        if data.starts_with(BOOT_MAGIC) {
-            if let Some(version) = data.u32_at(BOOT_HEADER_V0.offset_header_version as usize) {
-                let layout = match version {
-                    0 => &BOOT_HEADER_V0,
-                    1 => &BOOT_HEADER_V1,
-                    2 => &BOOT_HEADER_V2,
-                    3 => &BOOT_HEADER_V3,
-                    4 => &BOOT_HEADER_V4,
-                    _ => bail!("unsupported boot version {}", version),
-                };
+        self.hdr_space
+    }
 
-                let data = &data[..layout.total_size as usize];
+    // Parsed with the layout of the latest known version
+    pub fn is_unknown_version(&self) -> bool {
+        self.unknown_version
+    }
 
-                return Ok(Self {
-                    data,
-                    layout,
-                    version: Android(version),
-                });
-            }
+    pub fn checked_page_size(&self) -> Result<usize> {
+        let page_size = self.page_size();
+        if page_size == 0 || !page_size.is_power_of_two() {
+            return Err(BootImgError::InvalidPageSize(page_size));
+        }
+        Ok(page_size)
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        Self::parse_with_context(data, &mut ParseContext::new(ParseOptions::default()))
+    }
+
+    pub(crate) fn parse_with_context(data: &'a [u8], ctx: &mut ParseContext) -> Result<Self> {
+        let mut unknown_version = false;
+        let (layout, version) = if data.starts_with(BOOT_MAGIC) {
+            let version = data
+                .u32_at(BOOT_HEADER_V0.offset_header_version as usize)
+                .ok_or(BootImgError::TruncatedHeader {
+                    size: BOOT_HEADER_V0.total_size as usize,
+                    available: data.len(),
+                })?;
+            let layout = match version {
+                0 => &BOOT_HEADER_V0,
+                1 => &BOOT_HEADER_V1,
+                2 => &BOOT_HEADER_V2,
+                3 => &BOOT_HEADER_V3,
+                4 => &BOOT_HEADER_V4,
+                _ if version > 4 && ctx.options.allow_unknown_versions => {
+                    unknown_version = true;
+                    &BOOT_HEADER_V4
+                }
+                _ => {
+                    return Err(BootImgError::UnsupportedHeaderVersion {
+                        found: version,
+                        vendor: false,
+                    });
+                }
+            };
+            (layout, Android(version))
         } else if data.starts_with(VENDOR_BOOT_MAGIC) {
-            if let Some(version) = data.u32_at(VENDOR_BOOT_HEADER_V3.offset_header_version as usize)
-            {
-                let layout = match version {
-                    3 => &VENDOR_BOOT_HEADER_V3,
-                    4 => &VENDOR_BOOT_HEADER_V4,
-                    _ => bail!("unsupported vendor boot version {}", version),
-                };
+            let version = data
+                .u32_at(VENDOR_BOOT_HEADER_V3.offset_header_version as usize)
+                .ok_or(BootImgError::TruncatedHeader {
+                    size: VENDOR_BOOT_HEADER_V3.total_size as usize,
+                    available: data.len(),
+                })?;
+            let layout = match version {
+                3 => &VENDOR_BOOT_HEADER_V3,
+                4 => &VENDOR_BOOT_HEADER_V4,
+                _ if version > 4 && ctx.options.allow_unknown_versions => {
+                    unknown_version = true;
+                    &VENDOR_BOOT_HEADER_V4
+                }
+                _ => {
+                    return Err(BootImgError::UnsupportedHeaderVersion {
+                        found: version,
+                        vendor: true,
+                    });
+                }
+            };
+            (layout, Vendor(version))
+        } else {
+            return Err(BootImgError::NotBootImage);
+        };
 
-                let data = &data[..layout.total_size as usize];
+        if unknown_version {
+            ctx.warn(format!(
+                "unknown header version {:?}, parsed as {}",
+                version, layout.name
+            ));
+        }
 
-                return Ok(Self {
-                    data,
-                    layout,
-                    version: Vendor(version),
-                });
-            }
+        let image_len = data.len();
+        let data = header_slice(data, layout)?;
+
+        debug!("header version {:?} layout {}", version, layout.name);
+
+        let mut header = Self {
+            data,
+            layout,
+            version,
+            page_size: Self::raw_page_size(layout, version, data),
+            unknown_version,
+            hdr_space: 0,
+        };
+        if let Err(e) = header.checked_page_size() {
+            ctx.recover(e)?;
+            // Fall back to the mkbootimg defaults
+            header.page_size = match version {
+                Android(_) => 2048,
+                Vendor(_) => 4096,
+            };
+        }
+
+        // header_size may be larger than the struct, and the vendor boot header
+        // spans more than one page with 2K pages
+        let header_size = if header.has_header_size() {
+            header.get_header_size() as usize
+        } else {
+            0
+        };
+        header.hdr_space = align_to(
+            header_size.max(layout.total_size as usize),
+            header.page_size,
+        );
+        if header.hdr_space > image_len {
+            ctx.recover(BootImgError::TruncatedHeader {
+                size: header_size,
+                available: image_len,
+            })?;
+            header.hdr_space = align_to(layout.total_size as usize, header.page_size);
         }
-        bail!("invalid boot image")
+        Ok(header)
     }
 }
 
 pub struct KernelImage<'a> {
     pub(crate) data: &'a [u8],
     pub(crate) compress_format: CompressFormat,
+    pub(crate) pure_len: usize,
+    pub(crate) options: ParseOptions,
 }
 
 impl KernelImage<'_> {
@@ -206,15 +585,161 @@ impl KernelImage<'_> {
         self.compress_format
     }
 
-    pub fn dump(&self, out: &mut dyn Write, raw: bool) -> anyhow::Result<()> {
-        dump_block(self.data, out, raw)
+    // The kernel without the appended dtb
+    pub fn get_pure_data(&self) -> &[u8] {
+        &self.data[..self.pure_len]
+    }
+
+    pub fn get_pure_len(&self) -> usize {
+        self.pure_len
+    }
+
+    pub fn appended_dtb(&self) -> Option<&[u8]> {
+        if self.pure_len < self.data.len() {
+            Some(&self.data[self.pure_len..])
+        } else {
+            None
+        }
+    }
+
+    pub fn dump(&self, out: &mut dyn Write, raw: bool) -> Result<()> {
+        dump_block("kernel", self.get_pure_data(), out, raw, &self.options)
+    }
+
+    pub fn get_uncompressed(&self) -> Result<Cow<'_, [u8]>> {
+        if self.compress_format == CompressFormat::UNKNOWN {
+            return Ok(Cow::Borrowed(self.get_pure_data()));
+        }
+        let mut data = Vec::new();
+        self.dump(&mut data, false)?;
+        Ok(Cow::Owned(data))
+    }
+
+    pub fn linux_banner(&self) -> Result<Option<String>> {
+        Ok(find_linux_banner(&self.get_uncompressed()?))
+    }
+
+    pub fn ikconfig(&self) -> Result<Option<Vec<u8>>> {
+        extract_ikconfig(
+            &self.get_uncompressed()?,
+            self.options.max_decompressed_size,
+        )
+    }
+
+    pub fn inspect(&self) -> Result<KernelInfo> {
+        let mut reader: Box<dyn Read> = if self.compress_format == CompressFormat::UNKNOWN {
+            Box::new(self.get_pure_data())
+        } else {
+            self.options
+                .decoder(self.compress_format, self.get_pure_data())
+                .map_err(|e| e.with_context("while decompressing kernel".to_string()))?
+        };
+        let mut header = Vec::with_capacity(KERNEL_HEADER_PROBE_SIZE);
+        reader
+            .as_mut()
+            .take(KERNEL_HEADER_PROBE_SIZE as u64)
+            .read_to_end(&mut header)?;
+        let rest = std::io::copy(&mut reader, &mut std::io::sink())?;
+        Ok(KernelInfo {
+            arch: detect_kernel_arch(&header),
+            compress_format: self.compress_format,
+            uncompressed_size: header.len() as u64 + rest,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RamdiskContentKind {
+    Cpio,
+    Erofs,
+    Ext4,
+    Unknown,
+}
+
+// Covers the ext4 superblock magic
+const RAMDISK_CONTENT_PROBE_SIZE: usize = 2048;
+
+impl RamdiskContentKind {
+    // `data` is the start of a decompressed ramdisk
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(CPIO_NEWC_MAGIC) || data.starts_with(CPIO_NEWC_CRC_MAGIC) {
+            RamdiskContentKind::Cpio
+        } else if data.u32_at(EROFS_SUPER_OFFSET) == Some(EROFS_SUPER_MAGIC) {
+            RamdiskContentKind::Erofs
+        } else if data.u16_at(EXT4_SUPER_MAGIC_OFFSET) == Some(EXT4_SUPER_MAGIC) {
+            RamdiskContentKind::Ext4
+        } else {
+            RamdiskContentKind::Unknown
+        }
+    }
+}
+
+fn detect_ramdisk_content(data: &[u8], options: &ParseOptions) -> Result<RamdiskContentKind> {
+    let format = parse_compress_format(data);
+    let reader: Box<dyn Read> = if format == CompressFormat::UNKNOWN {
+        Box::new(data)
+    } else {
+        options
+            .decoder(format, data)
+            .map_err(|e| e.with_context("while decompressing ramdisk".to_string()))?
+    };
+    let mut head = Vec::with_capacity(RAMDISK_CONTENT_PROBE_SIZE);
+    reader
+        .take(RAMDISK_CONTENT_PROBE_SIZE as u64)
+        .read_to_end(&mut head)?;
+    Ok(RamdiskContentKind::detect(&head))
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RamdiskSegment<'a> {
+    pub offset: usize,
+    pub data: &'a [u8],
+    pub compress_format: CompressFormat,
+    pub(crate) options: ParseOptions,
+}
+
+impl<'a> RamdiskSegment<'a> {
+    pub fn dump(&self, out: &mut dyn Write, raw: bool) -> Result<()> {
+        dump_block("ramdisk", self.data, out, raw, &self.options)
+    }
+
+    pub fn reader(&self) -> Result<Box<dyn Read + 'a>> {
+        block_reader("ramdisk", self.data, &self.options)
     }
 }
 
+// Splits concatenated compressed streams, anything that can't be walked
+// extends to the end of the data. Trailing zeros stay in the last segment.
+fn split_segments(data: &[u8], options: ParseOptions) -> Vec<RamdiskSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        let compress_format = parse_compress_format(rest);
+        let len = compressed_stream_len(compress_format, rest)
+            .filter(|&len| len > 0)
+            .unwrap_or(rest.len());
+        let len = if rest[len..].iter().all(|&b| b == 0) {
+            rest.len()
+        } else {
+            len
+        };
+        segments.push(RamdiskSegment {
+            offset,
+            data: &rest[..len],
+            compress_format,
+            options,
+        });
+        offset += len;
+    }
+    segments
+}
+
 pub struct RamdiskImage<'a> {
     pub(crate) data: &'a [u8],
     pub(crate) compress_format: CompressFormat,
     pub(crate) vendor_ramdisk_table: Option<Vec<VendorRamdiskEntry<'a>>>,
+    pub(crate) options: ParseOptions,
 }
 
 impl RamdiskImage<'_> {
@@ -226,11 +751,73 @@ impl RamdiskImage<'_> {
         self.compress_format
     }
 
-    pub fn dump(&self, out: &mut dyn Write, raw: bool) -> anyhow::Result<()> {
+    pub fn dump(&self, out: &mut dyn Write, raw: bool) -> Result<()> {
+        if self.vendor_ramdisk_table.is_some() {
+            return Err(BootImgError::InvalidOperation(
+                "vendor ramdisk must be dumped per entry".to_string(),
+            ));
+        }
+        if raw {
+            return dump_block("ramdisk", self.data, out, raw, &self.options);
+        }
+        for segment in self.segments() {
+            segment.dump(out, raw)?;
+        }
+        Ok(())
+    }
+
+    pub fn segments(&self) -> Vec<RamdiskSegment<'_>> {
+        split_segments(self.data, self.options)
+    }
+
+    // Streams what dump writes, every segment decompressed in turn
+    pub fn reader(&self) -> Result<Box<dyn Read + '_>> {
+        if self.vendor_ramdisk_table.is_some() {
+            return Err(BootImgError::InvalidOperation(
+                "vendor ramdisk must be read per entry".to_string(),
+            ));
+        }
+        let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+        for segment in self.segments() {
+            reader = Box::new(reader.chain(segment.reader()?));
+        }
+        Ok(reader)
+    }
+
+    pub fn get_content_kind(&self) -> Result<RamdiskContentKind> {
         if self.vendor_ramdisk_table.is_some() {
-            bail!("")
+            return Err(BootImgError::InvalidOperation(
+                "vendor ramdisk must be inspected per entry".to_string(),
+            ));
+        }
+        match self.segments().first() {
+            Some(segment) => detect_ramdisk_content(segment.data, &self.options),
+            None => Ok(RamdiskContentKind::Unknown),
         }
-        dump_block(self.data, out, raw)
+    }
+
+    // Loads every vendor ramdisk entry in table order into one cpio, also returns
+    // the index of the entry each path finally came from
+    pub fn merge_vendor_ramdisks(&self) -> Result<(Cpio<'static>, BTreeMap<Vec<u8>, usize>)> {
+        let Some(table) = &self.vendor_ramdisk_table else {
+            return Err(BootImgError::InvalidOperation(
+                "not a vendor ramdisk".to_string(),
+            ));
+        };
+        let mut merged = Cpio::new();
+        let mut provenance = BTreeMap::new();
+        let load_options = CpioLoadOptions {
+            lenient: self.options.lenient,
+            ..Default::default()
+        };
+        for (index, entry) in table.iter().enumerate() {
+            let cpio = Cpio::load_from_reader(entry.reader()?, &load_options)?;
+            for path in cpio.entries().keys() {
+                provenance.insert(path.clone(), index);
+            }
+            merged.merge(cpio);
+        }
+        Ok((merged, provenance))
     }
 
     pub fn is_vendor_ramdisk(&self) -> bool {
@@ -250,6 +837,11 @@ impl RamdiskImage<'_> {
             .and_then(|t| t.get(index))
     }
 
+    pub fn get_vendor_ramdisk_by_name(&self, name: &str) -> Option<&VendorRamdiskEntry<'_>> {
+        self.iter_vendor_ramdisk()
+            .find(|entry| entry.get_name_raw() == name.as_bytes())
+    }
+
     pub fn iter_vendor_ramdisk(&self) -> Iter<'_, VendorRamdiskEntry<'_>> {
         self.vendor_ramdisk_table
             .as_ref()
@@ -267,6 +859,9 @@ pub struct BootImageBlocks<'a> {
     pub(crate) dtb: Option<&'a [u8]>,
     pub(crate) signature: Option<&'a [u8]>,
     pub(crate) bootconfig: Option<&'a [u8]>,
+    // (name, offset, size) of every present block, in file order
+    pub(crate) offsets: Vec<(&'static str, usize, usize)>,
+    pub(crate) options: ParseOptions,
 }
 
 impl<'a> BootImageBlocks<'a> {
@@ -278,8 +873,61 @@ impl<'a> BootImageBlocks<'a> {
         self.ramdisk.as_ref()
     }
 
-    pub fn parse(data: &'a [u8], boot_header: &BootHeader) -> anyhow::Result<(Self, usize)> {
+    pub fn get_second(&self) -> Option<&'a [u8]> {
+        self.second
+    }
+
+    pub fn get_recovery_dtbo(&self) -> Option<&'a [u8]> {
+        self.recovery_dtbo
+    }
+
+    pub fn get_recovery_dtbo_table(&self) -> Result<Option<DtboTable<'a>>> {
+        self.recovery_dtbo.map(DtboTable::parse).transpose()
+    }
+
+    pub fn get_dtb(&self) -> Option<&'a [u8]> {
+        self.dtb
+    }
+
+    // Empty if there is no dtb block
+    pub fn get_dtb_entries(&self) -> Result<Vec<DtbEntry<'a>>> {
+        self.dtb.map_or(Ok(Vec::new()), parse_dtb_entries)
+    }
+
+    pub fn get_signature(&self) -> Option<&'a [u8]> {
+        self.signature
+    }
+
+    pub fn get_bootconfig(&self) -> Option<&'a [u8]> {
+        self.bootconfig
+    }
+
+    pub fn get_bootconfig_params(&self) -> Result<Option<Bootconfig<'a>>> {
+        self.bootconfig
+            .map(|data| Bootconfig::parse(data, self.options.lenient))
+            .transpose()
+    }
+
+    // Yields (name, offset, len) for every present block
+    pub fn blocks(&self) -> impl Iterator<Item = (&'static str, usize, usize)> + '_ {
+        self.offsets.iter().copied()
+    }
+
+    pub fn parse(data: &'a [u8], boot_header: &BootHeader) -> Result<(Self, usize)> {
+        Self::parse_with_context(
+            data,
+            boot_header,
+            &mut ParseContext::new(ParseOptions::default()),
+        )
+    }
+
+    pub(crate) fn parse_with_context(
+        data: &'a [u8],
+        boot_header: &BootHeader,
+        ctx: &mut ParseContext,
+    ) -> Result<(Self, usize)> {
         let mut off = boot_header.hdr_space();
+        let mut offsets = Vec::new();
         let page_size = boot_header.page_size();
 
         macro_rules! build_blocks {
@@ -291,11 +939,26 @@ impl<'a> BootImageBlocks<'a> {
                             let block_size = boot_header.[<get_ $name _size>]();
                             let size = block_size as usize;
                             if size > 0 {
-                                if let Some(slice) = data.get(off..off + size) {
+                                if let Some(slice) = off.checked_add(size).and_then(|end| data.get(off..end)) {
+                                    debug!("block {} off {} size {}", stringify!($name), off, size);
+                                    offsets.push((stringify!($name), off, size));
                                     off += align_to(size, page_size);
                                     Some(slice)
                                 } else {
-                                    bail!("invalid block {} off {} size {}", stringify!($name), off, size)
+                                    let available = data.len().saturating_sub(off);
+                                    ctx.recover(BootImgError::TruncatedBlock {
+                                        name: stringify!($name),
+                                        offset: off,
+                                        size,
+                                        available,
+                                    })?;
+                                    // Clamp to what is left of the data
+                                    let slice = &data[data.len() - available..];
+                                    if !slice.is_empty() {
+                                        offsets.push((stringify!($name), off, available));
+                                    }
+                                    off = data.len();
+                                    if slice.is_empty() { None } else { Some(slice) }
                                 }
                             } else {
                                 None
@@ -320,38 +983,47 @@ impl<'a> BootImageBlocks<'a> {
             bootconfig
         }
 
-        let kernel = if let Some(data) = kernel {
-            Some(KernelImage {
-                data,
-                compress_format: parse_compress_format(data),
-            })
-        } else {
-            None
-        };
+        let kernel = kernel.map(|data| KernelImage {
+            data,
+            compress_format: parse_compress_format(data),
+            pure_len: find_appended_dtb(data).unwrap_or(data.len()),
+            options: ctx.options,
+        });
 
         let vendor_ramdisk_table = if let Some(entry_table) = &vendor_ramdisk_table {
             let entry_size = boot_header.get_vendor_ramdisk_table_entry_size() as usize;
-            if entry_size != VendorRamdiskTableEntryV4::SIZE {
-                bail!("invalid vendor ramdisk table entry size: {}", entry_size);
+            // Newer entries may extend v4, only the known prefix is used
+            if entry_size < VendorRamdiskTableEntryV4::SIZE {
+                return Err(BootImgError::InvalidVendorRamdiskTable(format!(
+                    "invalid entry size: {}",
+                    entry_size
+                )));
             }
 
-            let entry_table_size =
-                boot_header.get_vendor_ramdisk_table_entry_num() as usize * entry_size;
+            let entry_num = boot_header.get_vendor_ramdisk_table_entry_num() as usize;
+            let entry_table_size = entry_num.checked_mul(entry_size).ok_or_else(|| {
+                BootImgError::InvalidVendorRamdiskTable(format!(
+                    "entry num {} overflows table size",
+                    entry_num
+                ))
+            })?;
 
+            // The table block itself is already bounded by the file
             if entry_table.len() < entry_table_size {
-                bail!(
-                    "invalid vendor ramdisk table entry size: {}",
+                return Err(BootImgError::InvalidVendorRamdiskTable(format!(
+                    "{} entries do not fit in table size {}",
+                    entry_num,
                     entry_table.len()
-                );
+                )));
             }
 
             let entry_table = &entry_table[..entry_table_size];
 
-            if ramdisk.is_none() {
-                bail!("missing ramdisk")
-            }
-
-            let ramdisk = ramdisk.as_ref().unwrap();
+            let Some(ramdisk) = ramdisk.as_ref() else {
+                return Err(BootImgError::InvalidVendorRamdiskTable(
+                    "missing ramdisk".to_string(),
+                ));
+            };
 
             let mut vec = Vec::new();
             for d in entry_table.chunks(entry_size) {
@@ -359,7 +1031,7 @@ impl<'a> BootImageBlocks<'a> {
 
                 let off = entry_v4.get_ramdisk_offset() as usize;
                 let sz = entry_v4.get_ramdisk_size() as usize;
-                if let Some(data) = ramdisk.get(off..off + sz) {
+                if let Some(data) = off.checked_add(sz).and_then(|end| ramdisk.get(off..end)) {
                     vec.push(VendorRamdiskEntry {
                         data,
                         entry_size: sz as u64,
@@ -367,30 +1039,59 @@ impl<'a> BootImageBlocks<'a> {
                         entry_type: entry_v4.get_ramdisk_type(),
                         compress_format: parse_compress_format(data),
                         entry: entry_v4,
+                        options: ctx.options,
                     })
                 } else {
-                    bail!("invalid vendor ramdisk entry off={} size={}", off, sz);
+                    return Err(BootImgError::InvalidVendorRamdiskTable(format!(
+                        "invalid entry off={} size={}",
+                        off, sz
+                    )));
                 }
             }
 
+            let mut sorted: Vec<_> = vec.iter().collect();
+            sorted.sort_by_key(|entry| entry.entry_offset);
+            let mut end = 0;
+            for entry in sorted {
+                if entry.entry_offset < end {
+                    return Err(BootImgError::InvalidVendorRamdiskTable(format!(
+                        "entry {} at {} overlaps previous entry ending at {}",
+                        String::from_utf8_lossy(entry.get_name_raw()),
+                        entry.entry_offset,
+                        end
+                    )));
+                }
+                if entry.entry_offset > end {
+                    ctx.warn(format!(
+                        "gap in vendor ramdisk between {} and {}",
+                        end, entry.entry_offset
+                    ));
+                }
+                end = entry.entry_offset + entry.entry_size;
+            }
+            if end < ramdisk.len() as u64 {
+                ctx.warn(format!(
+                    "vendor ramdisk data after the last entry: {} of {}",
+                    end,
+                    ramdisk.len()
+                ));
+            }
+
             Some(vec)
         } else {
             None
         };
 
-        let ramdisk = if let Some(data) = ramdisk {
-            Some(RamdiskImage {
-                data,
-                compress_format: if vendor_ramdisk_table.is_none() {
-                    parse_compress_format(data)
-                } else {
-                    CompressFormat::UNKNOWN
-                },
-                vendor_ramdisk_table,
-            })
-        } else {
-            None
-        };
+        let ramdisk = ramdisk.map(|data| RamdiskImage {
+            data,
+            compress_format: if vendor_ramdisk_table.is_none() {
+                parse_compress_format(data)
+            } else {
+                CompressFormat::UNKNOWN
+            },
+            vendor_ramdisk_table,
+            options: ctx.options,
+        });
 
         Ok((
             BootImageBlocks {
@@ -401,6 +1102,8 @@ impl<'a> BootImageBlocks<'a> {
                 dtb,
                 signature,
                 bootconfig,
+                offsets,
+                options: ctx.options,
             },
             off,
         ))
@@ -411,6 +1114,8 @@ pub(crate) struct BootImageAVBInfo<'a> {
     pub(crate) avb_tail: Option<&'a [u8]>,
     pub(crate) avb_header: &'a [u8],
     pub(crate) avb_footer: AvbFooter<'a>,
+    // End of the footer, smaller than the data if the dump was padded
+    pub(crate) partition_size: usize,
 }
 
 #[derive(Copy, Clone)]
@@ -421,6 +1126,7 @@ pub struct VendorRamdiskEntry<'a> {
     pub(crate) entry_type: VendorRamdiskTableEntryType,
     pub(crate) compress_format: CompressFormat,
     pub(crate) entry: VendorRamdiskTableEntryV4<'a>,
+    pub(crate) options: ParseOptions,
 }
 
 impl VendorRamdiskEntry<'_> {
@@ -432,7 +1138,7 @@ impl VendorRamdiskEntry<'_> {
         trim_end(self.entry.get_ramdisk_name())
     }
 
-    pub fn get_name(&self) -> anyhow::Result<&str> {
+    pub fn get_name(&self) -> Result<&str> {
         Ok(from_utf8(trim_end(self.entry.get_ramdisk_name()))?)
     }
 
@@ -440,30 +1146,97 @@ impl VendorRamdiskEntry<'_> {
         self.entry_type
     }
 
+    // Relative to the start of the ramdisk block
+    pub fn get_entry_offset(&self) -> u64 {
+        self.entry_offset
+    }
+
+    pub fn get_entry_size(&self) -> u64 {
+        self.entry_size
+    }
+
+    pub fn board_id(&self) -> [u32; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE] {
+        let raw = self.entry.get_board_id();
+        std::array::from_fn(|i| raw.u32_at(i * 4).unwrap())
+    }
+
     pub fn get_compress_format(&self) -> CompressFormat {
         self.compress_format
     }
 
-    pub fn dump(&self, out: &mut dyn Write, raw: bool) -> anyhow::Result<()> {
-        dump_block(self.data, out, raw)
+    pub fn dump(&self, out: &mut dyn Write, raw: bool) -> Result<()> {
+        let name = format!(
+            "vendor ramdisk {}",
+            String::from_utf8_lossy(self.get_name_raw())
+        );
+        dump_block(&name, self.data, out, raw, &self.options)
+    }
+
+    pub fn reader(&self) -> Result<Box<dyn Read + '_>> {
+        let name = format!(
+            "vendor ramdisk {}",
+            String::from_utf8_lossy(self.get_name_raw())
+        );
+        block_reader(&name, self.data, &self.options)
+    }
+
+    pub fn get_content_kind(&self) -> Result<RamdiskContentKind> {
+        detect_ramdisk_content(self.data, &self.options)
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ImageKind {
+    Boot,
+    InitBoot,
+    VendorBoot,
+    VendorKernelBoot,
+    Recovery,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ImageClassification {
+    pub kind: ImageKind,
+    // Which heuristic decided the kind
+    pub reason: &'static str,
+}
+
+const RECOVERY_BINARIES: [&str; 2] = ["sbin/recovery", "system/bin/recovery"];
+
+#[derive(Debug, Clone)]
+pub struct BlockSpan {
+    pub name: &'static str,
+    pub data_offset: usize,
+    pub data_len: usize,
+    // data_len plus the padding up to the next block
+    pub padded_len: usize,
+}
+
 pub struct BootImage<'a> {
     pub(crate) data: &'a [u8],
     pub(crate) header: BootHeader<'a>,
     pub(crate) blocks: BootImageBlocks<'a>,
     pub(crate) avb_info: Option<BootImageAVBInfo<'a>>,
+    pub(crate) warnings: Vec<String>,
 }
 
-fn dump_block(data: &[u8], out: &mut dyn Write, raw: bool) -> anyhow::Result<()> {
+fn dump_block(
+    name: &str,
+    data: &[u8],
+    out: &mut dyn Write,
+    raw: bool,
+    options: &ParseOptions,
+) -> Result<()> {
     let mut data = data;
     if !raw {
         let format = parse_compress_format(data);
         if format != CompressFormat::UNKNOWN {
-            let mut decoder = get_decoder(format, data)?;
-            std::io::copy(decoder.as_mut(), out)?;
-            return Ok(());
+            return options
+                .decoder(format, data)
+                .and_then(|mut decoder| Ok(std::io::copy(&mut decoder, out)?))
+                .map(|_| ())
+                .map_err(|e| e.with_context(format!("while decompressing {}", name)));
         }
     }
     std::io::copy(&mut data, out)?;
@@ -471,42 +1244,41 @@ fn dump_block(data: &[u8], out: &mut dyn Write, raw: bool) -> anyhow::Result<()>
     Ok(())
 }
 
+// Decompressed contents as a stream, bounded by max_decompressed_size
+pub(crate) fn block_reader<'a>(
+    name: &str,
+    data: &'a [u8],
+    options: &ParseOptions,
+) -> Result<Box<dyn Read + 'a>> {
+    let format = parse_compress_format(data);
+    if format == CompressFormat::UNKNOWN {
+        return Ok(Box::new(data));
+    }
+    options
+        .decoder(format, data)
+        .map_err(|e| e.with_context(format!("while decompressing {}", name)))
+}
+
 impl<'a> BootImage<'a> {
-    pub fn parse(data: &'a [u8]) -> anyhow::Result<Self> {
-        let header = BootHeader::parse(data)?;
-        let (blocks, tail) = BootImageBlocks::parse(data, &header)?;
-
-        let avb_info = if let Some(avb_footer) = data.get(data.len() - AvbFooter::SIZE..) {
-            if avb_footer.starts_with(AVB_FOOTER_MAGIC) {
-                let avb_footer = AvbFooter { data: avb_footer };
-                let off = avb_footer.get_vbmeta_offset() as usize;
-                if let Some(avb_header) = data.get(off..off + avb_footer.get_vbmeta_size() as usize)
-                {
-                    if avb_header.starts_with(AVB_MAGIC) {
-                        let avb_payload_size = avb_footer.get_original_image_size() as usize;
-                        let avb_tail = if avb_payload_size > tail {
-                            data.get(tail..avb_payload_size)
-                        } else if avb_payload_size < tail {
-                            bail!("invalid avb original image size")
-                        } else {
-                            None
-                        };
-                        Some(BootImageAVBInfo {
-                            avb_tail,
-                            avb_header,
-                            avb_footer,
-                        })
-                    } else {
-                        bail!("invalid avb header magic")
-                    }
-                } else {
-                    bail!("invalid avb header")
-                }
-            } else {
+    pub fn parse_owned(data: Vec<u8>) -> Result<OwnedBootImage> {
+        OwnedBootImage::parse(data)
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        Self::parse_with_options(data, ParseOptions::default())
+    }
+
+    pub fn parse_with_options(data: &'a [u8], options: ParseOptions) -> Result<Self> {
+        let mut ctx = ParseContext::new(options);
+        let header = BootHeader::parse_with_context(data, &mut ctx)?;
+        let (blocks, tail) = BootImageBlocks::parse_with_context(data, &header, &mut ctx)?;
+
+        let avb_info = match Self::parse_avb_info(data, tail, &ctx) {
+            Ok(avb_info) => avb_info,
+            Err(e) => {
+                ctx.recover(e)?;
                 None
             }
-        } else {
-            None
         };
 
         Ok(Self {
@@ -514,9 +1286,249 @@ impl<'a> BootImage<'a> {
             header,
             blocks,
             avb_info,
+            warnings: ctx.warnings,
         })
     }
 
+    fn find_avb_footer(data: &[u8], ctx: &ParseContext) -> Option<usize> {
+        let off = data.len().checked_sub(AvbFooter::SIZE)?;
+        if data[off..].starts_with(AVB_FOOTER_MAGIC) {
+            return Some(off);
+        }
+        if ctx.options.lenient {
+            // Dumps larger than the partition: the footer ends at a 4K aligned
+            // offset, or right at the end of the non-zero data
+            let limit = data.len().saturating_sub(LENIENT_AVB_TRAILING_ZEROS);
+            if let Some(last) = data[limit..].iter().rposition(|&b| b != 0) {
+                let data_end = limit + last + 1;
+                let aligned = align_to(data_end, AVB_PARTITION_ALIGN);
+                if let Some(footer_off) = aligned.checked_sub(AvbFooter::SIZE)
+                    && data
+                        .get(footer_off..aligned)
+                        .is_some_and(|it| it.starts_with(AVB_FOOTER_MAGIC))
+                {
+                    return Some(footer_off);
+                }
+                let start = data_end.saturating_sub(AvbFooter::SIZE);
+                if let Some(pos) = data[start..data_end]
+                    .windows(AVB_FOOTER_MAGIC.len())
+                    .rposition(|w| w == AVB_FOOTER_MAGIC)
+                    && start + pos + AvbFooter::SIZE <= data.len()
+                {
+                    return Some(start + pos);
+                }
+            }
+
+            let start = off.saturating_sub(LENIENT_AVB_FOOTER_SEARCH);
+            return data[start..off]
+                .windows(AVB_FOOTER_MAGIC.len())
+                .rposition(|w| w == AVB_FOOTER_MAGIC)
+                .map(|pos| start + pos);
+        }
+        None
+    }
+
+    fn parse_avb_info(
+        data: &'a [u8],
+        tail: usize,
+        ctx: &ParseContext,
+    ) -> Result<Option<BootImageAVBInfo<'a>>> {
+        let Some(footer_off) = Self::find_avb_footer(data, ctx) else {
+            return Ok(None);
+        };
+        let avb_footer = AvbFooter {
+            data: &data[footer_off..footer_off + AvbFooter::SIZE],
+        };
+        let off = avb_footer.get_vbmeta_offset() as usize;
+        let Some(avb_header) = (avb_footer.get_vbmeta_size() as usize)
+            .checked_add(off)
+            .and_then(|end| data.get(off..end))
+        else {
+            return Err(BootImgError::InvalidAvbFooter(
+                "invalid vbmeta offset".to_string(),
+            ));
+        };
+        if !avb_header.starts_with(AVB_MAGIC) {
+            return Err(BootImgError::InvalidAvbFooter(
+                "invalid vbmeta magic".to_string(),
+            ));
+        }
+        if avb_header.len() < AVB_HEADER_SIZE {
+            return Err(BootImgError::InvalidAvbFooter(format!(
+                "vbmeta size {} is smaller than its header",
+                avb_header.len()
+            )));
+        }
+        let avb_payload_size = avb_footer.get_original_image_size() as usize;
+        let avb_tail = if avb_payload_size > tail {
+            Some(data.get(tail..avb_payload_size).ok_or_else(|| {
+                BootImgError::InvalidAvbFooter("original image size exceeds image".to_string())
+            })?)
+        } else if avb_payload_size < tail {
+            return Err(BootImgError::InvalidAvbFooter(
+                "invalid original image size".to_string(),
+            ));
+        } else {
+            None
+        };
+        debug!(
+            "avb footer: vbmeta off {} size {}, original image size {}",
+            off,
+            avb_header.len(),
+            avb_payload_size
+        );
+        Ok(Some(BootImageAVBInfo {
+            avb_tail,
+            avb_header,
+            avb_footer,
+            partition_size: footer_off + AvbFooter::SIZE,
+        }))
+    }
+
+    pub fn is_unknown_version(&self) -> bool {
+        self.header.unknown_version
+    }
+
+    pub fn get_vbmeta(&self) -> Result<Option<VbMetaImage<'a>>> {
+        self.avb_info
+            .as_ref()
+            .map(|it| VbMetaImage::parse(it.avb_header))
+            .transpose()
+    }
+
+    // Size of the partition the image was dumped from, if it has an AVB footer
+    pub fn get_avb_partition_size(&self) -> Option<usize> {
+        self.avb_info.as_ref().map(|it| it.partition_size)
+    }
+
+    pub fn get_warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn ramdisk_has_recovery(&self) -> bool {
+        let Some(ramdisk) = &self.blocks.ramdisk else {
+            return false;
+        };
+        if ramdisk.is_vendor_ramdisk()
+            || !matches!(ramdisk.get_content_kind(), Ok(RamdiskContentKind::Cpio))
+        {
+            return false;
+        }
+        let Ok(reader) = ramdisk.reader() else {
+            return false;
+        };
+        let load_options = CpioLoadOptions {
+            metadata_only: true,
+            ..Default::default()
+        };
+        Cpio::load_from_reader(reader, &load_options)
+            .is_ok_and(|cpio| RECOVERY_BINARIES.iter().any(|path| cpio.exists(path)))
+    }
+
+    pub fn kind(&self) -> ImageClassification {
+        let (kind, reason) = match self.header.version {
+            Vendor(_) => {
+                let table = self
+                    .blocks
+                    .ramdisk
+                    .as_ref()
+                    .and_then(|it| it.vendor_ramdisk_table.as_ref());
+                if table.is_some_and(|table| {
+                    !table.is_empty()
+                        && table
+                            .iter()
+                            .all(|e| matches!(e.entry_type, VendorRamdiskTableEntryType::Dlkm))
+                }) {
+                    (
+                        ImageKind::VendorKernelBoot,
+                        "all vendor ramdisk entries are dlkm",
+                    )
+                } else {
+                    (ImageKind::VendorBoot, "vendor boot header")
+                }
+            }
+            Android(version) => {
+                if self.blocks.recovery_dtbo.is_some() {
+                    (ImageKind::Recovery, "recovery_dtbo present")
+                } else if self.ramdisk_has_recovery() {
+                    (ImageKind::Recovery, "ramdisk contains a recovery binary")
+                } else if version >= 4 && self.blocks.kernel.is_none() {
+                    (ImageKind::InitBoot, "v4 image without kernel")
+                } else {
+                    (ImageKind::Boot, "default")
+                }
+            }
+        };
+        ImageClassification { kind, reason }
+    }
+
+    // Covers the whole file in file order, unaccounted ranges are reported as
+    // "padding" (all zero) or "unknown" spans
+    pub fn block_map(&self) -> Vec<BlockSpan> {
+        let offset_of = |slice: &[u8]| slice.as_ptr() as usize - self.data.as_ptr() as usize;
+        let page_size = self.header.page_size();
+
+        let mut spans = vec![(
+            "header",
+            0,
+            self.header.layout.total_size as usize,
+            self.header.hdr_space(),
+        )];
+        for (name, offset, len) in self.blocks.blocks() {
+            spans.push((name, offset, len, align_to(len, page_size)));
+        }
+        if let Some(avb_info) = &self.avb_info {
+            if let Some(avb_tail) = avb_info.avb_tail {
+                spans.push((
+                    "avb_tail",
+                    offset_of(avb_tail),
+                    avb_tail.len(),
+                    avb_tail.len(),
+                ));
+            }
+            let vbmeta = avb_info.avb_header;
+            spans.push(("vbmeta", offset_of(vbmeta), vbmeta.len(), vbmeta.len()));
+            let footer = avb_info.avb_footer.data;
+            spans.push(("avb_footer", offset_of(footer), footer.len(), footer.len()));
+        }
+        spans.sort_by_key(|(_, offset, _, _)| *offset);
+
+        let mut map = Vec::new();
+        let mut pos = 0;
+        let push_gap = |map: &mut Vec<BlockSpan>, start: usize, end: usize| {
+            if start < end {
+                let name = if self.data[start..end].iter().all(|&b| b == 0) {
+                    "padding"
+                } else {
+                    "unknown"
+                };
+                map.push(BlockSpan {
+                    name,
+                    data_offset: start,
+                    data_len: end - start,
+                    padded_len: end - start,
+                });
+            }
+        };
+        for (i, &(name, offset, len, padded_len)) in spans.iter().enumerate() {
+            push_gap(&mut map, pos, offset);
+            let limit = spans
+                .get(i + 1)
+                .map(|(_, next, _, _)| *next)
+                .unwrap_or(self.data.len());
+            let padded_len = padded_len.max(len).min(limit.saturating_sub(offset));
+            map.push(BlockSpan {
+                name,
+                data_offset: offset,
+                data_len: len,
+                padded_len,
+            });
+            pos = pos.max(offset + padded_len);
+        }
+        push_gap(&mut map, pos, self.data.len());
+        map
+    }
+
     pub fn get_header(&self) -> &BootHeader<'_> {
         &self.header
     }
@@ -525,3 +1537,474 @@ impl<'a> BootImage<'a> {
         &self.blocks
     }
 }
+
+type OwnedData = dyn AsRef<[u8]> + Send + Sync;
+
+// A BootImage that owns its backing buffer (Vec<u8>, Mmap, ...), so it can be
+// returned from the function that opened the file.
+pub struct OwnedBootImage {
+    // Borrows from `owner`, must be dropped before it
+    image: ManuallyDrop<BootImage<'static>>,
+    owner: NonNull<OwnedData>,
+}
+
+// SAFETY: `owner` is uniquely owned and only accessed immutably, and BootImage only
+// holds shared references into it
+unsafe impl Send for OwnedBootImage {}
+unsafe impl Sync for OwnedBootImage {}
+
+impl OwnedBootImage {
+    pub fn parse<D: AsRef<[u8]> + Send + Sync + 'static>(data: D) -> Result<Self> {
+        Self::parse_with_options(data, ParseOptions::default())
+    }
+
+    pub fn parse_with_options<D: AsRef<[u8]> + Send + Sync + 'static>(
+        data: D,
+        options: ParseOptions,
+    ) -> Result<Self> {
+        let owner = NonNull::from(Box::leak(Box::new(data) as Box<OwnedData>));
+        // SAFETY: the boxed owner is never moved nor mutated until it is freed in drop,
+        // after the image borrowing from it
+        let data: &'static [u8] = unsafe { owner.as_ref() }.as_ref();
+        match BootImage::parse_with_options(data, options) {
+            Ok(image) => Ok(Self {
+                image: ManuallyDrop::new(image),
+                owner,
+            }),
+            Err(e) => {
+                // SAFETY: nothing borrows from the owner anymore
+                drop(unsafe { Box::from_raw(owner.as_ptr()) });
+                Err(e)
+            }
+        }
+    }
+
+    pub fn get(&self) -> &BootImage<'_> {
+        &self.image
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        self.image.data
+    }
+
+    pub fn get_header(&self) -> &BootHeader<'_> {
+        self.image.get_header()
+    }
+
+    pub fn get_blocks(&self) -> &BootImageBlocks<'_> {
+        self.image.get_blocks()
+    }
+}
+
+impl Drop for OwnedBootImage {
+    fn drop(&mut self) {
+        // SAFETY: the image is dropped before the buffer it borrows from, and neither
+        // is used afterwards
+        unsafe {
+            ManuallyDrop::drop(&mut self.image);
+            drop(Box::from_raw(self.owner.as_ptr()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::BootImageBuilder;
+    use crate::patcher::BootImagePatchOption;
+    use crate::testutil::{
+        FIXTURE_MODEL, FIXTURE_VERSIONS, ImageFixture, boot_v2, boot_v4, dtb, ramdisk,
+        vendor_boot_v4,
+    };
+    use std::io::Cursor;
+
+    // `data` with a header field overwritten
+    fn with_field(data: &[u8], offset: u16, value: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        let offset = offset as usize;
+        data[offset..offset + value.len()].copy_from_slice(value);
+        data
+    }
+
+    const LENIENT: ParseOptions = ParseOptions {
+        lenient: true,
+        allow_unknown_versions: false,
+        max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+    };
+
+    // Fails in both modes, lenient parsing recovers from a lot but not from a
+    // header it can't place the blocks with
+    fn assert_rejected(data: &[u8], what: &str) {
+        assert!(BootImage::parse(data).is_err(), "{} parsed", what);
+        assert!(
+            BootImage::parse_with_options(data, LENIENT).is_err(),
+            "{} parsed leniently",
+            what
+        );
+    }
+
+    // Fails strictly, lenient parsing recovers with a warning
+    fn assert_recovered<'a>(data: &'a [u8], what: &str) -> BootImage<'a> {
+        assert!(BootImage::parse(data).is_err(), "{} parsed", what);
+        let boot = BootImage::parse_with_options(data, LENIENT)
+            .unwrap_or_else(|e| panic!("{} not recovered: {}", what, e));
+        assert!(!boot.get_warnings().is_empty(), "{} without warnings", what);
+        boot
+    }
+
+    #[test]
+    fn truncated_header() {
+        for version in FIXTURE_VERSIONS {
+            let data = ImageFixture::new(version).build().unwrap();
+            let layout = BootHeader::parse(&data).unwrap().get_layout();
+            for len in 0..layout.total_size as usize {
+                assert_rejected(&data[..len], &format!("{:?} cut at {}", version, len));
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_blocks() {
+        for version in FIXTURE_VERSIONS {
+            let data = ImageFixture::new(version).build().unwrap();
+            let layout = BootHeader::parse(&data).unwrap().get_layout();
+            let page = layout.total_size as usize + 1;
+            assert_recovered(&data[..page], &format!("{:?} cut at {}", version, page));
+        }
+    }
+
+    #[test]
+    fn oversized_sizes() {
+        let boot = boot_v2();
+        let layout = BootHeader::parse(&boot).unwrap().get_layout();
+        for offset in [
+            layout.offset_kernel_size,
+            layout.offset_ramdisk_size,
+            layout.offset_second_size,
+            layout.offset_recovery_dtbo_size,
+            layout.offset_dtb_size,
+        ] {
+            let data = with_field(&boot, offset, &u32::MAX.to_le_bytes());
+            assert_recovered(&data, &format!("size at {:#x}", offset));
+        }
+        // The offset is informational, it only must not panic
+        let data = with_field(
+            &boot,
+            layout.offset_recovery_dtbo_offset,
+            &u64::MAX.to_le_bytes(),
+        );
+        let _ = BootImage::parse(&data);
+
+        let vendor = vendor_boot_v4();
+        let layout = BootHeader::parse(&vendor).unwrap().get_layout();
+        // Blocks past the end are truncated
+        for offset in [
+            layout.offset_ramdisk_size,
+            layout.offset_dtb_size,
+            layout.offset_vendor_ramdisk_table_size,
+            layout.offset_bootconfig_size,
+        ] {
+            let data = with_field(&vendor, offset, &u32::MAX.to_le_bytes());
+            assert_recovered(&data, &format!("vendor size at {:#x}", offset));
+        }
+        // Table entries past the table are not
+        for offset in [
+            layout.offset_vendor_ramdisk_table_entry_num,
+            layout.offset_vendor_ramdisk_table_entry_size,
+        ] {
+            let data = with_field(&vendor, offset, &u32::MAX.to_le_bytes());
+            assert_rejected(&data, &format!("vendor size at {:#x}", offset));
+        }
+    }
+
+    #[test]
+    fn vendor_table_entries_past_table() {
+        let vendor = vendor_boot_v4();
+        let layout = BootHeader::parse(&vendor).unwrap().get_layout();
+        // One more entry than the table holds
+        let data = with_field(
+            &vendor,
+            layout.offset_vendor_ramdisk_table_entry_num,
+            &4u32.to_le_bytes(),
+        );
+        assert_rejected(&data, "entry_num 4");
+        let data = with_field(
+            &vendor,
+            layout.offset_vendor_ramdisk_table_entry_size,
+            &0u32.to_le_bytes(),
+        );
+        assert_rejected(&data, "entry_size 0");
+    }
+
+    #[test]
+    fn page_size_zero() {
+        // Lenient parsing falls back to the mkbootimg defaults
+        for (data, fallback) in [(boot_v2(), 2048), (vendor_boot_v4(), 4096)] {
+            let original = BootImage::parse(&data).unwrap();
+            let layout = original.get_header().get_layout();
+            for page_size in [0u32, 3000] {
+                let data = with_field(&data, layout.offset_page_size, &page_size.to_le_bytes());
+                let what = format!("page size {}", page_size);
+                let boot = assert_recovered(&data, &what);
+                assert_eq!(boot.get_header().page_size(), fallback, "{}", what);
+                // The fixtures use 2K pages, only the boot blocks are found again
+                if original.get_header().page_size() == fallback {
+                    assert_eq!(
+                        boot.get_blocks().blocks().collect::<Vec<_>>(),
+                        original.get_blocks().blocks().collect::<Vec<_>>(),
+                        "{}",
+                        what
+                    );
+                }
+            }
+        }
+    }
+
+    // 1 MiB of zeros deflates to about a kilobyte
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn decompression_bomb() {
+        let mut builder = BootImageBuilder::new(BootImageVersion::Android(2)).unwrap();
+        builder
+            .add_block(
+                "ramdisk",
+                Box::new(Cursor::new(vec![0u8; 1 << 20])),
+                CompressFormat::GZIP,
+            )
+            .unwrap();
+        let mut data = Vec::new();
+        builder.build(&mut data).unwrap();
+        assert!(data.len() < 16 * 1024);
+
+        for lenient in [false, true] {
+            let options = ParseOptions {
+                lenient,
+                max_decompressed_size: 64 * 1024,
+                ..Default::default()
+            };
+            let boot = BootImage::parse_with_options(&data, options).unwrap();
+            let ramdisk = boot.get_blocks().get_ramdisk().unwrap();
+            let mut content = Vec::new();
+            let err = ramdisk.dump(&mut content, false).unwrap_err();
+            let BootImgError::Context { error, .. } = &err else {
+                panic!("{:?}", err);
+            };
+            assert!(
+                matches!(**error, BootImgError::DecompressLimitExceeded(65536)),
+                "{:?}",
+                err
+            );
+            let err = ramdisk
+                .reader()
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap_err();
+            assert!(matches!(
+                BootImgError::from(err),
+                BootImgError::DecompressLimitExceeded(65536)
+            ));
+        }
+        let boot = BootImage::parse(&data).unwrap();
+        let mut content = Vec::new();
+        boot.get_blocks()
+            .get_ramdisk()
+            .unwrap()
+            .dump(&mut content, false)
+            .unwrap();
+        assert_eq!(content.len(), 1 << 20);
+    }
+
+    // Spans in file order, each starting where the previous one ends
+    fn assert_tiles(data: &[u8]) -> Vec<&'static str> {
+        let boot = BootImage::parse(data).unwrap();
+        let map = boot.block_map();
+        let mut pos = 0;
+        for span in &map {
+            assert_eq!(
+                span.data_offset, pos,
+                "{} overlaps or leaves a gap",
+                span.name
+            );
+            assert!(span.data_len <= span.padded_len, "{}", span.name);
+            pos += span.padded_len;
+        }
+        assert_eq!(pos, data.len());
+        map.iter().map(|it| it.name).collect()
+    }
+
+    #[test]
+    fn block_map_tiles_file() {
+        assert_eq!(
+            assert_tiles(&boot_v2()),
+            [
+                "header",
+                "kernel",
+                "ramdisk",
+                "second",
+                "recovery_dtbo",
+                "dtb"
+            ]
+        );
+        assert_eq!(
+            assert_tiles(&boot_v4()),
+            ["header", "kernel", "ramdisk", "signature"]
+        );
+        let names = assert_tiles(&vendor_boot_v4());
+        assert!(names.contains(&"vendor_ramdisk_table") && names.contains(&"bootconfig"));
+
+        for version in [Android(2), Android(4), Vendor(4)] {
+            let data = ImageFixture::new(version)
+                .avb_footer(Some(256 * 1024))
+                .build()
+                .unwrap();
+            let names = assert_tiles(&data);
+            assert!(
+                names.ends_with(&["vbmeta", "padding", "avb_footer"]),
+                "{:?}",
+                names
+            );
+
+            let mut data = ImageFixture::new(version).build().unwrap();
+            data.extend_from_slice(b"trailing");
+            assert_eq!(assert_tiles(&data).last(), Some(&"unknown"));
+        }
+    }
+
+    #[test]
+    fn os_version_from_str() {
+        let version = OsVersion::new(13, 0, 0).unwrap();
+        for text in ["13", "13.0", "13.0.0"] {
+            assert_eq!(text.parse::<OsVersion>().unwrap(), version);
+        }
+        assert_eq!(version.to_string().parse::<OsVersion>().unwrap(), version);
+        for text in ["", "13.", "13.0.0.0", "a.b.c", "128.0.0", "13.0.-1"] {
+            assert!(text.parse::<OsVersion>().is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn patch_level_from_str() {
+        let level = PatchLevel::new(2023, 8).unwrap();
+        for text in [
+            "2023-08",
+            "2023-8",
+            "2023-08-05",
+            "2023-08-01",
+            "2023-08-31",
+        ] {
+            assert_eq!(text.parse::<PatchLevel>().unwrap(), level);
+        }
+        for text in ["2024-02-29", "2000-02-29", "2023-04-30", "2023-12-31"] {
+            assert!(text.parse::<PatchLevel>().is_ok(), "{:?}", text);
+        }
+        assert_eq!(level.to_string(), "2023-08");
+        for text in [
+            "",
+            "2023",
+            "2023-00",
+            "2023-13",
+            "1999-12",
+            "2128-01",
+            "2023-08-xx",
+            "2023-08-05-01",
+            "2023-08-",
+            "2023-08-0",
+            "2024-01-99",
+            "2023-08-32",
+            "2023-04-31",
+            "2023-02-29",
+            "2100-02-29",
+        ] {
+            assert!(text.parse::<PatchLevel>().is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn os_version_roundtrip() {
+        let boot = boot_v2();
+        let offset = BootHeader::parse(&boot)
+            .unwrap()
+            .get_layout()
+            .offset_os_version;
+        for (version, level) in [
+            ("13.0.0", "2023-08"),
+            ("14.1.2", "2024-01"),
+            ("127.127.127", "2127-12"),
+            ("0.0.1", "2000-01"),
+        ] {
+            let version: OsVersion = version.parse().unwrap();
+            let level: PatchLevel = level.parse().unwrap();
+            let raw = encode_os_version(&version, &level);
+            let data = with_field(&boot, offset, &raw.to_le_bytes());
+            let header = BootHeader::parse(&data).unwrap();
+            assert_eq!(header.get_os_version_raw(), raw);
+            assert_eq!(header.get_os_version(), Some((version, level)));
+
+            let source = BootImage::parse(&boot).unwrap();
+            let mut patcher = BootImagePatchOption::new(&source);
+            patcher.override_os_version((version, level));
+            let data = patcher.patch_to_vec().unwrap();
+            let header = BootHeader::parse(&data).unwrap();
+            assert_eq!(header.get_os_version(), Some((version, level)));
+        }
+    }
+
+    // The 2112 and 2128 byte vendor headers take two 2K pages
+    #[test]
+    fn vendor_boot_2k_pages() {
+        let ramdisk = ramdisk(&[("init", b"vendor init")]).unwrap();
+        let dtb = dtb(FIXTURE_MODEL);
+        for version in [Vendor(3), Vendor(4)] {
+            let mut builder = BootImageBuilder::new(version).unwrap();
+            builder.page_size(2048);
+            let raw = CompressFormat::UNKNOWN;
+            if version == Vendor(4) {
+                builder
+                    .add_vendor_ramdisk(
+                        "",
+                        VendorRamdiskTableEntryType::Platform,
+                        [0; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE],
+                        Box::new(Cursor::new(ramdisk.clone())),
+                        raw,
+                    )
+                    .unwrap();
+            } else {
+                builder
+                    .add_block("ramdisk", Box::new(Cursor::new(ramdisk.clone())), raw)
+                    .unwrap();
+            }
+            builder
+                .add_block("dtb", Box::new(Cursor::new(dtb.clone())), raw)
+                .unwrap();
+            let mut data = Vec::new();
+            builder.build(&mut data).unwrap();
+
+            let boot = BootImage::parse(&data).unwrap();
+            let header = boot.get_header();
+            assert_eq!(header.page_size(), 2048);
+            assert!(header.get_layout().total_size > 2048);
+            assert_eq!(header.hdr_space(), 4096, "{:?}", version);
+            let blocks = boot.get_blocks();
+            let offsets = blocks.blocks().collect::<Vec<_>>();
+            assert_eq!(
+                offsets[0],
+                ("ramdisk", 4096, ramdisk.len()),
+                "{:?}",
+                version
+            );
+            assert_eq!(offsets[1].1, align_to(4096 + ramdisk.len(), 2048));
+            assert_eq!(blocks.get_dtb(), Some(dtb.as_slice()));
+            let mut content = Vec::new();
+            let ramdisk_block = blocks.get_ramdisk().unwrap();
+            match version {
+                Vendor(4) => ramdisk_block
+                    .get_vendor_ramdisk(0)
+                    .unwrap()
+                    .dump(&mut content, false),
+                _ => ramdisk_block.dump(&mut content, false),
+            }
+            .unwrap();
+            assert_eq!(content, ramdisk, "{:?}", version);
+        }
+    }
+}
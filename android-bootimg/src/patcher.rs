@@ -1,26 +1,118 @@
-use crate::compress::{CompressFormat, get_encoder};
-use crate::layouts::AvbFooter;
+use crate::avb::{
+    AVB_RELEASE_STRING, AvbAlgorithm, VbMetaImage, build_vbmeta, encode_hash_descriptor,
+    hash_digest,
+};
+use crate::compress::{
+    CompressFormat, detect_format_from_reader, parse_compress_format, transcode,
+};
+pub use crate::compress::{CompressLevel, CompressOptions, GzipHeader};
+use crate::constants::AVB_PARTITION_ALIGN;
+use crate::dtbo::DtboTable;
+use crate::error::{BootImgError, Result};
+use crate::header_text::HeaderOverrides;
+use crate::id::{IdHashKind, IdHasher, IdWriter};
+use crate::layouts::{AvbFooter, AvbVBMetaImageHeader, BootHeaderLayout};
+use crate::parser::BootImageVersion::Android;
 use crate::parser::{BootImage, OsVersion, PatchLevel, VendorRamdiskEntry};
-use crate::utils::{WriteExt, align_to};
-use anyhow::bail;
+#[cfg(feature = "sign")]
+use crate::sign::AvbSigningKey;
+use crate::utils::{CountingWriter, WriteExt, align_to};
+use log::debug;
 use paste::paste;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::ops::DerefMut;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
 struct ReplacePayload {
     data: Box<dyn Read>,
-    compressed: bool,
+    // Block taken from another image, written in its own format
+    copy: bool,
+}
+
+impl ReplacePayload {
+    // Compressed data is copied as is, anything else is compressed with the
+    // format of the replaced block
+    fn detect(self) -> Result<(Box<dyn Read>, bool)> {
+        if self.copy {
+            return Ok((self.data, true));
+        }
+        let (format, data) = detect_format_from_reader(self.data)?;
+        Ok((Box::new(data), format != CompressFormat::UNKNOWN))
+    }
+}
+
+// The part past the cmdline field goes to extra_cmdline, like mkbootimg does
+pub(crate) fn split_cmdline(
+    layout: &BootHeaderLayout,
+    cmdline: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let size = layout.size_cmdline as usize;
+    let extra_size = layout.size_extra_cmdline as usize;
+    if cmdline.len() >= size + extra_size.saturating_sub(1) || size == 0 {
+        return Err(BootImgError::InvalidOperation(format!(
+            "cmdline too long: {} bytes",
+            cmdline.len()
+        )));
+    }
+    let split = cmdline.len().min(size - 1);
+    let mut main = cmdline[..split].to_vec();
+    main.resize(size, 0);
+    let mut extra = cmdline[split..].to_vec();
+    extra.resize(extra_size, 0);
+    Ok((main, extra))
+}
+
+pub(crate) fn compress_block<W: Write + ?Sized>(
+    name: &str,
+    format: CompressFormat,
+    options: &CompressOptions,
+    source: &mut dyn Read,
+    output: &mut W,
+) -> Result<()> {
+    transcode(CompressFormat::UNKNOWN, format, source, output, options)
+        .map(|_| ())
+        .map_err(|e| e.with_context(format!("while compressing {}", name)))
+}
+
+#[derive(Default)]
+pub enum AppendedDtbAction {
+    #[default]
+    Keep,
+    Replace(Box<dyn Read>),
+    Drop,
+}
+
+// Hash footer for images without one, like `avbtool add_hash_footer`
+#[derive(Debug, Clone)]
+pub struct AvbFooterOptions {
+    pub partition_name: String,
+    // Multiple of 4096, the output is padded to it
+    pub partition_size: u64,
+    pub salt: Vec<u8>,
+    // Anything but NONE needs a key from sign_avb
+    pub algorithm: AvbAlgorithm,
 }
 
 pub struct BootImagePatchOption<'a> {
     source_boot_image: &'a BootImage<'a>,
     replace_ramdisk: Option<ReplacePayload>,
     replace_kernel: Option<ReplacePayload>,
+    appended_dtb: AppendedDtbAction,
     replace_vendor_ramdisk: HashMap<usize, ReplacePayload>,
+    replace_ramdisk_segment: HashMap<usize, ReplacePayload>,
+    replace_recovery_dtbo: Option<Vec<u8>>,
+    replace_dtb: Option<Vec<u8>>,
+    replace_second: Option<Vec<u8>>,
     // TODO: allow replace other blocks
-    override_cmdline: Option<&'a [u8]>,
-    override_os_version: Option<(OsVersion, PatchLevel)>,
+    override_cmdline: Option<Cow<'a, [u8]>>,
+    // Name, os version and load addresses, cmdline is moved to override_cmdline
+    header_overrides: HeaderOverrides,
+    allow_unknown_version: bool,
+    preserve_padding: bool,
+    compress_options: CompressOptions,
+    add_avb_footer: Option<AvbFooterOptions>,
+    #[cfg(feature = "sign")]
+    avb_signing: Option<(AvbSigningKey, AvbAlgorithm)>,
 }
 
 impl<'a> BootImagePatchOption<'a> {
@@ -29,46 +121,130 @@ impl<'a> BootImagePatchOption<'a> {
             source_boot_image,
             replace_ramdisk: None,
             replace_kernel: None,
+            appended_dtb: AppendedDtbAction::Keep,
             replace_vendor_ramdisk: HashMap::new(),
+            replace_ramdisk_segment: HashMap::new(),
+            replace_recovery_dtbo: None,
+            replace_dtb: None,
+            replace_second: None,
             override_cmdline: None,
-            override_os_version: None,
+            header_overrides: HeaderOverrides::default(),
+            allow_unknown_version: false,
+            preserve_padding: false,
+            compress_options: CompressOptions::default(),
+            add_avb_footer: None,
+            #[cfg(feature = "sign")]
+            avb_signing: None,
         }
     }
 
-    pub fn replace_ramdisk(&mut self, ramdisk: Box<dyn Read>, compressed: bool) -> &mut Self {
+    pub fn replace_ramdisk(&mut self, ramdisk: Box<dyn Read>) -> &mut Self {
         self.replace_ramdisk = Some(ReplacePayload {
             data: ramdisk,
-            compressed,
+            copy: false,
         });
         self
     }
 
-    pub fn replace_kernel(&mut self, kernel: Box<dyn Read>, compressed: bool) -> &mut Self {
+    // Replaces one of RamdiskImage::segments(), the others are copied as is
+    pub fn replace_ramdisk_segment(&mut self, index: usize, segment: Box<dyn Read>) -> &mut Self {
+        self.replace_ramdisk_segment.insert(
+            index,
+            ReplacePayload {
+                data: segment,
+                copy: false,
+            },
+        );
+        self
+    }
+
+    pub fn replace_kernel(&mut self, kernel: Box<dyn Read>) -> &mut Self {
         self.replace_kernel = Some(ReplacePayload {
             data: kernel,
-            compressed,
+            copy: false,
         });
         self
     }
 
-    pub fn replace_vendor_ramdisk(
-        &mut self,
-        index: usize,
-        ramdisk: Box<dyn Read>,
-        compressed: bool,
-    ) -> &mut Self {
+    // Takes the kernel of another image with its appended dtb, keeping its
+    // compression format
+    pub fn replace_kernel_from(&mut self, other: &BootImage) -> Result<&mut Self> {
+        let Some(kernel) = &other.blocks.kernel else {
+            return Err(BootImgError::InvalidOperation(
+                "source image has no kernel".to_string(),
+            ));
+        };
+        self.replace_kernel = Some(ReplacePayload {
+            data: Box::new(Cursor::new(kernel.get_pure_data().to_vec())),
+            copy: true,
+        });
+        self.appended_dtb = match kernel.appended_dtb() {
+            Some(dtb) => AppendedDtbAction::Replace(Box::new(Cursor::new(dtb.to_vec()))),
+            None => AppendedDtbAction::Drop,
+        };
+        Ok(self)
+    }
+
+    // Takes the ramdisk of another boot image as is, vendor ramdisk tables are
+    // not supported on either side
+    pub fn replace_ramdisk_from(&mut self, other: &BootImage) -> Result<&mut Self> {
+        match &other.blocks.ramdisk {
+            Some(ramdisk) if !ramdisk.is_vendor_ramdisk() => {
+                self.replace_ramdisk = Some(ReplacePayload {
+                    data: Box::new(Cursor::new(ramdisk.data.to_vec())),
+                    copy: true,
+                });
+                Ok(self)
+            }
+            Some(_) => Err(BootImgError::InvalidOperation(
+                "source image has a vendor ramdisk table, use replace_vendor_ramdisk".to_string(),
+            )),
+            None => Err(BootImgError::InvalidOperation(
+                "source image has no ramdisk".to_string(),
+            )),
+        }
+    }
+
+    // Decides what follows the kernel when it was split into kernel + appended dtb
+    pub fn appended_dtb(&mut self, action: AppendedDtbAction) -> &mut Self {
+        self.appended_dtb = action;
+        self
+    }
+
+    pub fn replace_vendor_ramdisk(&mut self, index: usize, ramdisk: Box<dyn Read>) -> &mut Self {
         self.replace_vendor_ramdisk.insert(
             index,
             ReplacePayload {
                 data: ramdisk,
-                compressed,
+                copy: false,
             },
         );
         self
     }
 
+    pub fn replace_recovery_dtbo(&mut self, recovery_dtbo: Vec<u8>) -> &mut Self {
+        self.replace_recovery_dtbo = Some(recovery_dtbo);
+        self
+    }
+
+    // The dtb block of v2 boot and vendor boot images, see appended_dtb for
+    // older kernels
+    pub fn replace_dtb(&mut self, dtb: Vec<u8>) -> &mut Self {
+        self.replace_dtb = Some(dtb);
+        self
+    }
+
+    pub fn replace_second(&mut self, second: Vec<u8>) -> &mut Self {
+        self.replace_second = Some(second);
+        self
+    }
+
+    pub fn replace_recovery_dtbo_table(&mut self, table: &DtboTable) -> Result<&mut Self> {
+        Ok(self.replace_recovery_dtbo(table.to_bytes()?))
+    }
+
     pub fn override_cmdline(&mut self, override_cmdline: &'a [u8]) -> &mut Self {
-        self.override_cmdline = Some(override_cmdline);
+        self.override_cmdline = Some(Cow::Borrowed(override_cmdline));
         self
     }
 
@@ -76,11 +252,265 @@ impl<'a> BootImagePatchOption<'a> {
         &mut self,
         override_os_version: (OsVersion, PatchLevel),
     ) -> &mut Self {
-        self.override_os_version = Some(override_os_version);
+        self.header_overrides.os_version = Some(override_os_version.0);
+        self.header_overrides.patch_level = Some(override_os_version.1);
+        self
+    }
+
+    // Fields set in `overrides` replace the ones set before, see parse_header_text
+    pub fn override_header(&mut self, overrides: HeaderOverrides) -> &mut Self {
+        let HeaderOverrides {
+            name,
+            cmdline,
+            os_version,
+            patch_level,
+            kernel_addr,
+            ramdisk_addr,
+            second_addr,
+            tags_addr,
+            dtb_addr,
+        } = overrides;
+        if let Some(cmdline) = cmdline {
+            self.override_cmdline = Some(Cow::Owned(cmdline.into_bytes()));
+        }
+        let current = &mut self.header_overrides;
+        macro_rules! merge {
+            ($($name:ident),*) => {
+                $(
+                    if $name.is_some() {
+                        current.$name = $name;
+                    }
+                )*
+            };
+        }
+        merge! { name, os_version, patch_level, kernel_addr, ramdisk_addr, second_addr, tags_addr, dtb_addr }
+        self
+    }
+
+    // Fields past the v4 layout of an unknown version are copied but not updated
+    pub fn allow_unknown_version(&mut self, allow: bool) -> &mut Self {
+        self.allow_unknown_version = allow;
+        self
+    }
+
+    // Copies the padding after blocks that are not replaced from the source
+    // instead of zero filling it, some OEMs keep data there
+    pub fn preserve_padding(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_padding = preserve;
+        self
+    }
+
+    // Used for every block recompressed from uncompressed replacements
+    pub fn compress_options(&mut self, options: CompressOptions) -> &mut Self {
+        self.compress_options = options;
+        self
+    }
+
+    pub fn add_avb_footer(&mut self, options: AvbFooterOptions) -> &mut Self {
+        self.add_avb_footer = Some(options);
+        self
+    }
+
+    // Recomputes the hash descriptor and re-signs the vbmeta of the AVB footer,
+    // the public key in the auxiliary block is replaced by the one of `key`.
+    // With add_avb_footer the algorithm of the footer options is used instead.
+    #[cfg(feature = "sign")]
+    pub fn sign_avb(&mut self, key: AvbSigningKey, algorithm: AvbAlgorithm) -> &mut Self {
+        self.avb_signing = Some((key, algorithm));
         self
     }
 
-    pub fn patch<P: Write + Seek>(mut self, output: &mut P) -> anyhow::Result<()> {
+    // Nothing replaced or overridden, the output is then a byte-identical copy
+    // of the source, padding and trailing data included
+    fn is_identity(&self) -> bool {
+        #[cfg(feature = "sign")]
+        let signing = self.avb_signing.is_some();
+        #[cfg(not(feature = "sign"))]
+        let signing = false;
+        self.replace_ramdisk.is_none()
+            && self.replace_kernel.is_none()
+            && matches!(self.appended_dtb, AppendedDtbAction::Keep)
+            && self.replace_vendor_ramdisk.is_empty()
+            && self.replace_ramdisk_segment.is_empty()
+            && self.replace_recovery_dtbo.is_none()
+            && self.replace_dtb.is_none()
+            && self.replace_second.is_none()
+            && self.override_cmdline.is_none()
+            && self.header_overrides == HeaderOverrides::default()
+            && self.add_avb_footer.is_none()
+            && !signing
+    }
+
+    // Source bytes between the end of a block and the next page boundary
+    fn source_padding(&self, name: &str) -> Option<&'a [u8]> {
+        let page_size = self.source_boot_image.header.page_size();
+        let data = self.source_boot_image.data;
+        self.source_boot_image
+            .blocks
+            .blocks()
+            .find(|(it, _, _)| *it == name)
+            .map(|(_, offset, size)| {
+                let end = (offset + align_to(size, page_size)).min(data.len());
+                &data[(offset + size).min(end)..end]
+            })
+    }
+
+    // Same split as mkbootimg: the first size - 1 bytes go to cmdline, the rest
+    // to extra_cmdline (v0-v2). Both fields stay NUL terminated.
+    fn split_cmdline(&self, cmdline: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        split_cmdline(self.source_boot_image.header.layout, cmdline)
+    }
+
+    // New vbmeta over the written payload, None when the source one is kept
+    fn rebuild_vbmeta<P: Read + Seek>(
+        &self,
+        output: &mut P,
+        total_size: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        #[cfg(feature = "sign")]
+        let signing = self.avb_signing.as_ref();
+        #[cfg(feature = "sign")]
+        let (algorithm, public_key) = match (&self.add_avb_footer, signing) {
+            (Some(options), key) => (
+                options.algorithm,
+                key.map(|(key, _)| key.public_key()).unwrap_or_default(),
+            ),
+            (None, Some((key, algorithm))) => (*algorithm, key.public_key()),
+            (None, None) => return Ok(None),
+        };
+        #[cfg(not(feature = "sign"))]
+        let (algorithm, public_key) = match &self.add_avb_footer {
+            Some(options) => (options.algorithm, Vec::new()),
+            None => return Ok(None),
+        };
+
+        output.seek(SeekFrom::Start(0))?;
+        let mut payload = (&mut *output).take(total_size);
+        #[allow(unused_mut)]
+        let mut vbmeta = match (&self.add_avb_footer, &self.source_boot_image.avb_info) {
+            (Some(options), _) => {
+                // avbtool's default, independent of the signing algorithm
+                let hash_algorithm = "sha256";
+                let digest = hash_digest(hash_algorithm, &options.salt, &mut payload)?;
+                let descriptor = encode_hash_descriptor(
+                    &options.partition_name,
+                    total_size,
+                    hash_algorithm,
+                    &options.salt,
+                    &digest,
+                );
+                let header = AvbVBMetaImageHeader::create(AVB_RELEASE_STRING);
+                build_vbmeta(
+                    &AvbVBMetaImageHeader { data: &header },
+                    &descriptor,
+                    algorithm,
+                    &public_key,
+                )
+            }
+            (None, Some(avb_info)) => VbMetaImage::parse(avb_info.avb_header)?.rehash(
+                avb_info.avb_footer.get_original_image_size(),
+                total_size,
+                &mut payload,
+                algorithm,
+                &public_key,
+            )?,
+            (None, None) => return Ok(None),
+        };
+        #[cfg(feature = "sign")]
+        if let Some((key, _)) = signing {
+            key.sign_vbmeta(&mut vbmeta, algorithm)?;
+        }
+        Ok(Some(vbmeta))
+    }
+
+    // For sinks that cannot seek such as pipes, the image is built in memory first
+    pub fn patch_to_writer(self, output: &mut dyn Write) -> Result<()> {
+        output.write_all(&self.patch_to_vec()?)?;
+        Ok(())
+    }
+
+    // The whole image in memory, for callers without a seekable file
+    pub fn patch_to_vec(self) -> Result<Vec<u8>> {
+        let mut buffer = Cursor::new(Vec::new());
+        self.patch(&mut buffer)?;
+        Ok(buffer.into_inner())
+    }
+
+    // Read is only used to hash the written payload for AVB
+    pub fn patch<P: Read + Write + Seek>(mut self, output: &mut P) -> Result<()> {
+        if self.source_boot_image.is_unknown_version() && !self.allow_unknown_version {
+            return Err(BootImgError::InvalidOperation(format!(
+                "refusing to patch unknown header version {:?}",
+                self.source_boot_image.header.get_version()
+            )));
+        }
+        if let Some(options) = &self.add_avb_footer {
+            if self.source_boot_image.avb_info.is_some() {
+                return Err(BootImgError::InvalidOperation(
+                    "image already has an AVB footer".to_string(),
+                ));
+            }
+            if options.partition_size == 0
+                || !options
+                    .partition_size
+                    .is_multiple_of(AVB_PARTITION_ALIGN as u64)
+            {
+                return Err(BootImgError::InvalidOperation(format!(
+                    "partition size {} is not a multiple of {}",
+                    options.partition_size, AVB_PARTITION_ALIGN
+                )));
+            }
+        }
+        #[cfg(feature = "sign")]
+        let has_signing_key = self.avb_signing.is_some();
+        #[cfg(not(feature = "sign"))]
+        let has_signing_key = false;
+        #[cfg(feature = "sign")]
+        match (&self.avb_signing, &self.add_avb_footer) {
+            (Some((key, _)), Some(options)) => key.check_algorithm(options.algorithm)?,
+            (Some((key, algorithm)), None) => {
+                if self.source_boot_image.avb_info.is_none() {
+                    return Err(BootImgError::InvalidOperation(
+                        "image has no AVB footer to sign".to_string(),
+                    ));
+                }
+                key.check_algorithm(*algorithm)?;
+            }
+            (None, _) => {}
+        }
+        if let Some(options) = &self.add_avb_footer
+            && options.algorithm != AvbAlgorithm::None
+            && !has_signing_key
+        {
+            return Err(BootImgError::InvalidOperation(format!(
+                "algorithm {} needs a signing key",
+                options.algorithm.name()
+            )));
+        }
+        let page_size = self.source_boot_image.header.checked_page_size()? as u64;
+        let cmdline_fields = self
+            .override_cmdline
+            .as_deref()
+            .map(|cmdline| self.split_cmdline(cmdline))
+            .transpose()?;
+        let name_field = self
+            .header_overrides
+            .name
+            .as_deref()
+            .map(|name| {
+                let size = self.source_boot_image.header.layout.size_name as usize;
+                if size == 0 || name.len() > size {
+                    return Err(BootImgError::InvalidOperation(format!(
+                        "name {:?} does not fit in the header",
+                        name
+                    )));
+                }
+                let mut field = name.as_bytes().to_vec();
+                field.resize(size, 0);
+                Ok(field)
+            })
+            .transpose()?;
+
         output.seek(SeekFrom::Start(0))?;
 
         let mut pos: u64 = 0;
@@ -97,21 +527,55 @@ impl<'a> BootImagePatchOption<'a> {
 
         macro_rules! file_align {
             () => {
-                file_align_with!(self.source_boot_image.header.page_size() as u64);
+                file_align_with!(page_size);
+            };
+        }
+
+        let kernel_replaced =
+            self.replace_kernel.is_some() || !matches!(self.appended_dtb, AppendedDtbAction::Keep);
+        let ramdisk_replaced = self.replace_ramdisk.is_some()
+            || !self.replace_ramdisk_segment.is_empty()
+            || !self.replace_vendor_ramdisk.is_empty();
+        let identity = self.is_identity();
+        let preserve_padding = self.preserve_padding || identity;
+
+        // Padding is zero filled unless preserved from an unchanged block
+        macro_rules! pad_block {
+            ($name:expr, $replaced:expr) => {
+                let pad_len = (align_to(pos, page_size) - pos) as usize;
+                match self.source_padding($name) {
+                    Some(padding) if preserve_padding && !$replaced && padding.len() == pad_len => {
+                        output.write_all(padding)?;
+                        pos += pad_len as u64;
+                    }
+                    _ => {
+                        file_align!();
+                    }
+                }
             };
         }
 
-        let header_off = output.seek(SeekFrom::Current(0))?;
+        // The id is only recomputed if the source image had one
+        let header = &self.source_boot_image.header;
+        let id_kind = match header.get_version() {
+            Android(version) if header.has_id() => {
+                IdHashKind::detect(header.get_id()).map(|kind| (kind, version))
+            }
+            _ => None,
+        };
+        let mut id_hasher = id_kind.map(|_| IdHasher::default());
+
+        let header_off = pos;
         output
             .write_all(&self.source_boot_image.data[..self.source_boot_image.header.hdr_space()])?;
         pos += self.source_boot_image.header.hdr_space() as u64;
 
         let kernel_off = pos;
         let kernel_source: Option<(Box<dyn Read>, bool)> =
-            if let Some(payload) = self.replace_kernel {
-                Some((payload.data, payload.compressed))
+            if let Some(payload) = self.replace_kernel.take() {
+                Some(payload.detect()?)
             } else if let Some(kernel) = &self.source_boot_image.blocks.kernel {
-                Some((Box::new(kernel.data), true))
+                Some((Box::new(kernel.get_pure_data()), true))
             } else {
                 None
             };
@@ -119,29 +583,62 @@ impl<'a> BootImagePatchOption<'a> {
         let kernel_size = if let Some((mut kernel_source, compressed)) = kernel_source {
             let format = if compressed {
                 CompressFormat::UNKNOWN
+            } else if let Some(orig) = &self.source_boot_image.blocks.kernel {
+                orig.compress_format
             } else {
-                if let Some(orig) = &self.source_boot_image.blocks.kernel {
-                    orig.compress_format
-                } else {
-                    bail!("Could not determine compression format of kernel");
-                }
+                return Err(BootImgError::InvalidOperation(
+                    "Could not determine compression format of kernel".to_string(),
+                ));
+            };
+
+            let mut counter = CountingWriter::new(&mut *output);
+            let mut output = IdWriter {
+                inner: &mut counter,
+                hasher: id_hasher.as_mut(),
             };
+            let output = &mut output;
 
             if format == CompressFormat::UNKNOWN {
                 std::io::copy(&mut kernel_source, output)?;
             } else {
-                let mut encoder = get_encoder(format, output)?;
-                std::io::copy(&mut kernel_source, encoder.deref_mut())?;
-                encoder.finish()?;
+                compress_block(
+                    "kernel",
+                    format,
+                    &self.compress_options,
+                    &mut kernel_source,
+                    output,
+                )?;
             }
 
-            pos = output.seek(SeekFrom::Current(0))?;
+            match &mut self.appended_dtb {
+                AppendedDtbAction::Keep => {
+                    if let Some(dtb) = self
+                        .source_boot_image
+                        .blocks
+                        .kernel
+                        .as_ref()
+                        .and_then(|it| it.appended_dtb())
+                    {
+                        output.write_all(dtb)?;
+                    }
+                }
+                AppendedDtbAction::Replace(dtb) => {
+                    std::io::copy(dtb, output)?;
+                }
+                AppendedDtbAction::Drop => {}
+            }
+            output.flush()?;
+
+            pos += counter.count();
             pos - kernel_off
         } else {
             0
         };
+        if let Some(hasher) = id_hasher.as_mut() {
+            hasher.end_block(kernel_size);
+        }
 
-        file_align!();
+        pad_block!("kernel", kernel_replaced);
 
         let ramdisk_off = pos;
 
@@ -153,9 +650,10 @@ impl<'a> BootImagePatchOption<'a> {
             .and_then(|it| it.vendor_ramdisk_table.as_ref())
         {
             if self.replace_ramdisk.is_some() {
-                bail!(
+                return Err(BootImgError::InvalidOperation(
                     "Could not replace ramdisk for vendor boot v4, please use replace_vendor_ramdisk!"
-                );
+                        .to_string(),
+                ));
             }
             let mut vendor_ramdisk_table: Vec<VendorRamdiskEntry> = vendor_ramdisk_table.clone();
 
@@ -164,125 +662,257 @@ impl<'a> BootImagePatchOption<'a> {
                 .iter()
                 .find(|(index, _)| **index >= vendor_ramdisk_table.len())
             {
-                bail!("invalid index {}", index);
+                return Err(BootImgError::InvalidOperation(format!(
+                    "invalid index {}",
+                    index
+                )));
             }
 
-            for (index, entry) in vendor_ramdisk_table.iter_mut().enumerate() {
-                let (mut ramdisk_source, compressed): (Box<dyn Read>, bool) =
-                    if let Some(payload) = self.replace_vendor_ramdisk.remove(&index) {
-                        (payload.data, payload.compressed)
-                    } else {
-                        (Box::new(entry.data), true)
-                    };
-                let format = if compressed {
-                    CompressFormat::UNKNOWN
-                } else {
-                    entry.compress_format
-                };
-
-                let entry_off = pos;
-                entry.entry_offset = entry_off - ramdisk_off;
-
-                if format == CompressFormat::UNKNOWN {
-                    std::io::copy(&mut ramdisk_source, output)?;
-                } else {
-                    let mut encoder = get_encoder(format, output)?;
-                    std::io::copy(&mut ramdisk_source, encoder.deref_mut())?;
-                    encoder.finish()?;
-                }
+            // Replacements are read here as they may not be Send, fragments
+            // are then compressed in parallel and written in table order
+            let mut sources: Vec<(Cow<[u8]>, CompressFormat)> = Vec::new();
+            for (index, entry) in vendor_ramdisk_table.iter().enumerate() {
+                sources.push(match self.replace_vendor_ramdisk.remove(&index) {
+                    Some(mut payload) => {
+                        let mut data = Vec::new();
+                        payload.data.read_to_end(&mut data)?;
+                        let format = if parse_compress_format(&data) != CompressFormat::UNKNOWN {
+                            CompressFormat::UNKNOWN
+                        } else {
+                            entry.compress_format
+                        };
+                        (Cow::Owned(data), format)
+                    }
+                    None => (Cow::Borrowed(entry.data), CompressFormat::UNKNOWN),
+                });
+            }
+            let compress_options = &self.compress_options;
+            let compress = |index: usize, data: &[u8], format: CompressFormat| {
+                let mut buf = Vec::new();
+                compress_block(
+                    &format!("vendor ramdisk {}", index),
+                    format,
+                    compress_options,
+                    &mut &data[..],
+                    &mut buf,
+                )?;
+                Ok(buf)
+            };
+            let needs_compression = |format: &CompressFormat| *format != CompressFormat::UNKNOWN;
+            // wasm32 can't spawn threads
+            let fragments = if cfg!(target_family = "wasm") {
+                sources
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (data, format))| {
+                        needs_compression(format)
+                            .then(|| compress(index, data, *format))
+                            .transpose()
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            } else {
+                std::thread::scope(|scope| {
+                    let handles: Vec<_> = sources
+                        .iter()
+                        .enumerate()
+                        .map(|(index, (data, format))| {
+                            needs_compression(format)
+                                .then(|| scope.spawn(move || compress(index, data, *format)))
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| {
+                            handle
+                                .map(|it| {
+                                    it.join().unwrap_or_else(|e| std::panic::resume_unwind(e))
+                                })
+                                .transpose()
+                        })
+                        .collect::<Result<Vec<_>>>()
+                })?
+            };
 
-                pos = output.seek(SeekFrom::Current(0))?;
-                entry.entry_size = pos - entry_off;
+            for ((entry, (data, _)), compressed) in vendor_ramdisk_table
+                .iter_mut()
+                .zip(&sources)
+                .zip(&fragments)
+            {
+                let data = compressed.as_deref().unwrap_or(data);
+                entry.entry_offset = pos - ramdisk_off;
+                entry.entry_size = data.len() as u64;
+                output.write_all(data)?;
+                pos += data.len() as u64;
             }
 
             (pos - ramdisk_off, Some(vendor_ramdisk_table))
         } else {
             if !self.replace_vendor_ramdisk.is_empty() {
-                bail!("Could not replace vendor ramdisk, please use replace_ramdisk!");
+                return Err(BootImgError::InvalidOperation(
+                    "Could not replace vendor ramdisk, please use replace_ramdisk!".to_string(),
+                ));
             }
-            let ramdisk_source: Option<(Box<dyn Read>, bool)> =
-                if let Some(payload) = self.replace_ramdisk {
-                    Some((payload.data, payload.compressed))
-                } else if let Some(ramdisk) = &self.source_boot_image.blocks.ramdisk {
-                    Some((Box::new(ramdisk.data), true))
-                } else {
-                    None
+            if !self.replace_ramdisk_segment.is_empty() {
+                if self.replace_ramdisk.is_some() {
+                    return Err(BootImgError::InvalidOperation(
+                        "Could not replace both ramdisk and ramdisk segments".to_string(),
+                    ));
+                }
+                let segments = self
+                    .source_boot_image
+                    .blocks
+                    .ramdisk
+                    .as_ref()
+                    .map(|it| it.segments())
+                    .unwrap_or_default();
+                if let Some(index) = self
+                    .replace_ramdisk_segment
+                    .keys()
+                    .find(|index| **index >= segments.len())
+                {
+                    return Err(BootImgError::InvalidOperation(format!(
+                        "invalid segment index {}",
+                        index
+                    )));
+                }
+                let mut counter = CountingWriter::new(&mut *output);
+                let mut output = IdWriter {
+                    inner: &mut counter,
+                    hasher: id_hasher.as_mut(),
                 };
+                let output = &mut output;
+                for (index, segment) in segments.iter().enumerate() {
+                    if let Some(payload) = self.replace_ramdisk_segment.remove(&index) {
+                        let (mut data, compressed) = payload.detect()?;
+                        if compressed || segment.compress_format == CompressFormat::UNKNOWN {
+                            std::io::copy(&mut data, output)?;
+                        } else {
+                            compress_block(
+                                &format!("ramdisk segment {}", index),
+                                segment.compress_format,
+                                &self.compress_options,
+                                &mut data,
+                                output,
+                            )?;
+                        }
+                    } else {
+                        output.write_all(segment.data)?;
+                    }
+                }
+                pos += counter.count();
+                (pos - ramdisk_off, None)
+            } else {
+                let ramdisk_source: Option<(Box<dyn Read>, bool)> =
+                    if let Some(payload) = self.replace_ramdisk.take() {
+                        Some(payload.detect()?)
+                    } else if let Some(ramdisk) = &self.source_boot_image.blocks.ramdisk {
+                        Some((Box::new(ramdisk.data), true))
+                    } else {
+                        None
+                    };
 
-            let ramdisk_size = if let Some((mut ramdisk_source, compressed)) = ramdisk_source {
-                let format = if compressed {
-                    CompressFormat::UNKNOWN
-                } else {
-                    if let Some(orig) = &self.source_boot_image.blocks.ramdisk {
+                let ramdisk_size = if let Some((mut ramdisk_source, compressed)) = ramdisk_source {
+                    let mut counter = CountingWriter::new(&mut *output);
+                    let mut output = IdWriter {
+                        inner: &mut counter,
+                        hasher: id_hasher.as_mut(),
+                    };
+                    let output = &mut output;
+
+                    let format = if compressed {
+                        CompressFormat::UNKNOWN
+                    } else if let Some(orig) = &self.source_boot_image.blocks.ramdisk {
                         orig.compress_format
                     } else {
                         // https://github.com/topjohnwu/Magisk/blob/0919db6b111db6f59dd24889fa4f90b141ea4148/native/src/boot/bootimg.cpp#L852C14-L857
                         CompressFormat::LZ4_LEGACY
+                    };
+
+                    if format == CompressFormat::UNKNOWN {
+                        std::io::copy(&mut ramdisk_source, output)?;
+                    } else {
+                        compress_block(
+                            "ramdisk",
+                            format,
+                            &self.compress_options,
+                            &mut ramdisk_source,
+                            output,
+                        )?;
                     }
-                };
 
-                if format == CompressFormat::UNKNOWN {
-                    std::io::copy(&mut ramdisk_source, output)?;
+                    pos += counter.count();
+                    pos - ramdisk_off
                 } else {
-                    let mut encoder = get_encoder(format, output)?;
-                    std::io::copy(&mut ramdisk_source, encoder.deref_mut())?;
-                    encoder.finish()?;
-                }
-
-                pos = output.seek(SeekFrom::Current(0))?;
-                pos - ramdisk_off
-            } else {
-                0
-            };
+                    0
+                };
 
-            (ramdisk_size, None)
+                (ramdisk_size, None)
+            }
         };
 
-        file_align!();
+        if let Some(hasher) = id_hasher.as_mut() {
+            hasher.end_block(ramdisk_size);
+        }
 
-        let second_size;
-        let recovery_dtbo_size;
-        let dtb_size;
-        let signature_size;
-        let bootconfig_size;
+        pad_block!("ramdisk", ramdisk_replaced);
+
+        let (second_off, second_size);
+        let (recovery_dtbo_off, recovery_dtbo_size);
+        let (dtb_off, dtb_size);
+        let (signature_off, signature_size);
+        let (bootconfig_off, bootconfig_size);
 
         macro_rules! copy_block {
             ($name:ident) => {
+                copy_block! { $name, self.source_boot_image.blocks.$name, false }
+            };
+            ($name:ident, $data:expr, $replaced:expr) => {
                 paste! {
-                    let [<$name _off>] = pos;
-                    [<$name _size>] = if let Some(second) = self.source_boot_image.blocks.$name {
+                    [<$name _off>] = pos;
+                    [<$name _size>] = if let Some(second) = $data {
                         output.write_all(second)?;
-                        pos = output.seek(SeekFrom::Current(0))?;
+                        pos += second.len() as u64;
                         pos - [<$name _off>]
                     } else {
                         0
                     };
-                    file_align!();
+                    pad_block!(stringify!($name), $replaced);
                 }
             };
         }
 
-        copy_block! { second }
+        let second = self
+            .replace_second
+            .as_deref()
+            .or(self.source_boot_image.blocks.second);
+        copy_block! { second, second, self.replace_second.is_some() }
         // TODO: extra
-        copy_block! { recovery_dtbo }
-        copy_block! { dtb }
+        let recovery_dtbo = self
+            .replace_recovery_dtbo
+            .as_deref()
+            .or(self.source_boot_image.blocks.recovery_dtbo);
+        copy_block! { recovery_dtbo, recovery_dtbo, self.replace_recovery_dtbo.is_some() }
+        let dtb = self
+            .replace_dtb
+            .as_deref()
+            .or(self.source_boot_image.blocks.dtb);
+        copy_block! { dtb, dtb, self.replace_dtb.is_some() }
         copy_block! { signature }
 
         let vendor_ramdisk_table_off = pos;
         let vendor_ramdisk_table_size = if let Some(vendor_ramdisk_table) = vendor_ramdisk_table {
             for entry in vendor_ramdisk_table {
-                output.write_all(
+                pos += output.write_all_size(
                     &entry
                         .entry
                         .patch(entry.entry_size as u32, entry.entry_offset as u32),
-                )?;
+                )? as u64;
             }
-
-            pos = output.seek(SeekFrom::Current(0))?;
             pos - vendor_ramdisk_table_off
         } else {
             0
         };
+        pad_block!("vendor_ramdisk_table", ramdisk_replaced);
 
         copy_block! { bootconfig }
 
@@ -291,27 +921,55 @@ impl<'a> BootImagePatchOption<'a> {
         let mut zero_start = pos;
         let mut zero_end = self.source_boot_image.data.len() as u64;
 
-        if let Some(avb_info) = self.source_boot_image.avb_info.as_ref() {
+        let avb_layout = if let Some(avb_info) = self.source_boot_image.avb_info.as_ref() {
             if let Some(avb_tail) = avb_info.avb_tail {
-                output.write_all(avb_tail)?;
-                pos = output.seek(SeekFrom::Current(0))?;
+                pos += output.write_all_size(avb_tail)? as u64;
             }
             file_align!();
 
             let total_size = pos;
-            file_align_with!(4096);
+            file_align_with!(AVB_PARTITION_ALIGN as u64);
             let avb_header_off = pos;
+            let vbmeta_end = avb_header_off + avb_info.avb_header.len() as u64;
+            let footer_off = (avb_info.partition_size - AvbFooter::SIZE) as u64;
+            // The footer would land on the payload or the vbmeta
+            if vbmeta_end > footer_off {
+                return Err(BootImgError::PayloadTooLarge {
+                    size: vbmeta_end,
+                    partition_size: avb_info.partition_size as u64,
+                });
+            }
             output.write_all(avb_info.avb_header)?;
-            zero_start = output.seek(SeekFrom::Current(0))?;
+            zero_start = vbmeta_end;
 
-            zero_end = output.seek(SeekFrom::Start(
-                (self.source_boot_image.data.len() - AvbFooter::SIZE) as u64,
+            zero_end = output.seek(SeekFrom::Start(footer_off))?;
+            output.write_all(&avb_info.avb_footer.patch(
+                total_size,
+                avb_header_off,
+                avb_info.avb_header.len() as u64,
             ))?;
-            output.write_all(&avb_info.avb_footer.patch(total_size, avb_header_off))?;
-        }
+            Some((total_size, avb_header_off, avb_info.partition_size as u64))
+        } else if let Some(options) = &self.add_avb_footer {
+            file_align!();
+            let total_size = pos;
+            file_align_with!(AVB_PARTITION_ALIGN as u64);
+            // Written once the payload is final
+            zero_start = pos;
+            zero_end = pos;
+            Some((total_size, pos, options.partition_size))
+        } else {
+            None
+        };
 
         output.seek(SeekFrom::Start(zero_start))?;
-        output.write_zeros((zero_end - zero_start) as usize)?;
+        let source = self.source_boot_image.data;
+        if identity && avb_layout.is_none() && zero_start <= zero_end {
+            // Trailing data past the last block, e.g. SEANDROIDENFORCE
+            output.write_all(&source[zero_start as usize..zero_end as usize])?;
+        } else {
+            // Without AVB an image larger than the source has nothing to pad
+            output.write_zeros(zero_end.saturating_sub(zero_start) as usize)?;
+        }
 
         // Patch header
 
@@ -319,6 +977,7 @@ impl<'a> BootImagePatchOption<'a> {
             ($name:ident) => {
                 paste! {
                     if self.source_boot_image.header.layout.[<offset_ $name _size>] != 0 {
+                        debug!("patch {} size {}", stringify!($name), [<$name _size>]);
                         output.seek(SeekFrom::Start(header_off + self.source_boot_image.header.layout.[<offset_ $name _size>] as u64))?;
                         output.write_all(&([<$name _size>] as u32).to_le_bytes())?;
                     }
@@ -335,7 +994,114 @@ impl<'a> BootImagePatchOption<'a> {
         patch_size! { vendor_ramdisk_table }
         patch_size! { bootconfig }
 
-        // TODO: id
+        if self
+            .source_boot_image
+            .header
+            .layout
+            .offset_recovery_dtbo_offset
+            != 0
+            && recovery_dtbo_size != 0
+        {
+            output.seek(SeekFrom::Start(
+                header_off
+                    + self
+                        .source_boot_image
+                        .header
+                        .layout
+                        .offset_recovery_dtbo_offset as u64,
+            ))?;
+            output.write_all(&(recovery_dtbo_off - header_off).to_le_bytes())?;
+        }
+
+        let overrides = &self.header_overrides;
+        if (overrides.os_version.is_some() || overrides.patch_level.is_some())
+            && header.has_os_version_raw()
+        {
+            // Either half may be overridden on its own
+            let raw = header.get_os_version_raw();
+            let os_version = overrides
+                .os_version
+                .map(|it| it.encode_raw())
+                .unwrap_or(raw >> 11);
+            let patch_level = overrides
+                .patch_level
+                .map(|it| it.encode_raw())
+                .unwrap_or(raw & 0x7ff);
+            output.seek(SeekFrom::Start(
+                header_off + header.layout.offset_os_version as u64,
+            ))?;
+            output.write_all(&((os_version << 11) | patch_level).to_le_bytes())?;
+        }
+
+        if let Some(name) = name_field {
+            output.seek(SeekFrom::Start(
+                header_off + header.layout.offset_name as u64,
+            ))?;
+            output.write_all(&name)?;
+        }
+
+        macro_rules! patch_addr {
+            ($($name:ident),*) => {
+                paste! {
+                    $(
+                        if let Some(addr) = overrides.$name
+                            && header.[<has_ $name>]()
+                        {
+                            output.seek(SeekFrom::Start(
+                                header_off + header.layout.[<offset_ $name>] as u64,
+                            ))?;
+                            output.write_all(&addr.to_le_bytes())?;
+                        }
+                    )*
+                }
+            };
+        }
+        patch_addr! { kernel_addr, ramdisk_addr, second_addr, tags_addr, dtb_addr }
+
+        if let Some((cmdline, extra_cmdline)) = cmdline_fields {
+            let layout = self.source_boot_image.header.layout;
+            output.seek(SeekFrom::Start(header_off + layout.offset_cmdline as u64))?;
+            output.write_all(&cmdline)?;
+            if layout.offset_extra_cmdline != 0 {
+                output.seek(SeekFrom::Start(
+                    header_off + layout.offset_extra_cmdline as u64,
+                ))?;
+                output.write_all(&extra_cmdline)?;
+            }
+        }
+
+        if let (Some(mut hasher), Some((kind, version))) = (id_hasher, id_kind) {
+            hasher.add_tail_blocks(version, second, recovery_dtbo, dtb);
+            output.seek(SeekFrom::Start(header_off + header.layout.offset_id as u64))?;
+            output.write_all(&hasher.finish(kind))?;
+        }
+
+        // Last, the payload has to be final before hashing
+        if let Some((total_size, avb_header_off, partition_size)) = avb_layout
+            && let Some(vbmeta) = self.rebuild_vbmeta(output, total_size)?
+        {
+            let footer_off = partition_size - AvbFooter::SIZE as u64;
+            let vbmeta_end = avb_header_off + vbmeta.len() as u64;
+            if vbmeta_end > footer_off {
+                return Err(BootImgError::PayloadTooLarge {
+                    size: vbmeta_end,
+                    partition_size,
+                });
+            }
+            output.seek(SeekFrom::Start(avb_header_off))?;
+            output.write_all(&vbmeta)?;
+            output.write_zeros((footer_off - vbmeta_end) as usize)?;
+            let footer = match &self.source_boot_image.avb_info {
+                Some(avb_info) => {
+                    avb_info
+                        .avb_footer
+                        .patch(total_size, avb_header_off, vbmeta.len() as u64)
+                }
+                None => AvbFooter::create(total_size, avb_header_off, vbmeta.len() as u64),
+            };
+            output.write_all(&footer)?;
+        }
+
         // TODO: AVB1
         // TODO: special headers
 
@@ -344,3 +1110,178 @@ impl<'a> BootImagePatchOption<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtbo::DtboEntry;
+    use crate::parser::BootImageVersion::Android;
+    use crate::testutil::{FIXTURE_VERSIONS, ImageFixture, noise, ramdisk};
+
+    fn repack(data: &[u8]) -> Vec<u8> {
+        let image = BootImage::parse(data).unwrap();
+        BootImagePatchOption::new(&image).patch_to_vec().unwrap()
+    }
+
+    #[test]
+    fn identity_repack() {
+        for version in FIXTURE_VERSIONS {
+            let data = ImageFixture::new(version).build().unwrap();
+            assert!(repack(&data) == data, "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn identity_repack_with_avb() {
+        for version in FIXTURE_VERSIONS {
+            let data = ImageFixture::new(version).avb_footer(None).build().unwrap();
+            assert!(repack(&data) == data, "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn identity_repack_trailing_data() {
+        let mut data = ImageFixture::new(Android(0)).build().unwrap();
+        data.extend_from_slice(b"SEANDROIDENFORCE");
+        assert!(repack(&data) == data);
+    }
+
+    // Only a header built in the crate can get here, parsing rejects or
+    // replaces a bad page size
+    #[test]
+    fn invalid_page_size() {
+        let data = ImageFixture::new(Android(2)).build().unwrap();
+        for page_size in [0, 3000] {
+            let mut image = BootImage::parse(&data).unwrap();
+            image.header.page_size = page_size;
+            let result = BootImagePatchOption::new(&image).patch_to_vec();
+            assert!(
+                matches!(result, Err(BootImgError::InvalidPageSize(size)) if size == page_size),
+                "{}: {:?}",
+                page_size,
+                result.map(|it| it.len())
+            );
+        }
+    }
+
+    fn grown_ramdisk(len: usize) -> Box<dyn Read> {
+        let data = ramdisk(&[("noise", &noise(len, 1))]).unwrap();
+        Box::new(Cursor::new(data))
+    }
+
+    #[test]
+    fn payload_overflowing_avb_partition() {
+        // The smallest partition leaves a single page for the vbmeta
+        let data = ImageFixture::new(Android(3))
+            .avb_footer(None)
+            .build()
+            .unwrap();
+        let image = BootImage::parse(&data).unwrap();
+        let mut patcher = BootImagePatchOption::new(&image);
+        patcher.replace_ramdisk(grown_ramdisk(9000));
+        let result = patcher.patch_to_vec();
+        assert!(
+            matches!(result, Err(BootImgError::PayloadTooLarge { partition_size, .. })
+                if partition_size == data.len() as u64),
+            "{:?}",
+            result.map(|it| it.len())
+        );
+    }
+
+    #[test]
+    fn payload_growing_within_avb_partition() {
+        let data = ImageFixture::new(Android(3))
+            .avb_footer(Some(64 * 1024))
+            .build()
+            .unwrap();
+        let image = BootImage::parse(&data).unwrap();
+        let mut patcher = BootImagePatchOption::new(&image);
+        patcher.replace_ramdisk(grown_ramdisk(9000));
+        let patched = patcher.patch_to_vec().unwrap();
+        assert_eq!(patched.len(), data.len());
+        let patched = BootImage::parse(&patched).unwrap();
+        assert_eq!(patched.get_avb_partition_size(), Some(data.len()));
+        assert!(patched.get_vbmeta().unwrap().is_some());
+    }
+
+    #[test]
+    fn replace_recovery_dtbo_table() {
+        let data = ImageFixture::new(Android(1)).build().unwrap();
+        let image = BootImage::parse(&data).unwrap();
+        let mut table = image
+            .get_blocks()
+            .get_recovery_dtbo_table()
+            .unwrap()
+            .unwrap();
+        let original = table.entries[0].data.to_vec();
+        table.entries.push(DtboEntry::new(b"overlay".to_vec()));
+        let mut patcher = BootImagePatchOption::new(&image);
+        patcher.replace_recovery_dtbo_table(&table).unwrap();
+        let patched = patcher.patch_to_vec().unwrap();
+
+        let patched = BootImage::parse(&patched).unwrap();
+        let blocks = patched.get_blocks();
+        assert_eq!(
+            blocks.get_recovery_dtbo(),
+            Some(table.to_bytes().unwrap().as_slice())
+        );
+        let table = blocks.get_recovery_dtbo_table().unwrap().unwrap();
+        assert_eq!(table.entries.len(), 2);
+        assert_eq!(table.entries[0].data, original);
+        assert_eq!(table.entries[1].data, &b"overlay"[..]);
+        assert_eq!(
+            blocks.get_kernel().unwrap().get_data(),
+            image.get_blocks().get_kernel().unwrap().get_data()
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    fn patch_gzip_ramdisk(data: &[u8], options: CompressOptions) -> Vec<u8> {
+        let image = BootImage::parse(data).unwrap();
+        let mut patcher = BootImagePatchOption::new(&image);
+        patcher.compress_options(options);
+        let ramdisk = ramdisk(&[("init", b"patched init")]).unwrap();
+        patcher.replace_ramdisk(Box::new(Cursor::new(ramdisk)));
+        patcher.patch_to_vec().unwrap()
+    }
+
+    // mtime and OS bytes of the gzip header of the ramdisk
+    #[cfg(feature = "gzip")]
+    fn gzip_header(data: &[u8]) -> ([u8; 4], u8) {
+        let image = BootImage::parse(data).unwrap();
+        let ramdisk = image.get_blocks().get_ramdisk().unwrap().get_data();
+        assert_eq!(parse_compress_format(ramdisk), CompressFormat::GZIP);
+        (ramdisk[4..8].try_into().unwrap(), ramdisk[9])
+    }
+
+    // Best goes through zopfli when it is compiled in, fast through flate2
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn deterministic_gzip() {
+        use crate::compress::{GZIP_OS_UNIX, GZIP_OS_UNKNOWN};
+        use crate::digest::{DigestAlgorithm, digest_reader};
+
+        let sha256 = |data: &[u8]| digest_reader(DigestAlgorithm::Sha256, &mut &data[..]).unwrap();
+        let data = ImageFixture::new(Android(2)).build().unwrap();
+        for options in [CompressOptions::default(), CompressOptions::fast()] {
+            let first = patch_gzip_ramdisk(&data, options);
+            let second = patch_gzip_ramdisk(&data, options);
+            assert_eq!(sha256(&first), sha256(&second));
+            // No timestamp, nothing depends on the clock
+            assert_eq!(gzip_header(&first), ([0; 4], GZIP_OS_UNKNOWN));
+
+            let options = CompressOptions {
+                gzip_header: GzipHeader {
+                    mtime: 0x12345678,
+                    operating_system: GZIP_OS_UNIX,
+                },
+                ..options
+            };
+            let patched = patch_gzip_ramdisk(&data, options);
+            assert_eq!(
+                gzip_header(&patched),
+                (0x12345678u32.to_le_bytes(), GZIP_OS_UNIX)
+            );
+        }
+    }
+}
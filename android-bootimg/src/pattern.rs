@@ -0,0 +1,99 @@
+use crate::error::{BootImgError, Result};
+
+// Hex bytes as magiskboot hexpatch takes them, spaces are ignored and `??`
+// matches any byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexPattern {
+    bytes: Vec<Option<u8>>,
+}
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidHexPattern(msg)
+}
+
+impl HexPattern {
+    pub fn parse(s: &str) -> Result<Self> {
+        let digits: Vec<u8> = s.bytes().filter(|it| !it.is_ascii_whitespace()).collect();
+        if digits.is_empty() {
+            return Err(invalid("empty pattern".to_string()));
+        }
+        if !digits.len().is_multiple_of(2) {
+            return Err(invalid(format!("odd number of hex digits in {:?}", s)));
+        }
+        let bytes = digits
+            .chunks(2)
+            .map(|pair| match pair {
+                b"??" => Ok(None),
+                _ => str::from_utf8(pair)
+                    .ok()
+                    .and_then(|it| u8::from_str_radix(it, 16).ok())
+                    .map(Some)
+                    .ok_or_else(|| invalid(format!("invalid byte \"{}\"", pair.escape_ascii()))),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { bytes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Never true for a parsed pattern
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn has_wildcards(&self) -> bool {
+        self.bytes.iter().any(Option::is_none)
+    }
+
+    // The literal bytes, an error if there are wildcards
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.bytes
+            .iter()
+            .copied()
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid("wildcards are not allowed here".to_string()))
+    }
+
+    pub fn matches_at(&self, data: &[u8], pos: usize) -> bool {
+        data.get(pos..pos + self.len()).is_some_and(|window| {
+            window
+                .iter()
+                .zip(&self.bytes)
+                .all(|(b, p)| p.is_none_or(|p| p == *b))
+        })
+    }
+
+    // Offsets of non-overlapping matches
+    pub fn find_all(&self, data: &[u8]) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut pos = 0;
+        while pos + self.len() <= data.len() {
+            if self.matches_at(data, pos) {
+                found.push(pos);
+                pos += self.len();
+            } else {
+                pos += 1;
+            }
+        }
+        found
+    }
+}
+
+// Overwrites every match of `pattern` with `replacement` from the start of the
+// match, as magiskboot hexpatch. Returns the patched offsets.
+pub fn hexpatch(data: &mut [u8], pattern: &HexPattern, replacement: &[u8]) -> Result<Vec<usize>> {
+    let found = pattern.find_all(data);
+    for &pos in &found {
+        let Some(target) = data.get_mut(pos..pos + replacement.len()) else {
+            return Err(invalid(format!(
+                "replacement of {} bytes at {:#x} exceeds the data",
+                replacement.len(),
+                pos
+            )));
+        };
+        target.copy_from_slice(replacement);
+    }
+    Ok(found)
+}
@@ -0,0 +1,507 @@
+use crate::compress::CompressFormat;
+use crate::error::{BootImgError, Result};
+use crate::parser::{BootImage, OwnedBootImage, ParseOptions};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+// https://android.googlesource.com/platform/system/update_engine/+/refs/heads/main/update_metadata.proto
+const PAYLOAD_MAGIC: &[u8] = b"CrAU";
+// magic, file_format_version, manifest_size and metadata_signature_size
+const PAYLOAD_HEADER_SIZE: usize = 24;
+const DEFAULT_BLOCK_SIZE: u64 = 4096;
+
+const OP_REPLACE: u64 = 0;
+const OP_REPLACE_BZ: u64 = 1;
+const OP_ZERO: u64 = 6;
+const OP_DISCARD: u64 = 7;
+const OP_REPLACE_XZ: u64 = 8;
+const OP_REPLACE_ZSTD: u64 = 14;
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidPayload(msg)
+}
+
+// Offsets and sizes from the manifest, anything overflowing is corrupt
+fn checked<T>(value: Option<T>, what: &str) -> Result<T> {
+    value.ok_or_else(|| invalid(format!("{} out of range", what)))
+}
+
+fn to_usize(value: u64, what: &str) -> Result<usize> {
+    checked(usize::try_from(value).ok(), what)
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+// Just enough protobuf wire format for the manifest
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| invalid("truncated varint".to_string()))?;
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint too long".to_string()))
+    }
+
+    fn skip(&mut self, len: usize) -> Result<&'a [u8]> {
+        let data = self
+            .data
+            .get(self.pos..)
+            .and_then(|it| it.get(..len))
+            .ok_or_else(|| invalid("truncated field".to_string()))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, ProtoValue<'a>)>> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.skip(8)?;
+                ProtoValue::Fixed
+            }
+            2 => {
+                let len = to_usize(self.varint()?, "field length")?;
+                ProtoValue::Bytes(self.skip(len)?)
+            }
+            5 => {
+                self.skip(4)?;
+                ProtoValue::Fixed
+            }
+            wire_type => return Err(invalid(format!("unsupported wire type {}", wire_type))),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    start_block: u64,
+    num_blocks: u64,
+}
+
+#[derive(Debug, Clone)]
+struct InstallOperation {
+    kind: u64,
+    data_offset: u64,
+    data_length: u64,
+    dst_extents: Vec<Extent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PayloadPartition {
+    pub name: String,
+    pub size: u64,
+    pub sha256: Option<Vec<u8>>,
+    operations: Vec<InstallOperation>,
+}
+
+impl PayloadPartition {
+    // Only REPLACE, ZERO and DISCARD operations, which need no source partition
+    pub fn is_full(&self) -> bool {
+        self.operations.iter().all(|op| {
+            matches!(
+                op.kind,
+                OP_REPLACE | OP_REPLACE_BZ | OP_REPLACE_XZ | OP_REPLACE_ZSTD | OP_ZERO | OP_DISCARD
+            )
+        })
+    }
+}
+
+fn parse_extent(data: &[u8]) -> Result<Extent> {
+    let mut extent = Extent {
+        start_block: 0,
+        num_blocks: 0,
+    };
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Varint(v)) => extent.start_block = v,
+            (2, ProtoValue::Varint(v)) => extent.num_blocks = v,
+            _ => {}
+        }
+    }
+    Ok(extent)
+}
+
+fn parse_operation(data: &[u8]) -> Result<InstallOperation> {
+    let mut op = InstallOperation {
+        kind: 0,
+        data_offset: 0,
+        data_length: 0,
+        dst_extents: Vec::new(),
+    };
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Varint(v)) => op.kind = v,
+            (2, ProtoValue::Varint(v)) => op.data_offset = v,
+            (3, ProtoValue::Varint(v)) => op.data_length = v,
+            (6, ProtoValue::Bytes(v)) => op.dst_extents.push(parse_extent(v)?),
+            _ => {}
+        }
+    }
+    Ok(op)
+}
+
+fn parse_partition(data: &[u8]) -> Result<PayloadPartition> {
+    let mut partition = PayloadPartition {
+        name: String::new(),
+        size: 0,
+        sha256: None,
+        operations: Vec::new(),
+    };
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(v)) => partition.name = String::from_utf8_lossy(v).into_owned(),
+            // new_partition_info
+            (7, ProtoValue::Bytes(v)) => {
+                let mut info = ProtoReader::new(v);
+                while let Some((field, value)) = info.next_field()? {
+                    match (field, value) {
+                        (1, ProtoValue::Varint(size)) => partition.size = size,
+                        (2, ProtoValue::Bytes(hash)) => partition.sha256 = Some(hash.to_vec()),
+                        _ => {}
+                    }
+                }
+            }
+            (8, ProtoValue::Bytes(v)) => partition.operations.push(parse_operation(v)?),
+            _ => {}
+        }
+    }
+    Ok(partition)
+}
+
+// An update_engine payload.bin as found in OTA zips
+pub struct Payload<'a> {
+    data: &'a [u8],
+    data_offset: usize,
+    block_size: u64,
+    partitions: Vec<PayloadPartition>,
+    options: ParseOptions,
+}
+
+impl<'a> Payload<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        Self::parse_with_options(data, ParseOptions::default())
+    }
+
+    // Extracting a partition larger than options.max_decompressed_size fails
+    // with DecompressLimitExceeded
+    pub fn parse_with_options(data: &'a [u8], options: ParseOptions) -> Result<Self> {
+        if !data.starts_with(PAYLOAD_MAGIC) {
+            return Err(invalid("bad magic".to_string()));
+        }
+        let header = data
+            .get(..PAYLOAD_HEADER_SIZE)
+            .ok_or_else(|| invalid("truncated header".to_string()))?;
+        let version = u64::from_be_bytes(header[4..12].try_into().unwrap());
+        if version != 2 {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        let manifest_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+        let signature_size = u32::from_be_bytes(header[20..24].try_into().unwrap()) as usize;
+        let manifest = data[PAYLOAD_HEADER_SIZE..]
+            .get(..to_usize(manifest_size, "manifest size")?)
+            .ok_or_else(|| invalid("truncated manifest".to_string()))?;
+        let data_offset = checked(
+            (PAYLOAD_HEADER_SIZE + manifest.len()).checked_add(signature_size),
+            "metadata signature size",
+        )?;
+
+        let mut block_size = DEFAULT_BLOCK_SIZE;
+        let mut partitions = Vec::new();
+        let mut reader = ProtoReader::new(manifest);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (3, ProtoValue::Varint(v)) => block_size = v,
+                (13, ProtoValue::Bytes(v)) => partitions.push(parse_partition(v)?),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            data,
+            data_offset,
+            block_size,
+            partitions,
+            options,
+        })
+    }
+
+    pub fn partitions(&self) -> &[PayloadPartition] {
+        &self.partitions
+    }
+
+    pub fn get_partition(&self, name: &str) -> Option<&PayloadPartition> {
+        self.partitions.iter().find(|it| it.name == name)
+    }
+
+    // Rebuilds a partition of a full payload, the new_partition_info hash is checked
+    pub fn extract(&self, name: &str) -> Result<Vec<u8>> {
+        let partition = self
+            .get_partition(name)
+            .ok_or_else(|| invalid(format!("no partition {}", name)))?;
+        if !partition.is_full() {
+            return Err(invalid(format!(
+                "{} is a delta update, only full payloads can be extracted",
+                name
+            )));
+        }
+        // The manifest can claim any size, only what the operations write is
+        // allocated
+        let mut written = 0u64;
+        for extent in partition.operations.iter().flat_map(|op| &op.dst_extents) {
+            let (_, len) = self.extent_range(extent, name)?;
+            written = checked(written.checked_add(len), "extents")?;
+        }
+        if partition.size > written {
+            return Err(invalid(format!(
+                "{} of {} bytes is larger than its {} bytes of extents",
+                name, partition.size, written
+            )));
+        }
+        if partition.size > self.options.max_decompressed_size {
+            return Err(BootImgError::DecompressLimitExceeded(
+                self.options.max_decompressed_size,
+            ));
+        }
+        let size = to_usize(partition.size, "partition size")?;
+        let mut image = Vec::new();
+        image
+            .try_reserve_exact(size)
+            .map_err(|_| invalid(format!("cannot allocate {} bytes for {}", size, name)))?;
+        image.resize(size, 0);
+        for op in &partition.operations {
+            let what = format!("operation data of {}", name);
+            let start = checked(
+                self.data_offset
+                    .checked_add(to_usize(op.data_offset, &what)?),
+                &what,
+            )?;
+            let blob = self
+                .data
+                .get(start..)
+                .and_then(|it| it.get(..usize::try_from(op.data_length).ok()?))
+                .ok_or_else(|| invalid(format!("operation data of {} out of range", name)))?;
+            let mut content: Box<dyn Read> = match op.kind {
+                OP_REPLACE => Box::new(blob),
+                OP_REPLACE_BZ => self.options.strict_decoder(CompressFormat::BZIP2, blob)?,
+                OP_REPLACE_XZ => self.options.strict_decoder(CompressFormat::XZ, blob)?,
+                OP_REPLACE_ZSTD => self.options.strict_decoder(CompressFormat::ZSTD, blob)?,
+                // Already zero filled
+                _ => continue,
+            };
+            for extent in &op.dst_extents {
+                let (offset, len) = self.extent_range(extent, name)?;
+                // The last block may extend past the partition size
+                let end = offset.saturating_add(len).min(image.len() as u64);
+                let target = usize::try_from(offset)
+                    .ok()
+                    .and_then(|offset| image.get_mut(offset..end as usize))
+                    .ok_or_else(|| invalid(format!("extent of {} out of range", name)))?;
+                content.read_exact(target)?;
+            }
+        }
+        if let Some(expected) = &partition.sha256
+            && Sha256::digest(&image).as_slice() != expected.as_slice()
+        {
+            return Err(invalid(format!("{} does not match its sha256", name)));
+        }
+        Ok(image)
+    }
+
+    // Byte offset and length of an extent
+    fn extent_range(&self, extent: &Extent, name: &str) -> Result<(u64, u64)> {
+        let what = format!("extent of {}", name);
+        let offset = checked(extent.start_block.checked_mul(self.block_size), &what)?;
+        let len = checked(extent.num_blocks.checked_mul(self.block_size), &what)?;
+        checked(offset.checked_add(len), &what)?;
+        Ok((offset, len))
+    }
+
+    // For boot, init_boot, vendor_boot or recovery
+    pub fn boot_image(&self, name: &str) -> Result<OwnedBootImage> {
+        BootImage::parse_owned(self.extract(name)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn field(out: &mut Vec<u8>, number: u64, value: u64) {
+        varint(out, number << 3);
+        varint(out, value);
+    }
+
+    fn message(out: &mut Vec<u8>, number: u64, data: &[u8]) {
+        varint(out, (number << 3) | 2);
+        varint(out, data.len() as u64);
+        out.extend_from_slice(data);
+    }
+
+    struct Op {
+        kind: u64,
+        data: Vec<u8>,
+        // (start_block, num_blocks)
+        extents: Vec<(u64, u64)>,
+    }
+
+    // A full payload with one partition, blobs follow the metadata in order
+    fn payload(size: u64, ops: &[Op], data_offset_bias: u64) -> Vec<u8> {
+        let mut partition = Vec::new();
+        message(&mut partition, 1, b"boot");
+        let mut info = Vec::new();
+        field(&mut info, 1, size);
+        message(&mut partition, 7, &info);
+        let mut offset = 0;
+        for op in ops {
+            let mut encoded = Vec::new();
+            field(&mut encoded, 1, op.kind);
+            field(&mut encoded, 2, offset + data_offset_bias);
+            field(&mut encoded, 3, op.data.len() as u64);
+            for (start, num) in &op.extents {
+                let mut extent = Vec::new();
+                field(&mut extent, 1, *start);
+                field(&mut extent, 2, *num);
+                message(&mut encoded, 6, &extent);
+            }
+            message(&mut partition, 8, &encoded);
+            offset += op.data.len() as u64;
+        }
+        let mut manifest = Vec::new();
+        field(&mut manifest, 3, 4096);
+        message(&mut manifest, 13, &partition);
+
+        let mut out = PAYLOAD_MAGIC.to_vec();
+        out.extend_from_slice(&2u64.to_be_bytes());
+        out.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+        out.extend_from_slice(&0u32.to_be_bytes());
+        out.extend_from_slice(&manifest);
+        for op in ops {
+            out.extend_from_slice(&op.data);
+        }
+        out
+    }
+
+    fn replace(data: Vec<u8>, start_block: u64) -> Op {
+        let blocks = data.len().div_ceil(4096) as u64;
+        Op {
+            kind: OP_REPLACE,
+            data,
+            extents: vec![(start_block, blocks)],
+        }
+    }
+
+    fn is_invalid<T>(result: Result<T>) -> bool {
+        matches!(result, Err(BootImgError::InvalidPayload(_)))
+    }
+
+    #[test]
+    fn extract_full_partition() {
+        let ops = [
+            replace(vec![1; 4096], 0),
+            Op {
+                kind: OP_ZERO,
+                data: Vec::new(),
+                extents: vec![(1, 1)],
+            },
+            replace(vec![3; 4096], 2),
+        ];
+        let data = payload(3 * 4096, &ops, 0);
+        let image = Payload::parse(&data).unwrap().extract("boot").unwrap();
+        assert_eq!(&image[..4096], &[1; 4096]);
+        assert_eq!(&image[4096..8192], &[0; 4096]);
+        assert_eq!(&image[8192..], &[3; 4096]);
+    }
+
+    #[test]
+    fn partition_past_limit() {
+        let data = payload(2 * 4096, &[replace(vec![1; 2 * 4096], 0)], 0);
+        let options = ParseOptions {
+            max_decompressed_size: 4096,
+            ..Default::default()
+        };
+        let payload = Payload::parse_with_options(&data, options).unwrap();
+        assert!(matches!(
+            payload.extract("boot"),
+            Err(BootImgError::DecompressLimitExceeded(4096))
+        ));
+    }
+
+    #[test]
+    fn manifest_size_overflow() {
+        let mut data = payload(4096, &[replace(vec![1; 4096], 0)], 0);
+        data[12..20].copy_from_slice(&(u64::MAX - 8).to_be_bytes());
+        assert!(is_invalid(Payload::parse(&data)));
+    }
+
+    #[test]
+    fn signature_size_past_end() {
+        let mut data = payload(4096, &[replace(vec![1; 4096], 0)], 0);
+        data[20..24].copy_from_slice(&u32::MAX.to_be_bytes());
+        let payload = Payload::parse(&data).unwrap();
+        assert!(is_invalid(payload.extract("boot")));
+    }
+
+    #[test]
+    fn data_offset_overflow() {
+        let data = payload(4096, &[replace(vec![1; 4096], 0)], u64::MAX - 16);
+        let payload = Payload::parse(&data).unwrap();
+        assert!(is_invalid(payload.extract("boot")));
+    }
+
+    #[test]
+    fn extent_overflow() {
+        for extent in [(u64::MAX / 2, 1), (0, u64::MAX / 2), (u64::MAX / 4096, 2)] {
+            let op = Op {
+                kind: OP_REPLACE,
+                data: vec![1; 4096],
+                extents: vec![extent],
+            };
+            let data = payload(4096, &[op], 0);
+            let payload = Payload::parse(&data).unwrap();
+            assert!(is_invalid(payload.extract("boot")), "{:?}", extent);
+        }
+    }
+
+    #[test]
+    fn size_larger_than_extents() {
+        let data = payload(u64::MAX, &[replace(vec![1; 4096], 0)], 0);
+        let payload = Payload::parse(&data).unwrap();
+        assert!(is_invalid(payload.extract("boot")));
+    }
+}
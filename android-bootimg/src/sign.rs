@@ -0,0 +1,126 @@
+use crate::avb::AvbAlgorithm;
+use crate::error::{BootImgError, Result};
+use crate::layouts::{AVB_HEADER_SIZE, AvbVBMetaImageHeader};
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPrivateKey};
+use sha2::{Digest, Sha256, Sha512};
+
+// RSA key for the authentication block, same as `avbtool --key`
+pub struct AvbSigningKey {
+    key: RsaPrivateKey,
+}
+
+fn invalid(msg: String) -> BootImgError {
+    BootImgError::InvalidSigningKey(msg)
+}
+
+fn to_be_padded(num: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = num.to_bytes_be();
+    let mut v = vec![0u8; len.saturating_sub(bytes.len())];
+    v.extend_from_slice(&bytes);
+    v
+}
+
+impl AvbSigningKey {
+    pub fn from_pkcs8_pem(pem: &str) -> Result<Self> {
+        RsaPrivateKey::from_pkcs8_pem(pem)
+            .map_err(|e| invalid(e.to_string()))
+            .and_then(Self::new)
+    }
+
+    pub fn from_pkcs8_der(der: &[u8]) -> Result<Self> {
+        RsaPrivateKey::from_pkcs8_der(der)
+            .map_err(|e| invalid(e.to_string()))
+            .and_then(Self::new)
+    }
+
+    fn new(key: RsaPrivateKey) -> Result<Self> {
+        let bits = key.size() * 8;
+        if ![2048, 4096, 8192].contains(&bits) {
+            return Err(invalid(format!("unsupported key size {} bits", bits)));
+        }
+        Ok(Self { key })
+    }
+
+    pub fn key_bits(&self) -> u32 {
+        (self.key.size() * 8) as u32
+    }
+
+    // SHA256 with the matching key size, avbtool has no default either
+    pub fn default_algorithm(&self) -> AvbAlgorithm {
+        match self.key_bits() {
+            2048 => AvbAlgorithm::Sha256Rsa2048,
+            4096 => AvbAlgorithm::Sha256Rsa4096,
+            _ => AvbAlgorithm::Sha256Rsa8192,
+        }
+    }
+
+    // AvbRSAPublicKeyHeader followed by n and rr = (2^bits)^2 mod n
+    pub fn public_key(&self) -> Vec<u8> {
+        let len = self.key.size();
+        let n = self.key.n();
+        let modulus = to_be_padded(n, len);
+
+        // -1 / n mod 2^32 by Newton iteration, n is odd
+        let n0 = u32::from_be_bytes(modulus[len - 4..].try_into().unwrap());
+        let mut inv = n0;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inv)));
+        }
+        let rr = (BigUint::from(1u32) << (self.key_bits() as usize * 2)) % n;
+
+        let mut v = Vec::with_capacity(8 + len * 2);
+        v.extend_from_slice(&self.key_bits().to_be_bytes());
+        v.extend_from_slice(&inv.wrapping_neg().to_be_bytes());
+        v.extend_from_slice(&modulus);
+        v.extend_from_slice(&to_be_padded(&rr, len));
+        v
+    }
+
+    pub(crate) fn check_algorithm(&self, algorithm: AvbAlgorithm) -> Result<()> {
+        if algorithm.key_bits() != self.key_bits() {
+            return Err(invalid(format!(
+                "algorithm {} needs a {} bits key, got {}",
+                algorithm.name(),
+                algorithm.key_bits(),
+                self.key_bits()
+            )));
+        }
+        Ok(())
+    }
+
+    // Fills hash and signature of a vbmeta laid out by build_vbmeta, both cover
+    // the header and auxiliary blocks
+    pub(crate) fn sign_vbmeta(&self, vbmeta: &mut [u8], algorithm: AvbAlgorithm) -> Result<()> {
+        self.check_algorithm(algorithm)?;
+        let header = AvbVBMetaImageHeader {
+            data: &vbmeta[..AVB_HEADER_SIZE],
+        };
+        let auth_end = AVB_HEADER_SIZE + header.get_authentication_data_block_size() as usize;
+        let hash_off = AVB_HEADER_SIZE + header.get_hash_offset() as usize;
+        let signature_off = AVB_HEADER_SIZE + header.get_signature_offset() as usize;
+
+        let mut signed = vbmeta[..AVB_HEADER_SIZE].to_vec();
+        signed.extend_from_slice(&vbmeta[auth_end..]);
+        let (hash, scheme) = if algorithm.hash_algorithm() == Some("sha256") {
+            (
+                Sha256::digest(&signed).to_vec(),
+                Pkcs1v15Sign::new::<Sha256>(),
+            )
+        } else {
+            (
+                Sha512::digest(&signed).to_vec(),
+                Pkcs1v15Sign::new::<Sha512>(),
+            )
+        };
+        let signature = self
+            .key
+            .sign(scheme, &hash)
+            .map_err(|e| invalid(e.to_string()))?;
+
+        vbmeta[hash_off..hash_off + hash.len()].copy_from_slice(&hash);
+        vbmeta[signature_off..signature_off + signature.len()].copy_from_slice(&signature);
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+// Minimal valid images built in memory, one per layout BootImageBuilder
+// supports, so tests don't need binary fixtures. Every block holds real, if
+// tiny, content of its format: an arm64 Image header, a newc ramdisk, an fdt
+// with a model, a dt table and a bootconfig.
+use crate::avb::AvbAlgorithm;
+use crate::builder::BootImageBuilder;
+use crate::compress::CompressFormat;
+use crate::constants::VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE;
+use crate::cpio::{Cpio, CpioEntry};
+use crate::dtb::{FDT_BEGIN_NODE, FDT_END, FDT_END_NODE, FDT_HEADER_SIZE, FDT_MAGIC, FDT_PROP};
+use crate::dtbo::{DtboEntry, DtboTable};
+use crate::error::Result;
+use crate::layouts::VendorRamdiskTableEntryType;
+use crate::parser::BootImageVersion::{self, Android, Vendor};
+use crate::parser::{OsVersion, OwnedBootImage, PatchLevel};
+use crate::patcher::{AvbFooterOptions, BootImagePatchOption};
+use crate::utils::align_to;
+use std::io::Cursor;
+
+pub const FIXTURE_NAME: &str = "fixture";
+pub const FIXTURE_CMDLINE: &str = "console=ttyS0 androidboot.fixture=1";
+pub const FIXTURE_MODEL: &str = "Fixture Board";
+// Every layout ImageFixture can build
+pub const FIXTURE_VERSIONS: [BootImageVersion; 7] = [
+    Android(0),
+    Android(1),
+    Android(2),
+    Android(3),
+    Android(4),
+    Vendor(3),
+    Vendor(4),
+];
+// Size of the v4 boot signature block, a zeroed page
+const SIGNATURE_SIZE: usize = 4096;
+const AVB_SALT: [u8; 32] = [0x5a; 32];
+
+// arm64 Image header (Documentation/arch/arm64/booting.rst) followed by a
+// page of code
+pub fn kernel() -> Vec<u8> {
+    let size = 4096;
+    let mut data = vec![0u8; size];
+    // b #16, then text_offset 0x80000 and image_size
+    data[0..4].copy_from_slice(&0x14000010u32.to_le_bytes());
+    data[8..16].copy_from_slice(&0x80000u64.to_le_bytes());
+    data[16..24].copy_from_slice(&(size as u64).to_le_bytes());
+    data[56..60].copy_from_slice(b"ARM\x64");
+    data
+}
+
+// Incompressible bytes from a xorshift generator, the same for a given seed
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+// Uncompressed newc archive with `files` and their parent directories
+pub fn ramdisk(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    let mut cpio = Cpio::new();
+    for (path, data) in files {
+        cpio.add_with_parents(path, CpioEntry::regular(0o644, data.to_vec()))?;
+    }
+    let mut data = Vec::new();
+    cpio.dump(&mut data)?;
+    Ok(data)
+}
+
+fn default_ramdisk(name: &str) -> Result<Vec<u8>> {
+    let prop = format!("ro.fixture.ramdisk={}\n", name);
+    ramdisk(&[
+        ("init", b"#!/system/bin/sh\n"),
+        ("system/etc/fixture.prop", prop.as_bytes()),
+    ])
+}
+
+// Flattened device tree with a root node holding model and compatible
+pub fn dtb(model: &str) -> Vec<u8> {
+    fn put(data: &mut Vec<u8>, values: &[u32]) {
+        for v in values {
+            data.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+    // Properties reference their names by offset in the strings block
+    let strings = b"model\0compatible\0";
+    let model = format!("{}\0", model);
+    let props: [(u32, &[u8]); 2] = [(0, model.as_bytes()), (6, b"android,fixture\0")];
+    let mut structure = Vec::new();
+    // The root node name is empty, the terminating NUL is padded to 4 bytes
+    put(&mut structure, &[FDT_BEGIN_NODE, 0]);
+    for (name, value) in props {
+        put(&mut structure, &[FDT_PROP, value.len() as u32, name]);
+        structure.extend_from_slice(value);
+        structure.resize(align_to(structure.len(), 4), 0);
+    }
+    put(&mut structure, &[FDT_END_NODE, FDT_END]);
+
+    // Header, empty memory reservation map, structure block, strings block
+    let off_mem_rsvmap = FDT_HEADER_SIZE;
+    let off_dt_struct = off_mem_rsvmap + 16;
+    let off_dt_strings = off_dt_struct + structure.len();
+    let total_size = off_dt_strings + strings.len();
+    let mut data = Vec::with_capacity(total_size);
+    put(
+        &mut data,
+        &[
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            // version, last_comp_version, boot_cpuid_phys
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ],
+    );
+    data.resize(off_dt_struct, 0);
+    data.extend_from_slice(&structure);
+    data.extend_from_slice(strings);
+    data
+}
+
+// dt table with a single overlay, as stored in recovery_dtbo
+pub fn dtbo() -> Result<Vec<u8>> {
+    let table = DtboTable {
+        entries: vec![DtboEntry::new(dtb(FIXTURE_MODEL))],
+        ..Default::default()
+    };
+    table.to_bytes()
+}
+
+// Bare parameters, vendor_boot stores them without the trailer
+pub fn bootconfig() -> Vec<u8> {
+    b"androidboot.hardware=fixture\nandroidboot.fixture=1\n".to_vec()
+}
+
+// Builds a fixture of one layout with every block it can hold:
+//   boot v0: kernel, ramdisk, second
+//   boot v1: v0 and recovery_dtbo
+//   boot v2: v1 and dtb
+//   boot v3: kernel and ramdisk, GKI style
+//   boot v4: v3 and a boot signature
+//   vendor v3: ramdisk and dtb
+//   vendor v4: platform, recovery and dlkm ramdisk fragments, dtb, bootconfig
+pub struct ImageFixture {
+    version: BootImageVersion,
+    ramdisk_format: CompressFormat,
+    avb_partition_size: Option<u64>,
+}
+
+impl ImageFixture {
+    pub fn new(version: BootImageVersion) -> Self {
+        // gzip is the format of stock ramdisks, keep them raw without it
+        let ramdisk_format = if cfg!(feature = "gzip") {
+            CompressFormat::GZIP
+        } else {
+            CompressFormat::UNKNOWN
+        };
+        Self {
+            version,
+            ramdisk_format,
+            avb_partition_size: None,
+        }
+    }
+
+    pub fn get_version(&self) -> BootImageVersion {
+        self.version
+    }
+
+    // Every ramdisk and vendor ramdisk fragment is compressed with `format`
+    pub fn ramdisk_format(&mut self, format: CompressFormat) -> &mut Self {
+        self.ramdisk_format = format;
+        self
+    }
+
+    // Appends an unsigned AVB footer with a hash descriptor, the image is padded
+    // to `partition_size` (a multiple of 4096). None picks the smallest size.
+    pub fn avb_footer(&mut self, partition_size: Option<u64>) -> &mut Self {
+        self.avb_partition_size = Some(partition_size.unwrap_or(0));
+        self
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>> {
+        let mut builder = BootImageBuilder::new(self.version)?;
+        // Only the header fields the layout has, boot v3 dropped the name and
+        // vendor boot has no os version
+        let layout = builder.get_layout();
+        builder.cmdline(FIXTURE_CMDLINE);
+        if layout.size_name != 0 {
+            builder.name(FIXTURE_NAME);
+        }
+        if layout.offset_os_version != 0 {
+            builder.os_version(OsVersion::new(14, 0, 0)?, PatchLevel::new(2024, 1)?);
+        }
+        let format = self.ramdisk_format;
+        let raw = CompressFormat::UNKNOWN;
+        macro_rules! block {
+            ($name:expr, $data:expr, $format:expr) => {
+                builder.add_block($name, Box::new(Cursor::new($data)), $format)?
+            };
+        }
+        match self.version {
+            Android(version) => {
+                block!("kernel", kernel(), raw);
+                block!("ramdisk", default_ramdisk("boot")?, format);
+                if version <= 2 {
+                    block!("second", b"second stage bootloader".to_vec(), raw);
+                }
+                if (1..=2).contains(&version) {
+                    block!("recovery_dtbo", dtbo()?, raw);
+                }
+                if version == 2 {
+                    block!("dtb", dtb(FIXTURE_MODEL), raw);
+                }
+                if version >= 4 {
+                    block!("signature", vec![0u8; SIGNATURE_SIZE], raw);
+                }
+            }
+            Vendor(version) => {
+                if version >= 4 {
+                    let fragments = [
+                        ("", VendorRamdiskTableEntryType::Platform),
+                        ("recovery", VendorRamdiskTableEntryType::Recovery),
+                        ("dlkm", VendorRamdiskTableEntryType::Dlkm),
+                    ];
+                    for (name, entry_type) in fragments {
+                        let label = if name.is_empty() { "platform" } else { name };
+                        builder.add_vendor_ramdisk(
+                            name,
+                            entry_type,
+                            [0; VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE],
+                            Box::new(Cursor::new(default_ramdisk(label)?)),
+                            format,
+                        )?;
+                    }
+                    block!("bootconfig", bootconfig(), raw);
+                } else {
+                    block!("ramdisk", default_ramdisk("vendor_boot")?, format);
+                }
+                block!("dtb", dtb(FIXTURE_MODEL), raw);
+            }
+        }
+        let mut data = Vec::new();
+        builder.build(&mut data)?;
+
+        let Some(partition_size) = self.avb_partition_size else {
+            return Ok(data);
+        };
+        let partition_name = match self.version {
+            Android(_) => "boot",
+            Vendor(_) => "vendor_boot",
+        };
+        // Image, then a page for the vbmeta and a page for the footer
+        let partition_size = match partition_size {
+            0 => align_to(data.len(), 4096) as u64 + 2 * 4096,
+            size => size,
+        };
+        let image = OwnedBootImage::parse(data)?;
+        let mut patcher = BootImagePatchOption::new(image.get());
+        patcher.add_avb_footer(AvbFooterOptions {
+            partition_name: partition_name.to_string(),
+            partition_size,
+            salt: AVB_SALT.to_vec(),
+            algorithm: AvbAlgorithm::None,
+        });
+        patcher.patch_to_vec()
+    }
+}
+
+pub fn boot_v0() -> Vec<u8> {
+    ImageFixture::new(Android(0))
+        .build()
+        .expect("boot v0 fixture")
+}
+
+pub fn boot_v2() -> Vec<u8> {
+    ImageFixture::new(Android(2))
+        .build()
+        .expect("boot v2 fixture")
+}
+
+pub fn boot_v3() -> Vec<u8> {
+    ImageFixture::new(Android(3))
+        .build()
+        .expect("boot v3 fixture")
+}
+
+pub fn boot_v4() -> Vec<u8> {
+    ImageFixture::new(Android(4))
+        .build()
+        .expect("boot v4 fixture")
+}
+
+pub fn vendor_boot_v3() -> Vec<u8> {
+    ImageFixture::new(Vendor(3))
+        .build()
+        .expect("vendor boot v3 fixture")
+}
+
+pub fn vendor_boot_v4() -> Vec<u8> {
+    ImageFixture::new(Vendor(4))
+        .build()
+        .expect("vendor boot v4 fixture")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avb::{AvbDescriptor, AvbHashStatus};
+    use crate::parser::BootImage;
+    use std::io::Read;
+
+    fn ramdisk_cpio(image: &BootImage) -> Cpio<'static> {
+        let ramdisk = image.get_blocks().get_ramdisk().unwrap();
+        if ramdisk.is_vendor_ramdisk() {
+            return ramdisk.merge_vendor_ramdisks().unwrap().0;
+        }
+        let mut data = Vec::new();
+        ramdisk.reader().unwrap().read_to_end(&mut data).unwrap();
+        Cpio::load_from_data(&data).unwrap().into_owned()
+    }
+
+    #[test]
+    fn fixtures_parse() {
+        for version in FIXTURE_VERSIONS {
+            let data = ImageFixture::new(version).build().unwrap();
+            let image = BootImage::parse(&data).unwrap();
+            assert_eq!(image.get_header().get_version(), version);
+            assert_eq!(image.get_header().cmdline_str().unwrap(), FIXTURE_CMDLINE);
+            assert!(ramdisk_cpio(&image).exists("system/etc/fixture.prop"));
+
+            let blocks = image.get_blocks();
+            let expected = match version {
+                Android(0) => &["kernel", "ramdisk", "second"][..],
+                Android(1) => &["kernel", "ramdisk", "second", "recovery_dtbo"],
+                Android(2) => &["kernel", "ramdisk", "second", "recovery_dtbo", "dtb"],
+                Android(3) => &["kernel", "ramdisk"],
+                Android(_) => &["kernel", "ramdisk", "signature"],
+                Vendor(3) => &["ramdisk", "dtb"],
+                Vendor(_) => &["ramdisk", "dtb", "vendor_ramdisk_table", "bootconfig"],
+            };
+            let names = blocks.blocks().map(|it| it.0).collect::<Vec<_>>();
+            assert_eq!(names, expected, "{:?}", version);
+            if let Some(image) = blocks.get_kernel() {
+                assert_eq!(image.get_data(), kernel());
+            }
+            if let Some(dtb) = blocks.get_dtb() {
+                let entries = crate::dtb::parse_dtb_entries(dtb).unwrap();
+                assert_eq!(entries[0].model.as_deref(), Some(FIXTURE_MODEL));
+            }
+            if let Some(table) = blocks.get_recovery_dtbo_table().unwrap() {
+                assert_eq!(table.entries.len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn vendor_v4_fragments() {
+        let data = vendor_boot_v4();
+        let image = BootImage::parse(&data).unwrap();
+        let blocks = image.get_blocks();
+        let fragments = blocks
+            .get_ramdisk()
+            .unwrap()
+            .iter_vendor_ramdisk()
+            .map(|it| (it.get_name().unwrap().to_string(), it.get_entry_type()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fragments,
+            [
+                ("".to_string(), VendorRamdiskTableEntryType::Platform),
+                (
+                    "recovery".to_string(),
+                    VendorRamdiskTableEntryType::Recovery
+                ),
+                ("dlkm".to_string(), VendorRamdiskTableEntryType::Dlkm),
+            ]
+        );
+        let bootconfig = blocks.get_bootconfig_params().unwrap().unwrap();
+        assert_eq!(
+            bootconfig.get("androidboot.hardware").unwrap().value(),
+            "fixture"
+        );
+    }
+
+    #[test]
+    fn raw_ramdisk() {
+        let data = ImageFixture::new(Android(2))
+            .ramdisk_format(CompressFormat::UNKNOWN)
+            .build()
+            .unwrap();
+        let image = BootImage::parse(&data).unwrap();
+        let ramdisk = image.get_blocks().get_ramdisk().unwrap();
+        assert_eq!(ramdisk.get_data(), default_ramdisk("boot").unwrap());
+    }
+
+    #[test]
+    fn avb_footer() {
+        for version in FIXTURE_VERSIONS {
+            let data = ImageFixture::new(version).avb_footer(None).build().unwrap();
+            assert_eq!(data.len() % 4096, 0);
+            let image = BootImage::parse(&data).unwrap();
+            assert_eq!(image.get_avb_partition_size(), Some(data.len()));
+            let vbmeta = image.get_vbmeta().unwrap().unwrap();
+            let Some(AvbDescriptor::Hash(hash)) = vbmeta.descriptors().unwrap().pop() else {
+                panic!("no hash descriptor");
+            };
+            let name = if matches!(version, Android(_)) {
+                "boot"
+            } else {
+                "vendor_boot"
+            };
+            assert_eq!(hash.partition_name, name);
+            assert_eq!(hash.salt, AVB_SALT);
+            assert!(matches!(
+                image.verify_avb().unwrap(),
+                AvbHashStatus::Matched(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn explicit_partition_size() {
+        let data = ImageFixture::new(Android(3))
+            .avb_footer(Some(64 * 1024))
+            .build()
+            .unwrap();
+        assert_eq!(data.len(), 64 * 1024);
+    }
+}
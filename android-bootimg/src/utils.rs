@@ -2,7 +2,7 @@ use bytemuck::{Pod, bytes_of, bytes_of_mut};
 use std::cmp::min;
 use std::fmt::{Debug, Display, LowerHex};
 use std::io;
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 use std::mem::MaybeUninit;
 
 // https://github.com/topjohnwu/Magisk/blob/0bbc7360519726f7e3b5004542c0131fa0c0c86f/native/src/base/files.rs#L24-L128
@@ -10,6 +10,7 @@ use std::mem::MaybeUninit;
 pub trait ReadExt {
     #[allow(unused)]
     fn skip(&mut self, len: usize) -> io::Result<()>;
+    #[cfg_attr(not(feature = "lz4"), allow(unused))]
     fn read_pod<F: Pod>(&mut self, data: &mut F) -> io::Result<()>;
 }
 
@@ -34,6 +35,7 @@ impl<T: Read> ReadExt for T {
 pub trait WriteExt {
     #[allow(unused)]
     fn write_zeros(&mut self, len: usize) -> io::Result<usize>;
+    #[cfg_attr(not(feature = "lz4"), allow(unused))]
     fn write_pod<F: Pod>(&mut self, data: &F) -> io::Result<()>;
 
     fn write_all_size(&mut self, data: &[u8]) -> io::Result<usize>;
@@ -61,12 +63,85 @@ impl<T: Write> WriteExt for T {
     }
 }
 
+// Counts the bytes passed through, so sizes are known without seeking
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    #[allow(unused)]
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct CountingReader<R: Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    #[allow(unused)]
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount);
+        self.count += amount as u64;
+    }
+}
+
 pub struct Chunker {
     chunk: Box<[u8]>,
     chunk_size: usize,
     pos: usize,
 }
 
+#[cfg_attr(not(feature = "lz4"), allow(unused))]
 impl Chunker {
     pub fn new(chunk_size: usize) -> Self {
         Chunker {
@@ -132,14 +207,38 @@ pub fn align_to<N: num_traits::PrimInt + Display + Debug + LowerHex>(num: N, ali
 }
 
 pub trait SliceExt {
+    fn u16_at(&self, offset: usize) -> Option<u16>;
     fn u32_at(&self, offset: usize) -> Option<u32>;
+    fn u64_at(&self, offset: usize) -> Option<u64>;
+    fn u32_be_at(&self, offset: usize) -> Option<u32>;
+    fn u64_be_at(&self, offset: usize) -> Option<u64>;
 }
 
 impl SliceExt for [u8] {
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        self.get(offset..offset + 2)
+            .map(|data| u16::from_le_bytes(data.try_into().unwrap()))
+    }
+
     fn u32_at(&self, offset: usize) -> Option<u32> {
         self.get(offset..offset + 4)
             .map(|data| u32::from_le_bytes(data.try_into().unwrap()))
     }
+
+    fn u64_at(&self, offset: usize) -> Option<u64> {
+        self.get(offset..offset + 8)
+            .map(|data| u64::from_le_bytes(data.try_into().unwrap()))
+    }
+
+    fn u32_be_at(&self, offset: usize) -> Option<u32> {
+        self.get(offset..offset + 4)
+            .map(|data| u32::from_be_bytes(data.try_into().unwrap()))
+    }
+
+    fn u64_be_at(&self, offset: usize) -> Option<u64> {
+        self.get(offset..offset + 8)
+            .map(|data| u64::from_be_bytes(data.try_into().unwrap()))
+    }
 }
 
 pub fn trim_end(data: &[u8]) -> &[u8] {
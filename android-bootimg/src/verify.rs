@@ -0,0 +1,282 @@
+use crate::avb::AvbHashStatus;
+use crate::digest::DigestAlgorithm;
+use crate::error::Result;
+use crate::info::AvbDescriptorInfo;
+use crate::parser::{BootHeader, BootImage};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockChange {
+    Unchanged,
+    // Same decompressed content, only the compressed bytes differ
+    Recompressed,
+    Modified,
+    Added,
+    Removed,
+}
+
+// Sizes are those of the stored, possibly compressed, blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDiff {
+    pub name: &'static str,
+    pub change: BlockChange,
+    pub original_size: Option<usize>,
+    pub patched_size: Option<usize>,
+}
+
+// None when the field only exists in one of the images
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub name: &'static str,
+    pub original: Option<String>,
+    pub patched: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageDiff {
+    pub header: Vec<FieldChange>,
+    pub blocks: Vec<BlockDiff>,
+    // Footer and vbmeta fields, see avb_fields
+    pub avb: Vec<FieldChange>,
+    pub original_has_avb: bool,
+    pub patched_has_avb: bool,
+    // Hash descriptor of the patched image
+    pub avb_hash: AvbHashStatus,
+    // Partition size of the original image, from its AVB footer
+    pub partition_size: Option<usize>,
+    pub patched_size: usize,
+}
+
+fn block_data<'a>(image: &BootImage<'a>, name: &str) -> Option<&'a [u8]> {
+    image
+        .get_blocks()
+        .blocks()
+        .find(|(it, _, _)| *it == name)
+        .map(|(_, off, len)| &image.data[off..off + len])
+}
+
+fn field_changes(
+    names: Vec<&'static str>,
+    value: impl Fn(bool, &str) -> Option<String>,
+) -> Vec<FieldChange> {
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (a, b) = (value(false, name), value(true, name));
+            (a != b).then_some(FieldChange {
+                name,
+                original: a,
+                patched: b,
+            })
+        })
+        .collect()
+}
+
+// Footer and vbmeta fields compared by diff. digest and salt come from the
+// hash descriptor.
+const AVB_FIELDS: &[&str] = &[
+    "partition_size",
+    "flags",
+    "rollback_index",
+    "algorithm",
+    "public_key_sha256",
+    "release_string",
+    "digest",
+    "salt",
+];
+
+fn avb_fields(image: &BootImage) -> Option<HashMap<&'static str, String>> {
+    let avb = image.info().avb?;
+    let vbmeta = avb.vbmeta;
+    let mut fields = HashMap::from([
+        ("partition_size", avb.partition_size.to_string()),
+        ("flags", vbmeta.flags.to_string()),
+        ("rollback_index", vbmeta.rollback_index.to_string()),
+        (
+            "algorithm",
+            vbmeta
+                .algorithm
+                .map_or(vbmeta.algorithm_type.to_string(), str::to_string),
+        ),
+    ]);
+    if let Some(key) = vbmeta.public_key_sha256 {
+        fields.insert("public_key_sha256", key);
+    }
+    if let Some(release) = vbmeta.release_string {
+        fields.insert("release_string", release);
+    }
+    if let Some(AvbDescriptorInfo::Hash { digest, salt, .. }) = vbmeta
+        .descriptors
+        .into_iter()
+        .find(|it| matches!(it, AvbDescriptorInfo::Hash { .. }))
+    {
+        fields.insert("digest", digest);
+        fields.insert("salt", salt);
+    }
+    Some(fields)
+}
+
+fn compare_avb(original: &BootImage, patched: &BootImage) -> Vec<FieldChange> {
+    let fields = [avb_fields(original), avb_fields(patched)];
+    field_changes(AVB_FIELDS.to_vec(), |patched, name| {
+        fields[patched as usize]
+            .as_ref()
+            .and_then(|it| it.get(name).cloned())
+    })
+}
+
+fn compare_header(original: &BootImage, patched: &BootImage) -> Vec<FieldChange> {
+    let (original, patched) = (original.get_header(), patched.get_header());
+    let value = |header: &BootHeader, name: &str| {
+        header
+            .get_layout()
+            .fields()
+            .iter()
+            .find(|it| it.name == name)
+            .map(|field| header.field_value(field).to_string())
+    };
+    let mut names: Vec<&'static str> = original
+        .get_layout()
+        .fields()
+        .iter()
+        .map(|it| it.name)
+        .collect();
+    for field in patched.get_layout().fields() {
+        if !names.contains(&field.name) {
+            names.push(field.name);
+        }
+    }
+    field_changes(names, |is_patched, name| {
+        value(if is_patched { patched } else { original }, name)
+    })
+}
+
+impl BootImage<'_> {
+    // Block, header and AVB differences from self to `patched`. Blocks whose
+    // bytes differ are compared again by a digest of their decompressed content.
+    pub fn diff(&self, patched: &BootImage) -> Result<ImageDiff> {
+        let original = self;
+        let mut names: Vec<&'static str> = original.get_blocks().blocks().map(|it| it.0).collect();
+        for (name, _, _) in patched.get_blocks().blocks() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        let mut blocks = Vec::new();
+        for name in names {
+            let (a, b) = (block_data(original, name), block_data(patched, name));
+            let change = match (a, b) {
+                (Some(a), Some(b)) if a == b => BlockChange::Unchanged,
+                (Some(_), Some(_)) => {
+                    let algorithm = DigestAlgorithm::Sha256;
+                    if original.digest_block(name, algorithm)?
+                        == patched.digest_block(name, algorithm)?
+                    {
+                        BlockChange::Recompressed
+                    } else {
+                        BlockChange::Modified
+                    }
+                }
+                (None, Some(_)) => BlockChange::Added,
+                (Some(_), None) => BlockChange::Removed,
+                (None, None) => continue,
+            };
+            blocks.push(BlockDiff {
+                name,
+                change,
+                original_size: a.map(<[u8]>::len),
+                patched_size: b.map(<[u8]>::len),
+            });
+        }
+
+        Ok(ImageDiff {
+            header: compare_header(original, patched),
+            blocks,
+            avb: compare_avb(original, patched),
+            original_has_avb: original.avb_info.is_some(),
+            patched_has_avb: patched.avb_info.is_some(),
+            avb_hash: patched.verify_avb()?,
+            partition_size: original.get_avb_partition_size(),
+            patched_size: patched.data.len(),
+        })
+    }
+}
+
+impl ImageDiff {
+    pub fn fits_partition(&self) -> bool {
+        self.partition_size
+            .is_none_or(|size| self.patched_size <= size)
+    }
+
+    // Differences not covered by `expect`, which holds block names, header and
+    // vbmeta field names, "header" for every header field or "avb" for every
+    // vbmeta field and dropping the footer. Size, offset and id fields, the
+    // AVB digest and the vendor ramdisk table may follow the blocks that
+    // changed.
+    pub fn unexpected(&self, expect: &[&str]) -> Vec<String> {
+        let mut found = Vec::new();
+        let blocks_changed = self
+            .blocks
+            .iter()
+            .any(|it| it.change != BlockChange::Unchanged);
+        let ramdisk_changed = self
+            .blocks
+            .iter()
+            .any(|it| it.name == "ramdisk" && it.change != BlockChange::Unchanged);
+        for block in &self.blocks {
+            match block.change {
+                BlockChange::Unchanged | BlockChange::Recompressed => {}
+                _ if expect.contains(&block.name) => {}
+                // Entry sizes and offsets follow the vendor ramdisks
+                _ if block.name == "vendor_ramdisk_table" && ramdisk_changed => {}
+                change => found.push(format!("block {} {}", block.name, change)),
+            }
+        }
+        for field in &self.header {
+            let derived = field.name.ends_with("_size")
+                || field.name.ends_with("_offset")
+                || field.name == "id";
+            if !(expect.contains(&"header")
+                || expect.contains(&field.name)
+                || (derived && blocks_changed))
+            {
+                found.push(format!("header field {} changed", field.name));
+            }
+        }
+        if self.original_has_avb && self.patched_has_avb {
+            for field in &self.avb {
+                let derived = field.name == "digest" && blocks_changed;
+                if !(expect.contains(&"avb") || expect.contains(&field.name) || derived) {
+                    found.push(format!("avb field {} changed", field.name));
+                }
+            }
+        }
+        if let AvbHashStatus::Mismatched(_) = self.avb_hash {
+            found.push("avb hash mismatched".to_string());
+        }
+        if self.original_has_avb && !self.patched_has_avb && !expect.contains(&"avb") {
+            found.push("avb footer removed".to_string());
+        }
+        if !self.fits_partition() {
+            found.push(format!(
+                "{} bytes exceed the partition size {}",
+                self.patched_size,
+                self.partition_size.unwrap_or_default()
+            ));
+        }
+        found
+    }
+}
+
+impl Display for BlockChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BlockChange::Unchanged => "unchanged",
+            BlockChange::Recompressed => "recompressed",
+            BlockChange::Modified => "modified",
+            BlockChange::Added => "added",
+            BlockChange::Removed => "removed",
+        })
+    }
+}
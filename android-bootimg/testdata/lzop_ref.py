@@ -0,0 +1,162 @@
+#!/usr/bin/env python3
+# Writes plain.lzo and mixed.lzo. lzop is not available where the fixtures
+# were made, so this is the LZO1X-1 compressor of the Linux kernel
+# (lib/lzo/lzo1x_compress.c, without the RLE extension) in the lzop container
+# of lzop.c. The tests only decode the files, `lzop -9` output of the same
+# inputs must pass them as well.
+
+import os
+import struct
+import zlib
+
+D_BITS = 13
+M2_MAX_LEN, M3_MAX_LEN, M4_MAX_LEN = 8, 33, 9
+M2_MAX_OFFSET, M3_MAX_OFFSET, M4_MAX_OFFSET = 0x0800, 0x4000, 0xBFFF
+M3_MARKER, M4_MARKER = 32, 16
+
+
+def le32(data, pos):
+    return struct.unpack_from('<I', data, pos)[0]
+
+
+def do_compress(data, start, in_len, out, ti):
+    in_end = start + in_len
+    ip_end = start + in_len - 20
+    ii = start
+    ip = start + (4 - ti if ti < 4 else 0)
+    dictionary = [0] * (1 << D_BITS)
+    while True:
+        ip += 1 + ((ip - ii) >> 5)
+        while True:
+            if ip >= ip_end:
+                return in_end - (ii - ti)
+            dv = le32(data, ip)
+            t = ((dv * 0x1824429D) & 0xFFFFFFFF) >> (32 - D_BITS)
+            m_pos = start + dictionary[t]
+            dictionary[t] = ip - start
+            if dv != le32(data, m_pos):
+                break
+
+            ii -= ti
+            ti = 0
+            t = ip - ii
+            if t != 0:
+                if t <= 3:
+                    out[-2] |= t
+                elif t <= 18:
+                    out.append(t - 3)
+                else:
+                    tt = t - 18
+                    out.append(0)
+                    while tt > 255:
+                        tt -= 255
+                        out.append(0)
+                    out.append(tt)
+                out.extend(data[ii:ii + t])
+
+            m_len = 4
+            while ip + m_len < ip_end and data[ip + m_len] == data[m_pos + m_len]:
+                m_len += 1
+            m_off = ip - m_pos
+            ip += m_len
+            ii = ip
+            if m_len <= M2_MAX_LEN and m_off <= M2_MAX_OFFSET:
+                m_off -= 1
+                out.append(((m_len - 1) << 5) | ((m_off & 7) << 2))
+                out.append(m_off >> 3)
+                continue
+            if m_off <= M3_MAX_OFFSET:
+                m_off -= 1
+                if m_len <= M3_MAX_LEN:
+                    out.append(M3_MARKER | (m_len - 2))
+                else:
+                    m_len -= M3_MAX_LEN
+                    out.append(M3_MARKER)
+                    while m_len > 255:
+                        m_len -= 255
+                        out.append(0)
+                    out.append(m_len)
+            else:
+                m_off -= 0x4000
+                if m_len <= M4_MAX_LEN:
+                    out.append(M4_MARKER | ((m_off >> 11) & 8) | (m_len - 2))
+                else:
+                    m_len -= M4_MAX_LEN
+                    out.append(M4_MARKER | ((m_off >> 11) & 8))
+                    while m_len > 255:
+                        m_len -= 255
+                        out.append(0)
+                    out.append(m_len)
+            out.append((m_off << 2) & 0xFF)
+            out.append(m_off >> 6)
+
+
+def lzo1x_1_compress(data):
+    out = bytearray()
+    pos, remaining, t = 0, len(data), 0
+    while remaining > 20:
+        ll = min(remaining, M4_MAX_OFFSET + 1)
+        t = do_compress(data, pos, ll, out, t)
+        pos += ll
+        remaining -= ll
+    t += remaining
+    if t > 0:
+        ii = len(data) - t
+        if not out and t <= 238:
+            out.append(17 + t)
+        elif t <= 3:
+            out[-2] |= t
+        elif t <= 18:
+            out.append(t - 3)
+        else:
+            tt = t - 18
+            out.append(0)
+            while tt > 255:
+                tt -= 255
+                out.append(0)
+            out.append(tt)
+        out.extend(data[ii:])
+    out.extend(bytes([M4_MARKER | 1, 0, 0]))
+    return bytes(out)
+
+
+def lzop(data, name=b'plain'):
+    # F_OS_UNIX | F_ADLER32_D, the defaults of lzop
+    flags = 0x03000001
+    header = struct.pack('>HHHBBIIII', 0x1040, 0x20A0, 0x0940, 1, 5, flags,
+                         0o100644, 0, 0)
+    header += bytes([len(name)]) + name
+    out = b'\x89LZO\x00\x0d\x0a\x1a\x0a' + header
+    out += struct.pack('>I', zlib.adler32(header))
+    # lzop splits the input into 256K blocks
+    for start in range(0, len(data), 256 * 1024):
+        block = data[start:start + 256 * 1024]
+        compressed = lzo1x_1_compress(block)
+        out += struct.pack('>II', len(block), min(len(compressed), len(block)))
+        out += struct.pack('>I', zlib.adler32(block))
+        out += compressed if len(compressed) < len(block) else block
+    return out + struct.pack('>I', 0)
+
+
+# fixture_text() of compress.rs
+def plain():
+    return b'android-bootimg compression fixture\n' * 256
+
+
+# lzo_mixed_text() of compress.rs. Four letters give short matches close by
+# and longer ones past 2K, the digits repeated 17K later take M4 matches.
+def mixed():
+    x, out = 1, bytearray()
+    for i in range(26 * 1024):
+        if i == 8 * 1024:
+            out += b'0123456789' * 10
+        x = (x * 1103515245 + 12345) & 0x7FFFFFFF
+        out.append(b'ACGT'[(x >> 16) & 3])
+    return bytes(out + b'0123456789' * 10)
+
+
+if __name__ == '__main__':
+    os.chdir(os.path.dirname(os.path.abspath(__file__)))
+    for name, data in [('plain', plain()), ('mixed', mixed())]:
+        with open(name + '.lzo', 'wb') as f:
+            f.write(lzop(data, name.encode()))
@@ -0,0 +1,41 @@
+#!/bin/sh
+# Writes the inputs and the golden images of the builder and mkbootimg tests.
+# MKBOOTIMG can point at AOSP mkbootimg.py instead of the copy of its layout
+# code, e.g. MKBOOTIMG="python3 system/tools/mkbootimg/mkbootimg.py".
+set -e
+cd "$(dirname "$0")"
+MKBOOTIMG=${MKBOOTIMG:-"python3 mkbootimg_ref.py"}
+
+# Sizes that are not page multiples, the kernel spans pages
+for name in kernel:2500 ramdisk:700 second:300 recovery_dtbo:200 dtb:400 \
+    vendor_ramdisk:500 dlkm:250 bootconfig:60; do
+    python3 -c "import sys; n, s = sys.argv[1].split(':'); \
+sys.stdout.write(('%s golden input\n' % n * int(s))[:int(s)])" "$name" > "${name%:*}"
+done
+
+CMDLINE="console=ttyMSM0,115200n8 androidboot.hardware=qcom"
+
+$MKBOOTIMG --header_version 0 --kernel kernel --ramdisk ramdisk --second second \
+    --cmdline "$CMDLINE" --board golden --base 0x80000000 --pagesize 2048 \
+    --os_version 10.0.0 --os_patch_level 2020-01 -o boot-v0.img
+$MKBOOTIMG --header_version 1 --kernel kernel --ramdisk ramdisk \
+    --recovery_dtbo recovery_dtbo --cmdline "$CMDLINE" --base 0x00000000 \
+    --kernel_offset 0x00080000 --pagesize 4096 --os_version 11 \
+    --os_patch_level 2021-06 -o boot-v1.img
+$MKBOOTIMG --header_version 2 --kernel kernel --ramdisk ramdisk --second second \
+    --recovery_dtbo recovery_dtbo --dtb dtb --cmdline "$CMDLINE" --board golden \
+    --base 0x40000000 --ramdisk_offset 0x02000000 --tags_offset 0x00000100 \
+    --dtb_offset 0x01f00000 --pagesize 2048 --os_version 12.1.0 \
+    --os_patch_level 2022-03 -o boot-v2.img
+$MKBOOTIMG --header_version 3 --kernel kernel --ramdisk ramdisk \
+    --cmdline "$CMDLINE" --os_version 12 --os_patch_level 2022-05 -o boot-v3.img
+$MKBOOTIMG --header_version 4 --kernel kernel --ramdisk ramdisk \
+    --cmdline "$CMDLINE" --os_version 13.0.0 --os_patch_level 2023-05 -o boot-v4.img
+$MKBOOTIMG --header_version 3 --vendor_boot vendor_boot-v3.img \
+    --vendor_ramdisk vendor_ramdisk --dtb dtb --vendor_cmdline "$CMDLINE" \
+    --board golden --base 0x00000000 --kernel_offset 0x00008000 --pagesize 2048
+$MKBOOTIMG --header_version 4 --vendor_boot vendor_boot-v4.img \
+    --vendor_ramdisk vendor_ramdisk --dtb dtb --vendor_cmdline "$CMDLINE" \
+    --board golden --pagesize 4096 --vendor_bootconfig bootconfig \
+    --ramdisk_type dlkm --ramdisk_name dlkm --board_id0 0x1234 \
+    --vendor_ramdisk_fragment dlkm
@@ -0,0 +1,261 @@
+#!/usr/bin/env python3
+# The layout code of AOSP mkbootimg.py (write_header, write_vendor_boot_header,
+# write_data, write_vendor_boot_data) copied for the arguments goldens.sh
+# uses, so the goldens can be made without an AOSP checkout:
+# https://android.googlesource.com/platform/system/tools/mkbootimg/+/refs/heads/main/mkbootimg.py
+# Running goldens.sh with MKBOOTIMG pointing at the real mkbootimg.py must
+# give the same files.
+
+import argparse
+from hashlib import sha1
+from struct import pack
+
+BOOT_MAGIC = b'ANDROID!'
+VENDOR_BOOT_MAGIC = b'VNDRBOOT'
+BOOT_NAME_SIZE = 16
+BOOT_ARGS_SIZE = 512
+BOOT_EXTRA_ARGS_SIZE = 1024
+BOOT_IMAGE_HEADER_V1_SIZE = 1648
+BOOT_IMAGE_HEADER_V2_SIZE = 1660
+BOOT_IMAGE_HEADER_V3_SIZE = 1580
+BOOT_IMAGE_HEADER_V3_PAGESIZE = 4096
+BOOT_IMAGE_HEADER_V4_SIZE = 1584
+VENDOR_BOOT_ARGS_SIZE = 2048
+VENDOR_BOOT_NAME_SIZE = 16
+VENDOR_BOOT_IMAGE_HEADER_V3_SIZE = 2112
+VENDOR_BOOT_IMAGE_HEADER_V4_SIZE = 2128
+VENDOR_RAMDISK_TABLE_ENTRY_V4_SIZE = 108
+VENDOR_RAMDISK_NAME_SIZE = 32
+VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE = 16
+VENDOR_RAMDISK_TYPES = {'none': 0, 'platform': 1, 'recovery': 2, 'dlkm': 3}
+
+
+def filesize(f):
+    if f is None:
+        return 0
+    with open(f, 'rb') as f:
+        return len(f.read())
+
+
+def read(f):
+    with open(f, 'rb') as f:
+        return f.read()
+
+
+def pad_file(f, padding):
+    pad = (padding - (f.tell() & (padding - 1))) & (padding - 1)
+    f.write(pack(str(pad) + 'x'))
+
+
+def update_sha(sha, f):
+    if f:
+        sha.update(read(f))
+        sha.update(pack('I', filesize(f)))
+    else:
+        sha.update(pack('I', 0))
+
+
+def get_number_of_pages(image_size, page_size):
+    return (image_size + page_size - 1) // page_size
+
+
+def get_recovery_dtbo_offset(args):
+    num_header_pages = 1
+    num_kernel_pages = get_number_of_pages(filesize(args.kernel), args.pagesize)
+    num_ramdisk_pages = get_number_of_pages(filesize(args.ramdisk), args.pagesize)
+    num_second_pages = get_number_of_pages(filesize(args.second), args.pagesize)
+    return args.pagesize * (num_header_pages + num_kernel_pages +
+                            num_ramdisk_pages + num_second_pages)
+
+
+def os_version(value):
+    a, b, c = (list(map(int, value.split('.'))) + [0, 0])[:3]
+    return (a << 14) | (b << 7) | c
+
+
+def os_patch_level(value):
+    y, m = map(int, value.split('-')[:2])
+    return ((y - 2000) << 4) | m
+
+
+def write_header_v3_and_above(args):
+    if args.header_version > 3:
+        boot_header_size = BOOT_IMAGE_HEADER_V4_SIZE
+    else:
+        boot_header_size = BOOT_IMAGE_HEADER_V3_SIZE
+    args.output.write(pack(f'{len(BOOT_MAGIC)}s', BOOT_MAGIC))
+    args.output.write(pack('I', filesize(args.kernel)))
+    args.output.write(pack('I', filesize(args.ramdisk)))
+    args.output.write(pack('I', (args.os_version << 11) | args.os_patch_level))
+    args.output.write(pack('I', boot_header_size))
+    args.output.write(pack('4I', 0, 0, 0, 0))
+    args.output.write(pack('I', args.header_version))
+    args.output.write(pack(f'{BOOT_ARGS_SIZE + BOOT_EXTRA_ARGS_SIZE}s',
+                           args.cmdline.encode()))
+    if args.header_version >= 4:
+        args.output.write(pack('I', 0))
+    pad_file(args.output, BOOT_IMAGE_HEADER_V3_PAGESIZE)
+
+
+def write_vendor_boot_header(args, ramdisks):
+    if args.header_version > 3:
+        vendor_ramdisk_size = sum(len(data) for _, _, _, data in ramdisks)
+        vendor_boot_header_size = VENDOR_BOOT_IMAGE_HEADER_V4_SIZE
+    else:
+        vendor_ramdisk_size = filesize(args.vendor_ramdisk)
+        vendor_boot_header_size = VENDOR_BOOT_IMAGE_HEADER_V3_SIZE
+    out = args.vendor_boot
+    out.write(pack(f'{len(VENDOR_BOOT_MAGIC)}s', VENDOR_BOOT_MAGIC))
+    out.write(pack('I', args.header_version))
+    out.write(pack('I', args.pagesize))
+    out.write(pack('I', args.base + args.kernel_offset))
+    out.write(pack('I', args.base + args.ramdisk_offset))
+    out.write(pack('I', vendor_ramdisk_size))
+    out.write(pack(f'{VENDOR_BOOT_ARGS_SIZE}s', args.vendor_cmdline.encode()))
+    out.write(pack('I', args.base + args.tags_offset))
+    out.write(pack(f'{VENDOR_BOOT_NAME_SIZE}s', args.board.encode()))
+    out.write(pack('I', vendor_boot_header_size))
+    out.write(pack('I', filesize(args.dtb)))
+    out.write(pack('Q', args.base + args.dtb_offset))
+    if args.header_version > 3:
+        out.write(pack('I', len(ramdisks) * VENDOR_RAMDISK_TABLE_ENTRY_V4_SIZE))
+        out.write(pack('I', len(ramdisks)))
+        out.write(pack('I', VENDOR_RAMDISK_TABLE_ENTRY_V4_SIZE))
+        out.write(pack('I', filesize(args.vendor_bootconfig)))
+    pad_file(out, args.pagesize)
+
+
+def write_header(args):
+    if args.header_version > 2:
+        return write_header_v3_and_above(args)
+    out = args.output
+    out.write(pack(f'{len(BOOT_MAGIC)}s', BOOT_MAGIC))
+    out.write(pack('4I', filesize(args.kernel), args.base + args.kernel_offset,
+                   filesize(args.ramdisk), args.base + args.ramdisk_offset))
+    out.write(pack('2I', filesize(args.second), args.base + args.second_offset))
+    out.write(pack('I', args.base + args.tags_offset))
+    out.write(pack('I', args.pagesize))
+    out.write(pack('I', args.header_version))
+    out.write(pack('I', (args.os_version << 11) | args.os_patch_level))
+    out.write(pack(f'{BOOT_NAME_SIZE}s', args.board.encode()))
+    out.write(pack(f'{BOOT_ARGS_SIZE}s', args.cmdline[:BOOT_ARGS_SIZE - 1].encode()))
+
+    sha = sha1()
+    update_sha(sha, args.kernel)
+    update_sha(sha, args.ramdisk)
+    update_sha(sha, args.second)
+    if args.header_version > 0:
+        update_sha(sha, args.recovery_dtbo)
+    if args.header_version > 1:
+        update_sha(sha, args.dtb)
+    out.write(pack('32s', sha.digest()))
+
+    out.write(pack(f'{BOOT_EXTRA_ARGS_SIZE}s',
+                   args.cmdline[BOOT_ARGS_SIZE - 1:].encode()))
+    if args.header_version > 0:
+        if args.recovery_dtbo:
+            out.write(pack('I', filesize(args.recovery_dtbo)))
+            out.write(pack('Q', get_recovery_dtbo_offset(args)))
+        else:
+            out.write(pack('I', 0))
+            out.write(pack('Q', 0))
+        if args.header_version == 1:
+            out.write(pack('I', BOOT_IMAGE_HEADER_V1_SIZE))
+        else:
+            out.write(pack('I', BOOT_IMAGE_HEADER_V2_SIZE))
+    if args.header_version > 1:
+        out.write(pack('I', filesize(args.dtb)))
+        out.write(pack('Q', args.base + args.dtb_offset))
+    pad_file(out, args.pagesize)
+
+
+def write_padded_file(out, f, padding):
+    if f is None:
+        return
+    out.write(read(f))
+    pad_file(out, padding)
+
+
+def write_data(args, pagesize):
+    write_padded_file(args.output, args.kernel, pagesize)
+    write_padded_file(args.output, args.ramdisk, pagesize)
+    write_padded_file(args.output, args.second, pagesize)
+    if 0 < args.header_version < 3:
+        write_padded_file(args.output, args.recovery_dtbo, pagesize)
+    if args.header_version == 2:
+        write_padded_file(args.output, args.dtb, pagesize)
+
+
+def write_vendor_boot_data(args, ramdisks):
+    out = args.vendor_boot
+    if args.header_version > 3:
+        for _, _, _, data in ramdisks:
+            out.write(data)
+        pad_file(out, args.pagesize)
+        write_padded_file(out, args.dtb, args.pagesize)
+        offset = 0
+        for name, ramdisk_type, board_id, data in ramdisks:
+            out.write(pack('I', len(data)))
+            out.write(pack('I', offset))
+            out.write(pack('I', ramdisk_type))
+            out.write(pack(f'{VENDOR_RAMDISK_NAME_SIZE}s', name.encode()))
+            out.write(pack(f'{VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE}I', *board_id))
+            offset += len(data)
+        pad_file(out, args.pagesize)
+        write_padded_file(out, args.vendor_bootconfig, args.pagesize)
+    else:
+        write_padded_file(out, args.vendor_ramdisk, args.pagesize)
+        write_padded_file(out, args.dtb, args.pagesize)
+
+
+def parse_args():
+    parser = argparse.ArgumentParser()
+    for name in ['kernel', 'ramdisk', 'second', 'dtb', 'recovery_dtbo',
+                 'vendor_ramdisk', 'vendor_bootconfig']:
+        parser.add_argument(f'--{name}')
+    parser.add_argument('--cmdline', default='')
+    parser.add_argument('--vendor_cmdline', default='')
+    parser.add_argument('--board', default='')
+    parser.add_argument('--base', type=lambda x: int(x, 0), default=0x10000000)
+    parser.add_argument('--kernel_offset', type=lambda x: int(x, 0), default=0x00008000)
+    parser.add_argument('--ramdisk_offset', type=lambda x: int(x, 0), default=0x01000000)
+    parser.add_argument('--second_offset', type=lambda x: int(x, 0), default=0x00f00000)
+    parser.add_argument('--dtb_offset', type=lambda x: int(x, 0), default=0x01f00000)
+    parser.add_argument('--tags_offset', type=lambda x: int(x, 0), default=0x00000100)
+    parser.add_argument('--pagesize', type=int, default=2048)
+    parser.add_argument('--os_version', type=os_version, default=0)
+    parser.add_argument('--os_patch_level', type=os_patch_level, default=0)
+    parser.add_argument('--header_version', type=int, default=0)
+    parser.add_argument('-o', '--output', type=argparse.FileType('wb'))
+    parser.add_argument('--vendor_boot', type=argparse.FileType('wb'))
+    # Only one fragment, which is enough for the goldens
+    parser.add_argument('--ramdisk_type', default='none')
+    parser.add_argument('--ramdisk_name', default='')
+    parser.add_argument('--board_id0', type=lambda x: int(x, 0), default=0)
+    parser.add_argument('--vendor_ramdisk_fragment')
+    return parser.parse_args()
+
+
+def main():
+    args = parse_args()
+    if args.output is not None:
+        write_header(args)
+        if args.header_version > 2:
+            pagesize = BOOT_IMAGE_HEADER_V3_PAGESIZE
+        else:
+            pagesize = args.pagesize
+        write_data(args, pagesize)
+    if args.vendor_boot is not None:
+        ramdisks = []
+        if args.vendor_ramdisk:
+            ramdisks.append(('', 1, [0] * 16, read(args.vendor_ramdisk)))
+        if args.vendor_ramdisk_fragment:
+            board_id = [args.board_id0] + [0] * 15
+            ramdisks.append((args.ramdisk_name, VENDOR_RAMDISK_TYPES[args.ramdisk_type],
+                             board_id, read(args.vendor_ramdisk_fragment)))
+        write_vendor_boot_header(args, ramdisks)
+        write_vendor_boot_data(args, ramdisks)
+
+
+if __name__ == '__main__':
+    main()
@@ -0,0 +1,26 @@
+// Boot image inspection for the browser, build with
+// wasm-pack build --target web examples/wasm-inspector
+use android_bootimg::parser::BootImage;
+use android_bootimg::patcher::BootImagePatchOption;
+use std::io::Cursor;
+use wasm_bindgen::prelude::*;
+
+fn js_error(e: impl std::fmt::Display) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+// The JSON of the CLI info command for an image passed as a Uint8Array
+#[wasm_bindgen]
+pub fn info(image: &[u8]) -> Result<String, JsError> {
+    let boot = BootImage::parse(image).map_err(js_error)?;
+    serde_json::to_string(&boot.info()).map_err(js_error)
+}
+
+// The image with its ramdisk replaced, compressed like the original one
+#[wasm_bindgen]
+pub fn replace_ramdisk(image: &[u8], ramdisk: Vec<u8>) -> Result<Vec<u8>, JsError> {
+    let boot = BootImage::parse(image).map_err(js_error)?;
+    let mut patcher = BootImagePatchOption::new(&boot);
+    patcher.replace_ramdisk(Box::new(Cursor::new(ramdisk)));
+    patcher.patch_to_vec().map_err(js_error)
+}
@@ -0,0 +1,14 @@
+#![no_main]
+
+use android_bootimg::cpio::Cpio;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(cpio) = Cpio::load_from_data(data) else {
+        return;
+    };
+    let mut output = Vec::new();
+    // Loaded archives must dump and load again
+    cpio.dump(&mut output).unwrap();
+    Cpio::load_from_data(&output).unwrap();
+});
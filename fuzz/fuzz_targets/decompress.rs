@@ -0,0 +1,26 @@
+#![no_main]
+
+use android_bootimg::compress::{
+    CompressFormat, LimitedDecoder, estimate_uncompressed_size, get_decoder,
+    parse_compress_format,
+};
+use libfuzzer_sys::fuzz_target;
+use std::io::Read;
+
+// Small inputs can still expand to gigabytes
+const OUTPUT_LIMIT: u64 = 16 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    let format = parse_compress_format(data);
+    if format == CompressFormat::UNKNOWN {
+        return;
+    }
+    let _ = estimate_uncompressed_size(format, data);
+    let Ok(decoder) = get_decoder(format, data) else {
+        return;
+    };
+    let _ = std::io::copy(
+        &mut LimitedDecoder::new(decoder, OUTPUT_LIMIT).take(OUTPUT_LIMIT + 1),
+        &mut std::io::sink(),
+    );
+});
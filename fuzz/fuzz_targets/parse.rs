@@ -0,0 +1,13 @@
+#![no_main]
+
+use android_bootimg::parser::BootImage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(image) = BootImage::parse(data) else {
+        return;
+    };
+    let _ = image.info();
+    let _ = image.block_map();
+    let _ = image.kind();
+});
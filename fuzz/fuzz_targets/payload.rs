@@ -0,0 +1,18 @@
+#![no_main]
+
+use android_bootimg::payload::Payload;
+use libfuzzer_sys::fuzz_target;
+
+// Zero operations can legitimately describe huge partitions
+const PARTITION_LIMIT: u64 = 16 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(payload) = Payload::parse(data) else {
+        return;
+    };
+    for partition in payload.partitions() {
+        if partition.size <= PARTITION_LIMIT {
+            let _ = payload.extract(&partition.name);
+        }
+    }
+});
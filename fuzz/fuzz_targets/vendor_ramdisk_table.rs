@@ -0,0 +1,57 @@
+#![no_main]
+
+// Wraps the input in a vendor boot v4 header so that every run reaches the
+// vendor ramdisk table. The first bytes pick the entry count, the entry size
+// and how much of the rest is ramdisk, the remainder is the table.
+use android_bootimg::parser::BootImage;
+use libfuzzer_sys::fuzz_target;
+
+const PAGE_SIZE: usize = 4096;
+const ENTRY_SIZE: u32 = 108;
+
+fn pad(image: &mut Vec<u8>) {
+    image.resize(image.len().next_multiple_of(PAGE_SIZE), 0);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let [entry_num, entry_size, len_lo, len_hi, rest @ ..] = data else {
+        return;
+    };
+    let ramdisk_len = (u16::from_le_bytes([*len_lo, *len_hi]) as usize).min(rest.len());
+    let (ramdisk, table) = rest.split_at(ramdisk_len);
+    let entry_size = if *entry_size == 0 {
+        ENTRY_SIZE
+    } else {
+        *entry_size as u32
+    };
+
+    let mut image = b"VNDRBOOT".to_vec();
+    for value in [4, PAGE_SIZE as u32, 0, 0, ramdisk.len() as u32] {
+        image.extend_from_slice(&value.to_le_bytes());
+    }
+    // cmdline, tags_addr, name
+    image.resize(image.len() + 2048 + 4 + 16, 0);
+    image.extend_from_slice(&2128u32.to_le_bytes());
+    // dtb_size, dtb_addr
+    image.resize(image.len() + 4 + 8, 0);
+    for value in [table.len() as u32, *entry_num as u32, entry_size, 0] {
+        image.extend_from_slice(&value.to_le_bytes());
+    }
+    pad(&mut image);
+    image.extend_from_slice(ramdisk);
+    pad(&mut image);
+    image.extend_from_slice(table);
+    pad(&mut image);
+
+    let Ok(image) = BootImage::parse(&image) else {
+        return;
+    };
+    let _ = image.info();
+    if let Some(ramdisk) = image.get_blocks().get_ramdisk() {
+        for entry in ramdisk.iter_vendor_ramdisk() {
+            let _ = entry.get_name();
+            let _ = entry.get_content_kind();
+        }
+        let _ = ramdisk.merge_vendor_ramdisks();
+    }
+});